@@ -0,0 +1,89 @@
+//! Compares `--jobs 1` with bounded parallelism on a profile with many
+//! generations, against a runner that makes every command take a fixed time.
+//!
+//! Run with `cargo bench --bench parallel`.
+
+use std::process::Output;
+use std::time::{Duration, Instant};
+
+use nix_timemach::error::Result;
+use nix_timemach::services::runner::{CommandRunner, MockCommandRunner};
+use nix_timemach::NixService;
+
+const PROFILE: &str = "/nix/var/nix/profiles/system";
+const GENERATIONS: usize = 60;
+const LATENCY: Duration = Duration::from_millis(5);
+
+/// Sleeps for `LATENCY` before answering, like a nix command would.
+struct SlowRunner(MockCommandRunner);
+
+impl CommandRunner for SlowRunner {
+    fn run(&self, program: &str, args: &[&str]) -> Result<Output> {
+        std::thread::sleep(LATENCY);
+        self.0.run(program, args)
+    }
+}
+
+fn runner() -> SlowRunner {
+    let list: String = (1..=GENERATIONS)
+        .map(|id| format!("{:4}   2024-02-09 10:00:00   \n", id))
+        .collect();
+    let mut mock = MockCommandRunner::new()
+        .with_stdout(&format!("nix-env --list-generations -p {}", PROFILE), &list)
+        .with_stdout(
+            &format!("readlink {}", PROFILE),
+            &format!("system-{}-link\n", GENERATIONS),
+        );
+
+    for id in 1..=GENERATIONS {
+        mock = mock.with_stdout(
+            &format!("nix-store -q --references {}-{}-link", PROFILE, id),
+            &format!("/nix/store/{:0>32}-pkg-{}\n", id, id),
+        );
+    }
+    let paths: Vec<String> = (1..GENERATIONS)
+        .map(|id| format!("/nix/store/{:0>32}-pkg-{}", id, id))
+        .collect();
+    let info: String = paths
+        .iter()
+        .map(|path| format!("\"{}\":{{\"narSize\":1024}}", path))
+        .collect::<Vec<_>>()
+        .join(",");
+    mock = mock.with_stdout(
+        &format!("nix path-info -S --json {}", paths.join(" ")),
+        &format!("{{{}}}", info),
+    );
+
+    SlowRunner(mock)
+}
+
+fn time(label: &str, jobs: usize, query: impl Fn(&NixService<SlowRunner>)) -> Duration {
+    let service = NixService::with_runner(runner()).with_jobs(jobs);
+    let started = Instant::now();
+    query(&service);
+    let elapsed = started.elapsed();
+    println!("{:<18} jobs={:<2} {:>8.1?}", label, jobs, elapsed);
+    elapsed
+}
+
+fn main() {
+    for (label, query) in [
+        (
+            "list-generations",
+            (|s: &NixService<SlowRunner>| {
+                s.list_generations().unwrap();
+            }) as fn(&NixService<SlowRunner>),
+        ),
+        ("gc-preview", |s| {
+            s.gc_preview().unwrap();
+        }),
+    ] {
+        let sequential = time(label, 1, query);
+        let parallel = time(label, 8, query);
+        println!(
+            "{:<18} speedup  {:>7.1}x",
+            label,
+            sequential.as_secs_f64() / parallel.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,95 @@
+//! Baselines for the generation-list parser and the reference set
+//! difference behind `diff`, on synthetic input so no Nix store is needed.
+//!
+//! Run with `cargo bench --bench parse`.
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+
+use nix_timemach::services::diff::diff_references;
+use nix_timemach::services::parse::parse_generations_output;
+use nix_timemach::services::runner::MockCommandRunner;
+use nix_timemach::NixService;
+
+const PROFILE: &str = "/nix/var/nix/profiles/system";
+const GENERATIONS: usize = 1000;
+const REFERENCES: usize = 5000;
+/// About the size of a desktop NixOS closure, as diffed by `--mode requisites`.
+const REQUISITES: usize = 50_000;
+
+fn generation_list() -> String {
+    (1..=GENERATIONS)
+        .map(|id| {
+            format!(
+                "{:5}   2024-02-09 10:{:02}:{:02}   nixos-24.05.{}\n",
+                id,
+                id / 60 % 60,
+                id % 60,
+                id
+            )
+        })
+        .collect()
+}
+
+/// `count` paths per side, overlapping by half. Every seventh shared
+/// package changes version, so the diff has additions, removals and
+/// modifications.
+fn references(count: usize) -> (Vec<String>, Vec<String>) {
+    let path = |id: usize, major: usize| {
+        format!("/nix/store/{:0>32}-pkg-{}-{}.0", id * 10 + major, id, major)
+    };
+    let from = (0..count).map(|id| path(id, 1)).collect();
+    let to = (count / 2..count * 3 / 2)
+        .map(|id| path(id, if id % 7 == 0 { 2 } else { 1 }))
+        .collect();
+    (from, to)
+}
+
+/// A service whose `get_diff` falls back to diffing the references above,
+/// as it does when nix-diff isn't installed.
+fn service(from_refs: &[String], to_refs: &[String]) -> NixService<MockCommandRunner> {
+    let mut runner = MockCommandRunner::new().with_missing_program("nix-diff");
+    for (id, references) in [("1", from_refs), ("2", to_refs)] {
+        let link = format!("{}-{}-link", PROFILE, id);
+        runner = runner
+            .with_stdout(
+                &format!("nix-env -p {} --query --out-path", link),
+                &format!("/nix/store/{:0>32}-nixos-system-{}\n", id, id),
+            )
+            .with_stdout(
+                &format!("nix-store -q --references {}", link),
+                &(references.join("\n") + "\n"),
+            );
+    }
+    NixService::with_runner(runner)
+}
+
+fn bench_parse_generations(c: &mut Criterion) {
+    let output = generation_list();
+    c.bench_function("parse_generations_output/1000", |b| {
+        b.iter(|| parse_generations_output(black_box(&output), PROFILE, "1000").unwrap())
+    });
+}
+
+fn bench_diff(c: &mut Criterion) {
+    let (from_refs, to_refs) = references(REFERENCES);
+    c.bench_function("diff_references/5000", |b| {
+        b.iter(|| diff_references(black_box(&from_refs), black_box(&to_refs)))
+    });
+    let (from_requisites, to_requisites) = references(REQUISITES);
+    c.bench_function("diff_references/50000", |b| {
+        b.iter(|| diff_references(black_box(&from_requisites), black_box(&to_requisites)))
+    });
+    // A fresh service per iteration, so the reference cache stays cold.
+    c.bench_function("get_diff/5000", |b| {
+        b.iter_batched(
+            || service(&from_refs, &to_refs),
+            |service| service.get_diff("1", "2").unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group!(benches, bench_parse_generations, bench_diff);
+criterion_main!(benches);
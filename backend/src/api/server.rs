@@ -0,0 +1,693 @@
+use axum::extract::{Query, Request, State};
+use axum::http::{header, HeaderValue, Method, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use futures_util::stream::{self, Stream};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::{info, warn};
+
+use crate::error::{Error, Result};
+use crate::models::diff::{DetailedDiff, GenerationDiff};
+use crate::models::event::WatchEvent;
+use crate::models::page::Page;
+use crate::services::async_nix::AsyncNixService;
+use crate::services::metrics::Metrics;
+use crate::services::runner::AsyncCommandRunner;
+use crate::services::watch::GenerationWatcher;
+
+/// Shared by all handlers: the service, the channel profile changes are
+/// published on for `/events` subscribers, and the metrics `/metrics`
+/// serves, if enabled. Also how requests are let in: which origins may
+/// make them and the bearer tokens they must carry.
+pub struct AppState<R: AsyncCommandRunner> {
+    service: Arc<AsyncNixService<R>>,
+    events: broadcast::Sender<WatchEvent>,
+    metrics: Option<Arc<Metrics>>,
+    auth_token: Option<Arc<str>>,
+    metrics_token: Option<Arc<str>>,
+    cors: Option<CorsLayer>,
+}
+
+impl<R: AsyncCommandRunner> AppState<R> {
+    pub fn new(service: AsyncNixService<R>) -> Self {
+        let (events, _) = broadcast::channel(64);
+        Self {
+            service: Arc::new(service),
+            events,
+            metrics: None,
+            auth_token: None,
+            metrics_token: None,
+            cors: None,
+        }
+    }
+
+    /// Reject requests without an `Authorization: Bearer token` header.
+    pub fn with_auth_token(mut self, token: &str) -> Self {
+        self.auth_token = Some(token.into());
+        self
+    }
+
+    /// Require `token` on `/metrics` instead of the auth token, e.g. for a
+    /// scraper that shouldn't be able to read generations.
+    pub fn with_metrics_token(mut self, token: &str) -> Self {
+        self.metrics_token = Some(token.into());
+        self
+    }
+
+    /// Let browsers on `origins` call the API; `*` allows any origin.
+    /// Without this only pages served from the API's own origin can.
+    pub fn with_cors_origins(mut self, origins: &[String]) -> Result<Self> {
+        let allow_origin = if origins.iter().any(|origin| origin == "*") {
+            AllowOrigin::any()
+        } else {
+            let origins = origins
+                .iter()
+                .map(|origin| {
+                    HeaderValue::from_str(origin).map_err(|_| {
+                        Error::InvalidArgument(format!("invalid CORS origin '{}'", origin))
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            AllowOrigin::list(origins)
+        };
+        self.cors = Some(
+            CorsLayer::new()
+                .allow_origin(allow_origin)
+                .allow_methods([Method::GET])
+                .allow_headers([header::AUTHORIZATION]),
+        );
+        Ok(self)
+    }
+
+    /// Count requests in `metrics` and serve them on `/metrics`. Commands
+    /// are counted by the service's runner, which should share `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+}
+
+impl<R: AsyncCommandRunner> Clone for AppState<R> {
+    fn clone(&self) -> Self {
+        Self {
+            service: self.service.clone(),
+            events: self.events.clone(),
+            metrics: self.metrics.clone(),
+            auth_token: self.auth_token.clone(),
+            metrics_token: self.metrics_token.clone(),
+            cors: self.cors.clone(),
+        }
+    }
+}
+
+/// An `Error` rendered as `{"error": "..."}` with a matching status code.
+struct ApiError(Error);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = match self.0 {
+            Error::GenerationNotFound(_) | Error::NoGenerations(_) => StatusCode::NOT_FOUND,
+            Error::InvalidGenerationId(_) | Error::InvalidArgument(_) => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
+        let body = serde_json::json!({ "error": self.0.to_string() });
+        (status, Json(body)).into_response()
+    }
+}
+
+type ApiResult = std::result::Result<Response, ApiError>;
+
+/// The diff modes produce differently shaped diffs.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum DiffResponse {
+    Paths(GenerationDiff),
+    Packages(DetailedDiff),
+}
+
+/// Generations per page when `/generations` isn't given a `limit`.
+pub const DEFAULT_PAGE_SIZE: usize = 50;
+
+/// Kept as strings so bad values get the API's JSON 400 rather than axum's
+/// plain-text rejection.
+#[derive(Debug, Deserialize)]
+struct PageQuery {
+    limit: Option<String>,
+    offset: Option<String>,
+}
+
+impl PageQuery {
+    fn parse(name: &str, value: Option<&str>) -> Result<Option<usize>> {
+        value
+            .map(|value| {
+                value.parse().map_err(|_| {
+                    Error::InvalidArgument(format!(
+                        "{} must be a non-negative integer, got '{}'",
+                        name, value
+                    ))
+                })
+            })
+            .transpose()
+    }
+
+    fn limit(&self) -> Result<usize> {
+        Ok(Self::parse("limit", self.limit.as_deref())?.unwrap_or(DEFAULT_PAGE_SIZE))
+    }
+
+    fn offset(&self) -> Result<usize> {
+        Ok(Self::parse("offset", self.offset.as_deref())?.unwrap_or(0))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DiffQuery {
+    from: String,
+    to: String,
+    #[serde(default)]
+    mode: Option<String>,
+}
+
+/// Routes serving the same JSON as the corresponding CLI commands, except
+/// that `/generations` is paged, `DEFAULT_PAGE_SIZE` at a time by default.
+/// `/events` streams each new generation as a server-sent `generation`
+/// event, and rollbacks as `rollback` events. `/metrics` only exists when
+/// the state has metrics, and takes the metrics token if there is one.
+pub fn router<R>(state: AppState<R>) -> Router
+where
+    R: AsyncCommandRunner + 'static,
+{
+    let api = with_token(
+        Router::new()
+            .route("/generations", get(list_generations::<R>))
+            .route("/generations/current", get(current_generation::<R>))
+            .route("/diff", get(diff::<R>))
+            .route("/events", get(events::<R>)),
+        state.auth_token.clone(),
+    );
+    let router = match state.metrics.clone() {
+        Some(metrics) => api
+            .merge(with_token(
+                Router::new().route("/metrics", get(metrics_text::<R>)),
+                state.metrics_token.clone().or(state.auth_token.clone()),
+            ))
+            .layer(middleware::from_fn_with_state(metrics, count_request)),
+        None => api,
+    };
+    // Outermost, so preflight requests, which carry no token, are answered.
+    let router = match state.cors.clone() {
+        Some(cors) => router.layer(cors),
+        None => router,
+    };
+    router.with_state(state)
+}
+
+fn with_token<S: Clone + Send + Sync + 'static>(
+    router: Router<S>,
+    token: Option<Arc<str>>,
+) -> Router<S> {
+    match token {
+        Some(token) => router.route_layer(middleware::from_fn_with_state(token, require_token)),
+        None => router,
+    }
+}
+
+/// Serve the API, polling the profile every `poll_interval` for changes to
+/// publish on `/events`.
+pub async fn serve<R>(state: AppState<R>, addr: SocketAddr, poll_interval: Duration) -> Result<()>
+where
+    R: AsyncCommandRunner + 'static,
+{
+    tokio::spawn(watch_generations(state.clone(), poll_interval));
+
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!(%addr, "serving API");
+    axum::serve(listener, router(state)).await?;
+    Ok(())
+}
+
+async fn watch_generations<R>(state: AppState<R>, poll_interval: Duration)
+where
+    R: AsyncCommandRunner + 'static,
+{
+    let mut watcher = GenerationWatcher::new();
+    let mut ticker = tokio::time::interval(poll_interval);
+
+    loop {
+        ticker.tick().await;
+        // Only the generations in events are worth reading versions for.
+        match state.service.read_generations().await {
+            Ok(generations) => {
+                for mut event in watcher.poll(generations) {
+                    let (WatchEvent::NewGeneration(generation)
+                    | WatchEvent::Rollback { to: generation, .. }) = &mut event;
+                    state.service.enrich(std::slice::from_mut(generation)).await;
+                    info!(?event, "profile changed");
+                    // Sending only fails while nobody is subscribed.
+                    let _ = state.events.send(event);
+                }
+            }
+            Err(e) => warn!(error = %e, "failed to poll generations"),
+        }
+    }
+}
+
+/// Serialize a service result directly, so fields keep the CLI's order.
+fn json<T: Serialize>(result: Result<T>) -> ApiResult {
+    let body = result
+        .and_then(|value| {
+            serde_json::to_string(&value).map_err(|e| Error::ParseError(e.to_string()))
+        })
+        .map_err(ApiError)?;
+
+    Ok(([(header::CONTENT_TYPE, "application/json")], body).into_response())
+}
+
+async fn require_token(State(token): State<Arc<str>>, request: Request, next: Next) -> Response {
+    let bearer = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match bearer {
+        Some(bearer) if constant_time_eq(bearer.as_bytes(), token.as_bytes()) => {
+            next.run(request).await
+        }
+        _ => (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+            Json(serde_json::json!({ "error": "missing or invalid bearer token" })),
+        )
+            .into_response(),
+    }
+}
+
+/// Compare without stopping at the first difference, so response times
+/// don't reveal how much of a guessed token was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+async fn count_request(
+    State(metrics): State<Arc<Metrics>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    metrics.record_request();
+    next.run(request).await
+}
+
+async fn metrics_text<R>(State(state): State<AppState<R>>) -> Response
+where
+    R: AsyncCommandRunner + 'static,
+{
+    let body = state
+        .metrics
+        .as_ref()
+        .map(|m| m.render())
+        .unwrap_or_default();
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body).into_response()
+}
+
+async fn list_generations<R>(
+    State(state): State<AppState<R>>,
+    Query(query): Query<PageQuery>,
+) -> ApiResult
+where
+    R: AsyncCommandRunner + 'static,
+{
+    let (limit, offset) = (
+        query.limit().map_err(ApiError)?,
+        query.offset().map_err(ApiError)?,
+    );
+    json(
+        state
+            .service
+            .list_generations()
+            .await
+            .map(|generations| Page::new(generations, offset, Some(limit))),
+    )
+}
+
+async fn current_generation<R>(State(state): State<AppState<R>>) -> ApiResult
+where
+    R: AsyncCommandRunner + 'static,
+{
+    json(state.service.get_current().await)
+}
+
+async fn events<R>(
+    State(state): State<AppState<R>>,
+) -> Sse<impl Stream<Item = std::result::Result<Event, Infallible>>>
+where
+    R: AsyncCommandRunner + 'static,
+{
+    let stream = stream::unfold(state.events.subscribe(), |mut events| async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    let sse = match &event {
+                        WatchEvent::NewGeneration(generation) => {
+                            Event::default().event("generation").json_data(generation)
+                        }
+                        WatchEvent::Rollback { .. } => {
+                            Event::default().event("rollback").json_data(&event)
+                        }
+                    };
+                    match sse {
+                        Ok(sse) => return Some((Ok(sse), events)),
+                        Err(e) => warn!(error = %e, "failed to serialize event"),
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!(skipped, "events subscriber lagged behind");
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+async fn diff<R>(State(state): State<AppState<R>>, Query(query): Query<DiffQuery>) -> ApiResult
+where
+    R: AsyncCommandRunner + 'static,
+{
+    json(diff_generations(&state.service, &query).await)
+}
+
+async fn diff_generations<R: AsyncCommandRunner>(
+    service: &AsyncNixService<R>,
+    query: &DiffQuery,
+) -> Result<DiffResponse> {
+    let from = service.resolve_ref(&query.from).await?;
+    let to = service.resolve_ref(&query.to).await?;
+    for id in [&from, &to] {
+        if !service.generation_exists(id).await? {
+            return Err(Error::GenerationNotFound(id.clone()));
+        }
+    }
+
+    Ok(match query.mode.as_deref().unwrap_or("references") {
+        "references" => DiffResponse::Paths(service.get_reference_diff(&from, &to).await?),
+        "auto" => DiffResponse::Paths(service.get_diff(&from, &to).await?),
+        "nix-diff" | "derivations" => DiffResponse::Paths(service.get_nix_diff(&from, &to).await?),
+        "closures" => DiffResponse::Packages(service.get_closure_diff(&from, &to).await?),
+        mode => {
+            return Err(Error::InvalidArgument(format!(
+                "unknown diff mode '{}', expected auto, nix-diff, references or closures",
+                mode
+            )))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::generation::Generation;
+    use crate::services::runner::MockCommandRunner;
+    use axum::body::Body;
+    use axum::http::Request;
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    const REFERENCES: &str = "nix-store -q --references /nix/var/nix/profiles/system";
+
+    fn state() -> AppState<MockCommandRunner> {
+        let runner = MockCommandRunner::new()
+            .with_stdout(
+                "nix-env --list-generations -p /nix/var/nix/profiles/system",
+                r#"   1   2024-02-09 10:00:00   
+   2   2024-02-10 11:30:00   (current)
+   3   2024-02-11 09:00:00   "#,
+            )
+            .with_stdout("readlink /nix/var/nix/profiles/system", "system-2-link\n")
+            .with_stdout(
+                &format!("{}-1-link", REFERENCES),
+                "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-bash-5.2\n",
+            )
+            .with_stdout(
+                &format!("{}-2-link", REFERENCES),
+                "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-vim-9.0\n",
+            );
+        AppState::new(AsyncNixService::with_runner(runner))
+    }
+
+    async fn get(uri: &str) -> (StatusCode, serde_json::Value) {
+        let response = router(state())
+            .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_generations_endpoints() {
+        let (status, body) = get("/generations").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["items"].as_array().unwrap().len(), 3);
+        assert_eq!(
+            (body["total"].as_u64(), body["offset"].as_u64()),
+            (Some(3), Some(0))
+        );
+
+        let (status, body) = get("/generations/current").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["id"], "2");
+    }
+
+    #[tokio::test]
+    async fn test_generations_pagination() {
+        let (status, body) = get("/generations?limit=1&offset=1").await;
+        assert_eq!(status, StatusCode::OK);
+        let ids: Vec<&str> = body["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|g| g["id"].as_str().unwrap())
+            .collect();
+        assert_eq!(ids, ["2"]);
+        assert_eq!(
+            (body["total"].as_u64(), body["offset"].as_u64()),
+            (Some(3), Some(1))
+        );
+
+        let (_, body) = get("/generations?offset=5").await;
+        assert!(body["items"].as_array().unwrap().is_empty());
+        assert_eq!(body["total"], 3);
+
+        for uri in [
+            "/generations?limit=-1",
+            "/generations?offset=x",
+            "/generations?limit=1.5",
+        ] {
+            let (status, body) = get(uri).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", uri);
+            assert!(body["error"]
+                .as_str()
+                .unwrap()
+                .contains("non-negative integer"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_diff_endpoint() {
+        let (status, body) = get("/diff?from=1&to=current").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["summary"]["added"], 1);
+        assert_eq!(body["summary"]["removed"], 1);
+
+        let (status, body) = get("/diff?from=1&to=abc").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["error"].as_str().unwrap().contains("abc"));
+    }
+
+    #[tokio::test]
+    async fn test_error_status_codes() {
+        let (status, _) = get("/diff?from=HEAD~5&to=current").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, body) = get("/diff?from=1&to=7").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(body["error"].as_str().unwrap().contains('7'));
+
+        // Generation 3 is listed but its references can't be queried.
+        let (status, body) = get("/diff?from=1&to=3").await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(body["error"].is_string());
+    }
+
+    #[tokio::test]
+    async fn test_metrics_endpoint() {
+        let response = router(state())
+            .oneshot(Request::get("/metrics").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let app = router(state().with_metrics(Arc::new(Metrics::new())));
+        for uri in ["/generations", "/metrics"] {
+            app.clone()
+                .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+        }
+        let response = app
+            .oneshot(Request::get("/metrics").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(
+            body.contains("nix_timemach_http_requests_total 3\n"),
+            "{}",
+            body
+        );
+    }
+
+    async fn status(app: &Router, request: Request<Body>) -> StatusCode {
+        app.clone().oneshot(request).await.unwrap().status()
+    }
+
+    fn bearer(uri: &str, token: &str) -> Request<Body> {
+        Request::get(uri)
+            .header(header::AUTHORIZATION, format!("Bearer {}", token))
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_auth_token() {
+        let app = router(
+            state()
+                .with_metrics(Arc::new(Metrics::new()))
+                .with_auth_token("s3cret")
+                .with_metrics_token("scrape"),
+        );
+        let anonymous = |uri| Request::get(uri).body(Body::empty()).unwrap();
+
+        let response = app
+            .clone()
+            .oneshot(anonymous("/generations"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer");
+        assert_eq!(
+            status(&app, bearer("/generations", "wrong")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(&app, bearer("/generations", "s3cret")).await,
+            StatusCode::OK
+        );
+
+        // /metrics takes its own token, and only that one.
+        assert_eq!(
+            status(&app, anonymous("/metrics")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(&app, bearer("/metrics", "s3cret")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(&app, bearer("/metrics", "scrape")).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(&app, bearer("/generations", "scrape")).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cors_origins() {
+        let origin = "https://dash.example.com";
+        let preflight = || {
+            Request::options("/generations")
+                .header(header::ORIGIN, origin)
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+                .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "authorization")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // Same-origin only by default.
+        let response = router(state()).oneshot(preflight()).await.unwrap();
+        assert!(!response
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        let app = router(
+            state()
+                .with_auth_token("s3cret")
+                .with_cors_origins(&[origin.to_string()])
+                .unwrap(),
+        );
+        let response = app.clone().oneshot(preflight()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            origin
+        );
+
+        let mut request = bearer("/generations", "s3cret");
+        request.headers_mut().insert(
+            header::ORIGIN,
+            HeaderValue::from_static("https://evil.example"),
+        );
+        let response = app.oneshot(request).await.unwrap();
+        assert!(!response
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        assert!(matches!(
+            state().with_cors_origins(&["bad\norigin".to_string()]),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_events_stream_new_generations() {
+        let state = state();
+        let response = router(state.clone())
+            .oneshot(Request::get("/events").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/event-stream"
+        );
+
+        state
+            .events
+            .send(WatchEvent::NewGeneration(Generation {
+                id: "3".to_string(),
+                ..Default::default()
+            }))
+            .unwrap();
+        let mut body = response.into_body();
+        let frame = body.frame().await.unwrap().unwrap().into_data().unwrap();
+        let frame = String::from_utf8(frame.to_vec()).unwrap();
+        assert!(
+            frame.starts_with("event: generation\ndata: {\"id\":\"3\""),
+            "{}",
+            frame
+        );
+    }
+}
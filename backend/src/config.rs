@@ -0,0 +1,118 @@
+use serde::{Deserialize, Deserializer};
+use std::env;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result};
+use crate::models::timestamp::Timezone;
+use crate::output::OutputFormat;
+
+/// Defaults read from `config.toml`. Command-line flags override every
+/// field; a missing field falls back to the flag's built-in default.
+///
+/// ```toml
+/// profile = "home-manager"
+/// format = "table"
+/// timezone = "local"
+/// cache-ttl = 10
+/// timeout = 120
+/// ```
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Like `--profile`: system, home-manager, or a path.
+    pub profile: Option<String>,
+    #[serde(default, deserialize_with = "value_enum")]
+    pub format: Option<OutputFormat>,
+    #[serde(default, deserialize_with = "value_enum")]
+    pub timezone: Option<Timezone>,
+    /// Seconds, like `--cache-ttl`.
+    pub cache_ttl: Option<u64>,
+    /// Seconds, like `--timeout`.
+    pub timeout: Option<u64>,
+}
+
+/// `$XDG_CONFIG_HOME/nix-timemach/config.toml`, falling back to `~/.config`.
+/// `--config` and `NIX_TIMEMACH_CONFIG` override it.
+pub fn default_config_path() -> PathBuf {
+    env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .unwrap_or_default()
+        .join("nix-timemach/config.toml")
+}
+
+impl Config {
+    /// Read the config at `path`. A missing file is only an error when
+    /// `required`, i.e. when the path was given explicitly.
+    pub fn load(path: &Path, required: bool) -> Result<Self> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !required => {
+                return Ok(Self::default());
+            }
+            Err(e) => return Err(config_parse(path, e.to_string())),
+        };
+        Self::parse(&contents).map_err(|reason| config_parse(path, reason))
+    }
+
+    fn parse(contents: &str) -> std::result::Result<Self, String> {
+        toml::from_str(contents).map_err(|e| e.message().to_string())
+    }
+}
+
+fn config_parse(path: &Path, reason: String) -> Error {
+    Error::ConfigParse {
+        path: path.to_path_buf(),
+        reason,
+    }
+}
+
+/// Accept the same spellings as the corresponding command-line flag.
+fn value_enum<'de, D, T>(deserializer: D) -> std::result::Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: clap::ValueEnum,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|value| T::from_str(&value, false).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config = Config::parse(
+            "profile = \"home-manager\"\nformat = \"json-pretty\"\ntimezone = \"local\"\n\
+             cache-ttl = 10\ntimeout = 120\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            Config {
+                profile: Some("home-manager".to_string()),
+                format: Some(OutputFormat::JsonPretty),
+                timezone: Some(Timezone::Local),
+                cache_ttl: Some(10),
+                timeout: Some(120),
+            }
+        );
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+
+        assert!(Config::parse("format = \"xml\"").is_err());
+        assert!(Config::parse("colour = \"always\"").is_err());
+        assert!(Config::parse("timeout = ").is_err());
+    }
+
+    #[test]
+    fn test_load_missing_config() {
+        let path = Path::new("/nonexistent/nix-timemach/config.toml");
+        assert_eq!(Config::load(path, false).unwrap(), Config::default());
+        assert!(matches!(
+            Config::load(path, true),
+            Err(Error::ConfigParse { .. })
+        ));
+    }
+}
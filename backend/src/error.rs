@@ -1,10 +1,140 @@
 use thiserror::Error;
 
 #[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     #[error("Failed to execute nix command: {0}")]
-    NixCommandFailed(String),
+    NixCommandError(String),
     #[error("Failed to parse nix output: {0}")]
-    NixOutputParseFailed(String),
+    ParseError(String),
+    #[error("Generation not found: {0}")]
+    GenerationNotFound(String),
+    #[error("Profile {0} has no generations yet")]
+    NoGenerations(String),
+    #[error("No generation contains {0}")]
+    PackageNotFound(String),
+    #[error("Invalid generation id '{0}': expected a number")]
+    InvalidGenerationId(String),
+    #[error("Refusing to delete generation {id}: {reason}")]
+    RefusedToDelete { id: String, reason: String },
+    #[error("Cannot determine the current generation of {profile}: {reason}")]
+    CurrentGenerationUnavailable { profile: String, reason: String },
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+    #[error("Insufficient privileges: {0}")]
+    PermissionDenied(String),
+    #[error("Cannot reach {host} over ssh: {reason}")]
+    RemoteConnectionFailed { host: String, reason: String },
+    #[error("Command `{command}` timed out after {seconds}s")]
+    CommandTimedOut { command: String, seconds: f64 },
+    #[error(
+        "Nix is not available: {0}. Install Nix from https://nixos.org/download, \
+         or pass --skip-env-check if it is installed somewhere unusual"
+    )]
+    NixNotAvailable(String),
+    #[error("`{0}` is not installed; it is needed for this command")]
+    ToolNotInstalled(String),
+    #[error("Failed to write output to {}: {source}", path.display())]
+    OutputWriteFailed {
+        path: std::path::PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Invalid config file {}: {reason}", path.display())]
+    ConfigParse {
+        path: std::path::PathBuf,
+        reason: String,
+    },
+    #[error("History database error: {0}")]
+    Database(#[from] rusqlite::Error),
+    #[error("I/O error while running nix command: {0}")]
+    Io(#[from] std::io::Error),
 }
 
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Stable name of the variant, for `--error-format json`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::NixCommandError(_) => "nix_command",
+            Error::ParseError(_) => "parse",
+            Error::GenerationNotFound(_) => "generation_not_found",
+            Error::NoGenerations(_) => "no_generations",
+            Error::PackageNotFound(_) => "package_not_found",
+            Error::InvalidGenerationId(_) => "invalid_generation_id",
+            Error::RefusedToDelete { .. } => "refused_to_delete",
+            Error::CurrentGenerationUnavailable { .. } => "current_generation_unavailable",
+            Error::InvalidArgument(_) => "invalid_argument",
+            Error::PermissionDenied(_) => "permission_denied",
+            Error::RemoteConnectionFailed { .. } => "remote_connection_failed",
+            Error::CommandTimedOut { .. } => "command_timed_out",
+            Error::NixNotAvailable(_) => "nix_not_available",
+            Error::ToolNotInstalled(_) => "tool_not_installed",
+            Error::OutputWriteFailed { .. } => "output_write_failed",
+            Error::ConfigParse { .. } => "config_parse",
+            Error::Database(_) => "database",
+            Error::Io(_) => "io",
+        }
+    }
+
+    /// Process exit code for this kind of failure, documented under
+    /// `--help`. 2 is shared with clap's usage errors; 1 is never used, so
+    /// it can't be mistaken for one of these.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::InvalidArgument(_) | Error::InvalidGenerationId(_) => 2,
+            Error::NixCommandError(_) => 3,
+            Error::ParseError(_) => 4,
+            Error::GenerationNotFound(_) => 5,
+            Error::NoGenerations(_) => 16,
+            Error::PackageNotFound(_) => 6,
+            Error::RefusedToDelete { .. } => 7,
+            Error::CurrentGenerationUnavailable { .. } => 8,
+            Error::PermissionDenied(_) => 9,
+            Error::RemoteConnectionFailed { .. } => 10,
+            Error::CommandTimedOut { .. } => 11,
+            Error::NixNotAvailable(_) => 17,
+            Error::ToolNotInstalled(_) => 12,
+            Error::OutputWriteFailed { .. } => 13,
+            Error::ConfigParse { .. } => 18,
+            Error::Database(_) => 14,
+            Error::Io(_) => 15,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::{Command, Output};
+
+    fn spawn(program: &str) -> Result<Output> {
+        Ok(Command::new(program).output()?)
+    }
+
+    #[test]
+    fn test_missing_binary_maps_to_io_error() {
+        let err = spawn("nix-timemach-nonexistent-binary").unwrap_err();
+        assert!(matches!(err, Error::Io(ref e) if e.kind() == std::io::ErrorKind::NotFound));
+        assert!(err
+            .to_string()
+            .starts_with("I/O error while running nix command:"));
+        assert_eq!(err.kind(), "io");
+    }
+
+    #[test]
+    fn test_kinds_and_exit_codes_are_distinct() {
+        let errors = [
+            Error::NixCommandError(String::new()),
+            Error::GenerationNotFound(String::new()),
+            Error::InvalidArgument(String::new()),
+            Error::ToolNotInstalled(String::new()),
+            Error::Io(std::io::Error::other("")),
+        ];
+        let kinds: std::collections::HashSet<_> = errors.iter().map(Error::kind).collect();
+        let codes: std::collections::HashSet<_> = errors.iter().map(Error::exit_code).collect();
+        assert_eq!(kinds.len(), errors.len());
+        assert_eq!(codes.len(), errors.len());
+        assert!(codes.iter().all(|&code| code >= 2));
+    }
+}
@@ -0,0 +1,11 @@
+pub mod api;
+pub mod config;
+pub mod error;
+pub mod models;
+pub mod output;
+pub mod schema;
+pub mod services;
+
+pub use models::diff::{DetailedDiff, GenerationDiff, PackageChange};
+pub use models::generation::Generation;
+pub use services::nix::NixService;
@@ -1,196 +1,1642 @@
-use chrono::{DateTime, NaiveDateTime, Utc};
-use clap::{Command, Subcommand};
-use serde::{Serialize, Serializer};
-use std::process::Command as StdCommand;
-use thiserror::Error;
-
-#[derive(Error, Debug)]
-pub enum Error {
-    #[error("Failed to execute nix command: {0}")]
-    NixCommandFailed(String),
-    #[error("Failed to parse nix output: {0}")]
-    NixOutputParseFailed(String),
-    #[error("Failed to parse generation diff: {0}")]
-    DiffParseFailed(String),
-}
-
-#[derive(Serialize)]
-struct Generation {
-    id: String,
-    #[serde(serialize_with = "serialize_timestamp_as_string")]
-    timestamp: DateTime<Utc>,
-    description: String,
-    profiles: Vec<String>,
-}
-
-#[derive(Serialize)]
-struct GenerationDiff {
-    added: Vec<String>,
-    removed: Vec<String>,
-    modified: Vec<String>,
-}
-
-#[derive(Subcommand)]
-enum Commands {
-    ListGenerations,
-    Diff { from: String, to: String },
-}
-
-fn parse_timestamp(date: &str, time: &str) -> Result<DateTime<Utc>, Error> {
-    let datetime_str = format!("{} {}", date, time);
-    NaiveDateTime::parse_from_str(&datetime_str, "%Y-%m-%d %H:%M:%S")
-        .map_err(|e| Error::NixOutputParseFailed(e.to_string()))
-        .map(|dt| DateTime::from_naive_utc_and_offset(dt, Utc))
-}
-
-fn serialize_timestamp_as_string<S>(
-    timestamp: &DateTime<Utc>,
-    serializer: S,
-) -> Result<S::Ok, S::Error>
-where
-    S: Serializer,
-{
-    serializer.serialize_str(&timestamp.to_rfc3339())
-}
-
-fn list_generations() -> Result<Vec<Generation>, Error> {
-    let output = StdCommand::new("nixos-rebuild")
-        .arg("list-generations")
-        .output()
-        .map_err(|e| Error::NixCommandFailed(e.to_string()))?;
-
-    if !output.status.success() {
-        return Err(Error::NixCommandFailed(
-            String::from_utf8_lossy(&output.stderr).to_string(),
-        ));
-    }
+use chrono::{DateTime, Days, Duration, Local, NaiveDate, SubsecRound, TimeZone, Utc};
+use clap::parser::ValueSource;
+use clap::Command;
+use nix_timemach::api::server::{serve, AppState};
+use nix_timemach::config::{default_config_path, Config};
+use nix_timemach::error::{Error, Result};
+use nix_timemach::models::diff::{ChangeDirection, DetailedDiff, DiffCategory, GenerationDiff};
+use nix_timemach::models::generation::compare_generation_ids;
+use nix_timemach::models::note::GenerationNote;
+use nix_timemach::models::page::paginate;
+use nix_timemach::models::partial::Partial;
+use nix_timemach::models::stats::GenerationStats;
+use nix_timemach::models::timestamp::{
+    set_timestamp_format, set_timezone, TimestampFormat, Timezone,
+};
+use nix_timemach::output::{
+    create_output_file, humanize_duration, render_colored, render_error, write_output, ColorChoice,
+    ErrorFormat, OutputFormat, Table,
+};
+use nix_timemach::schema::SchemaType;
+use nix_timemach::services::async_nix::AsyncNixService;
+use nix_timemach::services::darwin::DarwinService;
+use nix_timemach::services::diff::NameFilter;
+use nix_timemach::services::history::{default_history_path, HistoryStore};
+use nix_timemach::services::metrics::Metrics;
+use nix_timemach::services::nix::Direction;
+use nix_timemach::services::nix_profile::NixProfileService;
+use nix_timemach::services::notes::{default_notes_path, NoteStore};
+use nix_timemach::services::profile::{default_profile, resolve_profile};
+use nix_timemach::services::provider::{GenerationProvider, Platform, ProfileKind};
+use nix_timemach::services::runner::{RealCommandRunner, TokioCommandRunner};
+use nix_timemach::services::timeline::merge_timeline;
+use nix_timemach::services::watch::GenerationWatcher;
+use nix_timemach::{Generation, NixService};
+use serde::Serialize;
+use std::io::{BufRead, IsTerminal, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+use tracing::warn;
+use tracing_subscriber::EnvFilter;
 
-    let output_str = String::from_utf8_lossy(&output.stdout);
-    let generations: Vec<Generation> = output_str
-        .lines()
-        .skip(1) // Skip header
-        .filter_map(|line| {
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.len() >= 4 {
-                let id = parts[0].trim_end_matches("current").to_string();
-                let date = parts[1];
-                let time = parts[2];
-                let description = if parts[0].contains("current") {
-                    "(current)".to_string()
-                } else {
-                    "".to_string()
-                };
+/// Keep in sync with `Error::exit_code`.
+const EXIT_CODES: &str = "\
+Exit codes:
+  0   success
+  2   invalid arguments or generation id
+  3   a nix command failed
+  4   nix output could not be parsed
+  5   generation not found
+  6   no generation contains the package
+  7   refused to delete a generation
+  8   current generation unavailable
+  9   insufficient privileges
+  10  remote host unreachable over ssh
+  11  a command timed out
+  12  a required tool is not installed
+  13  output file could not be written
+  14  history database error
+  15  other I/O error
+  16  the profile has no generations
+  17  nix is not installed
+  18  the config file is invalid";
 
-                let timestamp = parse_timestamp(date, time).ok()?;
-                let profiles = vec![format!("/nix/var/nix/profiles/system-{}-link", &id)];
+/// `--cache-ttl` for `serve`, where the same list is requested repeatedly.
+const SERVE_CACHE_TTL: u64 = 30;
 
-                Some(Generation {
-                    id,
-                    timestamp,
-                    description,
-                    profiles,
-                })
-            } else {
-                None
-            }
+fn history_db_arg() -> clap::Arg {
+    clap::arg!(--db <PATH> "History database, ~/.local/share/nix-timemach/history.db by default")
+        .value_parser(clap::value_parser!(PathBuf))
+}
+
+fn history_db(matches: &clap::ArgMatches) -> PathBuf {
+    matches
+        .get_one::<PathBuf>("db")
+        .cloned()
+        .unwrap_or_else(default_history_path)
+}
+
+/// How results are printed: `--format`, whether `--color` applies, and
+/// the `--output-file` to write instead of stdout.
+#[derive(Clone, Copy)]
+struct Style<'a> {
+    format: OutputFormat,
+    color: bool,
+    output_file: Option<&'a Path>,
+}
+
+fn print_rendered<T: Serialize + Table + ?Sized>(value: &T, style: Style) -> Result<()> {
+    let rendered = render_colored(value, style.format, style.color)?;
+    match style.output_file {
+        Some(path) => write_output(path, &rendered),
+        None => {
+            print!("{}", rendered);
+            Ok(())
+        }
+    }
+}
+
+/// Newline-delimited JSON on stdout or `--output-file`, flushed after every
+/// line so consumers see each value as soon as it is written.
+struct JsonLines {
+    out: Box<dyn Write>,
+    path: Option<PathBuf>,
+}
+
+impl JsonLines {
+    fn create(output_file: Option<&Path>) -> Result<Self> {
+        let out: Box<dyn Write> = match output_file {
+            Some(path) => Box::new(create_output_file(path)?),
+            None => Box::new(std::io::stdout()),
+        };
+        Ok(Self {
+            out,
+            path: output_file.map(Path::to_path_buf),
         })
-        .collect();
+    }
 
-    Ok(generations)
+    fn write<T: Serialize>(&mut self, value: &T) -> Result<()> {
+        let line = serde_json::to_string(value).map_err(|e| Error::ParseError(e.to_string()))?;
+        writeln!(self.out, "{}", line)
+            .and_then(|_| self.out.flush())
+            .map_err(|source| match &self.path {
+                Some(path) => Error::OutputWriteFailed {
+                    path: path.clone(),
+                    source,
+                },
+                None => Error::Io(source),
+            })
+    }
 }
 
-fn get_diff(from: &str, to: &str) -> Result<GenerationDiff, Error> {
-    let from_path = format!("/nix/var/nix/profiles/system-{}-link", from);
-    let to_path = format!("/nix/var/nix/profiles/system-{}-link", to);
+/// Sort by `key` ("timestamp" or "id"); timestamp ties fall back to the id.
+fn sort_generations(generations: &mut [Generation], key: &str, reverse: bool) {
+    generations.sort_by(|a, b| {
+        let by_id = compare_generation_ids(&a.id, &b.id);
+        match key {
+            "id" => by_id,
+            _ => a.timestamp.cmp(&b.timestamp).then(by_id),
+        }
+    });
+    if reverse {
+        generations.reverse();
+    }
+}
 
-    let output = StdCommand::new("nix-store")
-        .args(["-q", "--references"])
-        .arg(&from_path)
-        .output()
-        .map_err(|e| Error::NixCommandFailed(e.to_string()))?;
+/// Parse a `--since`/`--until` bound given as `YYYY-MM-DD` or RFC3339. An
+/// upper bound is exclusive, so it is moved past what `value` names: the
+/// whole day for a bare date, the second itself for a timestamp.
+fn parse_date_bound(value: &str, upper: bool) -> Result<DateTime<Utc>> {
+    let past_end = || Error::InvalidArgument(format!("{} is out of range", value));
 
-    let from_refs: Vec<String> = String::from_utf8_lossy(&output.stdout)
-        .lines()
-        .map(|s| s.to_string())
-        .collect();
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(value) {
+        let timestamp = timestamp.with_timezone(&Utc);
+        // Build timestamps are whole seconds.
+        if !upper {
+            return Ok(timestamp);
+        }
+        return timestamp
+            .trunc_subsecs(0)
+            .checked_add_signed(Duration::seconds(1))
+            .ok_or_else(past_end);
+    }
 
-    let output = StdCommand::new("nix-store")
-        .args(["-q", "--references"])
-        .arg(&to_path)
-        .output()
-        .map_err(|e| Error::NixCommandFailed(e.to_string()))?;
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d").map_err(|_| {
+        Error::InvalidArgument(format!(
+            "invalid date '{}', expected YYYY-MM-DD or RFC3339",
+            value
+        ))
+    })?;
+    let date = if upper {
+        date.checked_add_days(Days::new(1)).ok_or_else(past_end)?
+    } else {
+        date
+    };
 
-    let to_refs: Vec<String> = String::from_utf8_lossy(&output.stdout)
-        .lines()
-        .map(|s| s.to_string())
-        .collect();
+    // Build dates are local, so a bare date means local midnight.
+    Local
+        .from_local_datetime(&date.and_hms_opt(0, 0, 0).unwrap())
+        .earliest()
+        .map(|midnight| midnight.with_timezone(&Utc))
+        .ok_or_else(|| Error::InvalidArgument(format!("{} has no local midnight", value)))
+}
 
-    let added: Vec<String> = to_refs
-        .iter()
-        .filter(|x| !from_refs.contains(x))
-        .cloned()
-        .collect();
+/// Either end of a `--since`/`--until` range; `None` leaves it open.
+type DateBound = Option<DateTime<Utc>>;
 
-    let removed: Vec<String> = from_refs
-        .iter()
-        .filter(|x| !to_refs.contains(x))
-        .cloned()
-        .collect();
-
-    // For modified, we'll look for packages with the same name but different hashes
-    let modified: Vec<String> = from_refs
-        .iter()
-        .filter(|x| {
-            let name = x.split("-").nth(1).unwrap_or("");
-            to_refs
-                .iter()
-                .any(|y| y.split("-").nth(1).unwrap_or("") == name && y != *x)
-        })
-        .cloned()
-        .collect();
+/// The `--since`/`--until` range of `list-generations`.
+fn date_range(matches: &clap::ArgMatches) -> Result<(DateBound, DateBound)> {
+    let since = matches
+        .get_one::<String>("since")
+        .map(|d| parse_date_bound(d, false))
+        .transpose()?;
+    let until = matches
+        .get_one::<String>("until")
+        .map(|d| parse_date_bound(d, true))
+        .transpose()?;
+    // Compare against the start of a bare `--until` day, so that
+    // `--since 2024-02-11 --until 2024-02-10` is rejected.
+    if let (Some(since), Some(until)) = (since, matches.get_one::<String>("until")) {
+        if since > parse_date_bound(until, false)? {
+            return Err(Error::InvalidArgument(
+                "--since must not be later than --until".to_string(),
+            ));
+        }
+    }
+    Ok((since, until))
+}
+
+/// Parse a duration such as `30d`, `12h` or `2w`, in the style of
+/// `nix-collect-garbage --delete-older-than`.
+fn parse_duration(value: &str) -> Result<Duration> {
+    let invalid = || {
+        Error::InvalidArgument(format!(
+            "invalid duration '{}', expected e.g. 30d, 12h or 2w",
+            value
+        ))
+    };
+
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(invalid)?;
+    let amount: i64 = value[..split].parse().map_err(|_| invalid())?;
+    let duration = match &value[split..] {
+        "s" => Duration::try_seconds(amount),
+        "m" => Duration::try_minutes(amount),
+        "h" => Duration::try_hours(amount),
+        "d" => Duration::try_days(amount),
+        "w" => Duration::try_weeks(amount),
+        _ => None,
+    };
+
+    duration.ok_or_else(invalid)
+}
+
+/// Parse a size in bytes, or with a binary unit such as `512KiB` or
+/// `1.5 GiB`.
+fn parse_size(value: &str) -> Result<u64> {
+    let invalid = || {
+        Error::InvalidArgument(format!(
+            "invalid size '{}', expected bytes or e.g. 512KiB, 1MiB or 2GiB",
+            value
+        ))
+    };
+
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let amount: f64 = value[..split].parse().map_err(|_| invalid())?;
+    let unit: u64 = match value[split..].trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kib" => 1 << 10,
+        "m" | "mib" => 1 << 20,
+        "g" | "gib" => 1 << 30,
+        "t" | "tib" => 1 << 40,
+        _ => return Err(invalid()),
+    };
+
+    Ok((amount * unit as f64).round() as u64)
+}
+
+/// Set each generation's `age`, measured from now.
+fn annotate_ages(generations: &mut [Generation]) {
+    let now = Utc::now();
+    for generation in generations {
+        generation.age = Some(humanize_duration(now - generation.timestamp));
+    }
+}
+
+/// Ask `question` on stderr and read a yes/no answer from stdin. Anything
+/// but yes, or a stdin that isn't a terminal, refuses.
+fn confirm(question: &str) -> Result<()> {
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() {
+        return Err(Error::InvalidArgument(
+            "refusing to continue without confirmation; pass --yes".to_string(),
+        ));
+    }
+
+    eprint!("{} [y/N] ", question);
+    let mut answer = String::new();
+    stdin.lock().read_line(&mut answer)?;
+    match answer.trim().to_ascii_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => Err(Error::InvalidArgument("aborted".to_string())),
+    }
+}
+
+/// Poll the profile and print each change as a line of JSON. Ctrl-C ends
+/// the watch successfully.
+async fn watch(
+    service: AsyncNixService,
+    interval: std::time::Duration,
+    output_file: Option<&Path>,
+) -> Result<()> {
+    let mut lines = JsonLines::create(output_file)?;
+    let mut watcher = GenerationWatcher::new();
+    let mut ticker = tokio::time::interval(interval);
+
+    loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+            _ = ticker.tick() => {}
+        }
+
+        let generations = tokio::select! {
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+            generations = service.list_generations() => generations,
+        };
+
+        for event in watcher.poll(generations?) {
+            lines.write(&event)?;
+        }
+    }
+}
 
-    Ok(GenerationDiff {
-        added,
-        removed,
-        modified,
-    })
+/// Log to stderr so stdout stays machine-readable. `-v` flags take
+/// precedence over `RUST_LOG`; without either only warnings are shown.
+fn init_logging(verbosity: u8) {
+    let filter = match verbosity {
+        0 => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("warn")),
+        1 => EnvFilter::new("debug"),
+        _ => EnvFilter::new("trace"),
+    };
+
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .init();
+}
+
+/// Write the completion script for `shell` to `out`.
+fn print_completions(shell: clap_complete::Shell, out: &mut impl Write) {
+    clap_complete::generate(shell, &mut cli(), env!("CARGO_BIN_NAME"), out);
 }
 
-fn main() -> Result<(), Error> {
-    let cli = Command::new("nix-timemach-backend")
+/// The command-line interface, shared by `main` and `completions`.
+fn cli() -> Command {
+    Command::new("nix-timemach-backend")
         .version("0.0.1")
         .about("Nix Time Machine")
+        .after_long_help(EXIT_CODES)
         .subcommand_required(true)
-        .subcommand(Command::new("list-generations").about("List all generations"))
+        .arg(
+            clap::arg!(--profile <PROFILE> "Profile to inspect: system, home-manager, or a path")
+                .long_help(
+                    "Profile to inspect: system, home-manager, or a path. `timeline` takes \
+                 several; other commands use the last one given. Defaults to system, or \
+                 on machines without a system profile to the last existing entry of \
+                 NIX_PROFILES.",
+                )
+                .global(true)
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            clap::arg!(--store <URI> "Pass --store URI to nix commands")
+                .long_help(
+                    "Pass --store URI to nix, nix-env and nix-store, e.g. /mnt to inspect \
+                 a system mounted from a rescue environment. Combine with a --profile \
+                 under the same root.",
+                )
+                .global(true),
+        )
+        .arg(
+            clap::arg!(--config <PATH> "Read defaults from PATH")
+                .long_help(
+                    "Read defaults for --profile, --format, --timezone, --cache-ttl and \
+                 --timeout from PATH, a TOML file. Defaults to \
+                 ~/.config/nix-timemach/config.toml when it exists.",
+                )
+                .global(true)
+                .env("NIX_TIMEMACH_CONFIG")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            clap::arg!(--format <FORMAT> "Output format")
+                .global(true)
+                .value_parser(clap::value_parser!(OutputFormat))
+                .default_value("json"),
+        )
+        .arg(
+            clap::arg!(--pretty "Indent JSON output, like --format json-pretty; ignored by \
+                 other formats")
+            .global(true),
+        )
+        .arg(
+            clap::arg!(--"show-age" [BOOL] "Show how long ago generations were built [default: \
+                 true for tables, false otherwise]")
+            .global(true)
+            .value_parser(clap::value_parser!(bool))
+            .default_missing_value("true"),
+        )
+        .arg(
+            clap::arg!(--"output-file" <PATH> "Write the result to PATH instead of stdout")
+                .global(true)
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            clap::arg!(--timezone <TZ> "Timezone of serialized timestamps")
+                .global(true)
+                .value_parser(clap::value_parser!(Timezone))
+                .default_value("utc"),
+        )
+        .arg(
+            clap::arg!(--"timestamp-format" <FORMAT> "How serialized timestamps are written")
+                .global(true)
+                .value_parser(clap::value_parser!(TimestampFormat))
+                .default_value("rfc3339"),
+        )
+        .arg(
+            clap::arg!(--"error-format" <FORMAT> "How failures are reported on stderr")
+                .long_help(
+                    "How failures are reported on stderr. The exit code is the same \
+                 either way.",
+                )
+                .global(true)
+                .value_parser(clap::value_parser!(ErrorFormat))
+                .default_value("text"),
+        )
+        .arg(
+            clap::arg!(--color <WHEN> "Color table output")
+                .global(true)
+                .value_parser(clap::value_parser!(ColorChoice))
+                .default_value("auto"),
+        )
+        .arg(
+            clap::arg!(--platform <PLATFORM> "System type, detected when omitted")
+                .global(true)
+                .value_parser(clap::value_parser!(Platform)),
+        )
+        .arg(
+            clap::arg!(--"profile-kind" <KIND> "How the profile is managed")
+                .global(true)
+                .value_parser(clap::value_parser!(ProfileKind))
+                .default_value("nix-env"),
+        )
+        .arg(
+            clap::arg!(-v --verbose "Log to stderr: -v for debug, -vv for trace")
+                .global(true)
+                .action(clap::ArgAction::Count),
+        )
+        .arg(
+            clap::arg!(--host <HOST> "Inspect HOST (user@host) over ssh instead of this machine")
+                .global(true),
+        )
+        .arg(
+            clap::arg!(--timeout <SECS> "Kill nix commands that run longer than SECS seconds")
+                .global(true)
+                .value_parser(clap::value_parser!(u64))
+                .default_value("60"),
+        )
+        .arg(
+            clap::arg!(-j --jobs <N> "Run at most N nix commands at once")
+                .global(true)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("4"),
+        )
+        .arg(
+            clap::arg!(
+                --strict "Fail on the first generation that can't be read, instead of \
+                 leaving it out of bisect, gc-preview and timeline with a warning"
+            )
+            .global(true),
+        )
+        .arg(
+            clap::arg!(--"cache-ttl" <SECS> "Reuse the generation list for SECS seconds")
+                .long_help(
+                    "Reuse the generation list for SECS seconds, or until the profile \
+                 switches generations. Defaults to 0 (disabled), or 30 for serve.",
+                )
+                .global(true)
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            clap::arg!(--refresh "Always re-read the generation list, bypassing the cache")
+                .global(true),
+        )
+        .arg(
+            clap::arg!(--"diff-cache" <N> "Keep the N most recently used diffs; 0 disables")
+                .global(true)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("64"),
+        )
+        .arg(
+            clap::arg!(--"skip-env-check" "Don't check that nix is installed before running")
+                .global(true),
+        )
+        .subcommand(
+            Command::new("list-generations")
+                .about("List all generations")
+                .arg(clap::arg!(--"with-sizes" "Compute the closure size of each generation"))
+                .arg(clap::arg!(--"with-flake" "Read the flake revision of each generation"))
+                .arg(
+                    clap::arg!(--"no-description" "Leave out each generation's description, \
+                     without reading versions or boot state from the store"),
+                )
+                .arg(
+                    clap::arg!(--field <FIELDS> "Only output these fields of each generation, \
+                     e.g. id,timestamp,current")
+                    .value_delimiter(',')
+                    .action(clap::ArgAction::Append),
+                )
+                .arg(
+                    clap::arg!(--"json-lines" "Write one JSON object per line as each is ready")
+                        .conflicts_with("format"),
+                )
+                .arg(
+                    clap::arg!(--sort <KEY> "Sort generations by this key")
+                        .value_parser(["timestamp", "id"])
+                        .default_value("timestamp"),
+                )
+                .arg(clap::arg!(--reverse "Reverse the sort order"))
+                .arg(clap::arg!(--since <DATE> "Only show generations built at or after DATE"))
+                .arg(clap::arg!(--until <DATE> "Only show generations built up to DATE"))
+                .arg(
+                    clap::arg!(--limit <N> "Show at most N generations")
+                        .value_parser(clap::value_parser!(usize)),
+                )
+                .arg(
+                    clap::arg!(--offset <M> "Skip the first M generations")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("0"),
+                ),
+        )
+        .subcommand(
+            Command::new("timeline")
+                .about("List the generations of every --profile, merged by build date"),
+        )
+        .subcommand(Command::new("current").about("Show the active generation"))
+        .subcommand(
+            Command::new("prev")
+                .about("Show the generation before ID")
+                .arg(clap::arg!(<id> "Generation: an id, current, previous, booted or HEAD~N")),
+        )
+        .subcommand(
+            Command::new("next")
+                .about("Show the generation after ID")
+                .arg(clap::arg!(<id> "Generation: an id, current, previous, booted or HEAD~N")),
+        )
+        .subcommand(Command::new("booted").about("Show the generation the system booted into"))
+        .subcommand(
+            Command::new("gc-preview")
+                .about("Estimate the space garbage collection would free per old generation"),
+        )
+        .subcommand(
+            Command::new("gc")
+                .about("Collect garbage with nix-collect-garbage")
+                .arg(clap::arg!(
+                    --"older-than" <DURATION> "First delete generations of every profile older \
+                     than DURATION, in whole days, e.g. 30d or 2w"
+                ))
+                .arg(clap::arg!(--"dry-run" "Print the command without running it"))
+                .arg(clap::arg!(-y --yes "Don't ask for confirmation")),
+        )
         .subcommand(
             Command::new("diff")
                 .about("Show diff between two generations")
-                .arg(clap::arg!(<from> "From generation ID"))
-                .arg(clap::arg!(<to> "To generation ID")),
+                .arg(
+                    clap::arg!(<from> "From generation: an id, current, previous, booted, \
+                 HEAD~N, or a FROM..TO range"),
+                )
+                .arg(clap::arg!([to] "To generation, omitted when FROM is a range"))
+                .arg(
+                    clap::arg!(--against <REF> "Diff FROM against REF, e.g. current or booted")
+                        .conflicts_with("to"),
+                )
+                .arg(
+                    clap::arg!(--"against-current" "Same as --against current")
+                        .conflicts_with_all(["to", "against"]),
+                )
+                .arg(
+                    clap::arg!(--mode <MODE> "Diff algorithm to use")
+                        .long_help(
+                            "Diff algorithm to use: references (direct store references), \
+                         requisites (every path in the closures; slower, with much larger \
+                         output), nix-diff (derivations, via nix-diff), auto (nix-diff, or \
+                         references when it isn't installed), or closures \
+                         (nix store diff-closures).",
+                        )
+                        .value_parser(clap::builder::PossibleValuesParser::new([
+                            clap::builder::PossibleValue::new("references"),
+                            clap::builder::PossibleValue::new("requisites"),
+                            clap::builder::PossibleValue::new("nix-diff").alias("derivations"),
+                            clap::builder::PossibleValue::new("auto"),
+                            clap::builder::PossibleValue::new("closures"),
+                        ]))
+                        .default_value("references"),
+                )
+                .arg(clap::arg!(--filter <PATTERN> "Only show packages whose name matches"))
+                .arg(clap::arg!(--exclude <PATTERN> "Hide packages whose name matches"))
+                .arg(clap::arg!(
+                    --"ignore-version-only" "Hide packages whose only change is their version"
+                ))
+                .arg(
+                    clap::arg!(--only <WHAT> "Only show these categories, or packages moving \
+                     in this direction")
+                    .long_help(
+                        "Only show these categories (added, removed, modified), or the \
+                         packages moving in this direction (upgrades, downgrades). Takes \
+                         several values, comma-separated or repeated: --only \
+                         modified,upgrades lists just the upgraded packages. The summary \
+                         counts what is shown, and `unfiltered` what was there before.",
+                    )
+                    .value_parser(["added", "removed", "modified", "upgrades", "downgrades"])
+                    .value_delimiter(',')
+                    .action(clap::ArgAction::Append),
+                )
+                .arg(clap::arg!(
+                    --group "Show packages with their old and new versions and paths, \
+                     grouping outputs like -dev and -man, instead of raw paths"
+                ))
+                .arg(clap::arg!(
+                    --"direct-only" "Only show packages the generations reference directly, \
+                     not their dependencies; implies --group"
+                ))
+                .arg(clap::arg!(
+                    --"name-only" "Only show the sorted, deduplicated names of the added, \
+                     removed and modified packages"
+                )),
+        )
+        .subcommand(
+            Command::new("find-package")
+                .about("Find the oldest generation that contains a package")
+                .arg(clap::arg!(<name> "Package name, e.g. openssl"))
+                .arg(clap::arg!(--version <VERSION> "Find when this version first appeared")),
+        )
+        .subcommand(
+            Command::new("bisect")
+                .about("List the generations in a range where a package's version changed")
+                .arg(clap::arg!(<package> "Package name, e.g. openssl"))
+                .arg(clap::arg!(<from> "First generation of the range"))
+                .arg(clap::arg!(<to> "Last generation of the range")),
+        )
+        .subcommand(
+            Command::new("churn")
+                .about("List the packages that most often flapped in and out across a range")
+                .arg(clap::arg!(<from> "First generation of the range"))
+                .arg(clap::arg!(<to> "Last generation of the range"))
+                .arg(
+                    clap::arg!(--top <N> "Show the N most churned packages")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("10"),
+                ),
+        )
+        .subcommand(
+            Command::new("snapshot")
+                .about("Record every generation and its references in the history database")
+                .arg(history_db_arg()),
         )
-        .get_matches();
+        .subcommand(
+            Command::new("history")
+                .about("Browse generations recorded by snapshot, even after they are deleted")
+                .subcommand_required(true)
+                .arg(history_db_arg())
+                .subcommand(Command::new("list").about("List recorded generations"))
+                .subcommand(
+                    Command::new("diff")
+                        .about("Diff the recorded references of two generations")
+                        .arg(clap::arg!(<from> "From generation id"))
+                        .arg(clap::arg!(<to> "To generation id")),
+                ),
+        )
+        .subcommand(
+            Command::new("scan")
+                .about("Check a generation's closure for known CVEs with vulnix")
+                .arg(clap::arg!(<id> "Generation to scan")),
+        )
+        .subcommand(
+            Command::new("scan-diff")
+                .about("Show CVEs introduced and resolved between two generations")
+                .arg(clap::arg!(<from> "From generation"))
+                .arg(clap::arg!(<to> "To generation")),
+        )
+        .subcommand(
+            Command::new("drv-diff")
+                .about(
+                    "Show which input derivations and environment variables changed \
+                     between two generations, from nix-diff",
+                )
+                .arg(clap::arg!(<from> "From generation"))
+                .arg(clap::arg!(<to> "To generation")),
+        )
+        .subcommand(
+            Command::new("common")
+                .about("List the references two generations have in common")
+                .arg(clap::arg!(<from> "From generation"))
+                .arg(clap::arg!(<to> "To generation")),
+        )
+        .subcommand(
+            Command::new("size-diff")
+                .about("Show how the closure size changes between two generations")
+                .arg(clap::arg!(<from> "From generation"))
+                .arg(clap::arg!(<to> "To generation"))
+                .arg(clap::arg!(--breakdown "Also show the size change of each changed package"))
+                .arg(clap::arg!(
+                    --"min-size" <SIZE> "Leave packages that changed by less than SIZE, e.g. \
+                     1MiB, out of the breakdown; implies --breakdown"
+                )),
+        )
+        .subcommand(
+            Command::new("stats")
+                .about("Summarize the generation history of the profile")
+                .arg(clap::arg!(--"with-sizes" "Also total and average the closure sizes")),
+        )
+        .subcommand(
+            Command::new("delete")
+                .about("Delete old generations from the profile")
+                .arg(
+                    clap::arg!([ids] ... "Generation IDs to delete")
+                        .conflicts_with_all(["keep-last", "older-than"]),
+                )
+                .arg(
+                    clap::arg!(--"keep-last" <N> "Delete all but the newest N generations")
+                        .value_parser(clap::value_parser!(usize)),
+                )
+                .arg(clap::arg!(
+                    --"older-than" <DURATION> "Delete generations older than DURATION, e.g. 30d"
+                ))
+                .arg(clap::arg!(--"dry-run" "Print the generations without deleting anything"))
+                .arg(clap::arg!(--"prune-notes" "Also forget the notes on deleted generations")),
+        )
+        .subcommand(
+            Command::new("note")
+                .about("Keep notes on generations, stored in ~/.local/share/nix-timemach")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("set")
+                        .about("Set the note on a generation")
+                        .arg(clap::arg!(<id> "Generation"))
+                        .arg(clap::arg!(<text> "The note")),
+                )
+                .subcommand(
+                    Command::new("get")
+                        .about("Show the note on a generation")
+                        .arg(clap::arg!(<id> "Generation")),
+                )
+                .subcommand(
+                    Command::new("clear")
+                        .about("Remove the note on a generation")
+                        .arg(clap::arg!(<id> "Generation")),
+                ),
+        )
+        .subcommand(
+            Command::new("watch")
+                .about("Print new generations and rollbacks as JSON lines until interrupted")
+                .arg(
+                    clap::arg!(--interval <SECS> "Seconds between profile polls")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("5"),
+                ),
+        )
+        .subcommand(
+            Command::new("serve")
+                .about("Serve generations and diffs as JSON over HTTP")
+                .arg(clap::arg!(--bind <ADDR> "Address to listen on").default_value("127.0.0.1"))
+                .arg(
+                    clap::arg!(--port <PORT> "Port to listen on")
+                        .value_parser(clap::value_parser!(u16))
+                        .default_value("8080"),
+                )
+                .arg(
+                    clap::arg!(--"poll-interval" <SECS> "Seconds between profile polls")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("5"),
+                )
+                .arg(clap::arg!(
+                    --"enable-metrics" "Serve request and nix command counters on /metrics \
+                     in the Prometheus format"
+                ))
+                .arg(
+                    clap::arg!(
+                        --"cors-origin" <ORIGIN> "Let pages on ORIGIN, e.g. \
+                         https://dash.example.com, call the API; * allows any. Repeatable"
+                    )
+                    .action(clap::ArgAction::Append),
+                )
+                .arg(
+                    clap::arg!(
+                        --"auth-token" <TOKEN> "Require an `Authorization: Bearer TOKEN` \
+                         header on every endpoint"
+                    )
+                    .env("NIX_TIMEMACH_AUTH_TOKEN"),
+                )
+                .arg(
+                    clap::arg!(
+                        --"metrics-token" <TOKEN> "Require this token on /metrics instead \
+                         of --auth-token"
+                    )
+                    .env("NIX_TIMEMACH_METRICS_TOKEN")
+                    .requires("enable-metrics"),
+                ),
+        )
+        .subcommand(
+            Command::new("rollback")
+                .about("Switch the system to a previous generation")
+                .arg(clap::arg!(<id> "Generation ID to activate"))
+                .arg(clap::arg!(--"dry-run" "Print the commands without activating anything")),
+        )
+        .subcommand(
+            Command::new("schema")
+                .about("Print the JSON Schema of an output type")
+                .arg(
+                    clap::arg!(<type> "Output type to describe")
+                        .value_parser(clap::value_parser!(SchemaType)),
+                ),
+        )
+        .subcommand(
+            Command::new("completions")
+                .about("Print a shell completion script")
+                .hide(true)
+                .arg(
+                    clap::arg!(<shell> "Shell to generate completions for")
+                        .value_parser(clap::value_parser!(clap_complete::Shell)),
+                ),
+        )
+}
+
+fn main() -> ExitCode {
+    let cli = cli().get_matches();
+    match run(&cli) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            let format = *cli.get_one::<ErrorFormat>("error-format").unwrap();
+            eprint!("{}", render_error(&err, format));
+            ExitCode::from(err.exit_code())
+        }
+    }
+}
+
+/// The value of flag `id`, or `configured` when the flag was left at its
+/// built-in default.
+fn or_configured<T: Clone + Send + Sync + 'static>(
+    cli: &clap::ArgMatches,
+    id: &str,
+    configured: Option<T>,
+) -> T {
+    match configured {
+        Some(value) if cli.value_source(id) == Some(ValueSource::DefaultValue) => value,
+        _ => cli.get_one::<T>(id).unwrap().clone(),
+    }
+}
+
+fn run(cli: &clap::ArgMatches) -> Result<()> {
+    if let Some(("completions", matches)) = cli.subcommand() {
+        let shell = *matches.get_one::<clap_complete::Shell>("shell").unwrap();
+        print_completions(shell, &mut std::io::stdout());
+        return Ok(());
+    }
+
+    init_logging(cli.get_count("verbose"));
+    let config = match cli.get_one::<PathBuf>("config") {
+        Some(path) => Config::load(path, true)?,
+        None => Config::load(&default_config_path(), false)?,
+    };
+    set_timezone(or_configured(cli, "timezone", config.timezone));
+    set_timestamp_format(*cli.get_one::<TimestampFormat>("timestamp-format").unwrap());
+
+    let profile_names: Vec<String> = match cli.get_many::<String>("profile") {
+        Some(names) => names.cloned().collect(),
+        None => vec![config
+            .profile
+            .unwrap_or_else(|| default_profile().to_string_lossy().into_owned())],
+    };
+    let timeout = std::time::Duration::from_secs(or_configured(cli, "timeout", config.timeout));
+    let host = cli.get_one::<String>("host");
+    let store = cli.get_one::<String>("store");
+    let profile = resolve_profile(profile_names.last().unwrap());
+    let cache_ttl = |default: u64| {
+        let secs = cli
+            .get_one::<u64>("cache-ttl")
+            .copied()
+            .or(config.cache_ttl)
+            .unwrap_or(default);
+        std::time::Duration::from_secs(if cli.get_flag("refresh") { 0 } else { secs })
+    };
+    let diff_cache = *cli.get_one::<usize>("diff-cache").unwrap();
+    let jobs = *cli.get_one::<usize>("jobs").unwrap();
+    let nix_service_for = |profile: PathBuf| {
+        let runner = RealCommandRunner::with_timeout(timeout);
+        let runner = match host {
+            Some(host) => runner.on_host(host),
+            None => runner,
+        };
+        let service = NixService::with_runner(runner)
+            .with_profile(profile)
+            .with_jobs(jobs)
+            .with_cache_ttl(cache_ttl(0))
+            .with_diff_cache(diff_cache)
+            .with_strict(cli.get_flag("strict"));
+        match store {
+            Some(store) => service.with_store(store),
+            None => service,
+        }
+    };
+    let service = nix_service_for(profile.clone());
+    let platform = cli
+        .get_one::<Platform>("platform")
+        .copied()
+        .unwrap_or_else(Platform::detect);
+    let profile_kind = *cli.get_one::<ProfileKind>("profile-kind").unwrap();
+    let provider_for = |profile: PathBuf| -> Box<dyn GenerationProvider> {
+        match (profile_kind, platform) {
+            (ProfileKind::NixProfile, _) => {
+                Box::new(NixProfileService::new(nix_service_for(profile)))
+            }
+            (ProfileKind::NixEnv, Platform::Nixos) => Box::new(nix_service_for(profile)),
+            (ProfileKind::NixEnv, Platform::Darwin) => {
+                Box::new(DarwinService::new(nix_service_for(profile)))
+            }
+        }
+    };
+    let provider = provider_for(profile.clone());
+    let async_service = |default_ttl, metrics: Option<Arc<Metrics>>| {
+        let runner = TokioCommandRunner::with_timeout(timeout);
+        let runner = match host {
+            Some(host) => runner.on_host(host),
+            None => runner,
+        };
+        let runner = match metrics {
+            Some(metrics) => runner.with_metrics(metrics),
+            None => runner,
+        };
+        let service = AsyncNixService::with_runner(runner)
+            .with_profile(profile.clone())
+            .with_jobs(jobs)
+            .with_cache_ttl(cache_ttl(default_ttl))
+            .with_diff_cache(diff_cache);
+        match store {
+            Some(store) => service.with_store(store),
+            None => service,
+        }
+    };
+    let output_file = cli.get_one::<PathBuf>("output-file").map(PathBuf::as_path);
+    // `auto` follows stdout, which a file isn't.
+    let style = Style {
+        format: match or_configured(cli, "format", config.format) {
+            OutputFormat::Json if cli.get_flag("pretty") => OutputFormat::JsonPretty,
+            format => format,
+        },
+        color: match cli.get_one::<ColorChoice>("color").unwrap() {
+            ColorChoice::Auto if output_file.is_some() => false,
+            choice => choice.enabled(),
+        },
+        output_file,
+    };
+
+    let show_age = cli
+        .get_one::<bool>("show-age")
+        .copied()
+        .unwrap_or(style.format == OutputFormat::Table);
+    let ages = |generations: &mut [Generation]| {
+        if show_age {
+            annotate_ages(generations);
+        }
+    };
+
+    // A bad range is reported before any nix command runs.
+    let (since, until) = match cli.subcommand() {
+        Some(("list-generations", matches)) => date_range(matches)?,
+        _ => (None, None),
+    };
+
+    // Nothing to check for commands that never run nix, or for a store that
+    // isn't on this machine.
+    let offline = matches!(cli.subcommand_name(), Some("schema" | "note" | "history"));
+    let remote = host.is_some() || store.is_some();
+    if !offline && !remote && !cli.get_flag("skip-env-check") {
+        service.check_environment()?;
+    }
 
     match cli.subcommand() {
-        Some(("list-generations", _)) => {
-            let generations = list_generations()?;
-            println!(
-                "{}",
-                serde_json::to_string(&generations)
-                    .map_err(|e| Error::NixOutputParseFailed(e.to_string()))?
+        Some(("list-generations", matches)) => {
+            let fields: Option<Vec<&str>> = matches
+                .get_many::<String>("field")
+                .map(|fields| fields.map(String::as_str).collect());
+            if let Some(unknown) = fields
+                .iter()
+                .flatten()
+                .find(|f| !Generation::FIELDS.contains(f))
+            {
+                return Err(Error::InvalidArgument(format!(
+                    "unknown field '{}', expected one of: {}",
+                    unknown,
+                    Generation::FIELDS.join(", ")
+                )));
+            }
+
+            let mut generations = if matches.get_flag("no-description") {
+                provider.read_generations()?
+            } else {
+                provider.list_generations()?
+            };
+            generations.retain(|g| {
+                since.is_none_or(|since| g.timestamp >= since)
+                    && until.is_none_or(|until| g.timestamp < until)
+            });
+            sort_generations(
+                &mut generations,
+                matches.get_one::<String>("sort").unwrap(),
+                matches.get_flag("reverse"),
+            );
+            let mut generations = paginate(
+                generations,
+                *matches.get_one::<usize>("offset").unwrap(),
+                matches.get_one::<usize>("limit").copied(),
             );
+            let notes = NoteStore::open(&default_notes_path())?;
+            let enrich = |generation: &mut Generation| -> Result<()> {
+                notes.annotate(&profile, std::slice::from_mut(generation));
+                ages(std::slice::from_mut(generation));
+                if matches.get_flag("no-description") {
+                    generation.description = None;
+                }
+                if matches.get_flag("with-sizes") {
+                    generation.size_bytes = service.generation_size(&generation.id).ok();
+                }
+                if matches.get_flag("with-flake") {
+                    generation.flake = service.generation_flake_info(&generation.id)?;
+                }
+                Ok(())
+            };
+            if matches.get_flag("json-lines") {
+                let mut lines = JsonLines::create(style.output_file)?;
+                for mut generation in generations {
+                    enrich(&mut generation)?;
+                    match &fields {
+                        Some(fields) => lines.write(&generation.project(fields))?,
+                        None => lines.write(&generation)?,
+                    }
+                }
+            } else {
+                for generation in &mut generations {
+                    enrich(generation)?;
+                }
+                match &fields {
+                    Some(fields) => {
+                        let projected: Vec<_> =
+                            generations.iter().map(|g| g.project(fields)).collect();
+                        print_rendered(&projected, style)?;
+                    }
+                    None => print_rendered(&generations, style)?,
+                }
+            }
+        }
+        Some(("timeline", _)) => {
+            let mut profiles = Vec::new();
+            let mut warnings = Vec::new();
+            for name in &profile_names {
+                match provider_for(resolve_profile(name)).list_generations() {
+                    Ok(generations) => profiles.push((name.clone(), generations)),
+                    Err(e) if !cli.get_flag("strict") => {
+                        warnings.push(format!("profile {}: {}", name, e.to_string().trim_end()))
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            let mut results = merge_timeline(profiles);
+            ages(&mut results);
+            print_rendered(&Partial { results, warnings }, style)?;
+        }
+        Some(("current", _)) => {
+            let mut current = provider.get_current()?;
+            NoteStore::open(&default_notes_path())?
+                .annotate(&profile, std::slice::from_mut(&mut current));
+            ages(std::slice::from_mut(&mut current));
+            print_rendered(&current, style)?;
+        }
+        Some((name @ ("prev" | "next"), matches)) => {
+            let id = service.resolve_ref(matches.get_one::<String>("id").unwrap())?;
+            let (direction, relation) = match name {
+                "prev" => (Direction::Previous, "before"),
+                _ => (Direction::Next, "after"),
+            };
+            let mut generation = provider
+                .get_neighbor(&id, direction)?
+                .ok_or_else(|| Error::GenerationNotFound(format!("{} {}", relation, id)))?;
+            NoteStore::open(&default_notes_path())?
+                .annotate(&profile, std::slice::from_mut(&mut generation));
+            ages(std::slice::from_mut(&mut generation));
+            print_rendered(&generation, style)?;
+        }
+        Some(("booted", _)) => {
+            let mut booted = service.get_booted()?;
+            NoteStore::open(&default_notes_path())?
+                .annotate(&profile, std::slice::from_mut(&mut booted));
+            ages(std::slice::from_mut(&mut booted));
+            print_rendered(&booted, style)?;
+        }
+        Some(("gc-preview", _)) => {
+            print_rendered(&service.gc_preview()?, style)?;
+        }
+        Some(("gc", matches)) => {
+            let older_than_days = matches
+                .get_one::<String>("older-than")
+                .map(|d| {
+                    // nix-collect-garbage only accepts a number of days.
+                    let days = parse_duration(d)?.num_days();
+                    match u64::try_from(days) {
+                        Ok(days) if days > 0 => Ok(days),
+                        _ => Err(Error::InvalidArgument(format!(
+                            "--older-than must be at least a day, got '{}'",
+                            d
+                        ))),
+                    }
+                })
+                .transpose()?;
+            let dry_run = matches.get_flag("dry-run");
+            if !dry_run && !matches.get_flag("yes") {
+                confirm("Delete unreachable store paths with nix-collect-garbage?")?;
+            }
+            print_rendered(&service.collect_garbage(older_than_days, dry_run)?, style)?;
         }
         Some(("diff", matches)) => {
             let from = matches.get_one::<String>("from").unwrap();
-            let to = matches.get_one::<String>("to").unwrap();
-            let diff = get_diff(from, to)?;
-            println!(
-                "{}",
-                serde_json::to_string(&diff)
-                    .map_err(|e| Error::NixOutputParseFailed(e.to_string()))?
-            );
+            let to = matches
+                .get_one::<String>("to")
+                .or(matches.get_one::<String>("against"))
+                .map(String::as_str)
+                .or(matches.get_flag("against-current").then_some("current"));
+            // Equal ids short-circuit to an empty diff in every mode.
+            let (from, to) = match to {
+                Some(_) if from.contains("..") => {
+                    return Err(Error::InvalidArgument(
+                        "a FROM..TO range takes no second generation".to_string(),
+                    ));
+                }
+                Some(to) => (service.resolve_ref(from)?, service.resolve_ref(to)?),
+                None if from.contains("..") => service.resolve_range(from)?,
+                None => {
+                    return Err(Error::InvalidArgument(
+                        "diff needs two generations or a FROM..TO range".to_string(),
+                    ));
+                }
+            };
+            let (from, to) = (from.as_str(), to.as_str());
+            let filter = NameFilter::new(
+                matches.get_one::<String>("filter").map(String::as_str),
+                matches.get_one::<String>("exclude").map(String::as_str),
+            )?;
+            let ignore_version_only = matches.get_flag("ignore-version-only");
+            let only: Vec<&str> = matches
+                .get_many::<String>("only")
+                .unwrap_or_default()
+                .map(String::as_str)
+                .collect();
+            let direction = match (only.contains(&"upgrades"), only.contains(&"downgrades")) {
+                (true, true) => {
+                    return Err(Error::InvalidArgument(
+                        "--only takes upgrades or downgrades, not both".to_string(),
+                    ))
+                }
+                (true, false) => Some(ChangeDirection::Upgrade),
+                (false, true) => Some(ChangeDirection::Downgrade),
+                (false, false) => None,
+            };
+            if direction.is_some() && ignore_version_only {
+                return Err(Error::InvalidArgument(
+                    "--only upgrades/downgrades can't be combined with --ignore-version-only"
+                        .to_string(),
+                ));
+            }
+            let categories: Vec<DiffCategory> = only
+                .iter()
+                .filter_map(|only| match *only {
+                    "added" => Some(DiffCategory::Added),
+                    "removed" => Some(DiffCategory::Removed),
+                    "modified" => Some(DiffCategory::Modified),
+                    _ => None,
+                })
+                .collect();
+            let direct_only = matches.get_flag("direct-only");
+            let group = matches.get_flag("group") || direct_only;
+            let paths = |diff: GenerationDiff| {
+                let mut diff = filter.apply(diff);
+                if ignore_version_only {
+                    diff = diff.without_version_only();
+                }
+                if only.is_empty() {
+                    return diff;
+                }
+                let unfiltered = diff.summary();
+                if let Some(direction) = direction {
+                    diff = diff.only(direction);
+                }
+                if !categories.is_empty() {
+                    diff = diff.only_categories(&categories);
+                }
+                diff.unfiltered = Some(unfiltered);
+                diff
+            };
+            let packages = |diff: DetailedDiff| {
+                let mut diff = filter.apply_detailed(diff);
+                if direct_only {
+                    diff = diff.direct_only();
+                }
+                if ignore_version_only {
+                    diff = diff.without_version_only();
+                }
+                if only.is_empty() {
+                    return diff;
+                }
+                let unfiltered = diff.summary();
+                if let Some(direction) = direction {
+                    diff = diff.only(direction);
+                }
+                if !categories.is_empty() {
+                    diff = diff.only_categories(&categories);
+                }
+                diff.unfiltered = Some(unfiltered);
+                diff
+            };
+            let name_only = matches.get_flag("name-only");
+            let print_detailed = |diff: DetailedDiff| {
+                let diff = packages(diff);
+                if name_only {
+                    print_rendered(&diff.names_only(), style)
+                } else {
+                    print_rendered(&diff, style)
+                }
+            };
+            // Classifying takes four more store queries, so it is only done
+            // for `--direct-only`, and a failure just leaves `direct` unset.
+            let print_packages = |mut diff: DetailedDiff| {
+                if direct_only {
+                    if let Err(e) = service.classify_dependencies(from, to, &mut diff) {
+                        warn!("can't tell direct dependencies apart: {}", e);
+                    }
+                }
+                print_detailed(diff)
+            };
+            let print_paths = |diff: GenerationDiff| {
+                if group {
+                    print_packages(diff.to_detailed())
+                } else if name_only {
+                    print_rendered(&paths(diff).names_only(), style)
+                } else {
+                    print_rendered(&paths(diff), style)
+                }
+            };
+            match matches.get_one::<String>("mode").unwrap().as_str() {
+                // `nix profile history` already records each package change.
+                _ if profile_kind == ProfileKind::NixProfile => {
+                    let history = NixProfileService::new(nix_service_for(profile.clone()));
+                    print_detailed(history.get_diff(from, to)?)?
+                }
+                "nix-diff" | "derivations" => print_paths(service.get_nix_diff(from, to)?)?,
+                "auto" => print_paths(service.get_diff(from, to)?)?,
+                "requisites" => print_paths(service.get_requisites_diff(from, to)?)?,
+                "closures" => print_packages(service.get_closure_diff(from, to)?)?,
+                _ => print_paths(service.get_reference_diff(from, to)?)?,
+            }
+        }
+        Some(("find-package", matches)) => {
+            let found = service.find_package(
+                matches.get_one::<String>("name").unwrap(),
+                matches.get_one::<String>("version").map(String::as_str),
+            )?;
+            print_rendered(&found, style)?;
+        }
+        Some(("bisect", matches)) => {
+            let transitions = service.bisect_package(
+                matches.get_one::<String>("package").unwrap(),
+                &service.resolve_ref(matches.get_one::<String>("from").unwrap())?,
+                &service.resolve_ref(matches.get_one::<String>("to").unwrap())?,
+            )?;
+            print_rendered(&transitions, style)?;
+        }
+        Some(("churn", matches)) => {
+            let churn = service.churn(
+                &service.resolve_ref(matches.get_one::<String>("from").unwrap())?,
+                &service.resolve_ref(matches.get_one::<String>("to").unwrap())?,
+                *matches.get_one::<usize>("top").unwrap(),
+            )?;
+            print_rendered(&churn, style)?;
+        }
+        Some(("snapshot", matches)) => {
+            let generations = service.list_with_references()?;
+            let mut history = HistoryStore::open(&history_db(matches))?;
+            let profile = profile.to_string_lossy();
+            print_rendered(&history.record(&profile, &generations)?, style)?;
+        }
+        Some(("history", matches)) => {
+            let history = HistoryStore::open_existing(&history_db(matches))?;
+            let profile = profile.to_string_lossy();
+            match matches.subcommand() {
+                Some(("diff", diff)) => {
+                    let (from, to) = (
+                        diff.get_one::<String>("from").unwrap(),
+                        diff.get_one::<String>("to").unwrap(),
+                    );
+                    print_rendered(&history.diff(&profile, from, to)?, style)?;
+                }
+                _ => print_rendered(&history.list_generations(&profile)?, style)?,
+            }
+        }
+        Some(("scan", matches)) => {
+            let id = service.resolve_ref(matches.get_one::<String>("id").unwrap())?;
+            print_rendered(&service.scan(&id)?, style)?;
+        }
+        Some(("scan-diff", matches)) => {
+            let from = service.resolve_ref(matches.get_one::<String>("from").unwrap())?;
+            let to = service.resolve_ref(matches.get_one::<String>("to").unwrap())?;
+            print_rendered(&service.scan_diff(&from, &to)?, style)?;
+        }
+        Some(("drv-diff", matches)) => {
+            let from = service.resolve_ref(matches.get_one::<String>("from").unwrap())?;
+            let to = service.resolve_ref(matches.get_one::<String>("to").unwrap())?;
+            print_rendered(&service.get_derivation_diff(&from, &to)?, style)?;
+        }
+        Some(("common", matches)) => {
+            let from = service.resolve_ref(matches.get_one::<String>("from").unwrap())?;
+            let to = service.resolve_ref(matches.get_one::<String>("to").unwrap())?;
+            print_rendered(&service.get_common_references(&from, &to)?, style)?;
+        }
+        Some(("size-diff", matches)) => {
+            let from = service.resolve_ref(matches.get_one::<String>("from").unwrap())?;
+            let to = service.resolve_ref(matches.get_one::<String>("to").unwrap())?;
+            let min_size = matches
+                .get_one::<String>("min-size")
+                .map(|size| parse_size(size))
+                .transpose()?;
+            let breakdown = matches.get_flag("breakdown") || min_size.is_some();
+            let diff = service.size_diff(&from, &to, breakdown)?;
+            match min_size {
+                Some(min_size) => print_rendered(&diff.without_smaller_than(min_size), style)?,
+                None => print_rendered(&diff, style)?,
+            }
+        }
+        Some(("stats", matches)) => {
+            let mut generations = provider.list_generations()?;
+            if matches.get_flag("with-sizes") {
+                for generation in &mut generations {
+                    generation.size_bytes = service.generation_size(&generation.id).ok();
+                }
+            }
+            print_rendered(&GenerationStats::from_generations(&generations), style)?;
+        }
+        Some(("delete", matches)) => {
+            let keep_last = matches.get_one::<usize>("keep-last").copied();
+            let older_than = matches
+                .get_one::<String>("older-than")
+                .map(|d| parse_duration(d).map(|d| Utc::now() - d))
+                .transpose()?;
+            let ids: Vec<String> = match matches.get_many::<String>("ids") {
+                Some(ids) => ids.cloned().collect(),
+                None if keep_last.is_some() || older_than.is_some() => {
+                    service.deletion_candidates(keep_last, older_than)?
+                }
+                None => {
+                    return Err(Error::InvalidArgument(
+                        "delete needs generation ids, --keep-last or --older-than".to_string(),
+                    ));
+                }
+            };
+            let plan = service.delete_generations(&ids, matches.get_flag("dry-run"))?;
+            if matches.get_flag("prune-notes") && !plan.dry_run {
+                let mut notes = NoteStore::open(&default_notes_path())?;
+                for id in &plan.generations {
+                    notes.clear(&profile, id);
+                }
+                notes.save()?;
+            }
+            print_rendered(&plan, style)?;
+        }
+        Some(("note", matches)) => {
+            let mut notes = NoteStore::open(&default_notes_path())?;
+            let (action, matches) = matches.subcommand().unwrap();
+            let id = service.resolve_ref(matches.get_one::<String>("id").unwrap())?;
+            let note = match action {
+                "set" => {
+                    if !service.generation_exists(&id)? {
+                        return Err(Error::GenerationNotFound(id));
+                    }
+                    let text = matches.get_one::<String>("text").unwrap();
+                    notes.set(&profile, &id, text);
+                    notes.save()?;
+                    Some(text.clone())
+                }
+                "clear" => {
+                    notes.clear(&profile, &id);
+                    notes.save()?;
+                    None
+                }
+                _ => notes.get(&profile, &id).map(str::to_string),
+            };
+            let note = GenerationNote {
+                profile: profile.to_string_lossy().into_owned(),
+                generation: id,
+                note,
+            };
+            print_rendered(&note, style)?;
+        }
+        Some(("watch", matches)) => {
+            let interval =
+                std::time::Duration::from_secs(*matches.get_one::<u64>("interval").unwrap());
+            tokio::runtime::Runtime::new()?.block_on(watch(
+                async_service(0, None),
+                interval,
+                output_file,
+            ))?;
+        }
+        Some(("serve", matches)) => {
+            let bind = matches.get_one::<String>("bind").unwrap();
+            let port = *matches.get_one::<u16>("port").unwrap();
+            let ip: IpAddr = bind
+                .parse()
+                .map_err(|_| Error::InvalidArgument(format!("invalid bind address '{}'", bind)))?;
+            let poll_interval =
+                std::time::Duration::from_secs(*matches.get_one::<u64>("poll-interval").unwrap());
+            let metrics = matches
+                .get_flag("enable-metrics")
+                .then(|| Arc::new(Metrics::new()));
+            let mut state = AppState::new(async_service(SERVE_CACHE_TTL, metrics.clone()));
+            if let Some(metrics) = metrics {
+                state = state.with_metrics(metrics);
+            }
+            if let Some(token) = matches.get_one::<String>("auth-token") {
+                state = state.with_auth_token(token);
+            }
+            if let Some(token) = matches.get_one::<String>("metrics-token") {
+                state = state.with_metrics_token(token);
+            }
+            let origins: Vec<String> = matches
+                .get_many::<String>("cors-origin")
+                .unwrap_or_default()
+                .cloned()
+                .collect();
+            if !origins.is_empty() {
+                state = state.with_cors_origins(&origins)?;
+            }
+            tokio::runtime::Runtime::new()?.block_on(serve(
+                state,
+                SocketAddr::new(ip, port),
+                poll_interval,
+            ))?;
+        }
+        Some(("schema", matches)) => {
+            print_rendered(
+                &matches.get_one::<SchemaType>("type").unwrap().schema(),
+                style,
+            )?;
+        }
+        Some(("rollback", matches)) => {
+            let id = matches.get_one::<String>("id").unwrap();
+            let dry_run = matches.get_flag("dry-run");
+            print_rendered(&service.rollback(id, dry_run)?, style)?;
         }
         _ => unreachable!(),
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn test_completions_for_every_shell() {
+        for shell in [
+            clap_complete::Shell::Bash,
+            clap_complete::Shell::Zsh,
+            clap_complete::Shell::Fish,
+            clap_complete::Shell::PowerShell,
+        ] {
+            let mut script = Vec::new();
+            print_completions(shell, &mut script);
+            assert!(String::from_utf8(script)
+                .unwrap()
+                .contains("list-generations"));
+        }
+    }
+
+    #[test]
+    fn test_json_lines_writes_one_value_per_line() {
+        let path = std::env::temp_dir().join(format!("nix-timemach-lines-{}", std::process::id()));
+        let mut lines = JsonLines::create(Some(&path)).unwrap();
+        lines.write(&generation("1", 10)).unwrap();
+        lines.write(&generation("2", 11)).unwrap();
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        let ids: Vec<String> = written
+            .lines()
+            .map(|line| serde_json::from_str::<Generation>(line).unwrap().id)
+            .collect();
+        assert_eq!(ids, ["1", "2"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_diff_positionals() {
+        cli().debug_assert();
+
+        let positionals = |args: &[&str]| {
+            let matches = cli().try_get_matches_from(args).unwrap();
+            let (_, diff) = matches.subcommand().unwrap();
+            (
+                diff.get_one::<String>("from").cloned(),
+                diff.get_one::<String>("to").cloned(),
+            )
+        };
+        assert_eq!(
+            positionals(&["nix-timemach", "diff", "41", "42"]),
+            (Some("41".to_string()), Some("42".to_string()))
+        );
+        assert_eq!(
+            positionals(&["nix-timemach", "diff", "41..42"]),
+            (Some("41..42".to_string()), None)
+        );
+        assert!(cli()
+            .try_get_matches_from(["nix-timemach", "diff"])
+            .is_err());
+
+        let matches = cli()
+            .try_get_matches_from(["nix-timemach", "diff", "40", "--against-current"])
+            .unwrap();
+        let (_, diff) = matches.subcommand().unwrap();
+        assert!(diff.get_flag("against-current"));
+        assert!(cli()
+            .try_get_matches_from(["nix-timemach", "diff", "40", "41", "--against", "booted"])
+            .is_err());
+
+        let matches = cli()
+            .try_get_matches_from([
+                "nix-timemach",
+                "diff",
+                "40",
+                "41",
+                "--only",
+                "added,removed",
+                "--only",
+                "upgrades",
+            ])
+            .unwrap();
+        let (_, diff) = matches.subcommand().unwrap();
+        assert_eq!(
+            diff.get_many::<String>("only").unwrap().collect::<Vec<_>>(),
+            ["added", "removed", "upgrades"]
+        );
+        assert!(cli()
+            .try_get_matches_from(["nix-timemach", "diff", "40", "41", "--only", "lost"])
+            .is_err());
+    }
+
+    fn generation(id: &str, hour: u32) -> Generation {
+        Generation {
+            id: id.to_string(),
+            timestamp: Utc.with_ymd_and_hms(2024, 2, 9, hour, 0, 0).unwrap(),
+            ..Default::default()
+        }
+    }
+
+    fn ids(generations: &[Generation]) -> Vec<&str> {
+        generations.iter().map(|g| g.id.as_str()).collect()
+    }
+
+    #[test]
+    fn test_sort_generations() {
+        let mut generations = vec![
+            generation("10", 9),
+            generation("9", 9),
+            generation("2", 8),
+            generation("11", 7),
+        ];
+
+        sort_generations(&mut generations, "timestamp", false);
+        assert_eq!(ids(&generations), ["11", "2", "9", "10"]);
+
+        sort_generations(&mut generations, "id", false);
+        assert_eq!(ids(&generations), ["2", "9", "10", "11"]);
+
+        sort_generations(&mut generations, "id", true);
+        assert_eq!(ids(&generations), ["11", "10", "9", "2"]);
+    }
+
+    #[test]
+    fn test_paginate_after_sort() {
+        let mut generations = vec![generation("1", 7), generation("2", 8), generation("3", 9)];
+        sort_generations(&mut generations, "timestamp", true);
+
+        assert_eq!(ids(&paginate(generations.clone(), 0, Some(2))), ["3", "2"]);
+        assert_eq!(ids(&paginate(generations.clone(), 1, None)), ["2", "1"]);
+        assert!(paginate(generations, 5, Some(1)).is_empty());
+    }
+
+    #[test]
+    fn test_parse_date_bound() {
+        let local = |value: &str, upper: bool| {
+            parse_date_bound(value, upper)
+                .unwrap()
+                .with_timezone(&Local)
+                .naive_local()
+                .to_string()
+        };
+        assert_eq!(local("2024-02-09", false), "2024-02-09 00:00:00");
+        assert_eq!(local("2024-02-09", true), "2024-02-10 00:00:00");
+        assert_eq!(
+            parse_date_bound("2024-02-09T10:00:00+02:00", false)
+                .unwrap()
+                .to_rfc3339(),
+            "2024-02-09T08:00:00+00:00"
+        );
+        // `--until` includes the second it names.
+        assert_eq!(
+            parse_date_bound("2024-02-09T10:00:00.5+02:00", true)
+                .unwrap()
+                .to_rfc3339(),
+            "2024-02-09T08:00:01+00:00"
+        );
+        assert!(matches!(
+            parse_date_bound("+262142-12-31", true),
+            Err(Error::InvalidArgument(message)) if message.ends_with("out of range")
+        ));
+        assert!(matches!(
+            parse_date_bound("2024-13-01", false),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30d").unwrap(), Duration::days(30));
+        assert_eq!(parse_duration("12h").unwrap(), Duration::hours(12));
+        assert_eq!(parse_duration("2w").unwrap(), Duration::weeks(2));
+        for invalid in ["", "d", "30", "30y", "-1d"] {
+            assert!(parse_duration(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert_eq!(parse_size("1MiB").unwrap(), 1 << 20);
+        assert_eq!(parse_size("1.5 GiB").unwrap(), 3 << 29);
+        assert_eq!(parse_size("512k").unwrap(), 512 << 10);
+        for invalid in ["", "MiB", "1MB", "-1", "1..2K"] {
+            assert!(parse_size(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,30 @@
+use serde::{Deserialize, Serialize};
+
+/// A derivation that differs between two generations and how, as nix-diff
+/// explains it, from `drv-diff`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DerivationChange {
+    /// The derivation name, e.g. `openssl` or `nixos-system-host-24.05`.
+    pub name: String,
+    /// How many inputs deep nix-diff found it; 0 for the generations' own
+    /// derivation.
+    pub depth: usize,
+    pub old_path: Option<String>,
+    pub new_path: Option<String>,
+    /// Inputs that differ, each listed after this one as a change of its own.
+    pub changed_inputs: Vec<String>,
+    pub added_inputs: Vec<String>,
+    pub removed_inputs: Vec<String>,
+    pub environment: Vec<EnvironmentChange>,
+    /// Anything else nix-diff reports, e.g. `The builders do not match`.
+    pub other: Vec<String>,
+}
+
+/// An environment variable that differs. A `None` side is unset; multi-line
+/// values only hold the lines that changed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EnvironmentChange {
+    pub name: String,
+    pub old_value: Option<String>,
+    pub new_value: Option<String>,
+}
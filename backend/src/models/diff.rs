@@ -1,8 +1,841 @@
-use serde::{Deserialize, Serialize};
+use schemars::{JsonSchema, Schema, SchemaGenerator};
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Serialize, Serializer};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap, HashSet};
 
-#[derive(Debug, Serialize, Deserialize)]
+use crate::models::is_zero;
+use crate::models::store_path::{compare_versions, StorePath};
+
+/// Serializes with an extra `summary` key holding `summary()`.
+///
+/// Both path-level algorithms in `NixService` fill this in: `get_nix_diff`
+/// from nix-diff's `+`/`-`/`~` lines (see `parse_diff_output`), and
+/// `get_reference_diff` from the generations' direct references (see
+/// `diff_references`). `get_diff` uses the first, or the second when
+/// nix-diff isn't installed.
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct GenerationDiff {
     pub added: Vec<String>,
     pub removed: Vec<String>,
+    /// Paths that changed rather than appeared or disappeared. nix-diff
+    /// decides this itself; the reference diff lists the old path of every
+    /// package whose name is referenced on both sides under another path.
     pub modified: Vec<String>,
+    /// Version-only changes removed by `without_version_only`.
+    #[serde(default)]
+    pub suppressed: usize,
+    /// `summary()` from before `--only` filtered the diff.
+    #[serde(default)]
+    pub unfiltered: Option<DiffSummary>,
+}
+
+/// Just the package names of a diff, for `diff --name-only`. Each name is
+/// in one list only: a package both added and removed, as in a version
+/// bump, is modified.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NameDiff {
+    /// Sorted, like the other two.
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub modified: Vec<String>,
+}
+
+impl NameDiff {
+    fn new(
+        added: BTreeSet<String>,
+        removed: BTreeSet<String>,
+        mut modified: BTreeSet<String>,
+    ) -> Self {
+        modified.extend(added.intersection(&removed).cloned());
+        let unmodified = |names: BTreeSet<String>| {
+            names
+                .into_iter()
+                .filter(|n| !modified.contains(n))
+                .collect()
+        };
+        Self {
+            added: unmodified(added),
+            removed: unmodified(removed),
+            modified: modified.iter().cloned().collect(),
+        }
+    }
+}
+
+/// One of the lists a diff is made of, for `only_categories`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffCategory {
+    Added,
+    Removed,
+    Modified,
+}
+
+/// How a diff was computed, which decides what it contains.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DiffMode {
+    /// `nix-diff`, falling back to references when it isn't installed.
+    Auto,
+    NixDiff,
+    References,
+    Requisites,
+    Closures,
+}
+
+/// Direct references two generations share, for `common`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommonReferences {
+    pub from: String,
+    pub to: String,
+    pub count: usize,
+    /// Sorted.
+    pub common: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, JsonSchema)]
+pub struct DiffSummary {
+    pub added: usize,
+    pub removed: usize,
+    pub modified: usize,
+    pub upgraded: usize,
+    pub downgraded: usize,
+    /// Version-only changes left out of the lists above.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub suppressed: usize,
+}
+
+/// A single package-level change parsed from a store path.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct PackageChange {
+    pub name: String,
+    pub old_version: Option<String>,
+    pub new_version: Option<String>,
+    #[serde(default)]
+    pub direction: ChangeDirection,
+    /// The path after the change, or the removed path for removals. Empty
+    /// when the source (e.g. `nix store diff-closures`) reports no paths.
+    pub store_path: String,
+    /// Change in closure size in bytes, when known.
+    pub size_delta: Option<i64>,
+    /// Every path of a modified package before and after the change, one
+    /// per output, when grouped from a path-level diff by `to_detailed`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub old_paths: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub new_paths: Vec<String>,
+    /// Whether the generation references the package directly, like a
+    /// system package, rather than pulling it in as a dependency of one.
+    /// Set by `NixService::classify_dependencies`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub direct: Option<bool>,
+}
+
+impl PackageChange {
+    /// The outputs among `old_paths` and `new_paths` in first-seen order,
+    /// `out` standing for paths without an output suffix.
+    pub fn outputs(&self) -> Vec<&'static str> {
+        let mut outputs = Vec::new();
+        for path in self.old_paths.iter().chain(&self.new_paths) {
+            let output = StorePath::parse(path).output().unwrap_or("out");
+            if !outputs.contains(&output) {
+                outputs.push(output);
+            }
+        }
+        outputs
+    }
+}
+
+/// Which way a package's version moved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeDirection {
+    Upgrade,
+    Downgrade,
+    /// Same version, e.g. a rebuild with different inputs.
+    Lateral,
+    /// A version is missing, or the two can't be meaningfully ordered.
+    #[default]
+    Unknown,
+}
+
+impl ChangeDirection {
+    /// Compare versions as semver, padding `1.2` to `1.2.0`, and like Nix's
+    /// `compareVersions` when either isn't semver. Date versions such as `20240209` and a
+    /// release against its own pre-release are `Unknown`.
+    pub fn between(old: Option<&str>, new: Option<&str>) -> Self {
+        let (Some(old), Some(new)) = (old, new) else {
+            return ChangeDirection::Unknown;
+        };
+        if old == new {
+            return ChangeDirection::Lateral;
+        }
+        if is_date_version(old) || is_date_version(new) {
+            return ChangeDirection::Unknown;
+        }
+
+        let ordering = match (lenient_semver(old), lenient_semver(new)) {
+            (Some(old), Some(new)) => {
+                let same_release =
+                    (old.major, old.minor, old.patch) == (new.major, new.minor, new.patch);
+                if same_release && old.pre.is_empty() != new.pre.is_empty() {
+                    return ChangeDirection::Unknown;
+                }
+                old.cmp(&new)
+            }
+            _ => compare_versions(old, new),
+        };
+
+        match ordering {
+            Ordering::Less => ChangeDirection::Upgrade,
+            Ordering::Greater => ChangeDirection::Downgrade,
+            Ordering::Equal => ChangeDirection::Lateral,
+        }
+    }
+}
+
+fn is_date_version(version: &str) -> bool {
+    version.len() >= 8
+        && version.bytes().take(8).all(|b| b.is_ascii_digit())
+        && (version.starts_with("19") || version.starts_with("20"))
+}
+
+/// Parse `1`, `1.2` or `1.2.3`, optionally followed by `-pre` or `+build`.
+fn lenient_semver(version: &str) -> Option<semver::Version> {
+    let version = version.strip_prefix('v').unwrap_or(version);
+    let split = version.find(['-', '+']).unwrap_or(version.len());
+    let (core, rest) = version.split_at(split);
+
+    let parts: Vec<&str> = core.split('.').collect();
+    if parts.len() > 3
+        || parts
+            .iter()
+            .any(|p| p.is_empty() || !p.bytes().all(|b| b.is_ascii_digit()))
+    {
+        return None;
+    }
+    let mut padded = parts.join(".");
+    for _ in parts.len()..3 {
+        padded.push_str(".0");
+    }
+
+    semver::Version::parse(&format!("{}{}", padded, rest)).ok()
+}
+
+/// Like `GenerationDiff`, serialized with a trailing `summary`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DetailedDiff {
+    pub added: Vec<PackageChange>,
+    pub removed: Vec<PackageChange>,
+    pub modified: Vec<PackageChange>,
+    #[serde(default)]
+    pub suppressed: usize,
+    #[serde(default)]
+    pub unfiltered: Option<DiffSummary>,
+}
+
+impl DetailedDiff {
+    /// Entry counts per category. Modified packages are also counted by
+    /// their `direction`.
+    pub fn summary(&self) -> DiffSummary {
+        let mut summary = DiffSummary {
+            added: self.added.len(),
+            removed: self.removed.len(),
+            modified: self.modified.len(),
+            suppressed: self.suppressed,
+            ..DiffSummary::default()
+        };
+
+        for change in &self.modified {
+            match change.direction {
+                ChangeDirection::Upgrade => summary.upgraded += 1,
+                ChangeDirection::Downgrade => summary.downgraded += 1,
+                ChangeDirection::Lateral | ChangeDirection::Unknown => {}
+            }
+        }
+
+        summary
+    }
+
+    /// Drop modified packages whose name is unchanged and only the version
+    /// differs, along with the added and removed entries for the same bump.
+    pub fn without_version_only(mut self) -> Self {
+        let (bumps, modified): (Vec<_>, Vec<_>) = self
+            .modified
+            .into_iter()
+            .partition(|change| is_version_bump(&change.old_version, &change.new_version));
+
+        for bump in &bumps {
+            self.added.retain(|c| {
+                c.name != bump.name
+                    || (c.new_version != bump.new_version
+                        && !bump.new_paths.contains(&c.store_path))
+            });
+            self.removed.retain(|c| {
+                c.name != bump.name
+                    || (c.old_version != bump.old_version
+                        && !bump.old_paths.contains(&c.store_path))
+            });
+        }
+
+        self.modified = modified;
+        self.suppressed += bumps.len();
+        self
+    }
+
+    /// Keep only modified packages moving in `direction`, plus the added and
+    /// removed entries of those same packages.
+    pub fn only(mut self, direction: ChangeDirection) -> Self {
+        self.modified.retain(|change| change.direction == direction);
+        let names: HashSet<String> = self.modified.iter().map(|c| c.name.clone()).collect();
+        self.added.retain(|change| names.contains(&change.name));
+        self.removed.retain(|change| names.contains(&change.name));
+        self
+    }
+
+    /// Empty every list whose category isn't in `keep`.
+    pub fn only_categories(mut self, keep: &[DiffCategory]) -> Self {
+        clear_unless(keep, DiffCategory::Added, &mut self.added);
+        clear_unless(keep, DiffCategory::Removed, &mut self.removed);
+        clear_unless(keep, DiffCategory::Modified, &mut self.modified);
+        self
+    }
+
+    pub fn names_only(&self) -> NameDiff {
+        let names = |changes: &[PackageChange]| changes.iter().map(|c| c.name.clone()).collect();
+        NameDiff::new(
+            names(&self.added),
+            names(&self.removed),
+            names(&self.modified),
+        )
+    }
+
+    /// Keep only the changes known to be direct references.
+    pub fn direct_only(mut self) -> Self {
+        for changes in [&mut self.added, &mut self.removed, &mut self.modified] {
+            changes.retain(|change| change.direct == Some(true));
+        }
+        self
+    }
+}
+
+fn clear_unless<T>(keep: &[DiffCategory], category: DiffCategory, list: &mut Vec<T>) {
+    if !keep.contains(&category) {
+        list.clear();
+    }
+}
+
+fn is_version_bump(old: &Option<String>, new: &Option<String>) -> bool {
+    matches!((old, new), (Some(old), Some(new)) if old != new)
+}
+
+impl Serialize for DetailedDiff {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("DetailedDiff", 5)?;
+        state.serialize_field("added", &self.added)?;
+        state.serialize_field("removed", &self.removed)?;
+        state.serialize_field("modified", &self.modified)?;
+        state.serialize_field("summary", &self.summary())?;
+        serialize_unfiltered(&mut state, self.unfiltered)?;
+        state.end()
+    }
+}
+
+impl Serialize for GenerationDiff {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("GenerationDiff", 5)?;
+        state.serialize_field("added", &self.added)?;
+        state.serialize_field("removed", &self.removed)?;
+        state.serialize_field("modified", &self.modified)?;
+        state.serialize_field("summary", &self.summary())?;
+        serialize_unfiltered(&mut state, self.unfiltered)?;
+        state.end()
+    }
+}
+
+fn serialize_unfiltered<S: SerializeStruct>(
+    state: &mut S,
+    unfiltered: Option<DiffSummary>,
+) -> Result<(), S::Error> {
+    match unfiltered {
+        Some(summary) => state.serialize_field("unfiltered", &summary),
+        None => state.skip_field("unfiltered"),
+    }
+}
+
+// The shape both diffs serialize to, which their derived `Deserialize`
+// doesn't match; only its schema is used. A plain comment, so it doesn't
+// become the schema's description.
+#[derive(JsonSchema)]
+#[allow(dead_code)]
+struct SerializedDiff<T> {
+    added: Vec<T>,
+    removed: Vec<T>,
+    modified: Vec<T>,
+    summary: DiffSummary,
+    #[serde(default)]
+    unfiltered: Option<DiffSummary>,
+}
+
+impl JsonSchema for DetailedDiff {
+    fn schema_name() -> Cow<'static, str> {
+        "DetailedDiff".into()
+    }
+
+    fn json_schema(generator: &mut SchemaGenerator) -> Schema {
+        SerializedDiff::<PackageChange>::json_schema(generator)
+    }
+}
+
+impl JsonSchema for GenerationDiff {
+    fn schema_name() -> Cow<'static, str> {
+        "GenerationDiff".into()
+    }
+
+    fn json_schema(generator: &mut SchemaGenerator) -> Schema {
+        SerializedDiff::<String>::json_schema(generator)
+    }
+}
+
+impl GenerationDiff {
+    pub fn summary(&self) -> DiffSummary {
+        self.to_detailed().summary()
+    }
+
+    /// Like `DetailedDiff::without_version_only`. A modified path is a bump
+    /// when an added path of the same output has the same name and a
+    /// different version.
+    pub fn without_version_only(mut self) -> Self {
+        let bumps: Vec<(String, String)> = self
+            .modified
+            .iter()
+            .filter_map(|old| {
+                let old_path = StorePath::parse(old);
+                self.added
+                    .iter()
+                    .find(|new| {
+                        let new_path = StorePath::parse(new);
+                        new_path.name() == old_path.name()
+                            && new_path.output() == old_path.output()
+                            && is_version_bump(
+                                &old_path.version().map(str::to_string),
+                                &new_path.version().map(str::to_string),
+                            )
+                    })
+                    .map(|new| (old.clone(), new.clone()))
+            })
+            .collect();
+
+        for (old, new) in &bumps {
+            self.modified.retain(|path| path != old);
+            self.removed.retain(|path| path != old);
+            self.added.retain(|path| path != new);
+        }
+
+        self.suppressed += bumps.len();
+        self
+    }
+
+    /// Like `DetailedDiff::only`, keeping the old and new paths of each
+    /// matching modified package.
+    pub fn only(mut self, direction: ChangeDirection) -> Self {
+        let detailed = self.to_detailed();
+        let mut old_paths = HashSet::new();
+        let mut new_paths = HashSet::new();
+        for change in detailed
+            .modified
+            .iter()
+            .filter(|c| c.direction == direction)
+        {
+            old_paths.extend(change.old_paths.iter().cloned());
+            new_paths.extend(change.new_paths.iter().cloned());
+        }
+
+        self.modified.retain(|path| old_paths.contains(path));
+        self.removed.retain(|path| old_paths.contains(path));
+        self.added.retain(|path| new_paths.contains(path));
+        self
+    }
+
+    /// The package names of every entry, parsed from the store paths.
+    pub fn names_only(&self) -> NameDiff {
+        let names = |paths: &[String]| {
+            paths
+                .iter()
+                .map(|path| StorePath::parse(path).name().to_string())
+                .collect()
+        };
+        NameDiff::new(
+            names(&self.added),
+            names(&self.removed),
+            names(&self.modified),
+        )
+    }
+
+    /// Like `DetailedDiff::only_categories`.
+    pub fn only_categories(mut self, keep: &[DiffCategory]) -> Self {
+        clear_unless(keep, DiffCategory::Added, &mut self.added);
+        clear_unless(keep, DiffCategory::Removed, &mut self.removed);
+        clear_unless(keep, DiffCategory::Modified, &mut self.modified);
+        self
+    }
+
+    /// Parse every entry into a `PackageChange`.
+    ///
+    /// Modified paths are grouped by package name, so the outputs of one
+    /// package (`openssl-3.0.10` and `openssl-3.0.10-dev`) become a single
+    /// change. Its new paths and version are those of the added paths with
+    /// the same name, if there are any.
+    pub fn to_detailed(&self) -> DetailedDiff {
+        let added_paths: Vec<StorePath> = self.added.iter().map(|p| StorePath::parse(p)).collect();
+
+        let added = added_paths
+            .iter()
+            .map(|path| PackageChange {
+                name: path.name().to_string(),
+                old_version: None,
+                new_version: path.version().map(str::to_string),
+                direction: ChangeDirection::Unknown,
+                store_path: path.path().to_string(),
+                size_delta: None,
+                old_paths: Vec::new(),
+                new_paths: Vec::new(),
+                direct: None,
+            })
+            .collect();
+
+        let removed = self
+            .removed
+            .iter()
+            .map(|path| {
+                let path = StorePath::parse(path);
+                PackageChange {
+                    name: path.name().to_string(),
+                    old_version: path.version().map(str::to_string),
+                    new_version: None,
+                    direction: ChangeDirection::Unknown,
+                    store_path: path.path().to_string(),
+                    size_delta: None,
+                    old_paths: Vec::new(),
+                    new_paths: Vec::new(),
+                    direct: None,
+                }
+            })
+            .collect();
+
+        DetailedDiff {
+            added,
+            removed,
+            modified: group_modified(&self.modified, &added_paths),
+            suppressed: self.suppressed,
+            unfiltered: self.unfiltered,
+        }
+    }
+}
+
+fn group_modified(modified: &[String], added: &[StorePath]) -> Vec<PackageChange> {
+    let old_paths: Vec<StorePath> = modified.iter().map(|p| StorePath::parse(p)).collect();
+    let mut groups: Vec<Vec<&StorePath>> = Vec::new();
+    let mut index: HashMap<&str, usize> = HashMap::new();
+    for path in &old_paths {
+        let i = *index.entry(path.name()).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
+        groups[i].push(path);
+    }
+
+    let mut added_by_name: HashMap<&str, Vec<&StorePath>> = HashMap::new();
+    for path in added {
+        added_by_name.entry(path.name()).or_default().push(path);
+    }
+
+    groups
+        .into_iter()
+        .map(|old| {
+            let new = added_by_name
+                .get(old[0].name())
+                .cloned()
+                .unwrap_or_default();
+            let (old_version, new_version) = (versions(&old), versions(&new));
+            let store_path = main_path(&new).unwrap_or(main_path(&old).unwrap());
+            PackageChange {
+                name: old[0].name().to_string(),
+                direction: ChangeDirection::between(old_version.as_deref(), new_version.as_deref()),
+                old_version,
+                new_version,
+                store_path: store_path.path().to_string(),
+                size_delta: None,
+                old_paths: old.iter().map(|p| p.path().to_string()).collect(),
+                new_paths: new.iter().map(|p| p.path().to_string()).collect(),
+                direct: None,
+            }
+        })
+        .collect()
+}
+
+/// The distinct versions of `paths` without output suffixes,
+/// comma-separated when they differ.
+fn versions(paths: &[&StorePath]) -> Option<String> {
+    let mut versions: Vec<&str> = Vec::new();
+    for version in paths.iter().filter_map(|p| p.base_version()) {
+        if !versions.contains(&version) {
+            versions.push(version);
+        }
+    }
+    (!versions.is_empty()).then(|| versions.join(","))
+}
+
+/// The path of the default output, or else the first path.
+fn main_path<'a>(paths: &[&'a StorePath]) -> Option<&'a StorePath> {
+    paths
+        .iter()
+        .find(|p| p.output().is_none())
+        .or(paths.first())
+        .copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(name: &str) -> String {
+        format!("/nix/store/0c7r3sa2dmw7ns6dyxmzif2g9mqkcgxy-{}", name)
+    }
+
+    #[test]
+    fn test_summary_counts() {
+        let diff = GenerationDiff {
+            added: vec![
+                path("openssl-3.0.12"),
+                path("glibc-2.37"),
+                path("firefox-122.0"),
+            ],
+            removed: vec![path("openssl-3.0.10"), path("glibc-2.38"), path("vim-9.0")],
+            modified: vec![path("openssl-3.0.10"), path("glibc-2.38")],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            diff.summary(),
+            DiffSummary {
+                added: 3,
+                removed: 3,
+                modified: 2,
+                upgraded: 1,
+                downgraded: 1,
+                suppressed: 0,
+            }
+        );
+
+        let json = serde_json::to_value(&diff).unwrap();
+        assert_eq!(json["summary"]["upgraded"], 1);
+        assert_eq!(json["summary"]["downgraded"], 1);
+    }
+
+    #[test]
+    fn test_to_detailed_pairs_modified_versions() {
+        let old = "/nix/store/cccccccccccccccccccccccccccccccc-openssl-3.0.10".to_string();
+        let diff = GenerationDiff {
+            added: vec![
+                "/nix/store/dddddddddddddddddddddddddddddddd-openssl-3.0.12".to_string(),
+                "/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-firefox-122.0".to_string(),
+            ],
+            removed: vec![old.clone()],
+            modified: vec![old],
+            ..Default::default()
+        };
+
+        let new = diff.added[0].clone();
+        let detailed = diff.to_detailed();
+        assert_eq!(detailed.added[1].name, "firefox");
+        assert_eq!(detailed.added[1].new_version.as_deref(), Some("122.0"));
+        assert_eq!(detailed.removed[0].old_version.as_deref(), Some("3.0.10"));
+        assert_eq!(
+            detailed.modified,
+            vec![PackageChange {
+                name: "openssl".to_string(),
+                old_version: Some("3.0.10".to_string()),
+                new_version: Some("3.0.12".to_string()),
+                direction: ChangeDirection::Upgrade,
+                store_path: new.clone(),
+                size_delta: None,
+                old_paths: diff.modified.clone(),
+                new_paths: vec![new],
+                direct: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_to_detailed_groups_outputs() {
+        let diff = GenerationDiff {
+            added: vec![path("openssl-3.0.12-dev"), path("openssl-3.0.12")],
+            modified: vec![
+                path("openssl-3.0.10"),
+                path("man-db-2.12.0"),
+                path("openssl-3.0.10-dev"),
+            ],
+            ..Default::default()
+        };
+
+        let detailed = diff.to_detailed();
+        assert_eq!(detailed.modified.len(), 2);
+        let openssl = &detailed.modified[0];
+        assert_eq!(openssl.name, "openssl");
+        assert_eq!(openssl.old_version.as_deref(), Some("3.0.10"));
+        assert_eq!(openssl.new_version.as_deref(), Some("3.0.12"));
+        assert_eq!(openssl.direction, ChangeDirection::Upgrade);
+        assert_eq!(openssl.store_path, path("openssl-3.0.12"));
+        assert_eq!(
+            openssl.old_paths,
+            vec![path("openssl-3.0.10"), path("openssl-3.0.10-dev")]
+        );
+        assert_eq!(openssl.new_paths, diff.added);
+        assert_eq!(openssl.outputs(), vec!["out", "dev"]);
+
+        let man_db = &detailed.modified[1];
+        assert_eq!(man_db.name, "man-db");
+        assert_eq!(man_db.new_version, None);
+        assert_eq!(man_db.store_path, path("man-db-2.12.0"));
+        assert_eq!(diff.summary().modified, 2);
+
+        let upgrades = diff.only(ChangeDirection::Upgrade);
+        assert_eq!(upgrades.modified.len(), 2);
+        assert_eq!(upgrades.added.len(), 2);
+    }
+
+    #[test]
+    fn test_change_direction() {
+        let between = |old, new| ChangeDirection::between(old, new);
+        assert_eq!(
+            between(Some("3.0.10"), Some("3.0.12")),
+            ChangeDirection::Upgrade
+        );
+        assert_eq!(
+            between(Some("122.0"), Some("121.0.1")),
+            ChangeDirection::Downgrade
+        );
+        assert_eq!(between(Some("9.0"), Some("9.0")), ChangeDirection::Lateral);
+        assert_eq!(
+            between(Some("1.2.3"), Some("1.2.3-rc1")),
+            ChangeDirection::Unknown
+        );
+        assert_eq!(
+            between(Some("20240209"), Some("20240301")),
+            ChangeDirection::Unknown
+        );
+        assert_eq!(between(None, Some("1.0")), ChangeDirection::Unknown);
+        assert_eq!(
+            between(Some("2.3a"), Some("2.3b")),
+            ChangeDirection::Upgrade
+        );
+        assert_eq!(
+            between(Some("1.2.3.9"), Some("1.2.3.10")),
+            ChangeDirection::Upgrade
+        );
+        assert_eq!(
+            between(Some("9.0a"), Some("10.0a")),
+            ChangeDirection::Upgrade
+        );
+    }
+
+    #[test]
+    fn test_only_direction() {
+        let diff = || GenerationDiff {
+            added: vec![
+                path("openssl-3.0.12"),
+                path("glibc-2.37"),
+                path("firefox-122.0"),
+            ],
+            removed: vec![path("openssl-3.0.10"), path("glibc-2.38")],
+            modified: vec![path("openssl-3.0.10"), path("glibc-2.38")],
+            ..Default::default()
+        };
+
+        let upgrades = diff().only(ChangeDirection::Upgrade);
+        assert_eq!(upgrades.added, vec![path("openssl-3.0.12")]);
+        assert_eq!(upgrades.removed, vec![path("openssl-3.0.10")]);
+        assert_eq!(upgrades.modified, vec![path("openssl-3.0.10")]);
+
+        let downgrades = diff().to_detailed().only(ChangeDirection::Downgrade);
+        assert_eq!(downgrades.modified.len(), 1);
+        assert_eq!(downgrades.modified[0].name, "glibc");
+        let json = serde_json::to_value(&downgrades).unwrap();
+        assert_eq!(json["modified"][0]["direction"], "downgrade");
+    }
+
+    #[test]
+    fn test_names_only() {
+        let diff = GenerationDiff {
+            added: vec![
+                path("zstd-1.5.5"),
+                path("openssl-3.0.12"),
+                path("openssl-3.0.12-dev"),
+                path("glibc-2.39"),
+            ],
+            removed: vec![path("vim-9.0"), path("openssl-3.0.10"), path("glibc-2.38")],
+            modified: vec![path("openssl-3.0.10")],
+            ..Default::default()
+        };
+
+        let expected = NameDiff {
+            added: vec!["zstd".to_string()],
+            removed: vec!["vim".to_string()],
+            modified: vec!["glibc".to_string(), "openssl".to_string()],
+        };
+        assert_eq!(diff.names_only(), expected);
+        assert_eq!(diff.to_detailed().names_only(), expected);
+    }
+
+    #[test]
+    fn test_only_categories() {
+        let diff = GenerationDiff {
+            added: vec![path("firefox-122.0")],
+            removed: vec![path("vim-9.0")],
+            modified: vec![path("openssl-3.0.10")],
+            ..Default::default()
+        };
+        let json = serde_json::to_value(&diff).unwrap();
+        assert!(json.get("unfiltered").is_none());
+
+        let unfiltered = diff.summary();
+        let mut removals = diff.only_categories(&[DiffCategory::Removed, DiffCategory::Modified]);
+        removals.unfiltered = Some(unfiltered);
+        assert!(removals.added.is_empty());
+        assert_eq!(removals.removed, vec![path("vim-9.0")]);
+
+        let json = serde_json::to_value(&removals).unwrap();
+        assert_eq!(json["added"], serde_json::json!([]));
+        assert_eq!(json["summary"]["added"], 0);
+        assert_eq!(json["unfiltered"]["added"], 1);
+
+        let detailed = removals
+            .to_detailed()
+            .only_categories(&[DiffCategory::Modified]);
+        assert!(detailed.removed.is_empty());
+        assert_eq!(detailed.modified.len(), 1);
+        assert_eq!(detailed.unfiltered, Some(unfiltered));
+    }
+
+    #[test]
+    fn test_without_version_only() {
+        let rebuilt = "/nix/store/1c7r3sa2dmw7ns6dyxmzif2g9mqkcgxy-vim-9.0".to_string();
+        let diff = GenerationDiff {
+            added: vec![path("openssl-3.0.12"), path("firefox-122.0"), rebuilt],
+            removed: vec![path("openssl-3.0.10"), path("vim-9.0"), path("htop-3.3")],
+            modified: vec![path("openssl-3.0.10"), path("vim-9.0")],
+            ..Default::default()
+        };
+
+        let diff = diff.without_version_only();
+        assert_eq!(diff.modified, vec![path("vim-9.0")]);
+        assert_eq!(diff.removed, vec![path("vim-9.0"), path("htop-3.3")]);
+        assert_eq!(diff.added.len(), 2);
+        assert_eq!(diff.summary().suppressed, 1);
+        assert_eq!(diff.summary().modified, 1);
+
+        let detailed = diff.to_detailed().without_version_only();
+        assert_eq!(detailed.suppressed, 1);
+        assert_eq!(detailed.modified.len(), 1);
+    }
 }
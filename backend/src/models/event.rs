@@ -0,0 +1,15 @@
+use serde::{Deserialize, Serialize};
+
+use crate::models::generation::Generation;
+
+/// A change to a profile noticed between two polls of its generation list.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum WatchEvent {
+    /// A generation that wasn't there before, serialized with the
+    /// generation's own fields next to `event`.
+    NewGeneration(Generation),
+    /// The current pointer moved back to an older, already existing
+    /// generation.
+    Rollback { from: String, to: Generation },
+}
@@ -0,0 +1,33 @@
+use serde::{Deserialize, Serialize};
+
+/// Space that garbage collection is estimated to free once old generations
+/// are deleted.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct GcPreview {
+    pub generations: Vec<ReclaimableGeneration>,
+    /// Size of every path referenced only by deletable generations. This can
+    /// exceed the per-generation sum, since paths shared between two old
+    /// generations are unique to neither.
+    pub total_bytes: u64,
+    /// Old generations left out because their references couldn't be read.
+    #[serde(default)]
+    pub warnings: Vec<String>,
+}
+
+/// What `nix-collect-garbage` removed, from its closing summary.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct GcResult {
+    pub freed_bytes: u64,
+    pub deleted_paths: usize,
+    /// Nothing was run; the counts are zero.
+    pub dry_run: bool,
+    pub commands: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReclaimableGeneration {
+    pub id: String,
+    /// Number of store paths no other generation references.
+    pub paths: usize,
+    pub reclaimable_bytes: u64,
+}
@@ -1,11 +1,152 @@
 use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::Value;
+use std::cmp::Ordering;
 
-#[derive(Debug, Serialize, Deserialize)]
+use crate::models::timestamp;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
 pub struct Generation {
     pub id: String,
+    #[serde(serialize_with = "timestamp::serialize")]
+    #[schemars(schema_with = "timestamp::schema")]
     pub timestamp: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub description: Option<String>,
     pub profiles: Vec<String>,
     pub current: bool,
+    /// Whether this is the generation the machine was booted into.
+    pub booted: bool,
+    pub nixos_version: Option<String>,
+    pub kernel_version: Option<String>,
+    /// Total closure size in bytes; only computed on request.
+    pub size_bytes: Option<u64>,
+    /// The `--profile` this generation was read from, set by `timeline`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile_name: Option<String>,
+    /// The flake revision the generation was built from; only read on
+    /// request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flake: Option<FlakeInfo>,
+    /// The user's note on this generation, from `note set`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    /// How long ago the generation was built, e.g. `3 days ago`; only set
+    /// with `--show-age`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub age: Option<String>,
+}
+
+/// Where a flake-based generation came from, per its
+/// `configurationRevision`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct FlakeInfo {
+    /// The git revision, or `None` when only a dirty tree was recorded.
+    pub revision: Option<String>,
+    /// Whether the tree had uncommitted changes.
+    pub dirty: bool,
+}
+
+impl Generation {
+    /// The keys `list-generations --field` can pick, in output order.
+    pub const FIELDS: &'static [&'static str] = &[
+        "id",
+        "timestamp",
+        "description",
+        "profiles",
+        "current",
+        "booted",
+        "nixos_version",
+        "kernel_version",
+        "size_bytes",
+        "profile_name",
+        "flake",
+        "note",
+        "age",
+    ];
+
+    /// Just `fields` of this generation, in the order given. Fields the full
+    /// output would leave out, like an unset `note`, are left out here too.
+    pub fn project(&self, fields: &[&str]) -> ProjectedGeneration {
+        let mut all = match serde_json::to_value(self) {
+            Ok(Value::Object(map)) => map,
+            _ => Default::default(),
+        };
+        ProjectedGeneration(
+            fields
+                .iter()
+                .map(|field| (field.to_string(), all.remove(*field)))
+                .collect(),
+        )
+    }
+}
+
+/// A generation cut down to the fields asked for with `--field`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProjectedGeneration(pub Vec<(String, Option<Value>)>);
+
+impl Serialize for ProjectedGeneration {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        for (field, value) in &self.0 {
+            if let Some(value) = value {
+                map.serialize_entry(field, value)?;
+            }
+        }
+        map.end()
+    }
+}
+
+/// Order generation ids numerically, so "10" sorts after "9". Non-numeric
+/// ids sort after numeric ones, lexically.
+pub fn compare_generation_ids(a: &str, b: &str) -> Ordering {
+    match (a.parse::<u64>(), b.parse::<u64>()) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        (Ok(_), Err(_)) => Ordering::Less,
+        (Err(_), Ok(_)) => Ordering::Greater,
+        (Err(_), Err(_)) => a.cmp(b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare_generation_ids() {
+        assert_eq!(compare_generation_ids("9", "10"), Ordering::Less);
+        assert_eq!(compare_generation_ids("10", "10"), Ordering::Equal);
+        assert_eq!(compare_generation_ids("abc", "2"), Ordering::Greater);
+    }
+
+    #[test]
+    fn test_fields_match_schema() {
+        let schema = schemars::schema_for!(Generation);
+        let properties: Vec<&str> = schema
+            .get("properties")
+            .and_then(Value::as_object)
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        let mut fields = Generation::FIELDS.to_vec();
+        fields.sort_unstable();
+        assert_eq!(fields, properties);
+    }
+
+    #[test]
+    fn test_project() {
+        let generation = Generation {
+            id: "42".to_string(),
+            current: true,
+            ..Default::default()
+        };
+        let projected = generation.project(&["current", "note", "id"]);
+        assert_eq!(
+            serde_json::to_string(&projected).unwrap(),
+            r#"{"current":true,"id":"42"}"#
+        );
+    }
 }
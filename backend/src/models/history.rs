@@ -0,0 +1,15 @@
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// What a `snapshot` recorded into the history database.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub database: PathBuf,
+    pub profile: String,
+    /// Generations recorded by this snapshot.
+    pub generations: usize,
+    /// Of those, the ones the database hadn't seen before.
+    pub new_generations: usize,
+    /// Distinct store paths in the database across all snapshots.
+    pub store_paths: usize,
+}
@@ -1,2 +1,22 @@
+pub mod deletion;
+pub mod derivation;
 pub mod diff;
+pub mod event;
+pub mod gc;
 pub mod generation;
+pub mod history;
+pub mod note;
+pub mod package;
+pub mod page;
+pub mod partial;
+pub mod rollback;
+pub mod size;
+pub mod stats;
+pub mod store_path;
+pub mod timestamp;
+pub mod vulnerability;
+
+/// For `skip_serializing_if` on counts that are usually zero.
+pub(crate) fn is_zero(n: &usize) -> bool {
+    *n == 0
+}
@@ -0,0 +1,9 @@
+use serde::{Deserialize, Serialize};
+
+/// The note on one generation, as printed by `note get/set/clear`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GenerationNote {
+    pub profile: String,
+    pub generation: String,
+    pub note: Option<String>,
+}
@@ -0,0 +1,42 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::models::diff::ChangeDirection;
+use crate::models::timestamp;
+
+/// The oldest generation whose closure contains a package, as found by
+/// `find-package`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PackageIntroduction {
+    pub package: String,
+    pub version: Option<String>,
+    pub generation: String,
+    #[serde(serialize_with = "timestamp::serialize")]
+    pub timestamp: DateTime<Utc>,
+    pub store_path: String,
+}
+
+/// How often a package appeared in or disappeared from the direct
+/// references of consecutive generations, as reported by `churn`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PackageChurn {
+    pub package: String,
+    /// `added + removed`.
+    pub changes: usize,
+    pub added: usize,
+    pub removed: usize,
+}
+
+/// A generation where a package's version differs from the generation
+/// before it, as reported by `bisect`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PackageTransition {
+    pub generation: String,
+    pub previous_generation: String,
+    pub package: String,
+    /// The package's versions before the change, comma-separated when the
+    /// closure holds several; `None` when the package was absent.
+    pub old_version: Option<String>,
+    pub new_version: Option<String>,
+    pub direction: ChangeDirection,
+}
@@ -0,0 +1,30 @@
+use serde::{Deserialize, Serialize};
+
+/// One page of a list, as served by `GET /generations?limit=&offset=`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Length of the whole list.
+    pub total: usize,
+    pub offset: usize,
+}
+
+impl<T> Page<T> {
+    pub fn new(items: Vec<T>, offset: usize, limit: Option<usize>) -> Self {
+        let total = items.len();
+        Self {
+            items: paginate(items, offset, limit),
+            total,
+            offset,
+        }
+    }
+}
+
+/// Apply `--offset`/`--limit` to an already sorted list.
+pub fn paginate<T>(items: Vec<T>, offset: usize, limit: Option<usize>) -> Vec<T> {
+    items
+        .into_iter()
+        .skip(offset)
+        .take(limit.unwrap_or(usize::MAX))
+        .collect()
+}
@@ -0,0 +1,11 @@
+use serde::{Deserialize, Serialize};
+
+/// The results of a command that reads many generations, with a warning for
+/// each it had to leave out because it couldn't be read, e.g. one that was
+/// deleted or partially garbage collected in the meantime.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Partial<T> {
+    pub results: T,
+    #[serde(default)]
+    pub warnings: Vec<String>,
+}
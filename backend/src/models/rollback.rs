@@ -0,0 +1,8 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RollbackPlan {
+    pub generation: String,
+    pub dry_run: bool,
+    pub commands: Vec<String>,
+}
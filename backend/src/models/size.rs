@@ -0,0 +1,41 @@
+use serde::{Deserialize, Serialize};
+
+use crate::models::is_zero;
+
+/// Net closure size change between two generations, from `size-diff`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SizeDiff {
+    pub from: String,
+    pub to: String,
+    pub from_bytes: u64,
+    pub to_bytes: u64,
+    pub delta_bytes: i64,
+    /// Per-package breakdown of the changed references, when requested,
+    /// largest change first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub packages: Vec<PackageSize>,
+    /// Packages left out of `packages` by `without_smaller_than`. They are
+    /// still part of `delta_bytes`.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub hidden_packages: usize,
+}
+
+impl SizeDiff {
+    /// Drop packages whose size changed by less than `min_bytes` either way.
+    pub fn without_smaller_than(mut self, min_bytes: u64) -> Self {
+        let before = self.packages.len();
+        self.packages
+            .retain(|package| package.delta_bytes.unsigned_abs() >= min_bytes);
+        self.hidden_packages += before - self.packages.len();
+        self
+    }
+}
+
+/// Combined size of a package's changed store paths on either side.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PackageSize {
+    pub name: String,
+    pub old_bytes: u64,
+    pub new_bytes: u64,
+    pub delta_bytes: i64,
+}
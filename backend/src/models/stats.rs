@@ -0,0 +1,118 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::models::generation::Generation;
+use crate::models::timestamp;
+
+/// An overview of a profile's generation history, from `stats`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GenerationStats {
+    pub count: usize,
+    #[serde(serialize_with = "timestamp::serialize_option")]
+    pub first: Option<DateTime<Utc>>,
+    #[serde(serialize_with = "timestamp::serialize_option")]
+    pub last: Option<DateTime<Utc>>,
+    /// Mean time between consecutive generations, in seconds.
+    pub average_interval_secs: Option<f64>,
+    pub busiest_day: Option<BusiestDay>,
+    /// Closure sizes, only with `--with-sizes`. Generations whose size
+    /// couldn't be read are left out of both.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_size_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub average_size_bytes: Option<u64>,
+}
+
+/// The (UTC) day with the most generations; the earliest such day on ties.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BusiestDay {
+    pub date: NaiveDate,
+    pub generations: usize,
+}
+
+impl GenerationStats {
+    pub fn from_generations(generations: &[Generation]) -> Self {
+        let mut timestamps: Vec<DateTime<Utc>> = generations.iter().map(|g| g.timestamp).collect();
+        timestamps.sort();
+        let (first, last) = (timestamps.first().copied(), timestamps.last().copied());
+
+        let average_interval_secs = match (first, last) {
+            (Some(first), Some(last)) if timestamps.len() > 1 => {
+                Some((last - first).num_seconds() as f64 / (timestamps.len() - 1) as f64)
+            }
+            _ => None,
+        };
+
+        let mut days: BTreeMap<NaiveDate, usize> = BTreeMap::new();
+        for timestamp in &timestamps {
+            *days.entry(timestamp.date_naive()).or_default() += 1;
+        }
+        // `max_by_key` keeps the last maximum, so walk the days newest first.
+        let busiest_day = days
+            .into_iter()
+            .rev()
+            .max_by_key(|&(_, count)| count)
+            .map(|(date, generations)| BusiestDay { date, generations });
+
+        let sizes: Vec<u64> = generations.iter().filter_map(|g| g.size_bytes).collect();
+        let total_size_bytes = (!sizes.is_empty()).then(|| sizes.iter().sum::<u64>());
+
+        Self {
+            count: generations.len(),
+            first,
+            last,
+            average_interval_secs,
+            busiest_day,
+            total_size_bytes,
+            average_size_bytes: total_size_bytes.map(|total| total / sizes.len() as u64),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn generation(day: u32, hour: u32, size_bytes: Option<u64>) -> Generation {
+        Generation {
+            timestamp: Utc.with_ymd_and_hms(2024, 2, day, hour, 0, 0).unwrap(),
+            size_bytes,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_generation_stats() {
+        let stats = GenerationStats::from_generations(&[
+            generation(12, 8, None),
+            generation(9, 10, Some(300)),
+            generation(10, 9, Some(100)),
+            generation(10, 18, None),
+            generation(12, 20, None),
+        ]);
+
+        assert_eq!(stats.count, 5);
+        assert_eq!(
+            stats.first,
+            Some(Utc.with_ymd_and_hms(2024, 2, 9, 10, 0, 0).unwrap())
+        );
+        // 82 hours over four gaps.
+        assert_eq!(stats.average_interval_secs, Some(82.0 * 3600.0 / 4.0));
+        assert_eq!(
+            stats.busiest_day,
+            Some(BusiestDay {
+                date: NaiveDate::from_ymd_opt(2024, 2, 10).unwrap(),
+                generations: 2,
+            })
+        );
+        assert_eq!(stats.total_size_bytes, Some(400));
+        assert_eq!(stats.average_size_bytes, Some(200));
+
+        assert_eq!(
+            GenerationStats::from_generations(&[]),
+            GenerationStats::default()
+        );
+    }
+}
@@ -0,0 +1,217 @@
+use std::cmp::Ordering;
+
+/// Length of the base32 hash prefix in a store path basename.
+const HASH_LEN: usize = 32;
+
+/// Outputs nixpkgs commonly splits packages into. Paths of any output but
+/// the default `out` end in its name, e.g. `openssl-3.0.12-dev`.
+const OUTPUTS: &[&str] = &[
+    "bin", "debug", "dev", "devdoc", "doc", "info", "lib", "man", "static",
+];
+
+/// A `/nix/store/<hash>-<name>-<version>` path split into its components.
+///
+/// Parsing is lenient: a basename without a hash prefix (as printed by
+/// `nix-diff`, for example) yields `hash() == None`, and a name without a
+/// version yields `version() == None`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct StorePath {
+    path: String,
+    hash: Option<String>,
+    name: String,
+    version: Option<String>,
+}
+
+impl StorePath {
+    pub fn parse(path: &str) -> Self {
+        let path = path.trim();
+        let basename = path.rsplit('/').next().unwrap_or(path);
+
+        let (hash, rest) = match basename.split_at_checked(HASH_LEN) {
+            Some((hash, rest))
+                if rest.starts_with('-') && hash.chars().all(|c| c.is_ascii_alphanumeric()) =>
+            {
+                (Some(hash.to_string()), &rest[1..])
+            }
+            _ => (None, basename),
+        };
+
+        // The version starts at the first dash that is followed by a digit,
+        // so names like `python3.11-requests` keep their inner dashes.
+        let split = rest
+            .char_indices()
+            .find(|&(i, c)| c == '-' && rest[i + 1..].starts_with(|c: char| c.is_ascii_digit()));
+        let (name, version) = match split {
+            Some((i, _)) => (&rest[..i], Some(rest[i + 1..].to_string())),
+            None => (rest, None),
+        };
+
+        Self {
+            path: path.to_string(),
+            hash,
+            name: name.to_string(),
+            version,
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn hash(&self) -> Option<&str> {
+        self.hash.as_deref()
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+
+    /// The output suffix on the version, or `None` for the default output.
+    pub fn output(&self) -> Option<&'static str> {
+        let version = self.version.as_deref()?;
+        OUTPUTS.iter().copied().find(|output| {
+            version
+                .strip_suffix(output)
+                .is_some_and(|rest| rest.ends_with('-'))
+        })
+    }
+
+    /// `version()` without its output suffix.
+    pub fn base_version(&self) -> Option<&str> {
+        let version = self.version.as_deref()?;
+        match self.output() {
+            Some(output) => Some(&version[..version.len() - output.len() - 1]),
+            None => Some(version),
+        }
+    }
+}
+
+/// Compare two versions the way Nix's `builtins.compareVersions` does:
+/// components split on `.`/`-` and digit/letter boundaries, numbers compared
+/// numerically, and `pre` sorting before everything else.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let a = version_components(a);
+    let b = version_components(b);
+
+    for i in 0..a.len().max(b.len()) {
+        let c1 = a.get(i).copied().unwrap_or("");
+        let c2 = b.get(i).copied().unwrap_or("");
+        if component_lt(c1, c2) {
+            return Ordering::Less;
+        }
+        if component_lt(c2, c1) {
+            return Ordering::Greater;
+        }
+    }
+
+    Ordering::Equal
+}
+
+fn version_components(version: &str) -> Vec<&str> {
+    let mut components = Vec::new();
+    for part in version.split(['.', '-']).filter(|p| !p.is_empty()) {
+        let mut start = 0;
+        let bytes = part.as_bytes();
+        for i in 1..=bytes.len() {
+            if i == bytes.len() || bytes[i].is_ascii_digit() != bytes[i - 1].is_ascii_digit() {
+                components.push(&part[start..i]);
+                start = i;
+            }
+        }
+    }
+    components
+}
+
+fn component_lt(c1: &str, c2: &str) -> bool {
+    let n1 = c1.parse::<u64>().ok();
+    let n2 = c2.parse::<u64>().ok();
+
+    match (n1, n2) {
+        (Some(n1), Some(n2)) => n1 < n2,
+        _ if c1.is_empty() && n2.is_some() => true,
+        _ if c1 == "pre" && c2 != "pre" => true,
+        _ if c2 == "pre" => false,
+        (Some(_), None) => false,
+        (None, Some(_)) => true,
+        _ => c1 < c2,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "0c7r3sa2dmw7ns6dyxmzif2g9mqkcgxy";
+
+    #[test]
+    fn test_parse_store_paths() {
+        let cases = [
+            ("gcc-wrapper-12.3.0", "gcc-wrapper", Some("12.3.0")),
+            ("libstdc++-12.3.0", "libstdc++", Some("12.3.0")),
+            (
+                "python3.11-requests-2.31.0",
+                "python3.11-requests",
+                Some("2.31.0"),
+            ),
+            ("openssl-3.0.12-dev", "openssl", Some("3.0.12-dev")),
+            ("etc", "etc", None),
+            (
+                "unit-script-nix-daemon-start",
+                "unit-script-nix-daemon-start",
+                None,
+            ),
+            (
+                "nixos-system-host-24.05.20240209.abc",
+                "nixos-system-host",
+                Some("24.05.20240209.abc"),
+            ),
+        ];
+
+        for (basename, name, version) in cases {
+            let path = StorePath::parse(&format!("/nix/store/{}-{}", HASH, basename));
+            assert_eq!(path.hash(), Some(HASH), "{}", basename);
+            assert_eq!(path.name(), name, "{}", basename);
+            assert_eq!(path.version(), version, "{}", basename);
+        }
+    }
+
+    #[test]
+    fn test_compare_versions() {
+        assert_eq!(compare_versions("3.0.10", "3.0.12"), Ordering::Less);
+        assert_eq!(compare_versions("3.0.12", "3.0.9"), Ordering::Greater);
+        assert_eq!(compare_versions("1.0", "1.0.1"), Ordering::Less);
+        assert_eq!(compare_versions("2.3pre1", "2.3"), Ordering::Less);
+        assert_eq!(compare_versions("2.3a", "2.3.1"), Ordering::Less);
+        assert_eq!(compare_versions("122.0", "122.0"), Ordering::Equal);
+    }
+
+    #[test]
+    fn test_outputs() {
+        let dev = StorePath::parse(&format!("/nix/store/{}-openssl-3.0.12-dev", HASH));
+        assert_eq!(dev.output(), Some("dev"));
+        assert_eq!(dev.base_version(), Some("3.0.12"));
+
+        let out = StorePath::parse(&format!("/nix/store/{}-openssl-3.0.12", HASH));
+        assert_eq!(out.output(), None);
+        assert_eq!(out.base_version(), Some("3.0.12"));
+
+        assert_eq!(
+            StorePath::parse("util-linux-2.39-lib").output(),
+            Some("lib")
+        );
+        assert_eq!(StorePath::parse("zlib-1.3").output(), None);
+        assert_eq!(StorePath::parse("etc").base_version(), None);
+    }
+
+    #[test]
+    fn test_parse_without_hash() {
+        let path = StorePath::parse("firefox-122.0");
+        assert_eq!(path.hash(), None);
+        assert_eq!(path.name(), "firefox");
+        assert_eq!(path.version(), Some("122.0"));
+    }
+}
@@ -0,0 +1,140 @@
+use chrono::{DateTime, Local, TimeZone, Utc};
+use serde::Serializer;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+/// The timezone timestamps are serialized in, chosen with `--timezone`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Timezone {
+    #[default]
+    Utc,
+    Local,
+}
+
+/// How timestamps are serialized, chosen with `--timestamp-format`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TimestampFormat {
+    /// An RFC3339 string in the chosen timezone.
+    #[default]
+    Rfc3339,
+    /// Integer seconds since the unix epoch.
+    Epoch,
+    /// Integer milliseconds since the unix epoch.
+    EpochMillis,
+}
+
+static LOCAL: AtomicBool = AtomicBool::new(false);
+static FORMAT: AtomicU8 = AtomicU8::new(TimestampFormat::Rfc3339 as u8);
+
+/// Serialize timestamps in `timezone` from now on. `serialize_with` can't be
+/// handed any state, so this is process-wide and set once by the binary.
+pub fn set_timezone(timezone: Timezone) {
+    LOCAL.store(timezone == Timezone::Local, Ordering::Relaxed);
+}
+
+pub fn timezone() -> Timezone {
+    if LOCAL.load(Ordering::Relaxed) {
+        Timezone::Local
+    } else {
+        Timezone::Utc
+    }
+}
+
+/// Serialize timestamps as `format` from now on; process-wide like
+/// `set_timezone`.
+pub fn set_timestamp_format(format: TimestampFormat) {
+    FORMAT.store(format as u8, Ordering::Relaxed);
+}
+
+pub fn timestamp_format() -> TimestampFormat {
+    match FORMAT.load(Ordering::Relaxed) {
+        f if f == TimestampFormat::Epoch as u8 => TimestampFormat::Epoch,
+        f if f == TimestampFormat::EpochMillis as u8 => TimestampFormat::EpochMillis,
+        _ => TimestampFormat::Rfc3339,
+    }
+}
+
+/// RFC3339 in the timezone chosen with `set_timezone`.
+pub fn to_rfc3339(timestamp: &DateTime<Utc>) -> String {
+    match timezone() {
+        Timezone::Utc => rfc3339_in(timestamp, &Utc),
+        Timezone::Local => rfc3339_in(timestamp, &Local),
+    }
+}
+
+fn rfc3339_in<Tz: TimeZone>(timestamp: &DateTime<Utc>, tz: &Tz) -> String
+where
+    Tz::Offset: Display,
+{
+    timestamp.with_timezone(tz).to_rfc3339()
+}
+
+/// `serialize_with` for timestamps, honoring `set_timezone` and
+/// `set_timestamp_format`.
+pub fn serialize<S: Serializer>(
+    timestamp: &DateTime<Utc>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    match epoch(timestamp, timestamp_format()) {
+        Some(epoch) => serializer.serialize_i64(epoch),
+        None => serializer.serialize_str(&to_rfc3339(timestamp)),
+    }
+}
+
+/// JSON Schema for `serialize`: an RFC3339 string, or an integer under the
+/// epoch formats.
+pub fn schema(_: &mut schemars::SchemaGenerator) -> schemars::Schema {
+    schemars::json_schema!({
+        "oneOf": [
+            { "type": "string", "format": "date-time" },
+            { "type": "integer" }
+        ]
+    })
+}
+
+/// `timestamp` as an integer under the epoch formats.
+fn epoch(timestamp: &DateTime<Utc>, format: TimestampFormat) -> Option<i64> {
+    match format {
+        TimestampFormat::Rfc3339 => None,
+        TimestampFormat::Epoch => Some(timestamp.timestamp()),
+        TimestampFormat::EpochMillis => Some(timestamp.timestamp_millis()),
+    }
+}
+
+/// `serialize` for optional timestamps.
+pub fn serialize_option<S: Serializer>(
+    timestamp: &Option<DateTime<Utc>>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    match timestamp {
+        Some(timestamp) => serialize(timestamp, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::FixedOffset;
+
+    #[test]
+    fn test_rfc3339_in_fixed_offset() {
+        let timestamp = Utc.with_ymd_and_hms(2024, 2, 10, 9, 30, 0).unwrap();
+        let offset = FixedOffset::east_opt(2 * 3600).unwrap();
+
+        assert_eq!(rfc3339_in(&timestamp, &Utc), "2024-02-10T09:30:00+00:00");
+        assert_eq!(rfc3339_in(&timestamp, &offset), "2024-02-10T11:30:00+02:00");
+    }
+
+    #[test]
+    fn test_epoch_formats() {
+        let timestamp = Utc.with_ymd_and_hms(2024, 2, 10, 9, 30, 0).unwrap();
+
+        assert_eq!(epoch(&timestamp, TimestampFormat::Rfc3339), None);
+        assert_eq!(epoch(&timestamp, TimestampFormat::Epoch), Some(1707557400));
+        assert_eq!(
+            epoch(&timestamp, TimestampFormat::EpochMillis),
+            Some(1707557400000)
+        );
+    }
+}
@@ -0,0 +1,27 @@
+use serde::{Deserialize, Serialize};
+
+/// A CVE reported by `vulnix` against a package in a generation's closure.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Vulnerability {
+    pub cve: String,
+    pub package: String,
+    /// CVSS v3 base score, when vulnix knows one.
+    pub severity: Option<f32>,
+}
+
+/// The vulnerabilities found in one generation by `scan`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GenerationScan {
+    pub generation: String,
+    pub store_path: String,
+    pub vulnerabilities: Vec<Vulnerability>,
+}
+
+/// CVEs that appear or disappear between two generations, by `scan-diff`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ScanDiff {
+    pub from: String,
+    pub to: String,
+    pub introduced: Vec<Vulnerability>,
+    pub resolved: Vec<Vulnerability>,
+}
@@ -0,0 +1,1147 @@
+use chrono::{DateTime, Duration, Local, Utc};
+use owo_colors::OwoColorize;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{IsTerminal, Write};
+use std::path::Path;
+
+use crate::error::{Error, Result};
+use crate::models::deletion::DeletionPlan;
+use crate::models::derivation::DerivationChange;
+use crate::models::diff::{
+    ChangeDirection, CommonReferences, DetailedDiff, GenerationDiff, NameDiff, PackageChange,
+};
+use crate::models::gc::{GcPreview, GcResult};
+use crate::models::generation::{Generation, ProjectedGeneration};
+use crate::models::history::Snapshot;
+use crate::models::note::GenerationNote;
+use crate::models::package::{PackageChurn, PackageIntroduction, PackageTransition};
+use crate::models::partial::Partial;
+use crate::models::rollback::RollbackPlan;
+use crate::models::size::SizeDiff;
+use crate::models::stats::GenerationStats;
+use crate::models::vulnerability::{GenerationScan, ScanDiff, Vulnerability};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Compact JSON on a single line
+    #[default]
+    Json,
+    /// Indented JSON
+    JsonPretty,
+    Yaml,
+    /// Column-aligned text for the terminal
+    Table,
+    /// Markdown sections for pasting into PRs; diffs only
+    Markdown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ColorChoice {
+    /// Color when stdout is a terminal and `NO_COLOR` is unset
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    pub fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+                    && std::io::stdout().is_terminal()
+            }
+        }
+    }
+}
+
+/// How a failing command reports its error on stderr.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ErrorFormat {
+    /// `Error: ...`
+    #[default]
+    Text,
+    /// `{"error": {"kind": "...", "message": "..."}}` on a single line
+    Json,
+}
+
+/// `err` as written to stderr, ending with a newline.
+pub fn render_error(err: &Error, format: ErrorFormat) -> String {
+    match format {
+        ErrorFormat::Text => format!("Error: {:?}\n", err),
+        ErrorFormat::Json => {
+            let body = serde_json::json!({
+                "error": { "kind": err.kind(), "message": err.to_string() }
+            });
+            format!("{}\n", body)
+        }
+    }
+}
+
+/// Serialize `output` in the requested format. The result always ends with
+/// a newline.
+pub fn render<T: Serialize + Table + ?Sized>(output: &T, format: OutputFormat) -> Result<String> {
+    render_colored(output, format, false)
+}
+
+/// Like `render`, with ANSI colors in table output when `color` is set.
+/// JSON and YAML are never colored.
+pub fn render_colored<T: Serialize + Table + ?Sized>(
+    output: &T,
+    format: OutputFormat,
+    color: bool,
+) -> Result<String> {
+    let rendered = match format {
+        OutputFormat::Json => serde_json::to_string(output).map(|s| s + "\n"),
+        OutputFormat::JsonPretty => serde_json::to_string_pretty(output).map(|s| s + "\n"),
+        OutputFormat::Yaml => {
+            return serde_yaml::to_string(output).map_err(|e| Error::ParseError(e.to_string()))
+        }
+        OutputFormat::Table if color => return Ok(output.to_colored_table()),
+        OutputFormat::Table => return Ok(output.to_table()),
+        OutputFormat::Markdown => {
+            return output.to_markdown().ok_or_else(|| {
+                Error::InvalidArgument("markdown output is only available for diffs".to_string())
+            })
+        }
+    };
+
+    rendered.map_err(|e| Error::ParseError(e.to_string()))
+}
+
+/// Create or truncate `path` for writing results, creating any missing
+/// parent directories.
+pub fn create_output_file(path: &Path) -> Result<File> {
+    let failed = |source| Error::OutputWriteFailed {
+        path: path.to_path_buf(),
+        source,
+    };
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent).map_err(failed)?;
+    }
+    File::create(path).map_err(failed)
+}
+
+/// Replace the contents of `path` with `contents`.
+pub fn write_output(path: &Path, contents: &str) -> Result<()> {
+    create_output_file(path)?
+        .write_all(contents.as_bytes())
+        .map_err(|source| Error::OutputWriteFailed {
+            path: path.to_path_buf(),
+            source,
+        })
+}
+
+/// Human-readable, column-aligned rendering for terminal output.
+pub trait Table {
+    fn to_table(&self) -> String;
+
+    /// `to_table` with ANSI colors, for types that have any.
+    fn to_colored_table(&self) -> String {
+        self.to_table()
+    }
+
+    /// Markdown rendering, for the types that support `--format markdown`.
+    fn to_markdown(&self) -> Option<String> {
+        None
+    }
+}
+
+impl Table for [Generation] {
+    fn to_table(&self) -> String {
+        // Only timelines mix profiles, so only they get a PROFILE column.
+        let with_profile = self.iter().any(|g| g.profile_name.is_some());
+        let with_note = self.iter().any(|g| g.note.is_some());
+        let with_age = self.iter().any(|g| g.age.is_some());
+        let with_description = self.iter().any(|g| g.description.is_some());
+        let rows: Vec<Vec<String>> = self
+            .iter()
+            .map(|g| {
+                let profile = g.profile_name.clone().unwrap_or_default();
+                let mut row = vec![g.id.clone(), format_local(&g.timestamp)];
+                if with_age {
+                    row.push(g.age.clone().unwrap_or_default());
+                }
+                row.extend([
+                    if g.current { "*" } else { "" }.to_string(),
+                    if g.booted { "*" } else { "" }.to_string(),
+                ]);
+                if with_description {
+                    row.push(g.description.clone().unwrap_or_default());
+                }
+                let row = if with_profile {
+                    [vec![profile], row].concat()
+                } else {
+                    row
+                };
+                if with_note {
+                    [row, vec![g.note.clone().unwrap_or_default()]].concat()
+                } else {
+                    row
+                }
+            })
+            .collect();
+
+        let mut headers = vec![
+            "PROFILE",
+            "ID",
+            "DATE",
+            "AGE",
+            "CURRENT",
+            "BOOTED",
+            "DESCRIPTION",
+        ];
+        if !with_description {
+            headers.remove(6);
+        }
+        if !with_age {
+            headers.remove(3);
+        }
+        if with_note {
+            headers.push("NOTE");
+        }
+        render_columns(&headers[usize::from(!with_profile)..], &rows)
+    }
+}
+
+impl Table for Generation {
+    fn to_table(&self) -> String {
+        std::slice::from_ref(self).to_table()
+    }
+}
+
+impl Table for Vec<Generation> {
+    fn to_table(&self) -> String {
+        self.as_slice().to_table()
+    }
+}
+
+/// One column per `--field`, headed by its upper-cased name. Flags show as
+/// `*` like the full table's CURRENT and BOOTED.
+impl Table for [ProjectedGeneration] {
+    fn to_table(&self) -> String {
+        let Some(first) = self.first() else {
+            return String::new();
+        };
+        let headers: Vec<String> = first.0.iter().map(|(f, _)| f.to_uppercase()).collect();
+        let rows: Vec<Vec<String>> = self
+            .iter()
+            .map(|g| {
+                g.0.iter()
+                    .map(|(_, value)| match value {
+                        None | Some(serde_json::Value::Null) => String::new(),
+                        Some(serde_json::Value::Bool(set)) => {
+                            if *set { "*" } else { "" }.to_string()
+                        }
+                        Some(serde_json::Value::String(s)) => s.clone(),
+                        Some(value) => value.to_string(),
+                    })
+                    .collect()
+            })
+            .collect();
+        let headers: Vec<&str> = headers.iter().map(String::as_str).collect();
+        render_columns(&headers, &rows)
+    }
+}
+
+impl Table for Vec<ProjectedGeneration> {
+    fn to_table(&self) -> String {
+        self.as_slice().to_table()
+    }
+}
+
+impl Table for GenerationDiff {
+    fn to_table(&self) -> String {
+        render_diff(
+            [&self.added, &self.removed, &self.modified],
+            self.suppressed,
+            false,
+        )
+    }
+
+    fn to_colored_table(&self) -> String {
+        render_diff(
+            [&self.added, &self.removed, &self.modified],
+            self.suppressed,
+            true,
+        )
+    }
+
+    fn to_markdown(&self) -> Option<String> {
+        self.to_detailed().to_markdown()
+    }
+}
+
+impl Table for NameDiff {
+    fn to_table(&self) -> String {
+        render_names(self, false)
+    }
+
+    fn to_colored_table(&self) -> String {
+        render_names(self, true)
+    }
+}
+
+impl DetailedDiff {
+    fn described(&self) -> [Vec<String>; 3] {
+        let describe = |changes: &[PackageChange]| -> Vec<String> {
+            changes.iter().map(describe_change).collect()
+        };
+        [
+            describe(&self.added),
+            describe(&self.removed),
+            describe(&self.modified),
+        ]
+    }
+}
+
+impl Table for DetailedDiff {
+    fn to_table(&self) -> String {
+        let [added, removed, modified] = self.described();
+        render_diff([&added, &removed, &modified], self.suppressed, false)
+    }
+
+    fn to_colored_table(&self) -> String {
+        let [added, removed, modified] = self.described();
+        render_diff([&added, &removed, &modified], self.suppressed, true)
+    }
+
+    /// Packages sorted by name under `### Added`, `### Removed`,
+    /// `### Upgraded`, `### Downgraded` and `### Changed`, leaving out empty
+    /// sections. Added and removed entries of a modified package are part
+    /// of its modification rather than listed separately.
+    fn to_markdown(&self) -> Option<String> {
+        let modified: HashSet<&str> = self.modified.iter().map(|c| c.name.as_str()).collect();
+        let standalone = |changes: &[PackageChange]| -> Vec<PackageChange> {
+            changes
+                .iter()
+                .filter(|c| !modified.contains(c.name.as_str()))
+                .cloned()
+                .collect()
+        };
+        let with_direction = |keep: fn(ChangeDirection) -> bool| -> Vec<PackageChange> {
+            self.modified
+                .iter()
+                .filter(|c| keep(c.direction))
+                .cloned()
+                .collect()
+        };
+
+        let sections = [
+            ("Added", standalone(&self.added)),
+            ("Removed", standalone(&self.removed)),
+            (
+                "Upgraded",
+                with_direction(|d| d == ChangeDirection::Upgrade),
+            ),
+            (
+                "Downgraded",
+                with_direction(|d| d == ChangeDirection::Downgrade),
+            ),
+            (
+                "Changed",
+                with_direction(|d| {
+                    !matches!(d, ChangeDirection::Upgrade | ChangeDirection::Downgrade)
+                }),
+            ),
+        ];
+
+        let mut out = String::new();
+        for (title, mut changes) in sections {
+            if changes.is_empty() {
+                continue;
+            }
+            changes.sort_by(|a, b| {
+                (&a.name, &a.old_version, &a.new_version).cmp(&(
+                    &b.name,
+                    &b.old_version,
+                    &b.new_version,
+                ))
+            });
+
+            if !out.is_empty() {
+                out.push('\n');
+            }
+            out.push_str(&format!("### {}\n\n", title));
+            for change in &changes {
+                out.push_str(&format!("- {}\n", markdown_change(change)));
+            }
+        }
+
+        if out.is_empty() {
+            out.push_str("No changes\n");
+        }
+        Some(out)
+    }
+}
+
+fn markdown_change(change: &PackageChange) -> String {
+    let description = match (&change.old_version, &change.new_version) {
+        (Some(old), Some(new)) if old != new => format!("{}: {} → {}", change.name, old, new),
+        (_, Some(version)) | (Some(version), None) => format!("{} {}", change.name, version),
+        (None, None) => change.name.clone(),
+    };
+    with_outputs(description, change)
+}
+
+/// Append the outputs of a change grouped from several, e.g. `(out, dev)`.
+fn with_outputs(description: String, change: &PackageChange) -> String {
+    match change.outputs() {
+        outputs if outputs.len() > 1 => format!("{} ({})", description, outputs.join(", ")),
+        _ => description,
+    }
+}
+
+fn render_suppressed(out: &mut String, suppressed: usize) {
+    if suppressed > 0 {
+        out.push_str(&format!("({} version-only changes hidden)\n", suppressed));
+    }
+}
+
+impl Table for RollbackPlan {
+    fn to_table(&self) -> String {
+        let title = if self.dry_run {
+            format!("Would activate generation {}", self.generation)
+        } else {
+            format!("Activated generation {}", self.generation)
+        };
+
+        let mut out = format!("{}:\n", title);
+        for command in &self.commands {
+            out.push_str(&format!("  {}\n", command));
+        }
+        out
+    }
+}
+
+impl Table for DeletionPlan {
+    fn to_table(&self) -> String {
+        if self.generations.is_empty() {
+            return "No generations to delete\n".to_string();
+        }
+
+        let verb = if self.dry_run {
+            "Would delete"
+        } else {
+            "Deleted"
+        };
+        let mut out = format!("{}: {}\n", verb, self.generations.join(", "));
+        for command in &self.commands {
+            out.push_str(&format!("  {}\n", command));
+        }
+        out
+    }
+}
+
+impl Table for PackageIntroduction {
+    fn to_table(&self) -> String {
+        let row = vec![
+            self.generation.clone(),
+            format_local(&self.timestamp),
+            self.package.clone(),
+            self.version.clone().unwrap_or_default(),
+        ];
+        render_columns(&["GENERATION", "DATE", "PACKAGE", "VERSION"], &[row])
+    }
+}
+
+impl Table for [PackageTransition] {
+    fn to_table(&self) -> String {
+        if self.is_empty() {
+            return "No changes\n".to_string();
+        }
+
+        let version = |version: &Option<String>| version.as_deref().unwrap_or("∅").to_string();
+        self.iter()
+            .map(|t| {
+                format!(
+                    "gen {}: {} {} → {}\n",
+                    t.generation,
+                    t.package,
+                    version(&t.old_version),
+                    version(&t.new_version)
+                )
+            })
+            .collect()
+    }
+}
+
+impl Table for Vec<PackageTransition> {
+    fn to_table(&self) -> String {
+        self.as_slice().to_table()
+    }
+}
+
+/// Each derivation indented under the one whose input it is, followed by its
+/// added and removed inputs, changed environment variables and anything else
+/// nix-diff noted. Changed inputs are not listed since they follow as their
+/// own entries.
+impl Table for [DerivationChange] {
+    fn to_table(&self) -> String {
+        if self.is_empty() {
+            return "No derivation changes\n".to_string();
+        }
+
+        let value = |value: &Option<String>| {
+            value
+                .as_deref()
+                .map_or("∅".to_string(), |v| v.replace('\n', " "))
+        };
+        let mut out = String::new();
+        for change in self {
+            let indent = "  ".repeat(change.depth);
+            out.push_str(&format!("{}{}\n", indent, change.name));
+            let details = change
+                .added_inputs
+                .iter()
+                .map(|input| format!("+ {}", input))
+                .chain(
+                    change
+                        .removed_inputs
+                        .iter()
+                        .map(|input| format!("- {}", input)),
+                )
+                .chain(change.environment.iter().map(|variable| {
+                    format!(
+                        "{}: {} → {}",
+                        variable.name,
+                        value(&variable.old_value),
+                        value(&variable.new_value)
+                    )
+                }))
+                .chain(change.other.iter().cloned());
+            for detail in details {
+                out.push_str(&format!("{}  {}\n", indent, detail));
+            }
+        }
+        out
+    }
+}
+
+impl Table for Vec<DerivationChange> {
+    fn to_table(&self) -> String {
+        self.as_slice().to_table()
+    }
+}
+
+impl Table for [PackageChurn] {
+    fn to_table(&self) -> String {
+        if self.is_empty() {
+            return "No flapping packages\n".to_string();
+        }
+
+        let rows: Vec<Vec<String>> = self
+            .iter()
+            .map(|c| {
+                vec![
+                    c.package.clone(),
+                    c.changes.to_string(),
+                    c.added.to_string(),
+                    c.removed.to_string(),
+                ]
+            })
+            .collect();
+        render_columns(&["PACKAGE", "CHANGES", "ADDED", "REMOVED"], &rows)
+    }
+}
+
+impl Table for Vec<PackageChurn> {
+    fn to_table(&self) -> String {
+        self.as_slice().to_table()
+    }
+}
+
+impl Table for Snapshot {
+    fn to_table(&self) -> String {
+        format!(
+            "Recorded {} generations of {} ({} new) in {}\n{} store paths in history\n",
+            self.generations,
+            self.profile,
+            self.new_generations,
+            self.database.display(),
+            self.store_paths
+        )
+    }
+}
+
+impl Table for GenerationScan {
+    fn to_table(&self) -> String {
+        if self.vulnerabilities.is_empty() {
+            return format!(
+                "No known vulnerabilities in generation {}\n",
+                self.generation
+            );
+        }
+        vulnerability_table(&self.vulnerabilities)
+    }
+}
+
+impl Table for ScanDiff {
+    fn to_table(&self) -> String {
+        let mut out = String::new();
+        for (title, vulnerabilities) in [
+            ("Introduced", &self.introduced),
+            ("Resolved", &self.resolved),
+        ] {
+            out.push_str(&format!("{} ({}):\n", title, vulnerabilities.len()));
+            if !vulnerabilities.is_empty() {
+                out.push_str(&vulnerability_table(vulnerabilities));
+            }
+        }
+        out
+    }
+}
+
+fn vulnerability_table(vulnerabilities: &[Vulnerability]) -> String {
+    let rows: Vec<Vec<String>> = vulnerabilities
+        .iter()
+        .map(|v| {
+            vec![
+                v.cve.clone(),
+                v.package.clone(),
+                v.severity.map(|s| format!("{:.1}", s)).unwrap_or_default(),
+            ]
+        })
+        .collect();
+    render_columns(&["CVE", "PACKAGE", "SEVERITY"], &rows)
+}
+
+impl Table for GcPreview {
+    fn to_table(&self) -> String {
+        let rows: Vec<Vec<String>> = self
+            .generations
+            .iter()
+            .map(|g| {
+                vec![
+                    g.id.clone(),
+                    g.paths.to_string(),
+                    format_size(g.reclaimable_bytes),
+                ]
+            })
+            .collect();
+
+        let mut out = render_columns(&["ID", "PATHS", "RECLAIMABLE"], &rows);
+        out.push_str(&format!(
+            "Total reclaimable: {}\n",
+            format_size(self.total_bytes)
+        ));
+        out.push_str(&render_warnings(&self.warnings));
+        out
+    }
+}
+
+impl<T: Table> Table for Partial<T> {
+    fn to_table(&self) -> String {
+        self.results.to_table() + &render_warnings(&self.warnings)
+    }
+
+    fn to_colored_table(&self) -> String {
+        self.results.to_colored_table() + &render_warnings(&self.warnings)
+    }
+}
+
+fn render_warnings(warnings: &[String]) -> String {
+    warnings
+        .iter()
+        .map(|warning| format!("warning: {}\n", warning))
+        .collect()
+}
+
+impl Table for GcResult {
+    fn to_table(&self) -> String {
+        let mut out = if self.dry_run {
+            "Would run:\n".to_string()
+        } else {
+            format!(
+                "Deleted {} store paths, freed {}:\n",
+                self.deleted_paths,
+                format_size(self.freed_bytes)
+            )
+        };
+        for command in &self.commands {
+            out.push_str(&format!("  {}\n", command));
+        }
+        out
+    }
+}
+
+/// Schemas have no table form and print as pretty JSON regardless.
+impl Table for schemars::Schema {
+    fn to_table(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap_or_default() + "\n"
+    }
+}
+
+impl Table for CommonReferences {
+    fn to_table(&self) -> String {
+        let mut out = format!(
+            "Generations {} and {} share {} reference(s)\n",
+            self.from, self.to, self.count
+        );
+        for path in &self.common {
+            out.push_str(&format!("  {}\n", path));
+        }
+        out
+    }
+}
+
+impl Table for GenerationNote {
+    fn to_table(&self) -> String {
+        match &self.note {
+            Some(note) => format!("Generation {}: {}\n", self.generation, note),
+            None => format!("Generation {} has no note\n", self.generation),
+        }
+    }
+}
+
+impl Table for SizeDiff {
+    fn to_table(&self) -> String {
+        let mut out = format!(
+            "Generation {}: {}\nGeneration {}: {}\nChange: {}\n",
+            self.from,
+            format_size(self.from_bytes),
+            self.to,
+            format_size(self.to_bytes),
+            format_size_delta(self.delta_bytes)
+        );
+        if !self.packages.is_empty() {
+            let rows: Vec<Vec<String>> = self
+                .packages
+                .iter()
+                .map(|p| {
+                    vec![
+                        p.name.clone(),
+                        format_size(p.old_bytes),
+                        format_size(p.new_bytes),
+                        format_size_delta(p.delta_bytes),
+                    ]
+                })
+                .collect();
+            out.push('\n');
+            out.push_str(&render_columns(&["PACKAGE", "OLD", "NEW", "CHANGE"], &rows));
+        }
+        if self.hidden_packages > 0 {
+            out.push_str(&format!(
+                "({} smaller package changes hidden)\n",
+                self.hidden_packages
+            ));
+        }
+        out
+    }
+}
+
+impl Table for GenerationStats {
+    fn to_table(&self) -> String {
+        let date = |timestamp: Option<DateTime<Utc>>| {
+            timestamp.map_or_else(|| "-".to_string(), |t| format_local(&t))
+        };
+        let mut rows = vec![
+            vec!["Generations".to_string(), self.count.to_string()],
+            vec!["First".to_string(), date(self.first)],
+            vec!["Last".to_string(), date(self.last)],
+            vec![
+                "Average interval".to_string(),
+                self.average_interval_secs
+                    .map_or_else(|| "-".to_string(), format_interval),
+            ],
+            vec![
+                "Busiest day".to_string(),
+                self.busiest_day.as_ref().map_or_else(
+                    || "-".to_string(),
+                    |day| format!("{} ({} generations)", day.date, day.generations),
+                ),
+            ],
+        ];
+        if let (Some(total), Some(average)) = (self.total_size_bytes, self.average_size_bytes) {
+            rows.push(vec!["Total size".to_string(), format_size(total)]);
+            rows.push(vec!["Average size".to_string(), format_size(average)]);
+        }
+
+        let width = rows.iter().map(|row| row[0].len()).max().unwrap_or(0);
+        rows.iter()
+            .map(|row| {
+                format!(
+                    "{:<width$}  {}\n",
+                    format!("{}:", row[0]),
+                    row[1],
+                    width = width + 1
+                )
+            })
+            .collect()
+    }
+}
+
+/// A duration in seconds as days, hours or minutes, e.g. `2.5 days`.
+fn format_interval(secs: f64) -> String {
+    match secs {
+        s if s >= 86400.0 => format!("{:.1} days", s / 86400.0),
+        s if s >= 3600.0 => format!("{:.1} hours", s / 3600.0),
+        s => format!("{:.0} minutes", s / 60.0),
+    }
+}
+
+/// `format_size` with an explicit sign, e.g. `+1.5 MiB`.
+pub fn format_size_delta(bytes: i64) -> String {
+    let sign = if bytes < 0 { '-' } else { '+' };
+    format!("{}{}", sign, format_size(bytes.unsigned_abs()))
+}
+
+/// Format a byte count with binary units, e.g. `1.5 MiB`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+fn describe_change(change: &PackageChange) -> String {
+    let version = |v: &Option<String>| v.clone().unwrap_or_else(|| "∅".to_string());
+    let description = match (&change.old_version, &change.new_version) {
+        (None, None) => change.name.clone(),
+        (old, new) => format!("{}: {} → {}", change.name, version(old), version(new)),
+    };
+    with_outputs(description, change)
+}
+
+type Paint = fn(&str) -> String;
+
+/// Added, removed and modified entries under counted headers, marked with
+/// `+`, `-` and `~` like nix-diff, and colored green, red and yellow.
+fn render_diff(sections: [&[String]; 3], suppressed: usize, color: bool) -> String {
+    let [added, removed, modified] = sections;
+    let styles: [(&str, char, Paint, &[String]); 3] = [
+        ("Added", '+', |line| line.green().to_string(), added),
+        ("Removed", '-', |line| line.red().to_string(), removed),
+        ("Modified", '~', |line| line.yellow().to_string(), modified),
+    ];
+
+    let mut out = String::new();
+    for (title, marker, paint, entries) in styles {
+        let header = format!("{} ({}):", title, entries.len());
+        if color {
+            out.push_str(&format!("{}\n", header.bold()));
+        } else {
+            out.push_str(&format!("{}\n", header));
+        }
+
+        for entry in entries {
+            let line = format!("{} {}", marker, entry);
+            let line = if color { paint(&line) } else { line };
+            out.push_str(&format!("  {}\n", line));
+        }
+    }
+    render_suppressed(&mut out, suppressed);
+    out
+}
+
+/// Every name in one sorted column, marked like `render_diff`.
+fn render_names(diff: &NameDiff, color: bool) -> String {
+    let mut lines: Vec<(&str, char, Paint)> = Vec::new();
+    let styles: [(char, Paint, &[String]); 3] = [
+        ('+', |line| line.green().to_string(), &diff.added),
+        ('-', |line| line.red().to_string(), &diff.removed),
+        ('~', |line| line.yellow().to_string(), &diff.modified),
+    ];
+    for (marker, paint, names) in styles {
+        lines.extend(names.iter().map(|name| (name.as_str(), marker, paint)));
+    }
+    lines.sort_by_key(|&(name, marker, _)| (name, marker));
+
+    let mut out = String::new();
+    for (name, marker, paint) in lines {
+        let line = format!("{} {}", marker, name);
+        let line = if color { paint(&line) } else { line };
+        out.push_str(&format!("{}\n", line));
+    }
+    out
+}
+
+/// `timestamp` in the machine's timezone, for tables. Serialized output
+/// follows `--timezone` instead.
+pub fn format_local(timestamp: &DateTime<Utc>) -> String {
+    timestamp
+        .with_timezone(&Local)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
+
+/// `elapsed` in its largest whole unit, e.g. `3 days ago`. A negative
+/// duration, from a timestamp ahead of the clock, is `in the future`.
+pub fn humanize_duration(elapsed: Duration) -> String {
+    let seconds = elapsed.num_seconds();
+    if seconds < 0 {
+        return "in the future".to_string();
+    }
+
+    const UNITS: [(&str, i64); 6] = [
+        ("year", 365 * 24 * 3600),
+        ("month", 30 * 24 * 3600),
+        ("week", 7 * 24 * 3600),
+        ("day", 24 * 3600),
+        ("hour", 3600),
+        ("minute", 60),
+    ];
+    match UNITS.iter().find(|&&(_, size)| seconds >= size) {
+        Some(&(unit, size)) => {
+            let count = seconds / size;
+            format!(
+                "{} {}{} ago",
+                count,
+                unit,
+                if count == 1 { "" } else { "s" }
+            )
+        }
+        None => "just now".to_string(),
+    }
+}
+
+/// Pad every column to its widest cell. The last column is left unpadded
+/// so rows don't carry trailing whitespace.
+fn render_columns(headers: &[&str], rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.chars().count()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let render_row = |cells: Vec<&str>| -> String {
+        let last = cells.len().saturating_sub(1);
+        let line = cells
+            .iter()
+            .enumerate()
+            .map(|(i, cell)| {
+                if i == last {
+                    cell.to_string()
+                } else {
+                    format!("{:width$}", cell, width = widths[i])
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("  ");
+        format!("{}\n", line.trim_end())
+    };
+
+    let mut out = render_row(headers.to_vec());
+    for row in rows {
+        out.push_str(&render_row(row.iter().map(String::as_str).collect()));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_formats() {
+        let diff = GenerationDiff {
+            added: vec!["a".to_string()],
+            removed: vec![],
+            modified: vec![],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            render(&diff, OutputFormat::Json).unwrap(),
+            "{\"added\":[\"a\"],\"removed\":[],\"modified\":[],\"summary\":\
+             {\"added\":1,\"removed\":0,\"modified\":0,\"upgraded\":0,\"downgraded\":0}}\n"
+        );
+        assert!(render(&diff, OutputFormat::JsonPretty)
+            .unwrap()
+            .starts_with("{\n  \"added\": [\n"));
+        assert_eq!(
+            render(&diff, OutputFormat::Yaml).unwrap(),
+            "added:\n- a\nremoved: []\nmodified: []\nsummary:\n  added: 1\n  removed: 0\n  \
+             modified: 0\n  upgraded: 0\n  downgraded: 0\n"
+        );
+    }
+
+    #[test]
+    fn test_render_error() {
+        let err = Error::GenerationNotFound("42".to_string());
+        let json: serde_json::Value =
+            serde_json::from_str(&render_error(&err, ErrorFormat::Json)).unwrap();
+        assert_eq!(json["error"]["kind"], "generation_not_found");
+        assert_eq!(json["error"]["message"], "Generation not found: 42");
+        assert_eq!(
+            render_error(&err, ErrorFormat::Text),
+            "Error: GenerationNotFound(\"42\")\n"
+        );
+    }
+
+    #[test]
+    fn test_humanize_duration() {
+        assert_eq!(humanize_duration(Duration::seconds(20)), "just now");
+        assert_eq!(humanize_duration(Duration::minutes(1)), "1 minute ago");
+        assert_eq!(humanize_duration(Duration::hours(5)), "5 hours ago");
+        assert_eq!(humanize_duration(Duration::hours(80)), "3 days ago");
+        assert_eq!(humanize_duration(Duration::days(15)), "2 weeks ago");
+        assert_eq!(humanize_duration(Duration::days(400)), "1 year ago");
+        assert_eq!(humanize_duration(Duration::hours(-1)), "in the future");
+    }
+
+    #[test]
+    fn test_generation_table_age_column() {
+        let mut generation = Generation {
+            id: "3".to_string(),
+            current: true,
+            ..Default::default()
+        };
+        assert!(!generation.to_table().contains("AGE"));
+
+        generation.age = Some("3 days ago".to_string());
+        let table = generation.to_table();
+        assert!(table.starts_with("ID  DATE "), "{}", table);
+        assert!(table.contains("AGE         CURRENT"), "{}", table);
+        assert!(table.contains("3 days ago  *"), "{}", table);
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+
+    #[test]
+    fn test_render_columns_aligns_cells() {
+        let rows = vec![
+            vec!["1".to_string(), "first".to_string()],
+            vec!["10".to_string(), "second".to_string()],
+        ];
+
+        assert_eq!(
+            render_columns(&["ID", "NAME"], &rows),
+            "ID  NAME\n1   first\n10  second\n"
+        );
+    }
+
+    #[test]
+    fn test_diff_table_sections() {
+        let diff = GenerationDiff {
+            added: vec!["a".to_string(), "b".to_string()],
+            removed: vec![],
+            modified: vec!["c".to_string()],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            diff.to_table(),
+            "Added (2):\n  + a\n  + b\nRemoved (0):\nModified (1):\n  ~ c\n"
+        );
+
+        let colored = render_colored(&diff, OutputFormat::Table, true).unwrap();
+        assert!(colored.contains("\x1b[32m+ a\x1b[39m"), "{:?}", colored);
+        assert!(colored.contains("\x1b[33m~ c\x1b[39m"), "{:?}", colored);
+        assert!(!render_colored(&diff, OutputFormat::Json, true)
+            .unwrap()
+            .contains('\x1b'));
+    }
+
+    #[test]
+    fn test_write_output() {
+        let dir = std::env::temp_dir().join(format!("nix-timemach-output-{}", std::process::id()));
+        let path = dir.join("nested/diff.json");
+
+        write_output(&path, "{}\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{}\n");
+
+        // A regular file where a parent directory should be.
+        let err = write_output(&path.join("diff.json"), "{}\n").unwrap_err();
+        assert!(
+            matches!(err, Error::OutputWriteFailed { path: ref p, .. } if p.ends_with("diff.json"))
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_name_diff_table() {
+        let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect();
+        let diff = NameDiff {
+            added: names(&["zstd", "firefox"]),
+            removed: names(&["vim"]),
+            modified: names(&["openssl"]),
+        };
+        assert_eq!(diff.to_table(), "+ firefox\n~ openssl\n- vim\n+ zstd\n");
+    }
+
+    #[test]
+    fn test_derivation_diff_table() {
+        let changes = vec![
+            DerivationChange {
+                name: "system-path".into(),
+                depth: 0,
+                changed_inputs: vec!["openssl".into()],
+                added_inputs: vec!["firefox-122.0".into()],
+                ..Default::default()
+            },
+            DerivationChange {
+                name: "openssl".into(),
+                depth: 1,
+                environment: vec![crate::models::derivation::EnvironmentChange {
+                    name: "version".into(),
+                    old_value: Some("3.0.10".into()),
+                    new_value: Some("3.0.12".into()),
+                }],
+                other: vec!["The input sources do not match".into()],
+                ..Default::default()
+            },
+        ];
+        assert_eq!(
+            changes.to_table(),
+            "system-path\n  + firefox-122.0\n  openssl\n    version: 3.0.10 → 3.0.12\n    \
+             The input sources do not match\n"
+        );
+    }
+
+    #[test]
+    fn test_diff_markdown() {
+        let path = |p: &str| format!("/nix/store/0c7r3sa2dmw7ns6dyxmzif2g9mqkcgxy-{}", p);
+        let diff = GenerationDiff {
+            added: vec![
+                path("zstd-1.5.5"),
+                path("openssl-3.0.12"),
+                path("firefox-122.0"),
+            ],
+            removed: vec![path("vim-9.0"), path("openssl-3.0.10")],
+            modified: vec![path("openssl-3.0.10")],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            render(&diff, OutputFormat::Markdown).unwrap(),
+            "### Added\n\n- firefox 122.0\n- zstd 1.5.5\n\n### Removed\n\n- vim 9.0\n\n\
+             ### Upgraded\n\n- openssl: 3.0.10 → 3.0.12\n"
+        );
+
+        let grouped = GenerationDiff {
+            added: vec![path("openssl-3.0.12"), path("openssl-3.0.12-dev")],
+            modified: vec![path("openssl-3.0.10"), path("openssl-3.0.10-dev")],
+            ..Default::default()
+        };
+        assert_eq!(
+            render(&grouped, OutputFormat::Markdown).unwrap(),
+            "### Upgraded\n\n- openssl: 3.0.10 → 3.0.12 (out, dev)\n"
+        );
+        assert!(matches!(
+            render(&GcPreview::default(), OutputFormat::Markdown),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+}
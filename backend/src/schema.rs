@@ -0,0 +1,106 @@
+use schemars::{schema_for, Schema};
+
+use crate::models::diff::{DetailedDiff, GenerationDiff};
+use crate::models::generation::Generation;
+
+/// The output types `schema` describes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SchemaType {
+    /// A generation, as listed by `list-generations` and `current`
+    Generation,
+    /// A path-level diff, as printed by `diff`
+    GenerationDiff,
+    /// A package-level diff, as printed by `diff --mode closures`
+    DetailedDiff,
+}
+
+impl SchemaType {
+    /// The JSON Schema of the type's serialized form, derived from the type
+    /// itself so the two can't drift apart.
+    pub fn schema(self) -> Schema {
+        match self {
+            SchemaType::Generation => schema_for!(Generation),
+            SchemaType::GenerationDiff => schema_for!(GenerationDiff),
+            SchemaType::DetailedDiff => schema_for!(DetailedDiff),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::diff::{ChangeDirection, PackageChange};
+    use crate::models::generation::FlakeInfo;
+    use chrono::{TimeZone, Utc};
+
+    fn assert_valid(schema: SchemaType, value: serde_json::Value) {
+        let schema = serde_json::to_value(schema.schema()).unwrap();
+        let validator = jsonschema::validator_for(&schema).unwrap();
+        let errors: Vec<String> = validator
+            .iter_errors(&value)
+            .map(|e| e.to_string())
+            .collect();
+        assert!(errors.is_empty(), "{} in {}", errors.join(", "), value);
+    }
+
+    #[test]
+    fn test_output_matches_schema() {
+        let generation = Generation {
+            id: "42".to_string(),
+            timestamp: Utc.with_ymd_and_hms(2024, 2, 9, 10, 0, 0).unwrap(),
+            profiles: vec!["/nix/var/nix/profiles/system-42-link".to_string()],
+            current: true,
+            nixos_version: Some("24.05.20240209.f9d39fb".to_string()),
+            flake: Some(FlakeInfo {
+                revision: None,
+                dirty: true,
+            }),
+            note: Some("known-good".to_string()),
+            ..Default::default()
+        };
+        assert_valid(
+            SchemaType::Generation,
+            serde_json::to_value(&generation).unwrap(),
+        );
+        assert_valid(
+            SchemaType::Generation,
+            serde_json::to_value(Generation::default()).unwrap(),
+        );
+
+        let path = |name: &str| format!("/nix/store/{}-{}", "c".repeat(32), name);
+        let diff = GenerationDiff {
+            added: vec![path("openssl-3.0.12")],
+            modified: vec![path("openssl-3.0.10")],
+            ..Default::default()
+        };
+        assert_valid(
+            SchemaType::GenerationDiff,
+            serde_json::to_value(&diff).unwrap(),
+        );
+        assert_valid(
+            SchemaType::DetailedDiff,
+            serde_json::to_value(diff.to_detailed()).unwrap(),
+        );
+
+        let change = PackageChange {
+            name: "openssl".to_string(),
+            old_version: Some("3.0.10".to_string()),
+            new_version: Some("3.0.12".to_string()),
+            direction: ChangeDirection::Upgrade,
+            store_path: String::new(),
+            size_delta: Some(-2048),
+            old_paths: Vec::new(),
+            new_paths: Vec::new(),
+            direct: None,
+        };
+        let diff = DetailedDiff {
+            modified: vec![change],
+            ..Default::default()
+        };
+        let mut value = serde_json::to_value(&diff).unwrap();
+        assert_valid(SchemaType::DetailedDiff, value.clone());
+        value["modified"][0]["direction"] = "sideways".into();
+        let schema = serde_json::to_value(SchemaType::DetailedDiff.schema()).unwrap();
+        assert!(!jsonschema::is_valid(&schema, &value));
+    }
+}
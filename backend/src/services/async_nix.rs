@@ -0,0 +1,255 @@
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::process::Output;
+use std::time::Duration;
+
+use crate::error::Result;
+use crate::models::diff::{DetailedDiff, GenerationDiff};
+use crate::models::generation::Generation;
+use crate::services::cache::{DiffCache, GenerationCache};
+use crate::services::queries::{join_chunked, Exec, Queries};
+use crate::services::runner::{AsyncCommandRunner, RetryPolicy, TokioCommandRunner};
+
+/// `NixService` for async callers: the same queries, issued through an
+/// `AsyncCommandRunner` so they don't tie up a runtime thread while nix runs.
+/// Both services answer them with the same `Queries`.
+pub struct AsyncNixService<R: AsyncCommandRunner = TokioCommandRunner> {
+    runner: R,
+    queries: Queries,
+}
+
+impl AsyncNixService {
+    pub fn new() -> Self {
+        Self::with_runner(TokioCommandRunner::default())
+    }
+}
+
+impl Default for AsyncNixService {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<R: AsyncCommandRunner> AsyncNixService<R> {
+    pub fn with_runner(runner: R) -> Self {
+        Self {
+            runner,
+            queries: Queries::default(),
+        }
+    }
+
+    /// Operate on `path` instead of the system profile.
+    pub fn with_profile(mut self, path: impl Into<PathBuf>) -> Self {
+        self.queries.profile_path = path.into();
+        self
+    }
+
+    /// Pass `--store uri` to every nix command.
+    pub fn with_store(mut self, uri: impl Into<String>) -> Self {
+        self.queries.store = Some(uri.into());
+        self
+    }
+
+    /// Retry transient command failures according to `policy`.
+    pub fn with_retry(mut self, policy: RetryPolicy) -> Self {
+        self.queries.retry = policy;
+        self
+    }
+
+    /// See `NixService::with_jobs`.
+    pub fn with_jobs(mut self, jobs: usize) -> Self {
+        self.queries.jobs = jobs.max(1);
+        self
+    }
+
+    /// See `NixService::with_cache_ttl`.
+    pub fn with_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.queries.cache = GenerationCache::new(ttl);
+        self
+    }
+
+    /// See `NixService::with_diff_cache`.
+    pub fn with_diff_cache(mut self, capacity: usize) -> Self {
+        self.queries.path_diffs = DiffCache::new(capacity);
+        self.queries.package_diffs = DiffCache::new(capacity);
+        self
+    }
+
+    pub fn profile_path(&self) -> &Path {
+        self.queries.profile_path()
+    }
+
+    pub async fn list_generations(&self) -> Result<Vec<Generation>> {
+        self.queries.list_generations(self).await
+    }
+
+    /// The generation list alone, without reading versions or boot state;
+    /// `enrich` reads those for the generations that need them.
+    pub async fn read_generations(&self) -> Result<Vec<Generation>> {
+        self.queries.read_generations(self).await
+    }
+
+    /// See `Queries::enrich`.
+    pub async fn enrich(&self, generations: &mut [Generation]) {
+        self.queries.enrich(self, generations).await
+    }
+
+    pub async fn generation_exists(&self, id: &str) -> Result<bool> {
+        self.queries.generation_exists(self, id).await
+    }
+
+    /// See `NixService::resolve_ref`.
+    pub async fn resolve_ref(&self, reference: &str) -> Result<String> {
+        self.queries.resolve_ref(self, reference).await
+    }
+
+    pub async fn get_booted_generation(&self) -> Result<Option<String>> {
+        self.queries.get_booted_generation(self).await
+    }
+
+    pub async fn read_nixos_version(&self, store_path: &str) -> Result<Option<String>> {
+        self.queries.read_nixos_version(self, store_path).await
+    }
+
+    pub async fn read_kernel_version(&self, store_path: &str) -> Result<Option<String>> {
+        self.queries.read_kernel_version(self, store_path).await
+    }
+
+    pub async fn get_current(&self) -> Result<Generation> {
+        self.queries.get_current(self).await
+    }
+
+    /// Diff two generations with `nix-diff`, or by their references when
+    /// nix-diff isn't installed.
+    pub async fn get_diff(&self, from: &str, to: &str) -> Result<GenerationDiff> {
+        self.queries.get_diff(self, from, to).await
+    }
+
+    pub async fn get_nix_diff(&self, from: &str, to: &str) -> Result<GenerationDiff> {
+        self.queries.get_nix_diff(self, from, to).await
+    }
+
+    pub async fn get_reference_diff(&self, from: &str, to: &str) -> Result<GenerationDiff> {
+        self.queries.get_reference_diff(self, from, to).await
+    }
+
+    pub async fn get_closure_diff(&self, from: &str, to: &str) -> Result<DetailedDiff> {
+        self.queries.get_closure_diff(self, from, to).await
+    }
+}
+
+impl<R: AsyncCommandRunner> Exec for AsyncNixService<R> {
+    fn run_command(
+        &self,
+        program: &str,
+        args: &[&str],
+    ) -> impl Future<Output = Result<Output>> + Send {
+        self.runner.run(program, args)
+    }
+
+    fn sleep(&self, delay: Duration) -> impl Future<Output = ()> + Send {
+        tokio::time::sleep(delay)
+    }
+
+    fn is_remote(&self) -> bool {
+        self.runner.is_remote()
+    }
+
+    fn join<U, Fut>(&self, futures: Vec<Fut>, jobs: usize) -> impl Future<Output = Vec<U>> + Send
+    where
+        U: Send,
+        Fut: Future<Output = U> + Send,
+    {
+        join_chunked(futures, jobs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::services::runner::MockCommandRunner;
+
+    const PROFILE: &str = "/nix/var/nix/profiles/system";
+
+    fn runner() -> MockCommandRunner {
+        MockCommandRunner::new()
+            .with_stdout(
+                &format!("nix-env --list-generations -p {}", PROFILE),
+                "   9   2024-02-09 10:00:00   \n  10   2024-02-10 11:30:00   (current)\n",
+            )
+            .with_stdout(&format!("readlink {}", PROFILE), "system-10-link\n")
+    }
+
+    #[tokio::test]
+    async fn test_list_generations_and_resolve_ref() {
+        let service = AsyncNixService::with_runner(runner());
+
+        let generations = service.list_generations().await.unwrap();
+        let ids: Vec<_> = generations.iter().map(|g| g.id.as_str()).collect();
+        assert_eq!(ids, ["9", "10"]);
+        assert!(generations[1].current);
+
+        assert_eq!(service.resolve_ref("previous").await.unwrap(), "9");
+        assert!(matches!(
+            service.resolve_ref("HEAD~2").await,
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_get_diff() {
+        let runner = runner()
+            .with_stdout(
+                &format!("nix-env -p {}-9-link --query --out-path", PROFILE),
+                "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-nixos-system-9\n",
+            )
+            .with_stdout(
+                &format!("nix-env -p {}-10-link --query --out-path", PROFILE),
+                "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-nixos-system-10\n",
+            )
+            .with_stdout(
+                "nix-diff /nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-nixos-system-9 \
+                 /nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-nixos-system-10",
+                "+ /nix/store/cccccccccccccccccccccccccccccccc-vim-9.0\n",
+            );
+        let service = AsyncNixService::with_runner(runner);
+
+        let diff = service.get_diff("9", "10").await.unwrap();
+        assert_eq!(
+            diff.added,
+            ["/nix/store/cccccccccccccccccccccccccccccccc-vim-9.0"]
+        );
+        assert!(service.get_diff("10", "10").await.unwrap().added.is_empty());
+
+        // The same diff again comes from the cache.
+        let calls = service.runner.calls().len();
+        let cached = service.get_diff("9", "10").await.unwrap();
+        assert_eq!(cached.added, diff.added);
+        assert_eq!(service.runner.calls().len(), calls);
+    }
+
+    #[tokio::test]
+    async fn test_retries_and_marks_booted() {
+        let locked = "error: SQLite database '/nix/var/nix/db/db.sqlite' is busy";
+        let runner = runner()
+            .with_response("readlink /run/booted-system", 1, "", locked)
+            .with_stdout(
+                "readlink /run/booted-system",
+                "/nix/store/a-nixos-system-9\n",
+            )
+            .with_stdout(
+                &format!("readlink {}-9-link", PROFILE),
+                "/nix/store/a-nixos-system-9\n",
+            );
+        let service = AsyncNixService::with_runner(runner).with_retry(RetryPolicy {
+            base_delay: Duration::ZERO,
+            ..RetryPolicy::default()
+        });
+
+        assert_eq!(
+            service.get_booted_generation().await.unwrap().as_deref(),
+            Some("9")
+        );
+    }
+}
@@ -0,0 +1,302 @@
+use lru::LruCache;
+use std::collections::HashMap;
+use std::future::Future;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::error::Result;
+use crate::models::diff::DiffMode;
+use crate::models::generation::Generation;
+
+/// Generation lists per profile, kept for `ttl` or until the profile
+/// symlink's mtime changes, whichever comes first. A zero TTL disables
+/// caching.
+#[derive(Debug, Default)]
+pub struct GenerationCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<PathBuf, Entry>>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    generations: Vec<Generation>,
+    fetched: Instant,
+    modified: Option<SystemTime>,
+}
+
+impl GenerationCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::default(),
+        }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// The cached list for `profile`, unless it expired or the profile has
+    /// switched generations since. `modified` is the profile's current mtime.
+    pub fn get(&self, profile: &Path, modified: Option<SystemTime>) -> Option<Vec<Generation>> {
+        if self.ttl.is_zero() {
+            return None;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get(profile)?;
+        if entry.fetched.elapsed() < self.ttl && entry.modified == modified {
+            return Some(entry.generations.clone());
+        }
+        entries.remove(profile);
+        None
+    }
+
+    /// Cache `generations` for `profile`. `modified` is the profile's mtime
+    /// from before the list was read, so a switch while reading is noticed.
+    pub fn insert(&self, profile: &Path, modified: Option<SystemTime>, generations: &[Generation]) {
+        if self.ttl.is_zero() {
+            return;
+        }
+
+        self.entries.lock().unwrap().insert(
+            profile.to_path_buf(),
+            Entry {
+                generations: generations.to_vec(),
+                fetched: Instant::now(),
+                modified,
+            },
+        );
+    }
+
+    pub fn invalidate(&self, profile: &Path) {
+        self.entries.lock().unwrap().remove(profile);
+    }
+}
+
+/// Direct references per path. A generation's references never change, so
+/// entries are kept until its link is deleted (and its id possibly reused).
+#[derive(Debug, Default)]
+pub struct ReferenceCache {
+    entries: Mutex<HashMap<String, Vec<String>>>,
+}
+
+impl ReferenceCache {
+    pub fn get(&self, path: &str) -> Option<Vec<String>> {
+        self.entries.lock().unwrap().get(path).cloned()
+    }
+
+    pub fn insert(&self, path: &str, references: &[String]) {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(path.to_string(), references.to_vec());
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+/// Diffs kept by NixService and AsyncNixService unless `--diff-cache` says
+/// otherwise.
+pub const DEFAULT_DIFF_CACHE: usize = 64;
+
+/// What a cached diff was computed from. Generation ids are only unique
+/// within one profile of one store.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DiffKey {
+    profile: PathBuf,
+    store: Option<String>,
+    from: String,
+    to: String,
+    mode: DiffMode,
+}
+
+impl DiffKey {
+    pub fn new(profile: &Path, store: Option<&str>, from: &str, to: &str, mode: DiffMode) -> Self {
+        Self {
+            profile: profile.to_path_buf(),
+            store: store.map(str::to_string),
+            from: from.to_string(),
+            to: to.to_string(),
+            mode,
+        }
+    }
+}
+
+/// The `capacity` most recently used diffs. The diff between two
+/// generations never changes, but a deleted generation's id can be reused
+/// by the next one, so everything is dropped once the profile symlink's
+/// mtime changes. A zero capacity disables caching.
+#[derive(Debug)]
+pub struct DiffCache<V> {
+    entries: Option<Mutex<DiffEntries<V>>>,
+}
+
+#[derive(Debug)]
+struct DiffEntries<V> {
+    diffs: LruCache<DiffKey, V>,
+    modified: Option<SystemTime>,
+}
+
+impl<V: Clone> DiffCache<V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: NonZeroUsize::new(capacity).map(|capacity| {
+                Mutex::new(DiffEntries {
+                    diffs: LruCache::new(capacity),
+                    modified: None,
+                })
+            }),
+        }
+    }
+
+    /// The diff for `key`, unless the profile changed since it was cached.
+    /// `modified` is the profile's current mtime.
+    pub fn get(&self, key: &DiffKey, modified: Option<SystemTime>) -> Option<V> {
+        let mut entries = self.entries.as_ref()?.lock().unwrap();
+        if entries.modified != modified {
+            entries.diffs.clear();
+            return None;
+        }
+        entries.diffs.get(key).cloned()
+    }
+
+    /// Cache `diff`, computed while the profile's mtime was `modified`.
+    pub fn insert(&self, key: DiffKey, modified: Option<SystemTime>, diff: &V) {
+        let Some(entries) = &self.entries else {
+            return;
+        };
+        let mut entries = entries.lock().unwrap();
+        if entries.modified != modified {
+            entries.diffs.clear();
+            entries.modified = modified;
+        }
+        entries.diffs.put(key, diff.clone());
+    }
+
+    /// The cached diff for `key`, or the result of `diff`, which is cached
+    /// unless it failed. `modified` is the profile's current mtime.
+    pub async fn get_or_try_insert(
+        &self,
+        modified: Option<SystemTime>,
+        key: DiffKey,
+        diff: impl Future<Output = Result<V>>,
+    ) -> Result<V> {
+        if let Some(cached) = self.get(&key, modified) {
+            return Ok(cached);
+        }
+        let diff = diff.await?;
+        self.insert(key, modified, &diff);
+        Ok(diff)
+    }
+
+    pub fn clear(&self) {
+        if let Some(entries) = &self.entries {
+            entries.lock().unwrap().diffs.clear();
+        }
+    }
+}
+
+impl<V: Clone> Default for DiffCache<V> {
+    fn default() -> Self {
+        Self::new(DEFAULT_DIFF_CACHE)
+    }
+}
+
+/// Mtime of the profile symlink itself; switching generations replaces it.
+pub fn profile_modified(profile: &Path) -> Option<SystemTime> {
+    std::fs::symlink_metadata(profile)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generations() -> Vec<Generation> {
+        vec![Generation {
+            id: "1".to_string(),
+            ..Default::default()
+        }]
+    }
+
+    #[test]
+    fn test_cache_expires_and_follows_profile_mtime() {
+        let dir = std::env::temp_dir().join(format!("nix-timemach-cache-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let profile = dir.join("profile");
+        let _ = std::fs::remove_file(&profile);
+        std::os::unix::fs::symlink("profile-1-link", &profile).unwrap();
+
+        let cache = GenerationCache::new(Duration::from_secs(60));
+        cache.insert(&profile, profile_modified(&profile), &generations());
+        assert_eq!(
+            cache
+                .get(&profile, profile_modified(&profile))
+                .unwrap()
+                .len(),
+            1
+        );
+
+        // Switching generations replaces the symlink.
+        std::thread::sleep(Duration::from_millis(10));
+        std::fs::remove_file(&profile).unwrap();
+        std::os::unix::fs::symlink("profile-2-link", &profile).unwrap();
+        assert!(cache.get(&profile, profile_modified(&profile)).is_none());
+
+        let cache = GenerationCache::new(Duration::from_millis(10));
+        cache.insert(&profile, profile_modified(&profile), &generations());
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(cache.get(&profile, profile_modified(&profile)).is_none());
+
+        let disabled = GenerationCache::default();
+        disabled.insert(&profile, profile_modified(&profile), &generations());
+        assert!(disabled.get(&profile, profile_modified(&profile)).is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_diff_cache_evicts_least_recently_used() {
+        let system = Path::new("/nix/var/nix/profiles/system");
+        let key = |to: &str| DiffKey::new(system, None, "1", to, DiffMode::References);
+        let cache = DiffCache::new(2);
+        cache.insert(key("2"), None, &2);
+        cache.insert(key("3"), None, &3);
+        assert_eq!(cache.get(&key("2"), None), Some(2));
+        cache.insert(key("4"), None, &4);
+        assert_eq!(cache.get(&key("3"), None), None);
+        assert_eq!(cache.get(&key("2"), None), Some(2));
+        assert_eq!(
+            cache.get(
+                &DiffKey::new(system, None, "1", "2", DiffMode::Closures),
+                None
+            ),
+            None
+        );
+        // The same ids in another profile or store are other generations.
+        let home = Path::new("/nix/var/nix/profiles/per-user/alice/home-manager");
+        assert_eq!(
+            cache.get(
+                &DiffKey::new(home, None, "1", "2", DiffMode::References),
+                None
+            ),
+            None
+        );
+        let remote = DiffKey::new(system, Some("ssh://cache"), "1", "2", DiffMode::References);
+        assert_eq!(cache.get(&remote, None), None);
+
+        // A new profile mtime drops everything.
+        assert_eq!(cache.get(&key("2"), Some(SystemTime::UNIX_EPOCH)), None);
+        assert_eq!(cache.get(&key("4"), None), None);
+
+        let disabled = DiffCache::new(0);
+        disabled.insert(key("2"), None, &2);
+        assert_eq!(disabled.get(&key("2"), None), None);
+    }
+}
@@ -0,0 +1,120 @@
+use crate::error::{Error, Result};
+use crate::models::generation::Generation;
+use crate::services::nix::{current_unavailable, NixService};
+use crate::services::parse::parse_generations_output;
+use crate::services::provider::GenerationProvider;
+use crate::services::runner::{CommandRunner, RealCommandRunner};
+
+/// Generations of a nix-darwin system, listed with `darwin-rebuild`.
+///
+/// Everything else goes through the wrapped `NixService`, so its profile,
+/// timeout and retry settings apply here too. `darwin-rebuild` always lists
+/// the system profile.
+pub struct DarwinService<R: CommandRunner = RealCommandRunner> {
+    nix: NixService<R>,
+}
+
+impl<R: CommandRunner> DarwinService<R> {
+    pub fn new(nix: NixService<R>) -> Self {
+        Self { nix }
+    }
+
+    pub fn nix(&self) -> &NixService<R> {
+        &self.nix
+    }
+
+    fn profile(&self) -> String {
+        self.nix.profile_path().to_string_lossy().into_owned()
+    }
+}
+
+impl<R: CommandRunner> GenerationProvider for DarwinService<R> {
+    fn list_generations(&self) -> Result<Vec<Generation>> {
+        let output = self.nix.run("darwin-rebuild", &["--list-generations"])?;
+
+        if !output.status.success() {
+            return Err(Error::NixCommandError(
+                String::from_utf8_lossy(&output.stderr).to_string(),
+            ));
+        }
+
+        let current = self.nix.get_current_generation()?;
+        parse_darwin_generations(
+            &String::from_utf8_lossy(&output.stdout),
+            &self.profile(),
+            &current,
+        )
+    }
+
+    fn get_current(&self) -> Result<Generation> {
+        let id = self
+            .nix
+            .get_current_generation()
+            .map_err(|e| current_unavailable(&self.profile(), e))?;
+
+        self.list_generations()?
+            .into_iter()
+            .find(|g| g.id == id)
+            .ok_or(Error::GenerationNotFound(id))
+    }
+
+    /// nix-darwin profiles have no manifest for `nix-env --query`, so the
+    /// generation link is resolved directly.
+    fn get_store_path(&self, id: &str) -> Result<String> {
+        self.nix.resolve_generation_link(id)
+    }
+}
+
+/// `darwin-rebuild --list-generations` prints `nix-env`'s table. nix-darwin
+/// systems carry no NixOS or kernel version, and are never marked booted.
+fn parse_darwin_generations(output: &str, profile: &str, current: &str) -> Result<Vec<Generation>> {
+    let mut generations = parse_generations_output(output, profile, current)?;
+    for generation in &mut generations {
+        generation.nixos_version = None;
+        generation.kernel_version = None;
+    }
+    Ok(generations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::nix::Direction;
+    use crate::services::runner::MockCommandRunner;
+
+    #[test]
+    fn test_darwin_generations() {
+        let runner = MockCommandRunner::new()
+            .with_stdout(
+                "darwin-rebuild --list-generations",
+                "  41   2024-03-01 09:12:44   \n  42   2024-03-04 18:03:10   (current)\n",
+            )
+            .with_stdout("readlink /nix/var/nix/profiles/system", "system-42-link\n")
+            .with_stdout(
+                "readlink -f /nix/var/nix/profiles/system-41-link",
+                "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-darwin-system-24.05\n",
+            )
+            .with_response("readlink -f /nix/var/nix/profiles/system-7-link", 1, "", "");
+        let darwin = DarwinService::new(NixService::with_runner(runner));
+
+        let generations = darwin.list_generations().unwrap();
+        assert_eq!(generations.len(), 2);
+        assert!(generations[1].current);
+        assert_eq!(darwin.get_current().unwrap().id, "42");
+        assert_eq!(
+            darwin.get_store_path("41").unwrap(),
+            "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-darwin-system-24.05"
+        );
+        assert!(matches!(
+            darwin.get_store_path("7"),
+            Err(Error::GenerationNotFound(_))
+        ));
+
+        let next = darwin.get_neighbor("41", Direction::Next).unwrap();
+        assert_eq!(next.map(|g| g.id).as_deref(), Some("42"));
+        assert!(darwin
+            .get_neighbor("42", Direction::Next)
+            .unwrap()
+            .is_none());
+    }
+}
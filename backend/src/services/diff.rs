@@ -0,0 +1,413 @@
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+
+use crate::error::{Error, Result};
+use crate::models::diff::{DetailedDiff, GenerationDiff, PackageChange};
+use crate::models::package::PackageChurn;
+use crate::models::store_path::StorePath;
+
+/// Compute a diff from two lists of store references.
+///
+/// `added` and `removed` are plain set differences. A path from `from_refs`
+/// counts as `modified` when `to_refs` contains a different path with the
+/// same package name. All three lists are sorted.
+pub fn diff_references(from_refs: &[String], to_refs: &[String]) -> GenerationDiff {
+    let from_set: HashSet<&str> = from_refs.iter().map(String::as_str).collect();
+    let to_set: HashSet<&str> = to_refs.iter().map(String::as_str).collect();
+
+    let mut added: Vec<String> = to_set
+        .difference(&from_set)
+        .map(|x| x.to_string())
+        .collect();
+    added.sort();
+
+    let mut removed: Vec<String> = from_set
+        .difference(&to_set)
+        .map(|x| x.to_string())
+        .collect();
+    removed.sort();
+
+    let mut to_by_name: HashMap<String, Vec<&str>> = HashMap::new();
+    for path in &to_set {
+        let name = StorePath::parse(path).name().to_string();
+        to_by_name.entry(name).or_default().push(path);
+    }
+
+    let mut modified: Vec<String> = from_set
+        .iter()
+        .filter(|x| {
+            to_by_name
+                .get(StorePath::parse(x).name())
+                .is_some_and(|paths| paths.iter().any(|y| y != *x))
+        })
+        .map(|x| x.to_string())
+        .collect();
+    modified.sort();
+
+    GenerationDiff {
+        added,
+        removed,
+        modified,
+        ..Default::default()
+    }
+}
+
+/// The paths in both `from_refs` and `to_refs`, sorted.
+pub fn common_references(from_refs: &[String], to_refs: &[String]) -> Vec<String> {
+    let from_set: HashSet<&str> = from_refs.iter().map(String::as_str).collect();
+    let to_set: HashSet<&str> = to_refs.iter().map(String::as_str).collect();
+
+    let mut common: Vec<String> = from_set
+        .intersection(&to_set)
+        .map(|x| x.to_string())
+        .collect();
+    common.sort();
+    common
+}
+
+/// A generation's direct references and full closure, for telling the
+/// packages it references itself from their dependencies.
+pub struct Dependencies {
+    direct_paths: HashSet<String>,
+    closure_paths: HashSet<String>,
+    direct_names: HashSet<String>,
+    closure_names: HashSet<String>,
+}
+
+impl Dependencies {
+    pub fn new(direct: &[String], closure: &[String]) -> Self {
+        let names = |refs: &[String]| -> HashSet<String> {
+            refs.iter()
+                .map(|path| StorePath::parse(path).name().to_string())
+                .collect()
+        };
+        Self {
+            direct_paths: direct.iter().cloned().collect(),
+            closure_paths: closure.iter().cloned().collect(),
+            direct_names: names(direct),
+            closure_names: names(closure),
+        }
+    }
+
+    /// `Some(true)` when one of the change's paths is a direct reference,
+    /// `Some(false)` when it is only deeper in the closure, and `None` when
+    /// it is in neither. Changes whose paths aren't found, such as the bare
+    /// names nix-diff prints or the pathless `nix store diff-closures`
+    /// entries, are matched by package name instead.
+    pub fn classify(&self, change: &PackageChange) -> Option<bool> {
+        let paths = || {
+            std::iter::once(&change.store_path)
+                .chain(&change.old_paths)
+                .chain(&change.new_paths)
+        };
+        if paths().any(|path| self.direct_paths.contains(path)) {
+            Some(true)
+        } else if paths().any(|path| self.closure_paths.contains(path)) {
+            Some(false)
+        } else if self.direct_names.contains(&change.name) {
+            Some(true)
+        } else if self.closure_names.contains(&change.name) {
+            Some(false)
+        } else {
+            None
+        }
+    }
+}
+
+/// Count, per package name, how often it appears in or disappears from
+/// one reference list to the next. Only packages that did both, i.e.
+/// flapped, are kept, most changes first and then by name.
+pub fn package_churn(generations: &[Vec<String>]) -> Vec<PackageChurn> {
+    let names: Vec<HashSet<String>> = generations
+        .iter()
+        .map(|refs| {
+            refs.iter()
+                .map(|path| StorePath::parse(path).name().to_string())
+                .collect()
+        })
+        .collect();
+
+    let mut counts: HashMap<&String, (usize, usize)> = HashMap::new();
+    for pair in names.windows(2) {
+        for name in pair[1].difference(&pair[0]) {
+            counts.entry(name).or_default().0 += 1;
+        }
+        for name in pair[0].difference(&pair[1]) {
+            counts.entry(name).or_default().1 += 1;
+        }
+    }
+
+    let mut churn: Vec<PackageChurn> = counts
+        .into_iter()
+        .filter(|(_, (added, removed))| *added > 0 && *removed > 0)
+        .map(|(package, (added, removed))| PackageChurn {
+            package: package.clone(),
+            changes: added + removed,
+            added,
+            removed,
+        })
+        .collect();
+    churn.sort_by(|a, b| {
+        b.changes
+            .cmp(&a.changes)
+            .then_with(|| a.package.cmp(&b.package))
+    });
+    churn
+}
+
+/// Split the references of generations slated for deletion into the paths
+/// each one holds on its own and the paths deleting all of them would free.
+///
+/// A path is unique to a candidate when no retained generation and no other
+/// candidate references it. The total is every candidate path that no
+/// retained generation references. All returned lists are sorted.
+pub fn unreferenced_paths(
+    candidates: &[Vec<String>],
+    retained: &[Vec<String>],
+) -> (Vec<Vec<String>>, Vec<String>) {
+    let retained_set: HashSet<&str> = retained.iter().flatten().map(String::as_str).collect();
+
+    let mut holders: HashMap<&str, usize> = HashMap::new();
+    for refs in candidates {
+        let set: HashSet<&str> = refs.iter().map(String::as_str).collect();
+        for path in set {
+            *holders.entry(path).or_default() += 1;
+        }
+    }
+
+    let unique = candidates
+        .iter()
+        .map(|refs| {
+            let set: HashSet<&str> = refs.iter().map(String::as_str).collect();
+            let mut paths: Vec<String> = set
+                .into_iter()
+                .filter(|path| holders[path] == 1 && !retained_set.contains(path))
+                .map(str::to_string)
+                .collect();
+            paths.sort();
+            paths
+        })
+        .collect();
+
+    let mut total: Vec<String> = holders
+        .into_keys()
+        .filter(|path| !retained_set.contains(path))
+        .map(str::to_string)
+        .collect();
+    total.sort();
+
+    (unique, total)
+}
+
+/// Narrows a diff to the packages whose parsed name matches `include`,
+/// then drops those matching `exclude`.
+#[derive(Debug, Default)]
+pub struct NameFilter {
+    include: Option<Regex>,
+    exclude: Option<Regex>,
+}
+
+impl NameFilter {
+    pub fn new(include: Option<&str>, exclude: Option<&str>) -> Result<Self> {
+        Ok(Self {
+            include: include.map(name_pattern).transpose()?,
+            exclude: exclude.map(name_pattern).transpose()?,
+        })
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        self.include.as_ref().is_none_or(|re| re.is_match(name))
+            && !self.exclude.as_ref().is_some_and(|re| re.is_match(name))
+    }
+
+    pub fn apply(&self, mut diff: GenerationDiff) -> GenerationDiff {
+        for paths in [&mut diff.added, &mut diff.removed, &mut diff.modified] {
+            paths.retain(|path| self.matches(StorePath::parse(path).name()));
+        }
+        diff
+    }
+
+    pub fn apply_detailed(&self, mut diff: DetailedDiff) -> DetailedDiff {
+        for changes in [&mut diff.added, &mut diff.removed, &mut diff.modified] {
+            changes.retain(|change| self.matches(&change.name));
+        }
+        diff
+    }
+}
+
+/// Compile a package-name pattern. Patterns using only `*`, `?`, `[...]`
+/// and `.` are globs matched against the whole name, so `python3.*` means
+/// names starting with `python3.`; anything else is a regex searched for
+/// anywhere in the name.
+fn name_pattern(pattern: &str) -> Result<Regex> {
+    let is_glob = !pattern.contains(['^', '$', '+', '(', ')', '|', '{', '}', '\\']);
+    let regex = if is_glob {
+        let mut regex = String::from("^");
+        for c in pattern.chars() {
+            match c {
+                '*' => regex.push_str(".*"),
+                '?' => regex.push('.'),
+                '[' | ']' => regex.push(c),
+                c => regex.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        regex.push('$');
+        regex
+    } else {
+        pattern.to_string()
+    };
+
+    Regex::new(&regex)
+        .map_err(|e| Error::InvalidArgument(format!("invalid pattern '{}': {}", pattern, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn refs(paths: &[&str]) -> Vec<String> {
+        paths.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn test_package_churn() {
+        let bash = "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-bash-5.2";
+        let vim = "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-vim-9.0";
+        let vim_new = "/nix/store/dddddddddddddddddddddddddddddddd-vim-9.1";
+        let htop = "/nix/store/cccccccccccccccccccccccccccccccc-htop-3.3.0";
+        let generations = [
+            refs(&[bash, vim]),
+            refs(&[bash, vim, htop]),
+            refs(&[bash, vim_new]),
+            refs(&[bash, vim_new, htop]),
+            refs(&[vim_new]),
+        ];
+
+        // vim only changed version and bash only disappeared once.
+        let churn = package_churn(&generations);
+        assert_eq!(
+            churn,
+            [PackageChurn {
+                package: "htop".to_string(),
+                changes: 4,
+                added: 2,
+                removed: 2,
+            }]
+        );
+        assert!(package_churn(&generations[..1]).is_empty());
+    }
+
+    #[test]
+    fn test_classify_dependencies() {
+        let firefox = "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-firefox-122.0";
+        let ffmpeg = "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-ffmpeg-6.1";
+        let dependencies = Dependencies::new(&refs(&[firefox]), &refs(&[firefox, ffmpeg]));
+        let diff = GenerationDiff {
+            added: refs(&[
+                firefox,
+                ffmpeg,
+                "/nix/store/cccccccccccccccccccccccccccccccc-htop-3.3.0",
+            ]),
+            ..Default::default()
+        }
+        .to_detailed();
+
+        let direct: Vec<Option<bool>> = diff
+            .added
+            .iter()
+            .map(|change| dependencies.classify(change))
+            .collect();
+        assert_eq!(direct, [Some(true), Some(false), None]);
+
+        // Without a path, as from `nix store diff-closures`, by name.
+        let mut change = diff.added[1].clone();
+        change.store_path = String::new();
+        assert_eq!(dependencies.classify(&change), Some(false));
+    }
+
+    #[test]
+    fn test_diff_references() {
+        let from = refs(&[
+            "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-vim-9.0",
+            "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-bash-5.2",
+            "/nix/store/cccccccccccccccccccccccccccccccc-openssl-3.0.10",
+        ]);
+        let to = refs(&[
+            "/nix/store/dddddddddddddddddddddddddddddddd-openssl-3.0.12",
+            "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-bash-5.2",
+            "/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-firefox-122.0",
+        ]);
+
+        let diff = diff_references(&from, &to);
+        assert_eq!(
+            diff.added,
+            refs(&[
+                "/nix/store/dddddddddddddddddddddddddddddddd-openssl-3.0.12",
+                "/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-firefox-122.0"
+            ])
+        );
+        assert_eq!(
+            diff.removed,
+            refs(&[
+                "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-vim-9.0",
+                "/nix/store/cccccccccccccccccccccccccccccccc-openssl-3.0.10"
+            ])
+        );
+        assert_eq!(
+            diff.modified,
+            refs(&["/nix/store/cccccccccccccccccccccccccccccccc-openssl-3.0.10"])
+        );
+        assert_eq!(
+            common_references(&from, &to),
+            refs(&["/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-bash-5.2"])
+        );
+    }
+
+    #[test]
+    fn test_unreferenced_paths() {
+        let bash = "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-bash-5.2";
+        let vim = "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-vim-9.0";
+        let openssl = "/nix/store/cccccccccccccccccccccccccccccccc-openssl-3.0.10";
+        let firefox = "/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-firefox-122.0";
+
+        let candidates = vec![refs(&[bash, vim, openssl]), refs(&[bash, openssl, firefox])];
+        let retained = vec![refs(&[bash])];
+
+        let (unique, total) = unreferenced_paths(&candidates, &retained);
+        assert_eq!(unique, vec![refs(&[vim]), refs(&[firefox])]);
+        assert_eq!(total, refs(&[vim, openssl, firefox]));
+    }
+
+    #[test]
+    fn test_name_filter() {
+        let diff = || GenerationDiff {
+            added: refs(&[
+                "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-python3.11-requests-2.31.0",
+                "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-python3.11-numpy-1.26.0",
+                "/nix/store/cccccccccccccccccccccccccccccccc-python3-3.11.7",
+            ]),
+            removed: refs(&["/nix/store/dddddddddddddddddddddddddddddddd-vim-9.0"]),
+            modified: vec![],
+            ..Default::default()
+        };
+
+        let filter = NameFilter::new(Some("python3.*"), None).unwrap();
+        assert_eq!(filter.apply(diff()).added.len(), 2);
+        assert!(filter.apply(diff()).removed.is_empty());
+
+        let filter = NameFilter::new(Some("^python3"), Some("*-numpy")).unwrap();
+        let filtered = filter.apply(diff());
+        assert_eq!(
+            filtered.added,
+            refs(&[
+                "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-python3.11-requests-2.31.0",
+                "/nix/store/cccccccccccccccccccccccccccccccc-python3-3.11.7",
+            ])
+        );
+
+        assert!(matches!(
+            NameFilter::new(Some("(python"), None),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+}
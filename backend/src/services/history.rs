@@ -0,0 +1,270 @@
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection};
+use std::env;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result};
+use crate::models::diff::GenerationDiff;
+use crate::models::generation::{compare_generation_ids, Generation};
+use crate::models::history::Snapshot;
+use crate::services::diff::diff_references;
+use crate::services::parse::generation_link;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS generations (
+        profile TEXT NOT NULL,
+        id TEXT NOT NULL,
+        timestamp TEXT NOT NULL,
+        description TEXT,
+        nixos_version TEXT,
+        kernel_version TEXT,
+        PRIMARY KEY (profile, id)
+    );
+    -- Generations share most of their references, so each path is stored once.
+    CREATE TABLE IF NOT EXISTS store_paths (
+        id INTEGER PRIMARY KEY,
+        path TEXT NOT NULL UNIQUE
+    );
+    CREATE TABLE IF NOT EXISTS generation_references (
+        profile TEXT NOT NULL,
+        generation TEXT NOT NULL,
+        store_path INTEGER NOT NULL REFERENCES store_paths (id),
+        PRIMARY KEY (profile, generation, store_path)
+    );
+";
+
+/// Generations and their references recorded by `snapshot`, so they can be
+/// listed and diffed after they have been garbage-collected.
+pub struct HistoryStore {
+    path: PathBuf,
+    conn: Connection,
+}
+
+/// `$XDG_DATA_HOME/nix-timemach/history.db`, defaulting to
+/// `~/.local/share/nix-timemach/history.db`.
+pub fn default_history_path() -> PathBuf {
+    env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")))
+        .unwrap_or_default()
+        .join("nix-timemach/history.db")
+}
+
+impl HistoryStore {
+    /// Open the database at `path`, creating it and its parent directories
+    /// if needed.
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent).map_err(|source| Error::OutputWriteFailed {
+                path: path.to_path_buf(),
+                source,
+            })?;
+        }
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            conn,
+        })
+    }
+
+    /// Open the database at `path`, failing if no snapshot created it yet.
+    pub fn open_existing(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Err(Error::InvalidArgument(format!(
+                "no history at {}; run `snapshot` first",
+                path.display()
+            )));
+        }
+        Self::open(path)
+    }
+
+    /// Record `generations` of `profile` with their references, replacing
+    /// what an earlier snapshot stored for the same ids.
+    pub fn record(
+        &mut self,
+        profile: &str,
+        generations: &[(Generation, Vec<String>)],
+    ) -> Result<Snapshot> {
+        let tx = self.conn.transaction()?;
+        let mut new_generations = 0;
+        for (generation, references) in generations {
+            let known: bool = tx.query_row(
+                "SELECT EXISTS (SELECT 1 FROM generations WHERE profile = ?1 AND id = ?2)",
+                params![profile, generation.id],
+                |row| row.get(0),
+            )?;
+            new_generations += usize::from(!known);
+
+            tx.execute(
+                "INSERT OR REPLACE INTO generations
+                     (profile, id, timestamp, description, nixos_version, kernel_version)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    profile,
+                    generation.id,
+                    generation.timestamp.to_rfc3339(),
+                    generation.description,
+                    generation.nixos_version,
+                    generation.kernel_version,
+                ],
+            )?;
+            tx.execute(
+                "DELETE FROM generation_references WHERE profile = ?1 AND generation = ?2",
+                params![profile, generation.id],
+            )?;
+            for path in references {
+                tx.execute(
+                    "INSERT OR IGNORE INTO store_paths (path) VALUES (?1)",
+                    params![path],
+                )?;
+                tx.execute(
+                    "INSERT OR IGNORE INTO generation_references (profile, generation, store_path)
+                     SELECT ?1, ?2, id FROM store_paths WHERE path = ?3",
+                    params![profile, generation.id, path],
+                )?;
+            }
+        }
+        let store_paths: i64 =
+            tx.query_row("SELECT COUNT(*) FROM store_paths", [], |row| row.get(0))?;
+        tx.commit()?;
+
+        Ok(Snapshot {
+            database: self.path.clone(),
+            profile: profile.to_string(),
+            generations: generations.len(),
+            new_generations,
+            store_paths: store_paths as usize,
+        })
+    }
+
+    /// Every recorded generation of `profile`, oldest id first. Whether a
+    /// generation is current or booted isn't recorded.
+    pub fn list_generations(&self, profile: &str) -> Result<Vec<Generation>> {
+        let mut statement = self.conn.prepare(
+            "SELECT id, timestamp, description, nixos_version, kernel_version
+             FROM generations WHERE profile = ?1",
+        )?;
+        let rows = statement.query_map(params![profile], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+            ))
+        })?;
+
+        let mut generations = Vec::new();
+        for row in rows {
+            let (id, timestamp, description, nixos_version, kernel_version) = row?;
+            let timestamp = DateTime::parse_from_rfc3339(&timestamp)
+                .map_err(|e| Error::ParseError(format!("bad timestamp in history: {}", e)))?
+                .with_timezone(&Utc);
+            generations.push(Generation {
+                profiles: vec![generation_link(profile, &id)],
+                id,
+                timestamp,
+                description,
+                nixos_version,
+                kernel_version,
+                ..Default::default()
+            });
+        }
+        generations.sort_by(|a, b| compare_generation_ids(&a.id, &b.id));
+        Ok(generations)
+    }
+
+    /// The references recorded for generation `id` of `profile`.
+    pub fn references(&self, profile: &str, id: &str) -> Result<Vec<String>> {
+        let known: bool = self.conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM generations WHERE profile = ?1 AND id = ?2)",
+            params![profile, id],
+            |row| row.get(0),
+        )?;
+        if !known {
+            return Err(Error::GenerationNotFound(id.to_string()));
+        }
+
+        let mut statement = self.conn.prepare(
+            "SELECT path FROM generation_references
+             JOIN store_paths ON store_paths.id = generation_references.store_path
+             WHERE profile = ?1 AND generation = ?2
+             ORDER BY path",
+        )?;
+        let paths = statement
+            .query_map(params![profile, id], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(paths)
+    }
+
+    /// Diff two recorded generations by their references.
+    pub fn diff(&self, profile: &str, from: &str, to: &str) -> Result<GenerationDiff> {
+        Ok(diff_references(
+            &self.references(profile, from)?,
+            &self.references(profile, to)?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROFILE: &str = "/nix/var/nix/profiles/system";
+
+    fn generation(id: &str) -> Generation {
+        Generation {
+            id: id.to_string(),
+            timestamp: DateTime::parse_from_rfc3339("2024-02-09T10:00:00Z")
+                .unwrap()
+                .with_timezone(&Utc),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_history_round_trip() {
+        let dir = std::env::temp_dir().join(format!("nix-timemach-history-{}", std::process::id()));
+        let path = dir.join("history.db");
+        let bash = "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-bash-5.2".to_string();
+        let vim = "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-vim-9.0".to_string();
+
+        let mut store = HistoryStore::open(&path).unwrap();
+        let snapshot = store
+            .record(
+                PROFILE,
+                &[
+                    (generation("1"), vec![bash.clone(), vim.clone()]),
+                    (generation("2"), vec![bash.clone()]),
+                ],
+            )
+            .unwrap();
+        assert_eq!(snapshot.new_generations, 2);
+        // `bash` is shared, and stored once.
+        assert_eq!(snapshot.store_paths, 2);
+
+        // Generation 1 is gone from the profile by the next snapshot.
+        let snapshot = store
+            .record(PROFILE, &[(generation("2"), vec![bash.clone()])])
+            .unwrap();
+        assert_eq!(snapshot.new_generations, 0);
+
+        let store = HistoryStore::open_existing(&path).unwrap();
+        let ids: Vec<String> = store
+            .list_generations(PROFILE)
+            .unwrap()
+            .into_iter()
+            .map(|g| g.id)
+            .collect();
+        assert_eq!(ids, ["1", "2"]);
+        assert_eq!(store.diff(PROFILE, "1", "2").unwrap().removed, [vim]);
+        assert!(matches!(
+            store.references(PROFILE, "3"),
+            Err(Error::GenerationNotFound(_))
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(HistoryStore::open_existing(&path).is_err());
+    }
+}
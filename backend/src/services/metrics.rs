@@ -0,0 +1,119 @@
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use std::time::Duration;
+
+/// Counters `serve --enable-metrics` exposes on `/metrics`, in the
+/// Prometheus text format. Commands are labelled with the program run, e.g.
+/// `nix-store`, even when it runs on a remote host through ssh.
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounter,
+    commands: IntCounterVec,
+    command_failures: IntCounterVec,
+    command_duration: HistogramVec,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let requests =
+            IntCounter::new("nix_timemach_http_requests_total", "HTTP requests served").unwrap();
+        let commands = IntCounterVec::new(
+            Opts::new("nix_timemach_commands_total", "Commands run"),
+            &["program"],
+        )
+        .unwrap();
+        let command_failures = IntCounterVec::new(
+            Opts::new(
+                "nix_timemach_command_failures_total",
+                "Commands that couldn't be run, timed out or exited non-zero",
+            ),
+            &["program"],
+        )
+        .unwrap();
+        let command_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "nix_timemach_command_duration_seconds",
+                "How long commands took to run",
+            )
+            .buckets(vec![
+                0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+            ]),
+            &["program"],
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(commands.clone())).unwrap();
+        registry
+            .register(Box::new(command_failures.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(command_duration.clone()))
+            .unwrap();
+        Self {
+            registry,
+            requests,
+            commands,
+            command_failures,
+            command_duration,
+        }
+    }
+
+    pub fn record_request(&self) {
+        self.requests.inc();
+    }
+
+    /// Count a run of `program` that took `duration`.
+    pub fn record_command(&self, program: &str, duration: Duration, succeeded: bool) {
+        self.commands.with_label_values(&[program]).inc();
+        self.command_duration
+            .with_label_values(&[program])
+            .observe(duration.as_secs_f64());
+        if !succeeded {
+            self.command_failures.with_label_values(&[program]).inc();
+        }
+    }
+
+    /// Every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        // Encoding into a Vec only fails on malformed metric families,
+        // which the fixed set above can't produce.
+        let _ = TextEncoder::new().encode(&self.registry.gather(), &mut buffer);
+        String::from_utf8_lossy(&buffer).into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_counts_commands() {
+        let metrics = Metrics::new();
+        metrics.record_request();
+        metrics.record_command("nix-store", Duration::from_millis(20), true);
+        metrics.record_command("nix-store", Duration::from_millis(200), false);
+
+        let text = metrics.render();
+        assert!(
+            text.contains("nix_timemach_http_requests_total 1\n"),
+            "{}",
+            text
+        );
+        assert!(text.contains("nix_timemach_commands_total{program=\"nix-store\"} 2\n"));
+        assert!(text.contains("nix_timemach_command_failures_total{program=\"nix-store\"} 1\n"));
+        assert!(text.contains(
+            "nix_timemach_command_duration_seconds_bucket{program=\"nix-store\",le=\"0.05\"} 1\n"
+        ));
+    }
+}
@@ -1 +1,17 @@
+pub mod async_nix;
+pub mod cache;
+pub mod darwin;
+pub mod diff;
+pub mod history;
+pub mod metrics;
 pub mod nix;
+pub mod nix_profile;
+pub mod notes;
+pub mod parallel;
+pub mod parse;
+pub mod profile;
+pub mod provider;
+pub mod queries;
+pub mod runner;
+pub mod timeline;
+pub mod watch;
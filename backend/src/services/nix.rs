@@ -1,22 +1,293 @@
 use chrono::{DateTime, Utc};
-use regex::Regex;
-use std::process::Command;
+use std::collections::{BTreeMap, HashMap};
+use std::future::{ready, Future};
+use std::path::{Path, PathBuf};
+use std::process::Output;
+use std::time::Duration;
+use tracing::warn;
 
 use crate::error::{Error, Result};
-use crate::models::diff::GenerationDiff;
-use crate::models::generation::Generation;
+use crate::models::deletion::DeletionPlan;
+use crate::models::derivation::DerivationChange;
+use crate::models::diff::{
+    ChangeDirection, CommonReferences, DetailedDiff, DiffMode, GenerationDiff,
+};
+use crate::models::gc::{GcPreview, GcResult, ReclaimableGeneration};
+use crate::models::generation::{compare_generation_ids, FlakeInfo, Generation};
+use crate::models::package::{PackageChurn, PackageIntroduction, PackageTransition};
+use crate::models::partial::Partial;
+use crate::models::rollback::RollbackPlan;
+use crate::models::size::{PackageSize, SizeDiff};
+use crate::models::store_path::StorePath;
+use crate::models::vulnerability::{GenerationScan, ScanDiff};
+use crate::services::cache::{DiffCache, GenerationCache};
+use crate::services::diff::{
+    common_references, diff_references, package_churn, unreferenced_paths, Dependencies,
+};
+use crate::services::parallel::{for_each_bounded, map_bounded};
+use crate::services::parse::{
+    parse_closure_diff_output, parse_derivation_diff, parse_flake_info, parse_gc_output,
+    parse_path_info, parse_references, parse_vulnix_output,
+};
+use crate::services::provider::GenerationProvider;
+use crate::services::queries::{block_on, Exec, Queries};
+use crate::services::runner::{CommandRunner, RealCommandRunner, RetryPolicy};
 
-pub struct NixService;
+pub struct NixService<R: CommandRunner = RealCommandRunner> {
+    runner: R,
+    queries: Queries,
+    strict: bool,
+}
+
+/// Commands run at once when a query touches many generations.
+pub const DEFAULT_JOBS: usize = 4;
 
 impl NixService {
     pub fn new() -> Self {
-        Self
+        Self::with_runner(RealCommandRunner::default())
+    }
+}
+
+impl Default for NixService {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<R: CommandRunner> NixService<R> {
+    pub fn with_runner(runner: R) -> Self {
+        Self {
+            runner,
+            queries: Queries::default(),
+            strict: false,
+        }
+    }
+
+    /// Operate on `path` instead of the system profile.
+    pub fn with_profile(mut self, path: impl Into<PathBuf>) -> Self {
+        self.queries.profile_path = path.into();
+        self
+    }
+
+    /// Retry transient command failures according to `policy`.
+    pub fn with_retry(mut self, policy: RetryPolicy) -> Self {
+        self.queries.retry = policy;
+        self
+    }
+
+    /// Run at most `jobs` commands concurrently; `1` runs everything in
+    /// sequence.
+    pub fn with_jobs(mut self, jobs: usize) -> Self {
+        self.queries.jobs = jobs.max(1);
+        self
+    }
+
+    /// Reuse the generation list for up to `ttl`, or until the profile
+    /// switches generations. A zero TTL (the default) disables the cache.
+    pub fn with_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.queries.cache = GenerationCache::new(ttl);
+        self
+    }
+
+    /// Pass `--store uri` to every nix command, e.g. `/mnt` to inspect a
+    /// system mounted from a rescue environment.
+    pub fn with_store(mut self, uri: impl Into<String>) -> Self {
+        self.queries.store = Some(uri.into());
+        self
+    }
+
+    /// Keep the `capacity` most recently used diffs of each shape; `0`
+    /// recomputes every diff.
+    pub fn with_diff_cache(mut self, capacity: usize) -> Self {
+        self.queries.path_diffs = DiffCache::new(capacity);
+        self.queries.package_diffs = DiffCache::new(capacity);
+        self
+    }
+
+    fn clear_diffs(&self) {
+        self.queries.path_diffs.clear();
+        self.queries.package_diffs.clear();
+    }
+
+    fn cached_paths(
+        &self,
+        from: &str,
+        to: &str,
+        mode: DiffMode,
+        diff: impl FnOnce() -> Result<GenerationDiff>,
+    ) -> Result<GenerationDiff> {
+        let key = self.queries.diff_key(from, to, mode);
+        block_on(async {
+            let modified = self.queries.profile_modified(self).await;
+            self.queries
+                .path_diffs
+                .get_or_try_insert(modified, key, async { diff() })
+                .await
+        })
+    }
+
+    /// Fail range queries on the first generation that can't be read,
+    /// instead of leaving it out with a warning.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    pub fn profile_path(&self) -> &Path {
+        self.queries.profile_path()
+    }
+
+    fn profile(&self) -> String {
+        self.queries.profile()
+    }
+
+    /// Run a command, retrying with exponential backoff while it fails with
+    /// a stderr the retry policy considers transient.
+    pub(crate) fn run(&self, program: &str, args: &[&str]) -> Result<Output> {
+        block_on(self.queries.run(self, program, args))
+    }
+
+    fn generation_link(&self, id: &str) -> String {
+        self.queries.generation_link(id)
     }
 
     pub fn list_generations(&self) -> Result<Vec<Generation>> {
-        let output = Command::new("nix-env")
-            .args(["--list-generations", "-p", "/nix/var/nix/profiles/system"])
-            .output()?;
+        block_on(self.queries.list_generations(self))
+    }
+
+    /// The generation list as printed by nix, without reading any versions
+    /// or boot state from the store.
+    pub fn read_generations(&self) -> Result<Vec<Generation>> {
+        block_on(self.queries.read_generations(self))
+    }
+
+    pub fn generation_exists(&self, id: &str) -> Result<bool> {
+        block_on(self.queries.generation_exists(self, id))
+    }
+
+    /// Map a generation ref to a concrete id. Besides plain ids this accepts
+    /// `current` (or `HEAD`), `previous`, `booted`, and `HEAD~N` for the Nth
+    /// generation before the current one.
+    pub fn resolve_ref(&self, reference: &str) -> Result<String> {
+        block_on(self.queries.resolve_ref(self, reference))
+    }
+
+    /// The generation right before or after `id`, or `None` at either end
+    /// of the profile's history.
+    pub fn neighbor(&self, id: &str, direction: Direction) -> Result<Option<String>> {
+        let ids = self.read_generations()?.into_iter().map(|g| g.id).collect();
+        step(ids, id, 1, direction)
+    }
+
+    /// Resolve both ends of a `FROM..TO` range, lowest id first.
+    pub fn resolve_range(&self, range: &str) -> Result<(String, String)> {
+        let (from, to) = range.split_once("..").ok_or_else(|| {
+            Error::InvalidArgument(format!("invalid range '{}', expected FROM..TO", range))
+        })?;
+        let from = self.resolve_ref(from)?;
+        let to = self.resolve_ref(to)?;
+
+        if compare_generation_ids(&from, &to).is_gt() {
+            Ok((to, from))
+        } else {
+            Ok((from, to))
+        }
+    }
+
+    /// Set `booted` on the generation whose store path `/run/booted-system`
+    /// points at. Without `/run/booted-system` every flag stays `false`.
+    fn mark_booted(&self, generations: &mut [Generation]) {
+        block_on(self.queries.mark_booted(self, generations))
+    }
+
+    /// Id of the generation the machine was booted into, if it is still in
+    /// the profile.
+    pub fn get_booted_generation(&self) -> Result<Option<String>> {
+        block_on(self.queries.get_booted_generation(self))
+    }
+
+    pub fn get_booted(&self) -> Result<Generation> {
+        block_on(self.queries.get_booted(self))
+    }
+
+    /// Read `<path>/nixos-version` from a system generation.
+    pub fn read_nixos_version(&self, store_path: &str) -> Result<Option<String>> {
+        block_on(self.queries.read_nixos_version(self, store_path))
+    }
+
+    /// The flake revision generation `id` was built from, from
+    /// `<generation>/nixos-version.json`. `None` when the generation didn't
+    /// record one, e.g. on systems not built from a flake.
+    pub fn generation_flake_info(&self, id: &str) -> Result<Option<FlakeInfo>> {
+        let link = self.generation_link(id);
+        let output = self.run("cat", &[&format!("{}/nixos-version.json", link)])?;
+
+        if !output.status.success() {
+            return Ok(None);
+        }
+
+        Ok(parse_flake_info(&String::from_utf8_lossy(&output.stdout)))
+    }
+
+    /// Resolve `<path>/kernel` and take the version of the kernel package it
+    /// points into, e.g. `/nix/store/<hash>-linux-6.6.15/bzImage`.
+    pub fn read_kernel_version(&self, store_path: &str) -> Result<Option<String>> {
+        block_on(self.queries.read_kernel_version(self, store_path))
+    }
+
+    /// The generation the profile symlink currently points at, with its
+    /// timestamp and description from the generation list.
+    pub fn get_current(&self) -> Result<Generation> {
+        block_on(self.queries.get_current(self))
+    }
+
+    pub(crate) fn get_current_generation(&self) -> Result<String> {
+        block_on(self.queries.get_current_generation(self))
+    }
+
+    /// Diff two generations with `nix-diff`, or by their references when
+    /// nix-diff isn't installed.
+    pub fn get_diff(&self, from: &str, to: &str) -> Result<GenerationDiff> {
+        block_on(self.queries.get_diff(self, from, to))
+    }
+
+    /// Diff the derivations of two generations with `nix-diff`.
+    pub fn get_nix_diff(&self, from: &str, to: &str) -> Result<GenerationDiff> {
+        block_on(self.queries.get_nix_diff(self, from, to))
+    }
+
+    /// Each derivation nix-diff descends into between two generations, with
+    /// the inputs and environment variables that changed.
+    pub fn get_derivation_diff(&self, from: &str, to: &str) -> Result<Vec<DerivationChange>> {
+        if same_generation(from, to)? {
+            return Ok(Vec::new());
+        }
+
+        let (from_path, to_path) = self.both(from, to, |id| self.get_generation_store_path(id))?;
+        Ok(parse_derivation_diff(
+            &self.run_nix_diff(&from_path, &to_path)?,
+        ))
+    }
+
+    fn run_nix_diff(&self, from_path: &str, to_path: &str) -> Result<String> {
+        block_on(self.queries.run_nix_diff(self, from_path, to_path))
+    }
+
+    /// Closure size of generation `id` in bytes, from `nix path-info -S`.
+    pub fn generation_size(&self, id: &str) -> Result<u64> {
+        self.path_info(&[&self.store_target(id)?])?
+            .iter()
+            .map(|(_, entry)| entry.get("closureSize").and_then(|size| size.as_u64()))
+            .sum::<Option<u64>>()
+            .ok_or_else(|| Error::ParseError("missing closureSize in path-info output".into()))
+    }
+
+    /// Run `nix path-info -S --json` on `paths`, returning each entry with
+    /// the store path it describes.
+    fn path_info(&self, paths: &[&str]) -> Result<Vec<(String, serde_json::Value)>> {
+        let mut args = vec!["path-info", "-S", "--json"];
+        args.extend_from_slice(paths);
+        let output = self.run("nix", &args)?;
 
         if !output.status.success() {
             return Err(Error::NixCommandError(
@@ -24,42 +295,236 @@ impl NixService {
             ));
         }
 
-        let output_str = String::from_utf8_lossy(&output.stdout);
-        self.parse_generations_output(&output_str)
+        parse_path_info(&output.stdout)
     }
 
-    fn parse_generations_output(&self, output: &str) -> Result<Vec<Generation>> {
-        let re = Regex::new(r"^\s*(\d+)\s+(\d{4}-\d{2}-\d{2}\s+\d{2}:\d{2}:\d{2})\s+(.*)$")
-            .map_err(|e| Error::ParseError(e.to_string()))?;
+    /// `field` (`narSize` or `closureSize`) of each of `paths`. A path
+    /// without it counts as 0, with a warning.
+    fn sizes(&self, paths: &[&str], field: &str) -> Result<Vec<(String, u64)>> {
+        Ok(self
+            .path_info(paths)?
+            .into_iter()
+            .map(|(path, entry)| {
+                let size = entry[field].as_u64().unwrap_or_else(|| {
+                    warn!(path, field, "no size in path-info output, counting it as 0");
+                    0
+                });
+                (path, size)
+            })
+            .collect())
+    }
 
-        let current_generation = self.get_current_generation()?;
+    /// How the closure size changes from `from` to `to`. With `breakdown`,
+    /// also the size change of each package among the changed references.
+    pub fn size_diff(&self, from: &str, to: &str, breakdown: bool) -> Result<SizeDiff> {
+        let (from_bytes, to_bytes) = self.both(from, to, |id| {
+            let sizes = self.sizes(&[&self.store_target(id)?], "closureSize")?;
+            Ok(sizes.iter().map(|(_, size)| size).sum::<u64>())
+        })?;
 
-        let mut generations = Vec::new();
-        for line in output.lines() {
-            if let Some(caps) = re.captures(line) {
-                let id = caps[1].to_string();
-                let timestamp = DateTime::parse_from_str(&caps[2], "%Y-%m-%d %H:%M:%S")
-                    .map_err(|e| Error::ParseError(e.to_string()))?
-                    .with_timezone(&Utc);
-                let description = Some(caps[3].trim().to_string());
+        let packages = if breakdown {
+            self.package_sizes(&self.get_reference_diff(from, to)?)?
+        } else {
+            Vec::new()
+        };
 
-                generations.push(Generation {
-                    id: id.clone(),
-                    timestamp,
-                    description,
-                    profiles: vec![format!("/nix/var/nix/profiles/system-{}-link", id)],
-                    current: id == current_generation,
-                });
+        Ok(SizeDiff {
+            from: from.to_string(),
+            to: to.to_string(),
+            from_bytes,
+            to_bytes,
+            delta_bytes: to_bytes as i64 - from_bytes as i64,
+            packages,
+            hidden_packages: 0,
+        })
+    }
+
+    /// Sum the removed and added paths of `diff` per package name.
+    fn package_sizes(&self, diff: &GenerationDiff) -> Result<Vec<PackageSize>> {
+        let paths: Vec<&str> = diff
+            .removed
+            .iter()
+            .chain(&diff.added)
+            .map(String::as_str)
+            .collect();
+        if paths.is_empty() {
+            return Ok(Vec::new());
+        }
+        let sizes: HashMap<String, u64> = self.sizes(&paths, "narSize")?.into_iter().collect();
+        let size_of = |path: &String| {
+            sizes.get(path).copied().unwrap_or_else(|| {
+                warn!(path, "path missing from path-info output, counting it as 0");
+                0
+            })
+        };
+
+        let mut packages: BTreeMap<String, (u64, u64)> = BTreeMap::new();
+        for path in &diff.removed {
+            packages
+                .entry(StorePath::parse(path).name().to_string())
+                .or_default()
+                .0 += size_of(path);
+        }
+        for path in &diff.added {
+            packages
+                .entry(StorePath::parse(path).name().to_string())
+                .or_default()
+                .1 += size_of(path);
+        }
+
+        let mut packages: Vec<PackageSize> = packages
+            .into_iter()
+            .map(|(name, (old_bytes, new_bytes))| PackageSize {
+                name,
+                old_bytes,
+                new_bytes,
+                delta_bytes: new_bytes as i64 - old_bytes as i64,
+            })
+            .collect();
+        // Stable, so equal changes stay sorted by name.
+        packages.sort_by_key(|package| std::cmp::Reverse(package.delta_bytes.unsigned_abs()));
+        Ok(packages)
+    }
+
+    /// Estimate what deleting every generation except the current and the
+    /// booted one would free, from the generations' store references.
+    pub fn gc_preview(&self) -> Result<GcPreview> {
+        let current = self.get_current_generation()?;
+        let mut generations = self.read_generations()?;
+        self.mark_booted(&mut generations);
+        generations.sort_by(|a, b| compare_generation_ids(&a.id, &b.id));
+
+        let (retained, candidates): (Vec<Generation>, Vec<Generation>) = generations
+            .into_iter()
+            .partition(|g| g.id == current || g.booted);
+        let references = |generations: &[Generation]| {
+            map_bounded(generations, self.queries.jobs, |g| {
+                self.store_target(&g.id)
+                    .and_then(|path| self.get_references(&path))
+            })
+        };
+        // Without the retained generations' references every path would look
+        // reclaimable, so those must always be read.
+        let retained = references(&retained)
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?;
+        let Partial {
+            results: candidates,
+            warnings,
+        } = self.skip_failed(&candidates, references(&candidates))?;
+        let (candidates, candidate_refs): (Vec<&Generation>, Vec<Vec<String>>) =
+            candidates.into_iter().unzip();
+        let (unique, total) = unreferenced_paths(&candidate_refs, &retained);
+
+        let sizes: HashMap<String, u64> = if total.is_empty() {
+            HashMap::new()
+        } else {
+            let paths: Vec<&str> = total.iter().map(String::as_str).collect();
+            self.path_info(&paths)?
+                .into_iter()
+                .map(|(path, entry)| (path, entry["narSize"].as_u64().unwrap_or(0)))
+                .collect()
+        };
+        let size_of =
+            |paths: &[String]| -> u64 { paths.iter().filter_map(|path| sizes.get(path)).sum() };
+
+        Ok(GcPreview {
+            generations: candidates
+                .iter()
+                .zip(&unique)
+                .map(|(generation, paths)| ReclaimableGeneration {
+                    id: generation.id.clone(),
+                    paths: paths.len(),
+                    reclaimable_bytes: size_of(paths),
+                })
+                .collect(),
+            total_bytes: size_of(&total),
+            warnings,
+        })
+    }
+
+    /// Pair each generation with its `results` entry, leaving out those that
+    /// failed with a warning naming them, or returning the first error when
+    /// strict.
+    fn skip_failed<'a, T>(
+        &self,
+        generations: &'a [Generation],
+        results: Vec<Result<T>>,
+    ) -> Result<Partial<Vec<(&'a Generation, T)>>> {
+        let mut read = Vec::new();
+        let mut warnings = Vec::new();
+        for (generation, result) in generations.iter().zip(results) {
+            match result {
+                Ok(value) => read.push((generation, value)),
+                Err(e) if !self.strict => {
+                    warn!("skipping generation {}: {}", generation.id, e);
+                    warnings.push(format!(
+                        "generation {}: {}",
+                        generation.id,
+                        e.to_string().trim_end()
+                    ));
+                }
+                Err(e) => return Err(e),
             }
         }
+        Ok(Partial {
+            results: read,
+            warnings,
+        })
+    }
 
-        Ok(generations)
+    /// Diff two generations by comparing the direct store references of
+    /// their profile links (`nix-store -q --references`).
+    pub fn get_reference_diff(&self, from: &str, to: &str) -> Result<GenerationDiff> {
+        block_on(self.queries.get_reference_diff(self, from, to))
     }
 
-    fn get_current_generation(&self) -> Result<String> {
-        let output = Command::new("readlink")
-            .args(["/nix/var/nix/profiles/system"])
-            .output()?;
+    /// The direct references generations `from` and `to` have in common.
+    pub fn get_common_references(&self, from: &str, to: &str) -> Result<CommonReferences> {
+        same_generation(from, to)?;
+        let (from_refs, to_refs) = self
+            .both(from, to, |id| {
+                self.store_target(id)
+                    .and_then(|path| self.get_references(&path))
+            })
+            .map_err(|e| self.unless_empty(e))?;
+
+        let common = common_references(&from_refs, &to_refs);
+        Ok(CommonReferences {
+            from: from.to_string(),
+            to: to.to_string(),
+            count: common.len(),
+            common,
+        })
+    }
+
+    fn get_references(&self, path: &str) -> Result<Vec<String>> {
+        block_on(self.queries.get_references(self, path))
+    }
+
+    /// Like `get_reference_diff`, over the generations' full closures
+    /// (`nix-store -q --requisites`) instead of their direct references, so
+    /// changes deep in the dependency tree show up too.
+    pub fn get_requisites_diff(&self, from: &str, to: &str) -> Result<GenerationDiff> {
+        self.cached_paths(from, to, DiffMode::Requisites, || {
+            if same_generation(from, to)? {
+                return Ok(GenerationDiff::default());
+            }
+
+            let (from_paths, to_paths) = self
+                .both(from, to, |id| {
+                    self.store_target(id)
+                        .and_then(|path| self.get_requisites(&path))
+                })
+                .map_err(|e| self.unless_empty(e))?;
+
+            Ok(diff_references(&from_paths, &to_paths))
+        })
+    }
+
+    /// The full closure of `path` (`nix-store -q --requisites`).
+    fn get_requisites(&self, path: &str) -> Result<Vec<String>> {
+        let output = self.run("nix-store", &["-q", "--requisites", path])?;
 
         if !output.status.success() {
             return Err(Error::NixCommandError(
@@ -67,92 +532,2097 @@ impl NixService {
             ));
         }
 
-        let path = String::from_utf8_lossy(&output.stdout);
-        let re = Regex::new(r"system-(\d+)-link").map_err(|e| Error::ParseError(e.to_string()))?;
+        Ok(parse_references(&String::from_utf8_lossy(&output.stdout)))
+    }
 
-        if let Some(caps) = re.captures(&path) {
-            Ok(caps[1].to_string())
-        } else {
-            Err(Error::ParseError(
-                "Failed to extract current generation ID".into(),
+    /// Tag the changes in `diff` as direct or transitive dependencies:
+    /// added and modified packages by the references of `to`, removed ones
+    /// (and modified ones gone from `to`) by those of `from`.
+    pub fn classify_dependencies(
+        &self,
+        from: &str,
+        to: &str,
+        diff: &mut DetailedDiff,
+    ) -> Result<()> {
+        if same_generation(from, to)? {
+            return Ok(());
+        }
+        let (from, to) = self.both(from, to, |id| {
+            let path = self.store_target(id)?;
+            Ok(Dependencies::new(
+                &self.get_references(&path)?,
+                &self.get_requisites(&path)?,
             ))
+        })?;
+
+        for change in &mut diff.removed {
+            change.direct = from.classify(change);
         }
+        for change in &mut diff.added {
+            change.direct = to.classify(change);
+        }
+        for change in &mut diff.modified {
+            change.direct = to.classify(change).or_else(|| from.classify(change));
+        }
+        Ok(())
     }
 
-    pub fn get_diff(&self, from: &str, to: &str) -> Result<GenerationDiff> {
-        // Get store paths for both generations
-        let from_path = self.get_generation_store_path(from)?;
-        let to_path = self.get_generation_store_path(to)?;
+    /// Every generation with its direct references, for `snapshot`.
+    pub fn list_with_references(&self) -> Result<Vec<(Generation, Vec<String>)>> {
+        let generations = self.list_generations()?;
+        let references = map_bounded(&generations, self.queries.jobs, |g| {
+            self.store_target(&g.id)
+                .and_then(|path| self.get_references(&path))
+        });
+        generations
+            .into_iter()
+            .zip(references)
+            .map(|(generation, references)| Ok((generation, references?)))
+            .collect()
+    }
+
+    /// The oldest generation whose direct references include a store path
+    /// named `package`, at `version` if given.
+    pub fn find_package(
+        &self,
+        package: &str,
+        version: Option<&str>,
+    ) -> Result<PackageIntroduction> {
+        let mut generations = self.read_generations()?;
+        generations.sort_by(|a, b| compare_generation_ids(&a.id, &b.id));
+
+        // Resolve `jobs` generations at a time, so the scan stops at the
+        // first batch with a match instead of resolving every generation.
+        for batch in generations.chunks(self.queries.jobs) {
+            let references = map_bounded(batch, self.queries.jobs, |g| {
+                self.store_target(&g.id)
+                    .and_then(|path| self.get_references(&path))
+            });
+            for (generation, references) in batch.iter().zip(references) {
+                let found = references?
+                    .iter()
+                    .map(|path| StorePath::parse(path))
+                    .find(|path| {
+                        path.name() == package && version.is_none_or(|v| path.version() == Some(v))
+                    });
+                if let Some(path) = found {
+                    return Ok(PackageIntroduction {
+                        package: package.to_string(),
+                        version: path.version().map(str::to_string),
+                        generation: generation.id.clone(),
+                        timestamp: generation.timestamp,
+                        store_path: path.path().to_string(),
+                    });
+                }
+            }
+        }
+
+        Err(Error::PackageNotFound(match version {
+            Some(version) => format!("{} {}", package, version),
+            None => package.to_string(),
+        }))
+    }
+
+    /// Every generation from `from` to `to` (inclusive, in either order)
+    /// where the versions of `package` differ from the generation before.
+    /// Generations that can't be read are skipped, so a transition may span
+    /// several of them.
+    pub fn bisect_package(
+        &self,
+        package: &str,
+        from: &str,
+        to: &str,
+    ) -> Result<Partial<Vec<PackageTransition>>> {
+        let generations = self.generations_between(from, to)?;
+        let references = map_bounded(&generations, self.queries.jobs, |g| {
+            self.store_target(&g.id)
+                .and_then(|path| self.get_references(&path))
+        });
+        let Partial {
+            results: read,
+            warnings,
+        } = self.skip_failed(&generations, references)?;
+        let versions: Vec<(&Generation, Option<String>)> = read
+            .into_iter()
+            .map(|(generation, references)| (generation, package_versions(&references, package)))
+            .collect();
+
+        let results = versions
+            .windows(2)
+            .filter(|pair| pair[0].1 != pair[1].1)
+            .map(|pair| {
+                let ((previous, old_version), (generation, new_version)) = (&pair[0], &pair[1]);
+                PackageTransition {
+                    generation: generation.id.clone(),
+                    previous_generation: previous.id.clone(),
+                    package: package.to_string(),
+                    old_version: old_version.clone(),
+                    new_version: new_version.clone(),
+                    direction: ChangeDirection::between(
+                        old_version.as_deref(),
+                        new_version.as_deref(),
+                    ),
+                }
+            })
+            .collect();
+        Ok(Partial { results, warnings })
+    }
+
+    /// The `top` packages that most often flapped in and out of the direct
+    /// references of consecutive generations from `from` to `to`
+    /// (inclusive, in either order).
+    pub fn churn(&self, from: &str, to: &str, top: usize) -> Result<Vec<PackageChurn>> {
+        let generations = self.generations_between(from, to)?;
+        let references = map_bounded(&generations, self.queries.jobs, |g| {
+            self.store_target(&g.id)
+                .and_then(|path| self.get_references(&path))
+        })
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+
+        let mut churn = package_churn(&references);
+        churn.truncate(top);
+        Ok(churn)
+    }
+
+    /// The generations from `from` to `to`, inclusive and in either order,
+    /// oldest first.
+    fn generations_between(&self, from: &str, to: &str) -> Result<Vec<Generation>> {
+        let (from, to) = match compare_generation_ids(from, to) {
+            std::cmp::Ordering::Greater => (to, from),
+            _ => (from, to),
+        };
+        let mut generations = self.read_generations()?;
+        for id in [from, to] {
+            if !generations.iter().any(|g| g.id == id) {
+                return Err(Error::GenerationNotFound(id.to_string()));
+            }
+        }
+        generations.retain(|g| {
+            compare_generation_ids(&g.id, from).is_ge() && compare_generation_ids(&g.id, to).is_le()
+        });
+        generations.sort_by(|a, b| compare_generation_ids(&a.id, &b.id));
+        Ok(generations)
+    }
+
+    /// Activate generation `id` via `nix-env --switch-generation` followed by
+    /// its `switch-to-configuration switch`. With `dry_run` nothing is run.
+    pub fn rollback(&self, id: &str, dry_run: bool) -> Result<RollbackPlan> {
+        let generations = self.list_generations()?;
+        if !generations.iter().any(|g| g.id == id) {
+            return Err(Error::GenerationNotFound(id.to_string()));
+        }
+
+        let profile = self.profile();
+        let link = self.generation_link(id);
+        // Home Manager generations activate themselves; everything else is
+        // assumed to be a NixOS system profile.
+        let (activate, activate_args): (String, &[&str]) =
+            if self.queries.profile_path.file_name() == Some("home-manager".as_ref()) {
+                (format!("{}/activate", link), &[])
+            } else {
+                (format!("{}/bin/switch-to-configuration", link), &["switch"])
+            };
+
+        let commands = vec![
+            format!("nix-env -p {} --switch-generation {}", profile, id),
+            std::iter::once(activate.as_str())
+                .chain(activate_args.iter().copied())
+                .collect::<Vec<_>>()
+                .join(" "),
+        ];
+
+        if !dry_run {
+            if self.requires_root() && !self.is_root()? {
+                return Err(Error::PermissionDenied(
+                    "rollback must be run as root (try sudo, or pass --dry-run)".to_string(),
+                ));
+            }
+
+            self.queries.cache.invalidate(&self.queries.profile_path);
+            self.run_checked("nix-env", &["-p", &profile, "--switch-generation", id])?;
+            self.run_checked(&activate, activate_args)?;
+        }
+
+        Ok(RollbackPlan {
+            generation: id.to_string(),
+            dry_run,
+            commands,
+        })
+    }
+
+    /// Delete `ids` from the profile. The current and the booted generation
+    /// are never deleted.
+    pub fn delete_generations(&self, ids: &[String], dry_run: bool) -> Result<DeletionPlan> {
+        let current = self.get_current_generation()?;
+        let mut generations = self.read_generations()?;
+        self.mark_booted(&mut generations);
 
-        // Use nix-diff to compare the generations
-        let output = Command::new("nix-diff")
-            .arg(&from_path)
-            .arg(&to_path)
-            .output()?;
+        for id in ids {
+            let generation = generations
+                .iter()
+                .find(|g| g.id == *id)
+                .ok_or_else(|| Error::GenerationNotFound(id.clone()))?;
+            let reason = if *id == current {
+                "it is the current generation"
+            } else if generation.booted {
+                "it is the booted generation"
+            } else {
+                continue;
+            };
+            return Err(Error::RefusedToDelete {
+                id: id.clone(),
+                reason: reason.to_string(),
+            });
+        }
+
+        let profile = self.profile();
+        let mut args = vec!["--delete-generations"];
+        args.extend(ids.iter().map(String::as_str));
+        args.extend(["-p", profile.as_str()]);
+        let commands = if ids.is_empty() {
+            Vec::new()
+        } else {
+            vec![format!("nix-env {}", args.join(" "))]
+        };
+
+        if !dry_run && !ids.is_empty() {
+            if self.requires_root() && !self.is_root()? {
+                return Err(Error::PermissionDenied(
+                    "delete must be run as root (try sudo, or pass --dry-run)".to_string(),
+                ));
+            }
+
+            self.queries.cache.invalidate(&self.queries.profile_path);
+            self.queries.references.clear();
+            self.clear_diffs();
+            self.run_checked("nix-env", &args)?;
+        }
+
+        Ok(DeletionPlan {
+            generations: ids.to_vec(),
+            dry_run,
+            commands,
+        })
+    }
+
+    /// Collect garbage with `nix-collect-garbage`, first deleting the
+    /// generations of every profile older than `older_than_days` when given.
+    /// With `dry_run` nothing is run; `gc_preview` estimates what would be
+    /// freed.
+    pub fn collect_garbage(&self, older_than_days: Option<u64>, dry_run: bool) -> Result<GcResult> {
+        let older_than = older_than_days.map(|days| format!("{}d", days));
+        let mut args = Vec::new();
+        if let Some(older_than) = &older_than {
+            args.extend(["--delete-older-than", older_than.as_str()]);
+        }
+        let commands = vec![std::iter::once("nix-collect-garbage")
+            .chain(args.iter().copied())
+            .collect::<Vec<_>>()
+            .join(" ")];
+
+        if dry_run {
+            return Ok(GcResult {
+                dry_run,
+                commands,
+                ..Default::default()
+            });
+        }
+        if self.requires_root() && !self.is_root()? {
+            return Err(Error::PermissionDenied(
+                "gc must be run as root (try sudo, or pass --dry-run)".to_string(),
+            ));
+        }
 
+        self.queries.cache.invalidate(&self.queries.profile_path);
+        self.queries.references.clear();
+        self.clear_diffs();
+        let output = self.run("nix-collect-garbage", &args)?;
         if !output.status.success() {
             return Err(Error::NixCommandError(
                 String::from_utf8_lossy(&output.stderr).to_string(),
             ));
         }
 
-        self.parse_diff_output(&String::from_utf8_lossy(&output.stdout))
+        // Newer Nix prints the summary to stderr, older Nix to stdout.
+        let mut result = parse_gc_output(&format!(
+            "{}\n{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        ))?;
+        result.commands = commands;
+        Ok(result)
     }
 
-    fn get_generation_store_path(&self, id: &str) -> Result<String> {
-        let output = Command::new("nix-env")
-            .args([
-                "-p",
-                &format!("/nix/var/nix/profiles/system-{}-link", id),
-                "--query",
-                "--out-path",
-            ])
-            .output()?;
+    /// Ids of the generations outside the newest `keep_last` and built before
+    /// `older_than`, oldest first. The current and the booted generation are
+    /// never selected.
+    pub fn deletion_candidates(
+        &self,
+        keep_last: Option<usize>,
+        older_than: Option<DateTime<Utc>>,
+    ) -> Result<Vec<String>> {
+        let current = self.get_current_generation()?;
+        let mut generations = self.read_generations()?;
+        self.mark_booted(&mut generations);
+        generations.sort_by(|a, b| compare_generation_ids(&a.id, &b.id));
 
-        if !output.status.success() {
-            return Err(Error::GenerationNotFound(id.to_string()));
+        let keep_from = generations.len().saturating_sub(keep_last.unwrap_or(0));
+        Ok(generations
+            .into_iter()
+            .enumerate()
+            .filter(|(i, g)| {
+                (keep_last.is_none() || *i < keep_from)
+                    && older_than.is_none_or(|cutoff| g.timestamp < cutoff)
+                    && g.id != current
+                    && !g.booted
+            })
+            .map(|(_, g)| g.id)
+            .collect())
+    }
+
+    /// Fail early with `NixNotAvailable` when nix-env can't be run or there
+    /// is no `/nix/store`, rather than with whatever the first command hits.
+    pub fn check_environment(&self) -> Result<()> {
+        match self.run("nix-env", &["--version"]) {
+            Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(Error::NixNotAvailable(
+                    "nix-env was not found on PATH".to_string(),
+                ));
+            }
+            output => output?,
+        };
+
+        if !self.run("test", &["-d", "/nix/store"])?.status.success() {
+            return Err(Error::NixNotAvailable(
+                "/nix/store does not exist".to_string(),
+            ));
         }
+        Ok(())
+    }
 
-        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    /// Profiles under `/nix/var/nix/profiles` (other than `per-user`) are
+    /// owned by root.
+    fn requires_root(&self) -> bool {
+        let profile = self.profile_path();
+        profile.starts_with("/nix/var/nix/profiles")
+            && !profile.starts_with("/nix/var/nix/profiles/per-user")
     }
 
-    fn parse_diff_output(&self, output: &str) -> Result<GenerationDiff> {
-        let mut added = Vec::new();
-        let mut removed = Vec::new();
-        let mut modified = Vec::new();
+    fn is_root(&self) -> Result<bool> {
+        let output = self.run("id", &["-u"])?;
+        Ok(String::from_utf8_lossy(&output.stdout).trim() == "0")
+    }
 
-        for line in output.lines() {
-            let line = line.trim();
-            if line.starts_with('+') {
-                added.push(line[1..].trim().to_string());
-            } else if line.starts_with('-') {
-                removed.push(line[1..].trim().to_string());
-            } else if line.starts_with('~') {
-                modified.push(line[1..].trim().to_string());
+    /// Check generation `id`'s runtime closure for known CVEs with vulnix.
+    pub fn scan(&self, id: &str) -> Result<GenerationScan> {
+        let store_path = self.get_generation_store_path(id)?;
+        let output = match self.run("vulnix", &["--json", "--closure", &store_path]) {
+            Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(Error::ToolNotInstalled("vulnix".to_string()));
             }
+            output => output?,
+        };
+
+        // vulnix exits with 2 when it found vulnerabilities.
+        if !matches!(output.status.code(), Some(0 | 2)) {
+            return Err(Error::NixCommandError(
+                String::from_utf8_lossy(&output.stderr).to_string(),
+            ));
         }
 
-        Ok(GenerationDiff {
-            added,
-            removed,
-            modified,
+        Ok(GenerationScan {
+            generation: id.to_string(),
+            store_path,
+            vulnerabilities: parse_vulnix_output(&String::from_utf8_lossy(&output.stdout))?,
         })
     }
+
+    /// CVEs found in `to` but not `from` (introduced), and the reverse
+    /// (resolved).
+    pub fn scan_diff(&self, from: &str, to: &str) -> Result<ScanDiff> {
+        let (from_scan, to_scan) = self.both(from, to, |id| self.scan(id))?;
+        let missing_from = |scan: &GenerationScan, other: &GenerationScan| {
+            scan.vulnerabilities
+                .iter()
+                .filter(|v| {
+                    !other
+                        .vulnerabilities
+                        .iter()
+                        .any(|o| o.cve == v.cve && o.package == v.package)
+                })
+                .cloned()
+                .collect()
+        };
+
+        Ok(ScanDiff {
+            from: from.to_string(),
+            to: to.to_string(),
+            introduced: missing_from(&to_scan, &from_scan),
+            resolved: missing_from(&from_scan, &to_scan),
+        })
+    }
+
+    fn run_checked(&self, program: &str, args: &[&str]) -> Result<()> {
+        let output = self.run(program, args)?;
+
+        if !output.status.success() {
+            return Err(Error::NixCommandError(
+                String::from_utf8_lossy(&output.stderr).to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Diff two generations with `nix store diff-closures`, which reports
+    /// version transitions and closure size deltas per package.
+    ///
+    /// Falls back to `get_diff` (nix-diff) when `nix store` is unavailable.
+    pub fn get_closure_diff(&self, from: &str, to: &str) -> Result<DetailedDiff> {
+        block_on(self.queries.get_closure_diff(self, from, to))
+    }
+
+    /// Run `query` for both sides of a diff concurrently.
+    fn both<T, F>(&self, from: &str, to: &str, query: F) -> Result<(T, T)>
+    where
+        T: Send,
+        F: Fn(&str) -> Result<T> + Sync,
+    {
+        let mut results = map_bounded(&[from, to], self.queries.jobs, |id| query(id)).into_iter();
+        match (results.next(), results.next()) {
+            (Some(from), Some(to)) => Ok((from?, to?)),
+            _ => unreachable!("one result per side"),
+        }
+    }
+
+    fn get_generation_store_path(&self, id: &str) -> Result<String> {
+        block_on(self.queries.get_generation_store_path(self, id))
+    }
+
+    /// What store queries about generation `id` are run on; see
+    /// `Queries::store_target`.
+    fn store_target(&self, id: &str) -> Result<String> {
+        block_on(self.queries.store_target(self, id))
+    }
+
+    /// The store path generation `id` points at, from its link alone, for
+    /// profiles with no manifest `nix-env --query` understands.
+    pub(crate) fn resolve_generation_link(&self, id: &str) -> Result<String> {
+        block_on(self.queries.resolve_generation_link(self, id))
+    }
+
+    /// `error`, or `NoGenerations` when it was caused by the profile having
+    /// no generations at all.
+    fn unless_empty(&self, error: Error) -> Error {
+        block_on(self.queries.unless_empty(self, error))
+    }
+}
+
+impl<R: CommandRunner> Exec for NixService<R> {
+    fn run_command(
+        &self,
+        program: &str,
+        args: &[&str],
+    ) -> impl Future<Output = Result<Output>> + Send {
+        ready(self.runner.run(program, args))
+    }
+
+    fn sleep(&self, delay: Duration) -> impl Future<Output = ()> + Send {
+        std::thread::sleep(delay);
+        ready(())
+    }
+
+    fn is_remote(&self) -> bool {
+        self.runner.is_remote()
+    }
+
+    fn join<U, Fut>(&self, futures: Vec<Fut>, jobs: usize) -> impl Future<Output = Vec<U>> + Send
+    where
+        U: Send,
+        Fut: Future<Output = U> + Send,
+    {
+        let mut slots: Vec<(Option<Fut>, Option<U>)> =
+            futures.into_iter().map(|f| (Some(f), None)).collect();
+        for_each_bounded(&mut slots, jobs, |(future, output)| {
+            *output = future.take().map(block_on);
+        });
+        ready(slots.into_iter().filter_map(|(_, output)| output).collect())
+    }
+}
+
+impl<R: CommandRunner> GenerationProvider for NixService<R> {
+    fn list_generations(&self) -> Result<Vec<Generation>> {
+        NixService::list_generations(self)
+    }
+
+    fn read_generations(&self) -> Result<Vec<Generation>> {
+        NixService::read_generations(self)
+    }
+
+    fn get_current(&self) -> Result<Generation> {
+        NixService::get_current(self)
+    }
+
+    fn get_store_path(&self, id: &str) -> Result<String> {
+        self.get_generation_store_path(id)
+    }
+}
+
+/// Parse `nix store diff-closures` output, or return `None` when this Nix
+/// can't diff closures and the caller should fall back to `nix-diff`.
+pub(crate) fn closure_diff_result(output: Result<Output>) -> Option<Result<DetailedDiff>> {
+    let output = match output {
+        Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => return Some(Err(e)),
+        Ok(output) => output,
+    };
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if stderr.contains("experimental Nix feature") || stderr.contains("not a recognised") {
+            return None;
+        }
+        return Some(Err(Error::NixCommandError(stderr.to_string())));
+    }
+
+    Some(parse_closure_diff_output(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
+}
+
+/// The sorted, comma-separated versions of `package` among `references`, or
+/// `None` if no reference is named `package`.
+fn package_versions(references: &[String], package: &str) -> Option<String> {
+    let paths: Vec<StorePath> = references
+        .iter()
+        .map(|path| StorePath::parse(path))
+        .filter(|path| path.name() == package)
+        .collect();
+    if paths.is_empty() {
+        return None;
+    }
+
+    let mut versions: Vec<&str> = paths.iter().filter_map(StorePath::version).collect();
+    versions.sort_unstable();
+    versions.dedup();
+    Some(versions.join(", "))
+}
+
+/// `args` with `--store uri` in front when `program` is a nix command.
+pub(crate) fn with_store<'a>(
+    program: &str,
+    args: &[&'a str],
+    store: Option<&'a str>,
+) -> Vec<&'a str> {
+    match store {
+        Some(uri) if matches!(program, "nix" | "nix-env" | "nix-store") => {
+            ["--store", uri].iter().chain(args).copied().collect()
+        }
+        _ => args.to_vec(),
+    }
+}
+
+/// Explain why the current generation of `profile` couldn't be read.
+pub(crate) fn current_unavailable(profile: &str, error: Error) -> Error {
+    Error::CurrentGenerationUnavailable {
+        profile: profile.to_string(),
+        reason: match error {
+            Error::NixCommandError(stderr) if stderr.trim().is_empty() => {
+                "profile symlink does not exist".to_string()
+            }
+            Error::NixCommandError(stderr) => stderr.trim().to_string(),
+            Error::ParseError(_) => "profile is not a generation symlink".to_string(),
+            e => e.to_string(),
+        },
+    }
+}
+
+/// A generation as named on the command line, before it is resolved
+/// against the profile.
+pub(crate) enum GenerationRef {
+    Id(String),
+    Booted,
+    /// Generations before the current one; `0` is the current generation.
+    Offset(usize),
+}
+
+pub(crate) fn parse_ref(reference: &str) -> Result<GenerationRef> {
+    Ok(match reference {
+        r if !r.is_empty() && r.chars().all(|c| c.is_ascii_digit()) => {
+            GenerationRef::Id(r.to_string())
+        }
+        "booted" => GenerationRef::Booted,
+        "current" | "HEAD" => GenerationRef::Offset(0),
+        "previous" => GenerationRef::Offset(1),
+        r => r
+            .strip_prefix("HEAD~")
+            .and_then(|n| n.parse::<usize>().ok())
+            .map(GenerationRef::Offset)
+            .ok_or_else(|| {
+                Error::InvalidArgument(format!(
+                    "unknown generation '{}', expected an id, current, previous, booted or HEAD~N",
+                    r
+                ))
+            })?,
+    })
+}
+
+/// Which way `NixService::neighbor` and `GenerationProvider::get_neighbor`
+/// step through the generation list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Previous,
+    Next,
+}
+
+/// The id `steps` generations from `id` in `ids` in numeric order, so gaps
+/// left by deletions are skipped. `None` past either end.
+pub(crate) fn step(
+    mut ids: Vec<String>,
+    id: &str,
+    steps: usize,
+    direction: Direction,
+) -> Result<Option<String>> {
+    ids.sort_by(|a, b| compare_generation_ids(a, b));
+    let position = ids
+        .iter()
+        .position(|i| i == id)
+        .ok_or_else(|| Error::GenerationNotFound(id.to_string()))?;
+
+    let target = match direction {
+        Direction::Previous => position.checked_sub(steps),
+        Direction::Next => position.checked_add(steps).filter(|&i| i < ids.len()),
+    };
+    Ok(target.map(|i| ids[i].clone()))
+}
+
+/// The id `offset` generations before `current` in `ids`.
+pub(crate) fn nth_before(
+    ids: Vec<String>,
+    current: &str,
+    offset: usize,
+    reference: &str,
+) -> Result<String> {
+    let preceding = ids
+        .iter()
+        .filter(|id| compare_generation_ids(id, current).is_lt())
+        .count();
+
+    step(ids, current, offset, Direction::Previous)?.ok_or_else(|| {
+        Error::InvalidArgument(format!(
+            "{} is out of range: only {} generation(s) precede current generation {}",
+            reference, preceding, current
+        ))
+    })
+}
+
+/// Reject ids that can't name a `<profile>-<id>-link`, and report whether
+/// both sides are the same generation so callers can skip the nix commands.
+pub(crate) fn same_generation(from: &str, to: &str) -> Result<bool> {
+    for id in [from, to] {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_digit()) {
+            return Err(Error::InvalidGenerationId(id.to_string()));
+        }
+    }
+
+    Ok(from == to)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::models::vulnerability::Vulnerability;
+    use crate::services::parse::parse_build_date;
+    use crate::services::runner::MockCommandRunner;
+
+    const LIST_GENERATIONS: &str = "nix-env --list-generations -p /nix/var/nix/profiles/system";
+    const READLINK: &str = "readlink /nix/var/nix/profiles/system";
+
+    fn service(runner: MockCommandRunner) -> NixService<MockCommandRunner> {
+        NixService::with_runner(runner)
+    }
+
+    const NIX_ENV_FIXTURE: &str = include_str!("../../tests/fixtures/nix-env-list-generations.txt");
+
+    #[test]
+    fn test_list_generations_reads_versions() {
+        let runner = MockCommandRunner::new()
+            .with_stdout(
+                LIST_GENERATIONS,
+                r#"   1   2024-02-09 10:00:00   nixos-22.11.20240209.123
+   2   2024-02-10 11:30:00   "#,
+            )
+            .with_stdout(READLINK, "system-2-link\n")
+            .with_stdout(
+                "cat /nix/var/nix/profiles/system-2-link/nixos-version",
+                "24.05.20240210.f9d39fb\n",
+            )
+            .with_stdout(
+                "readlink /nix/var/nix/profiles/system-2-link/kernel",
+                "/nix/store/0c7r3sa2dmw7ns6dyxmzif2g9mqkcgxy-linux-6.6.15/bzImage\n",
+            );
+
+        let generations = service(runner).list_generations().unwrap();
+        assert_eq!(
+            generations[0].nixos_version.as_deref(),
+            Some("22.11.20240209.123")
+        );
+        assert_eq!(generations[0].kernel_version, None);
+        assert_eq!(
+            generations[1].nixos_version.as_deref(),
+            Some("24.05.20240210.f9d39fb")
+        );
+        assert_eq!(generations[1].kernel_version.as_deref(), Some("6.6.15"));
+    }
+
+    #[test]
+    fn test_list_generations_marks_current() {
+        let runner = MockCommandRunner::new()
+            .with_stdout(
+                LIST_GENERATIONS,
+                "   1   2024-02-09 10:00:00   \n   2   2024-02-10 11:30:00   (current)\n",
+            )
+            .with_stdout(READLINK, "system-2-link\n");
+
+        let service = service(runner);
+        let generations = service.list_generations().unwrap();
+        assert_eq!(generations.len(), 2);
+        // The profile symlink is read once per listing, not once per line.
+        let readlinks = service
+            .runner
+            .calls()
+            .iter()
+            .filter(|c| *c == READLINK)
+            .count();
+        assert_eq!(readlinks, 1);
+        assert!(!generations[0].current);
+        assert!(generations[1].current);
+        assert_eq!(
+            generations[1].profiles,
+            vec!["/nix/var/nix/profiles/system-2-link"]
+        );
+    }
+
+    #[test]
+    fn test_generation_list_is_cached_within_ttl() {
+        let runner = MockCommandRunner::new()
+            .with_stdout(LIST_GENERATIONS, "   1   2024-02-09 10:00:00   (current)\n")
+            .with_stdout(READLINK, "system-1-link\n");
+        let service = service(runner).with_cache_ttl(Duration::from_millis(200));
+        let listings = || {
+            service
+                .runner
+                .calls()
+                .iter()
+                .filter(|call| *call == LIST_GENERATIONS)
+                .count()
+        };
+
+        service.list_generations().unwrap();
+        assert!(service.generation_exists("1").unwrap());
+        assert_eq!(listings(), 1);
+
+        service.queries.cache.invalidate(service.profile_path());
+        service.list_generations().unwrap();
+        assert_eq!(listings(), 2);
+
+        std::thread::sleep(Duration::from_millis(250));
+        service.list_generations().unwrap();
+        assert_eq!(listings(), 3);
+    }
+
+    #[test]
+    fn test_remote_generation_list_follows_remote_mtime() {
+        let stat = "stat -c %Y /nix/var/nix/profiles/system";
+        let runner = MockCommandRunner::new()
+            .remote()
+            .with_stdout(LIST_GENERATIONS, "   1   2024-02-09 10:00:00   (current)\n")
+            .with_stdout(READLINK, "system-1-link\n")
+            .with_stdout(stat, "1707472800\n")
+            .with_stdout(stat, "1707472800\n")
+            .with_stdout(stat, "1707559200\n");
+        let service = service(runner).with_cache_ttl(Duration::from_secs(60));
+        let listings = || {
+            service
+                .runner
+                .calls()
+                .iter()
+                .filter(|call| *call == LIST_GENERATIONS)
+                .count()
+        };
+
+        service.read_generations().unwrap();
+        service.read_generations().unwrap();
+        assert_eq!(listings(), 1);
+
+        // A switch on the remote host is noticed before the TTL runs out.
+        service.read_generations().unwrap();
+        assert_eq!(listings(), 2);
+    }
+
+    #[test]
+    fn test_get_current() {
+        let runner = MockCommandRunner::new()
+            .with_stdout(LIST_GENERATIONS, NIX_ENV_FIXTURE)
+            .with_stdout(READLINK, "system-2-link\n");
+
+        let current = service(runner).get_current().unwrap();
+        assert_eq!(current.id, "2");
+        assert!(current.current);
+        assert_eq!(
+            current.timestamp,
+            parse_build_date("2024-02-10 11:30:00").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_booted_generation() {
+        let runner = MockCommandRunner::new()
+            .with_stdout(LIST_GENERATIONS, NIX_ENV_FIXTURE)
+            .with_stdout(READLINK, "system-2-link\n")
+            .with_stdout(
+                "readlink /run/booted-system",
+                "/nix/store/aaaa-nixos-system-1\n",
+            )
+            .with_stdout(
+                "readlink /nix/var/nix/profiles/system-1-link",
+                "/nix/store/aaaa-nixos-system-1\n",
+            )
+            .with_stdout(
+                "readlink /nix/var/nix/profiles/system-2-link",
+                "/nix/store/bbbb-nixos-system-2\n",
+            );
+        let service = service(runner);
+
+        let generations = service.list_generations().unwrap();
+        assert!(generations[0].booted && !generations[0].current);
+        assert!(!generations[1].booted && generations[1].current);
+
+        // Finding the booted generation doesn't read every version.
+        let listed = service.runner.calls().len();
+        assert_eq!(
+            service.get_booted_generation().unwrap().as_deref(),
+            Some("1")
+        );
+        assert!(service.runner.calls()[listed..]
+            .iter()
+            .all(|call| !call.starts_with("cat ")));
+        assert_eq!(service.get_booted().unwrap().id, "1");
+    }
 
     #[test]
-    fn test_parse_generations_output() {
-        let service = NixService::new();
-        let sample_output = r#"   1   2024-02-09 10:00:00   nixos-22.11.20240209.123
-   2   2024-02-09 11:00:00   nixos-22.11.20240209.456"#;
+    fn test_read_generations_skips_the_store() {
+        let runner = MockCommandRunner::new()
+            .with_stdout(LIST_GENERATIONS, NIX_ENV_FIXTURE)
+            .with_stdout(READLINK, "system-2-link\n")
+            .with_stdout(
+                "readlink /run/booted-system",
+                "/nix/store/aaaa-nixos-system-1\n",
+            );
+        let service = service(runner);
 
-        let generations = service.parse_generations_output(sample_output).unwrap();
+        let generations = GenerationProvider::read_generations(&service).unwrap();
         assert_eq!(generations.len(), 2);
-        assert_eq!(generations[0].id, "1");
-        assert_eq!(generations[1].id, "2");
+        assert!(generations.iter().all(|g| !g.booted));
+        assert!(service.runner.calls().iter().all(|call| {
+            !call.starts_with("cat ")
+                && !call.starts_with("readlink /nix/var/nix/profiles/system-")
+                && call != "readlink /run/booted-system"
+        }));
+    }
+
+    #[test]
+    fn test_booted_generation_without_booted_system() {
+        let runner = MockCommandRunner::new()
+            .with_stdout(LIST_GENERATIONS, NIX_ENV_FIXTURE)
+            .with_stdout(READLINK, "system-2-link\n");
+        let service = service(runner);
+
+        let generations = service.list_generations().unwrap();
+        assert!(generations.iter().all(|g| !g.booted));
+        assert_eq!(service.get_booted_generation().unwrap(), None);
+    }
+
+    #[test]
+    fn test_get_current_generation_from_readlink() {
+        for (target, id) in [
+            ("system-42-link", "42"),
+            ("system-42-link\n", "42"),
+            ("/nix/var/nix/profiles/system-7-link\n", "7"),
+            ("./system-1234-link\n", "1234"),
+        ] {
+            let runner = MockCommandRunner::new().with_stdout(READLINK, target);
+            assert_eq!(service(runner).get_current_generation().unwrap(), id);
+        }
+
+        for target in [
+            "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-nixos-system\n",
+            "mysystem-42-link\n",
+            "system-42-link.bak\n",
+            "system--link\n",
+            "/nix/var/nix/profiles/system-42-link/sw\n",
+            "\n",
+        ] {
+            let runner = MockCommandRunner::new().with_stdout(READLINK, target);
+            let err = service(runner).get_current_generation().unwrap_err();
+            assert!(matches!(err, Error::ParseError(_)), "{:?}", target);
+        }
+    }
+
+    #[test]
+    fn test_get_current_with_broken_symlink() {
+        let missing = MockCommandRunner::new().with_response(
+            READLINK,
+            1,
+            "",
+            "readlink: /nix/var/nix/profiles/system: No such file or directory\n",
+        );
+        let err = service(missing).get_current().unwrap_err();
+        assert!(matches!(err, Error::CurrentGenerationUnavailable { .. }));
+
+        let malformed = MockCommandRunner::new().with_stdout(READLINK, "/nix/store/aaaa-foo\n");
+        let err = service(malformed).get_current().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Cannot determine the current generation of /nix/var/nix/profiles/system: \
+             profile is not a generation symlink"
+        );
+    }
+
+    #[test]
+    fn test_empty_profile() {
+        let runner = || {
+            let mut runner = MockCommandRunner::new()
+                .with_stdout(LIST_GENERATIONS, "")
+                .with_response(READLINK, 1, "", "");
+            for id in ["1", "2"] {
+                let link = format!("/nix/var/nix/profiles/system-{}-link", id);
+                runner = runner
+                    .with_response(
+                        &format!("nix-env -p {} --query --out-path", link),
+                        1,
+                        "",
+                        "",
+                    )
+                    .with_response(&format!("nix-store -q --references {}", link), 1, "", "");
+            }
+            runner
+        };
+
+        assert!(service(runner()).list_generations().unwrap().is_empty());
+        for err in [
+            service(runner()).get_current().unwrap_err(),
+            service(runner()).resolve_ref("previous").unwrap_err(),
+            service(runner()).get_reference_diff("1", "2").unwrap_err(),
+            service(runner()).get_nix_diff("1", "2").unwrap_err(),
+        ] {
+            assert!(
+                matches!(err, Error::NoGenerations(ref p) if p == "/nix/var/nix/profiles/system"),
+                "{:?}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_check_environment() {
+        let runner = || MockCommandRunner::new().with_stdout("nix-env --version", "nix-env 2.18\n");
+
+        let ok = runner().with_stdout("test -d /nix/store", "");
+        assert!(service(ok).check_environment().is_ok());
+
+        let no_store = runner().with_response("test -d /nix/store", 1, "", "");
+        let err = service(no_store).check_environment().unwrap_err();
+        assert!(matches!(err, Error::NixNotAvailable(ref r) if r == "/nix/store does not exist"));
+
+        let no_nix = MockCommandRunner::new().with_missing_program("nix-env");
+        let err = service(no_nix).check_environment().unwrap_err();
+        assert!(matches!(err, Error::NixNotAvailable(_)));
+    }
+
+    #[test]
+    fn test_list_generations_command_failure() {
+        let runner =
+            MockCommandRunner::new().with_response(LIST_GENERATIONS, 1, "", "permission denied");
+
+        let err = service(runner).list_generations().unwrap_err();
+        assert!(matches!(err, Error::NixCommandError(msg) if msg == "permission denied"));
+    }
+
+    #[test]
+    fn test_get_diff() {
+        let runner = MockCommandRunner::new()
+            .with_stdout(
+                "nix-env -p /nix/var/nix/profiles/system-1-link --query --out-path",
+                "/nix/store/aaaa-nixos-system-1\n",
+            )
+            .with_stdout(
+                "nix-env -p /nix/var/nix/profiles/system-2-link --query --out-path",
+                "/nix/store/bbbb-nixos-system-2\n",
+            )
+            .with_stdout(
+                "nix-diff /nix/store/aaaa-nixos-system-1 /nix/store/bbbb-nixos-system-2",
+                "+ firefox-122.0\n- vim-9.0\n~ openssl-3.0.12\n",
+            );
+
+        let diff = service(runner).get_diff("1", "2").unwrap();
+        assert_eq!(diff.added, vec!["firefox-122.0"]);
+        assert_eq!(diff.removed, vec!["vim-9.0"]);
+        assert_eq!(diff.modified, vec!["openssl-3.0.12"]);
+    }
+
+    const DIFF_CLOSURES: &str =
+        "nix store diff-closures /nix/store/aaaa-nixos-system-1 /nix/store/bbbb-nixos-system-2";
+
+    fn closure_runner() -> MockCommandRunner {
+        MockCommandRunner::new()
+            .with_stdout(
+                "nix-env -p /nix/var/nix/profiles/system-1-link --query --out-path",
+                "/nix/store/aaaa-nixos-system-1\n",
+            )
+            .with_stdout(
+                "nix-env -p /nix/var/nix/profiles/system-2-link --query --out-path",
+                "/nix/store/bbbb-nixos-system-2\n",
+            )
+    }
+
+    #[test]
+    fn test_get_closure_diff() {
+        let runner = closure_runner().with_stdout(
+            DIFF_CLOSURES,
+            "firefox: ∅ → 122.0, +234.5 MiB\n\
+             openssl: 3.0.10 → 3.0.12, +0.1 KiB\n\
+             vim: 9.0 → ∅, -30.0 MiB\n\
+             nixos-system: +2.0 KiB\n",
+        );
+
+        let diff = service(runner).get_closure_diff("1", "2").unwrap();
+        assert_eq!(diff.added[0].name, "firefox");
+        assert_eq!(diff.added[0].new_version.as_deref(), Some("122.0"));
+        assert_eq!(diff.removed[0].name, "vim");
+        assert_eq!(diff.removed[0].size_delta, Some(-30 * 1024 * 1024));
+        assert_eq!(diff.modified.len(), 2);
+        assert_eq!(diff.modified[0].old_version.as_deref(), Some("3.0.10"));
+        assert_eq!(diff.modified[0].new_version.as_deref(), Some("3.0.12"));
+        assert_eq!(diff.modified[0].size_delta, Some(102));
+        assert_eq!(diff.modified[1].old_version, None);
+        assert_eq!(diff.modified[1].size_delta, Some(2048));
+    }
+
+    #[test]
+    fn test_get_closure_diff_falls_back_to_nix_diff() {
+        let runner = closure_runner()
+            .with_response(
+                DIFF_CLOSURES,
+                1,
+                "",
+                "error: experimental Nix feature 'nix-command' is disabled",
+            )
+            .with_stdout(
+                "nix-diff /nix/store/aaaa-nixos-system-1 /nix/store/bbbb-nixos-system-2",
+                "+ firefox-122.0\n",
+            );
+
+        let diff = service(runner).get_closure_diff("1", "2").unwrap();
+        assert_eq!(diff.added[0].name, "firefox");
+        assert_eq!(diff.added[0].new_version.as_deref(), Some("122.0"));
+    }
+
+    #[test]
+    fn test_generation_size() {
+        let runner = MockCommandRunner::new()
+            .with_stdout(
+                "nix path-info -S --json /nix/var/nix/profiles/system-1-link",
+                r#"[{"path":"/nix/store/aaaa-nixos-system-1","closureSize":1048576}]"#,
+            )
+            .with_stdout(
+                "nix path-info -S --json /nix/var/nix/profiles/system-2-link",
+                r#"{"/nix/store/bbbb-nixos-system-2":{"closureSize":2097152}}"#,
+            )
+            .with_stdout(
+                "nix path-info -S --json /nix/var/nix/profiles/system-3-link",
+                r#"[{"path":"/nix/store/cccc-nixos-system-3"}]"#,
+            );
+        let service = service(runner);
+
+        assert_eq!(service.generation_size("1").unwrap(), 1048576);
+        assert_eq!(service.generation_size("2").unwrap(), 2097152);
+        assert!(matches!(
+            service.generation_size("3"),
+            Err(Error::ParseError(_))
+        ));
+    }
+
+    #[test]
+    fn test_gc_preview() {
+        let vim = "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-vim-9.0";
+        let openssl = "/nix/store/cccccccccccccccccccccccccccccccc-openssl-3.0.10";
+        let bash = "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-bash-5.2";
+        let references = |id: &str| {
+            format!(
+                "nix-store -q --references /nix/var/nix/profiles/system-{}-link",
+                id
+            )
+        };
+        let runner = ref_runner()
+            .with_stdout(
+                &references("9"),
+                &format!("{}\n{}\n{}\n", bash, vim, openssl),
+            )
+            .with_stdout(&references("10"), &format!("{}\n{}\n", bash, openssl))
+            .with_stdout(&references("11"), &format!("{}\n", bash))
+            .with_stdout(
+                &format!("nix path-info -S --json {} {}", vim, openssl),
+                &format!(
+                    r#"{{"{}":{{"narSize":1000}},"{}":{{"narSize":250}}}}"#,
+                    vim, openssl
+                ),
+            );
+
+        let preview = service(runner).gc_preview().unwrap();
+        assert_eq!(
+            preview.generations,
+            vec![
+                ReclaimableGeneration {
+                    id: "9".to_string(),
+                    paths: 1,
+                    reclaimable_bytes: 1000,
+                },
+                ReclaimableGeneration {
+                    id: "10".to_string(),
+                    paths: 0,
+                    reclaimable_bytes: 0,
+                },
+            ]
+        );
+        assert_eq!(preview.total_bytes, 1250);
+    }
+
+    #[test]
+    fn test_diff_rejects_non_numeric_ids() {
+        let service = service(MockCommandRunner::new());
+
+        for (from, to) in [("3", "abc"), ("", "2"), ("-1", "2")] {
+            let err = service.get_reference_diff(from, to).unwrap_err();
+            assert!(
+                matches!(err, Error::InvalidGenerationId(_)),
+                "{} {}",
+                from,
+                to
+            );
+        }
+        assert!(matches!(
+            service.get_diff("3", "abc"),
+            Err(Error::InvalidGenerationId(id)) if id == "abc"
+        ));
+        assert!(service.runner.calls().is_empty());
+    }
+
+    #[test]
+    fn test_classify_same_generation_runs_nothing() {
+        let service = service(MockCommandRunner::new());
+        let mut diff = DetailedDiff::default();
+        service.classify_dependencies("2", "2", &mut diff).unwrap();
+        assert!(service.runner.calls().is_empty());
+    }
+
+    #[test]
+    fn test_diff_same_generation_is_empty() {
+        let service = service(MockCommandRunner::new());
+
+        let diff = service.get_reference_diff("4", "4").unwrap();
+        assert!(diff.added.is_empty() && diff.removed.is_empty() && diff.modified.is_empty());
+        assert!(service.get_diff("4", "4").unwrap().added.is_empty());
+        assert!(service.get_closure_diff("4", "4").unwrap().added.is_empty());
+        assert!(service.runner.calls().is_empty());
+    }
+
+    const LOCKED: &str = "error: SQLite database '/nix/var/nix/db/db.sqlite' is busy";
+    const REFERENCES_1: &str = "nix-store -q --references /nix/var/nix/profiles/system-1-link";
+
+    fn retrying(runner: MockCommandRunner) -> NixService<MockCommandRunner> {
+        service(runner).with_retry(RetryPolicy {
+            base_delay: std::time::Duration::ZERO,
+            ..RetryPolicy::default()
+        })
+    }
+
+    #[test]
+    fn test_retries_transient_failures() {
+        let runner = MockCommandRunner::new()
+            .with_response(REFERENCES_1, 1, "", LOCKED)
+            .with_response(REFERENCES_1, 1, "", LOCKED)
+            .with_stdout(
+                REFERENCES_1,
+                "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-bash-5.2\n",
+            );
+        let service = retrying(runner);
+
+        assert_eq!(
+            service
+                .get_references(&service.generation_link("1"))
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(service.runner.calls().len(), 3);
+    }
+
+    #[test]
+    fn test_store_is_passed_to_nix_commands() {
+        let runner = MockCommandRunner::new()
+            .with_stdout(
+                "nix-store --store /mnt -q --references /nix/var/nix/profiles/system-1-link",
+                "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-bash-5.2\n",
+            )
+            .with_stdout("readlink /nix/var/nix/profiles/system", "system-1-link\n");
+        let service = service(runner).with_store("/mnt");
+
+        assert_eq!(
+            service
+                .get_references(&service.generation_link("1"))
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(service.get_current_generation().unwrap(), "1");
+    }
+
+    #[test]
+    fn test_store_references_resolve_links_first() {
+        let system = "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-nixos-system-1";
+        let runner = MockCommandRunner::new()
+            .with_stdout(
+                "readlink -f /nix/var/nix/profiles/system-1-link",
+                &format!("{system}\n"),
+            )
+            .with_stdout(
+                &format!("nix-store --store file:///tmp/store -q --references {system}"),
+                "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-bash-5.2\n",
+            );
+        let service = service(runner).with_store("file:///tmp/store");
+
+        assert_eq!(service.get_generation_store_path("1").unwrap(), system);
+        assert_eq!(service.store_target("1").unwrap(), system);
+        assert_eq!(
+            service
+                .get_references(&service.store_target("1").unwrap())
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_retry_gives_up() {
+        let runner = MockCommandRunner::new().with_response(REFERENCES_1, 1, "", LOCKED);
+        let service = retrying(runner);
+
+        assert!(matches!(
+            service.get_references(&service.generation_link("1")),
+            Err(Error::NixCommandError(_))
+        ));
+        assert_eq!(service.runner.calls().len(), 4);
+    }
+
+    #[test]
+    fn test_non_retryable_failure_fails_fast() {
+        let runner = MockCommandRunner::new().with_response(
+            REFERENCES_1,
+            1,
+            "",
+            "error: path '/nix/var/nix/profiles/system-1-link' is not valid",
+        );
+        // A long delay would make the test hang if it slept.
+        let service = service(runner).with_retry(RetryPolicy {
+            base_delay: std::time::Duration::from_secs(60),
+            ..RetryPolicy::default()
+        });
+
+        assert!(service
+            .get_references(&service.generation_link("1"))
+            .is_err());
+        assert_eq!(service.runner.calls().len(), 1);
+    }
+
+    fn ref_runner() -> MockCommandRunner {
+        MockCommandRunner::new()
+            .with_stdout(
+                LIST_GENERATIONS,
+                r#"   9   2024-02-08 10:00:00   
+  10   2024-02-09 10:00:00   
+  11   2024-02-10 11:30:00   (current)"#,
+            )
+            .with_stdout(READLINK, "system-11-link\n")
+    }
+
+    #[test]
+    fn test_find_package() {
+        let references = |id: &str| {
+            format!(
+                "nix-store -q --references /nix/var/nix/profiles/system-{}-link",
+                id
+            )
+        };
+        let runner = ref_runner()
+            .with_stdout(
+                &references("9"),
+                "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-bash-5.2\n",
+            )
+            .with_stdout(
+                &references("10"),
+                "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-openssl-3.0.10\n",
+            )
+            .with_stdout(
+                &references("11"),
+                "/nix/store/cccccccccccccccccccccccccccccccc-openssl-3.0.12\n",
+            );
+        let service = service(runner).with_jobs(1);
+
+        let found = service.find_package("openssl", None).unwrap();
+        assert_eq!(found.generation, "10");
+        assert_eq!(found.version.as_deref(), Some("3.0.10"));
+        let found = service.find_package("openssl", Some("3.0.12")).unwrap();
+        assert_eq!(found.generation, "11");
+        assert!(matches!(
+            service.find_package("vim", None),
+            Err(Error::PackageNotFound(name)) if name == "vim"
+        ));
+
+        // Each generation's references were resolved once.
+        let resolved = service
+            .runner
+            .calls()
+            .iter()
+            .filter(|call| call.starts_with("nix-store -q --references"))
+            .count();
+        assert_eq!(resolved, 3);
+    }
+
+    #[test]
+    fn test_bisect_package() {
+        let references = |id: &str| {
+            format!(
+                "nix-store -q --references /nix/var/nix/profiles/system-{}-link",
+                id
+            )
+        };
+        let runner = ref_runner()
+            .with_stdout(
+                &references("9"),
+                "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-openssl-3.0.10\n",
+            )
+            .with_stdout(
+                &references("10"),
+                "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-openssl-3.0.10\n",
+            )
+            .with_stdout(
+                &references("11"),
+                "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-openssl-3.0.12\n",
+            );
+        let service = service(runner);
+
+        let transitions = service.bisect_package("openssl", "11", "9").unwrap();
+        assert!(transitions.warnings.is_empty());
+        assert_eq!(
+            transitions.results,
+            vec![PackageTransition {
+                generation: "11".to_string(),
+                previous_generation: "10".to_string(),
+                package: "openssl".to_string(),
+                old_version: Some("3.0.10".to_string()),
+                new_version: Some("3.0.12".to_string()),
+                direction: ChangeDirection::Upgrade,
+            }]
+        );
+        assert!(service
+            .bisect_package("openssl", "9", "10")
+            .unwrap()
+            .results
+            .is_empty());
+        assert!(matches!(
+            service.bisect_package("openssl", "8", "10"),
+            Err(Error::GenerationNotFound(id)) if id == "8"
+        ));
+    }
+
+    #[test]
+    fn test_range_queries_skip_unreadable_generations() {
+        let references = |id: &str| {
+            format!(
+                "nix-store -q --references /nix/var/nix/profiles/system-{}-link",
+                id
+            )
+        };
+        let openssl = "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-openssl-3.0.10";
+        let runner = || {
+            ref_runner()
+                .with_stdout(&references("9"), &format!("{}\n", openssl))
+                .with_response(
+                    &references("10"),
+                    1,
+                    "",
+                    "error: path '/nix/store/bbbb-nixos-system-10' is not valid\n",
+                )
+                .with_stdout(
+                    &references("11"),
+                    "/nix/store/cccccccccccccccccccccccccccccccc-openssl-3.0.12\n",
+                )
+                .with_stdout(
+                    &format!("nix path-info -S --json {}", openssl),
+                    &format!(r#"{{"{}":{{"narSize":1000}}}}"#, openssl),
+                )
+        };
+        let warning = "generation 10: Failed to execute nix command: \
+                       error: path '/nix/store/bbbb-nixos-system-10' is not valid";
+
+        let transitions = service(runner())
+            .bisect_package("openssl", "9", "11")
+            .unwrap();
+        assert_eq!(transitions.warnings, [warning]);
+        let spans: Vec<(&str, &str)> = transitions
+            .results
+            .iter()
+            .map(|t| (t.previous_generation.as_str(), t.generation.as_str()))
+            .collect();
+        assert_eq!(spans, [("9", "11")]);
+
+        let preview = service(runner()).gc_preview().unwrap();
+        assert_eq!(preview.warnings, [warning]);
+        let ids: Vec<&str> = preview.generations.iter().map(|g| g.id.as_str()).collect();
+        assert_eq!(ids, ["9"]);
+        assert_eq!(preview.total_bytes, 1000);
+
+        let strict = service(runner()).with_strict(true);
+        assert!(matches!(
+            strict.bisect_package("openssl", "9", "11"),
+            Err(Error::NixCommandError(_))
+        ));
+        assert!(strict.gc_preview().is_err());
+    }
+
+    #[test]
+    fn test_scan_diff() {
+        let out_path = |id: &str| {
+            format!(
+                "nix-env -p /nix/var/nix/profiles/system-{}-link --query --out-path",
+                id
+            )
+        };
+        let vulnix = |path: &str| format!("vulnix --json --closure {}", path);
+        let entry = |cve: &str| {
+            format!(
+                r#"{{"pname": "openssl", "affected_by": ["{}"], "cvssv3_basescore": {{}}}}"#,
+                cve
+            )
+        };
+        let runner = MockCommandRunner::new()
+            .with_stdout(
+                &out_path("1"),
+                "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-nixos\n",
+            )
+            .with_stdout(
+                &out_path("2"),
+                "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-nixos\n",
+            )
+            .with_response(
+                &vulnix("/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-nixos"),
+                2,
+                &format!("[{}, {}]", entry("CVE-2023-1"), entry("CVE-2023-2")),
+                "",
+            )
+            .with_response(
+                &vulnix("/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-nixos"),
+                2,
+                &format!("[{}, {}]", entry("CVE-2023-2"), entry("CVE-2024-3")),
+                "",
+            );
+
+        let diff = service(runner).scan_diff("1", "2").unwrap();
+        let cves = |vulnerabilities: &[Vulnerability]| -> Vec<String> {
+            vulnerabilities.iter().map(|v| v.cve.clone()).collect()
+        };
+        assert_eq!(cves(&diff.introduced), ["CVE-2024-3"]);
+        assert_eq!(cves(&diff.resolved), ["CVE-2023-1"]);
+    }
+
+    #[test]
+    fn test_size_diff() {
+        let openssl_old = "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-openssl-3.0.10";
+        let openssl_new = "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-openssl-3.0.12";
+        let runner = MockCommandRunner::new()
+            .with_stdout(
+                "nix path-info -S --json /nix/var/nix/profiles/system-1-link",
+                r#"{"/nix/store/cccccccccccccccccccccccccccccccc-nixos":{"closureSize":1000}}"#,
+            )
+            .with_stdout(
+                "nix path-info -S --json /nix/var/nix/profiles/system-2-link",
+                r#"{"/nix/store/dddddddddddddddddddddddddddddddd-nixos":{}}"#,
+            )
+            .with_stdout(
+                "nix-store -q --references /nix/var/nix/profiles/system-1-link",
+                &format!("{}\n", openssl_old),
+            )
+            .with_stdout(
+                "nix-store -q --references /nix/var/nix/profiles/system-2-link",
+                &format!("{}\n", openssl_new),
+            )
+            .with_stdout(
+                &format!("nix path-info -S --json {} {}", openssl_old, openssl_new),
+                &format!(r#"{{"{}":{{"narSize":300}}}}"#, openssl_old),
+            );
+
+        let diff = service(runner).size_diff("1", "2", true).unwrap();
+        // Generation 2 has no size data, so it counts as empty.
+        assert_eq!(
+            (diff.from_bytes, diff.to_bytes, diff.delta_bytes),
+            (1000, 0, -1000)
+        );
+        assert_eq!(
+            diff.packages,
+            vec![PackageSize {
+                name: "openssl".to_string(),
+                old_bytes: 300,
+                new_bytes: 0,
+                delta_bytes: -300,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_size_diff_breakdown_order_and_min_size() {
+        let (bash, vim, firefox) = (
+            "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-bash-5.2",
+            "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-vim-9.0",
+            "/nix/store/cccccccccccccccccccccccccccccccc-firefox-122.0",
+        );
+        let runner = MockCommandRunner::new()
+            .with_stdout(
+                "nix path-info -S --json /nix/var/nix/profiles/system-1-link",
+                r#"{"/nix/store/dddddddddddddddddddddddddddddddd-nixos":{"closureSize":5000}}"#,
+            )
+            .with_stdout(
+                "nix path-info -S --json /nix/var/nix/profiles/system-2-link",
+                r#"{"/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-nixos":{"closureSize":9000}}"#,
+            )
+            .with_stdout(
+                "nix-store -q --references /nix/var/nix/profiles/system-1-link",
+                &format!("{}\n{}\n", bash, vim),
+            )
+            .with_stdout(
+                "nix-store -q --references /nix/var/nix/profiles/system-2-link",
+                &format!("{}\n", firefox),
+            )
+            .with_stdout(
+                &format!("nix path-info -S --json {} {} {}", bash, vim, firefox),
+                &format!(
+                    r#"{{"{}":{{"narSize":100}},"{}":{{"narSize":2000}},"{}":{{"narSize":6000}}}}"#,
+                    bash, vim, firefox
+                ),
+            );
+
+        let diff = service(runner).size_diff("1", "2", true).unwrap();
+        let names: Vec<&str> = diff.packages.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["firefox", "vim", "bash"]);
+
+        let diff = diff.without_smaller_than(2000);
+        let names: Vec<&str> = diff.packages.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["firefox", "vim"]);
+        assert_eq!((diff.hidden_packages, diff.delta_bytes), (1, 4000));
+    }
+
+    #[test]
+    fn test_diff_modes_agree_on_shape() {
+        let vim = "/nix/store/cccccccccccccccccccccccccccccccc-vim-9.0";
+        let openssl_old = "/nix/store/dddddddddddddddddddddddddddddddd-openssl-3.0.10";
+        let openssl_new = "/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-openssl-3.0.12";
+        let firefox = "/nix/store/ffffffffffffffffffffffffffffffff-firefox-122.0";
+        let runner = MockCommandRunner::new()
+            .with_stdout(
+                "nix-env -p /nix/var/nix/profiles/system-1-link --query --out-path",
+                "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-nixos\n",
+            )
+            .with_stdout(
+                "nix-env -p /nix/var/nix/profiles/system-2-link --query --out-path",
+                "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-nixos\n",
+            )
+            .with_stdout(
+                "nix-diff /nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-nixos \
+                 /nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-nixos",
+                &format!("+ {}\n- {}\n~ {}\n", firefox, vim, openssl_old),
+            )
+            .with_stdout(
+                "nix-store -q --references /nix/var/nix/profiles/system-1-link",
+                &format!("{}\n{}\n", vim, openssl_old),
+            )
+            .with_stdout(
+                "nix-store -q --references /nix/var/nix/profiles/system-2-link",
+                &format!("{}\n{}\n", openssl_new, firefox),
+            );
+        let service = service(runner);
+
+        let nix_diff = service.get_nix_diff("1", "2").unwrap();
+        let references = service.get_reference_diff("1", "2").unwrap();
+        for diff in [&nix_diff, &references] {
+            assert!(diff.added.contains(&firefox.to_string()));
+            assert!(diff.removed.contains(&vim.to_string()));
+            assert_eq!(diff.modified, [openssl_old]);
+            assert!(diff
+                .added
+                .iter()
+                .chain(&diff.removed)
+                .chain(&diff.modified)
+                .all(|path| path.starts_with("/nix/store/")));
+            let summary = diff.summary();
+            assert_eq!(
+                (summary.added, summary.removed, summary.modified),
+                (diff.added.len(), diff.removed.len(), diff.modified.len())
+            );
+        }
+        // The reference diff also lists both openssl paths as added/removed;
+        // nix-diff reports the change only as modified.
+        assert!(references.added.contains(&openssl_new.to_string()));
+        assert!(!nix_diff.added.contains(&openssl_new.to_string()));
+    }
+
+    #[test]
+    fn test_diffs_are_cached_per_mode() {
+        let runner = || {
+            MockCommandRunner::new()
+                .with_stdout(
+                    "nix-env -p /nix/var/nix/profiles/system-1-link --query --out-path",
+                    "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-nixos\n",
+                )
+                .with_stdout(
+                    "nix-env -p /nix/var/nix/profiles/system-2-link --query --out-path",
+                    "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-nixos\n",
+                )
+                .with_stdout(
+                    "nix-diff /nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-nixos \
+                     /nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-nixos",
+                    "+ /nix/store/dddddddddddddddddddddddddddddddd-firefox-122.0\n",
+                )
+        };
+        // Commands run by a second, identical diff.
+        let repeat_calls = |service: &NixService<MockCommandRunner>| {
+            service.get_nix_diff("1", "2").unwrap();
+            let calls = service.runner.calls().len();
+            let diff = service.get_nix_diff("1", "2").unwrap();
+            assert_eq!(
+                diff.added,
+                ["/nix/store/dddddddddddddddddddddddddddddddd-firefox-122.0"]
+            );
+            service.runner.calls().len() - calls
+        };
+
+        let cached = service(runner());
+        assert_eq!(repeat_calls(&cached), 0);
+        // Another mode or pair is a different entry.
+        assert!(cached.get_reference_diff("1", "2").is_err());
+        assert!(cached.get_nix_diff("2", "1").is_err());
+
+        let uncached = service(runner()).with_diff_cache(0);
+        assert_eq!(repeat_calls(&uncached), 3);
+
+        // On another host the profile's mtime comes from `stat` there, and a
+        // new one drops the cached diffs.
+        let stat = "stat -c %Y /nix/var/nix/profiles/system";
+        let remote = service(
+            runner()
+                .remote()
+                .with_stdout(stat, "1707472800\n")
+                .with_stdout(stat, "1707472800\n")
+                .with_stdout(stat, "1707559200\n"),
+        );
+        assert_eq!(repeat_calls(&remote), 1);
+        let calls = remote.runner.calls().len();
+        remote.get_nix_diff("1", "2").unwrap();
+        assert_eq!(remote.runner.calls().len() - calls, 4);
+    }
+
+    #[test]
+    fn test_derivation_diff() {
+        let runner = MockCommandRunner::new()
+            .with_stdout(
+                "nix-env -p /nix/var/nix/profiles/system-1-link --query --out-path",
+                "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-nixos\n",
+            )
+            .with_stdout(
+                "nix-env -p /nix/var/nix/profiles/system-2-link --query --out-path",
+                "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-nixos\n",
+            )
+            .with_stdout(
+                "nix-diff /nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-nixos \
+                 /nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-nixos",
+                include_str!("../../tests/fixtures/nix-diff-verbose.txt"),
+            );
+        let service = service(runner);
+
+        let changes = service.get_derivation_diff("1", "2").unwrap();
+        let names: Vec<&str> = changes.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "nixos-system-host-24.05.20240212.0c2c8a0",
+                "etc",
+                "system-path",
+                "openssl"
+            ]
+        );
+        assert!(service.get_derivation_diff("2", "2").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_diff_falls_back_without_nix_diff() {
+        let runner = MockCommandRunner::new()
+            .with_missing_program("nix-diff")
+            .with_stdout(
+                "nix-env -p /nix/var/nix/profiles/system-1-link --query --out-path",
+                "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-nixos\n",
+            )
+            .with_stdout(
+                "nix-env -p /nix/var/nix/profiles/system-2-link --query --out-path",
+                "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-nixos\n",
+            )
+            .with_stdout(
+                "nix-store -q --references /nix/var/nix/profiles/system-1-link",
+                "/nix/store/cccccccccccccccccccccccccccccccc-vim-9.0\n",
+            )
+            .with_stdout(
+                "nix-store -q --references /nix/var/nix/profiles/system-2-link",
+                "/nix/store/dddddddddddddddddddddddddddddddd-firefox-122.0\n",
+            );
+        let service = service(runner);
+
+        assert!(matches!(
+            service.get_nix_diff("1", "2"),
+            Err(Error::ToolNotInstalled(tool)) if tool == "nix-diff"
+        ));
+        let diff = service.get_diff("1", "2").unwrap();
+        assert_eq!(
+            diff.added,
+            ["/nix/store/dddddddddddddddddddddddddddddddd-firefox-122.0"]
+        );
+        assert_eq!(
+            diff.removed,
+            ["/nix/store/cccccccccccccccccccccccccccccccc-vim-9.0"]
+        );
+    }
+
+    #[test]
+    fn test_resolve_ref() {
+        let service = service(ref_runner());
+
+        assert_eq!(service.resolve_ref("7").unwrap(), "7");
+        assert_eq!(service.resolve_ref("current").unwrap(), "11");
+        assert_eq!(service.resolve_ref("HEAD").unwrap(), "11");
+        assert_eq!(service.resolve_ref("previous").unwrap(), "10");
+        assert_eq!(service.resolve_ref("HEAD~2").unwrap(), "9");
+        assert!(matches!(
+            service.resolve_ref("HEAD~3"),
+            Err(Error::InvalidArgument(msg)) if msg.contains("out of range")
+        ));
+        assert!(matches!(
+            service.resolve_ref("latest"),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn test_neighbor_skips_deleted_ids() {
+        let runner = MockCommandRunner::new()
+            .with_stdout(
+                LIST_GENERATIONS,
+                "   9   2024-02-08 10:00:00   \n  12   2024-02-09 10:00:00   \n\
+                 100   2024-02-10 11:30:00   (current)\n",
+            )
+            .with_stdout(READLINK, "system-100-link\n");
+        let service = service(runner);
+
+        let neighbor = |id, direction| service.neighbor(id, direction).unwrap();
+        assert_eq!(neighbor("12", Direction::Previous).as_deref(), Some("9"));
+        assert_eq!(neighbor("12", Direction::Next).as_deref(), Some("100"));
+        assert_eq!(neighbor("9", Direction::Next).as_deref(), Some("12"));
+        assert_eq!(neighbor("9", Direction::Previous), None);
+        assert_eq!(neighbor("100", Direction::Next), None);
+        assert!(matches!(
+            service.neighbor("10", Direction::Next),
+            Err(Error::GenerationNotFound(_))
+        ));
+        assert_eq!(service.resolve_ref("HEAD~2").unwrap(), "9");
+    }
+
+    #[test]
+    fn test_resolve_range_orders_ids() {
+        let service = service(ref_runner());
+
+        assert_eq!(
+            service.resolve_range("10..14").unwrap(),
+            ("10".to_string(), "14".to_string())
+        );
+        assert_eq!(
+            service.resolve_range("current..HEAD~2").unwrap(),
+            ("9".to_string(), "11".to_string())
+        );
+        assert!(service.resolve_range("10").is_err());
+    }
+
+    #[test]
+    fn test_get_generation_store_path_not_found() {
+        let runner = MockCommandRunner::new().with_response(
+            "nix-env -p /nix/var/nix/profiles/system-9-link --query --out-path",
+            1,
+            "",
+            "error: profile does not exist",
+        );
+
+        let err = service(runner).get_generation_store_path("9").unwrap_err();
+        assert!(matches!(err, Error::GenerationNotFound(id) if id == "9"));
+    }
+
+    #[test]
+    fn test_get_reference_diff() {
+        let runner = MockCommandRunner::new()
+            .with_stdout(
+                "nix-store -q --references /nix/var/nix/profiles/system-1-link",
+                "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-bash-5.2\n\
+                 /nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-vim-9.0\n",
+            )
+            .with_stdout(
+                "nix-store -q --references /nix/var/nix/profiles/system-2-link",
+                "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-bash-5.2\n\
+                 /nix/store/cccccccccccccccccccccccccccccccc-vim-9.1\n",
+            );
+
+        let diff = service(runner).get_reference_diff("1", "2").unwrap();
+        assert_eq!(
+            diff.added,
+            vec!["/nix/store/cccccccccccccccccccccccccccccccc-vim-9.1"]
+        );
+        assert_eq!(
+            diff.removed,
+            vec!["/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-vim-9.0"]
+        );
+        assert_eq!(
+            diff.modified,
+            vec!["/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-vim-9.0"]
+        );
+    }
+
+    #[test]
+    fn test_get_requisites_diff() {
+        let runner = MockCommandRunner::new()
+            .with_stdout(
+                "nix-store -q --requisites /nix/var/nix/profiles/system-1-link",
+                "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-glibc-2.38\n\
+                 /nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-bash-5.2\n",
+            )
+            .with_stdout(
+                "nix-store -q --requisites /nix/var/nix/profiles/system-2-link",
+                "/nix/store/cccccccccccccccccccccccccccccccc-glibc-2.39\n\
+                 /nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-bash-5.2\n",
+            );
+        let service = service(runner);
+
+        let diff = service.get_requisites_diff("1", "2").unwrap();
+        assert_eq!(
+            diff.modified,
+            vec!["/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-glibc-2.38"]
+        );
+        assert_eq!(diff.added.len(), 1);
+        assert!(service
+            .get_requisites_diff("2", "2")
+            .unwrap()
+            .added
+            .is_empty());
+    }
+
+    #[test]
+    fn test_list_generations_for_custom_profile() {
+        let profile = "/home/alice/.local/state/nix/profiles/home-manager";
+        let runner = MockCommandRunner::new()
+            .with_stdout(
+                &format!("nix-env --list-generations -p {}", profile),
+                "  41   2024-02-09 10:00:00   \n  42   2024-02-10 11:30:00   (current)\n",
+            )
+            .with_stdout(&format!("readlink {}", profile), "home-manager-42-link\n");
+
+        let service = service(runner).with_profile(profile);
+        let generations = service.list_generations().unwrap();
+        assert_eq!(generations.len(), 2);
+        assert!(generations[1].current);
+        assert_eq!(
+            generations[0].profiles,
+            vec![format!("{}-41-link", profile)]
+        );
+        // Only system generations have versions or boot state to read.
+        assert_eq!(service.runner.calls().len(), 2);
+    }
+
+    #[test]
+    fn test_rollback_dry_run_and_unknown_generation() {
+        let runner = MockCommandRunner::new()
+            .with_stdout(LIST_GENERATIONS, "   1   2024-02-09 10:00:00   \n")
+            .with_stdout(READLINK, "system-1-link\n");
+        let service = service(runner);
+
+        let plan = service.rollback("1", true).unwrap();
+        assert!(plan.dry_run);
+        assert_eq!(plan.commands.len(), 2);
+        assert!(!service
+            .runner
+            .calls()
+            .iter()
+            .any(|c| c.contains("--switch-generation")));
+
+        let err = service.rollback("7", true).unwrap_err();
+        assert!(matches!(err, Error::GenerationNotFound(id) if id == "7"));
+    }
+
+    #[test]
+    fn test_rollback_requires_root() {
+        let runner = MockCommandRunner::new()
+            .with_stdout(LIST_GENERATIONS, "   1   2024-02-09 10:00:00   \n")
+            .with_stdout(READLINK, "system-1-link\n")
+            .with_stdout("id -u", "1000\n");
+
+        let err = service(runner).rollback("1", false).unwrap_err();
+        assert!(matches!(err, Error::PermissionDenied(_)));
+    }
+
+    #[test]
+    fn test_collect_garbage() {
+        let runner = MockCommandRunner::new()
+            .with_stdout("id -u", "0\n")
+            .with_response(
+                "nix-collect-garbage --delete-older-than 30d",
+                0,
+                "removing old generations of profile /nix/var/nix/profiles/system\n",
+                "deleting unused links...\n12 store paths deleted, 1.50 MiB freed\n",
+            );
+        let service = service(runner);
+
+        let result = service.collect_garbage(Some(30), true).unwrap();
+        assert_eq!(
+            result.commands,
+            ["nix-collect-garbage --delete-older-than 30d"]
+        );
+        assert!(service.runner.calls().is_empty());
+
+        let result = service.collect_garbage(Some(30), false).unwrap();
+        assert_eq!(result.deleted_paths, 12);
+        assert_eq!(result.freed_bytes, 1_572_864);
+        assert!(!result.dry_run);
+    }
+
+    #[test]
+    fn test_collect_garbage_requires_root() {
+        let runner = MockCommandRunner::new().with_stdout("id -u", "1000\n");
+        let err = service(runner).collect_garbage(None, false).unwrap_err();
+        assert!(matches!(err, Error::PermissionDenied(_)));
+    }
+
+    #[test]
+    fn test_rollback_home_manager_profile() {
+        let profile = "/home/alice/.local/state/nix/profiles/home-manager";
+        let runner = MockCommandRunner::new()
+            .with_stdout(
+                &format!("nix-env --list-generations -p {}", profile),
+                "   3   2024-02-09 10:00:00   \n",
+            )
+            .with_stdout(&format!("readlink {}", profile), "home-manager-3-link\n")
+            .with_stdout(&format!("nix-env -p {} --switch-generation 3", profile), "")
+            .with_stdout(&format!("{}-3-link/activate", profile), "");
+        let service = service(runner).with_profile(profile);
+
+        let plan = service.rollback("3", false).unwrap();
+        assert_eq!(plan.commands[1], format!("{}-3-link/activate", profile));
+        assert!(!service.runner.calls().contains(&"id -u".to_string()));
+    }
+
+    fn deletion_runner() -> MockCommandRunner {
+        ref_runner()
+            .with_stdout(
+                "readlink /run/booted-system",
+                "/nix/store/ffffffffffffffffffffffffffffffff-nixos-system-10\n",
+            )
+            .with_stdout(
+                "readlink /nix/var/nix/profiles/system-10-link",
+                "/nix/store/ffffffffffffffffffffffffffffffff-nixos-system-10\n",
+            )
+    }
+
+    #[test]
+    fn test_delete_refuses_protected_generations() {
+        let service = service(deletion_runner());
+
+        for (id, reason) in [("11", "current"), ("10", "booted")] {
+            let err = service
+                .delete_generations(&[id.to_string()], true)
+                .unwrap_err();
+            assert!(
+                matches!(&err, Error::RefusedToDelete { reason: r, .. } if r.contains(reason)),
+                "{}",
+                err
+            );
+        }
+        assert!(matches!(
+            service.delete_generations(&["4".to_string()], true),
+            Err(Error::GenerationNotFound(id)) if id == "4"
+        ));
+    }
+
+    #[test]
+    fn test_delete_generations() {
+        let runner = deletion_runner().with_stdout("id -u", "0\n").with_stdout(
+            "nix-env --delete-generations 9 -p /nix/var/nix/profiles/system",
+            "",
+        );
+        let service = service(runner);
+
+        let plan = service
+            .delete_generations(&["9".to_string()], true)
+            .unwrap();
+        assert_eq!(
+            plan.commands,
+            ["nix-env --delete-generations 9 -p /nix/var/nix/profiles/system"]
+        );
+        assert!(!service
+            .runner
+            .calls()
+            .iter()
+            .any(|c| c.contains("--delete-generations")));
+
+        service
+            .delete_generations(&["9".to_string()], false)
+            .unwrap();
+        assert!(service
+            .runner
+            .calls()
+            .iter()
+            .any(|c| c.contains("--delete-generations")));
+    }
+
+    #[test]
+    fn test_deletion_candidates() {
+        let service = service(deletion_runner());
+        let cutoff = |date: &str| Some(parse_build_date(date).unwrap());
+
+        assert_eq!(service.deletion_candidates(Some(1), None).unwrap(), ["9"]);
+        assert!(service
+            .deletion_candidates(Some(3), None)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            service
+                .deletion_candidates(None, cutoff("2024-02-09 00:00:00"))
+                .unwrap(),
+            ["9"]
+        );
+        assert!(service
+            .deletion_candidates(None, cutoff("2024-02-08 00:00:00"))
+            .unwrap()
+            .is_empty());
     }
 }
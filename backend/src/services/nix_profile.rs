@@ -0,0 +1,190 @@
+use std::collections::BTreeMap;
+
+use crate::error::{Error, Result};
+use crate::models::diff::{ChangeDirection, DetailedDiff, PackageChange};
+use crate::models::generation::Generation;
+use crate::services::nix::{current_unavailable, NixService};
+use crate::services::parse::parse_profile_history;
+use crate::services::provider::GenerationProvider;
+use crate::services::runner::{CommandRunner, RealCommandRunner};
+
+/// Generations of a profile managed by the new-style `nix profile`, listed
+/// with `nix profile history`.
+///
+/// Like `DarwinService`, everything else goes through the wrapped
+/// `NixService`.
+pub struct NixProfileService<R: CommandRunner = RealCommandRunner> {
+    nix: NixService<R>,
+}
+
+/// Installed packages and their versions at one point in the history.
+type Packages = BTreeMap<String, Option<String>>;
+
+impl<R: CommandRunner> NixProfileService<R> {
+    pub fn new(nix: NixService<R>) -> Self {
+        Self { nix }
+    }
+
+    pub fn nix(&self) -> &NixService<R> {
+        &self.nix
+    }
+
+    fn profile(&self) -> String {
+        self.nix.profile_path().to_string_lossy().into_owned()
+    }
+
+    /// Every generation with its package changes against the one before it.
+    pub fn history(&self) -> Result<Vec<(Generation, DetailedDiff)>> {
+        let profile = self.profile();
+        let output = self
+            .nix
+            .run("nix", &["profile", "history", "--profile", &profile])?;
+
+        if !output.status.success() {
+            return Err(Error::NixCommandError(
+                String::from_utf8_lossy(&output.stderr).to_string(),
+            ));
+        }
+
+        let current = self.nix.get_current_generation()?;
+        parse_profile_history(&String::from_utf8_lossy(&output.stdout), &profile, &current)
+    }
+
+    /// The packages that changed between two generations, found by
+    /// replaying the history's per-generation changes up to each of them.
+    pub fn get_diff(&self, from: &str, to: &str) -> Result<DetailedDiff> {
+        let mut packages = Packages::new();
+        let (mut from_packages, mut to_packages) = (None, None);
+        for (generation, changes) in self.history()? {
+            apply(&mut packages, changes);
+            if generation.id == from {
+                from_packages = Some(packages.clone());
+            }
+            if generation.id == to {
+                to_packages = Some(packages.clone());
+            }
+        }
+
+        let from_packages =
+            from_packages.ok_or_else(|| Error::GenerationNotFound(from.to_string()))?;
+        let to_packages = to_packages.ok_or_else(|| Error::GenerationNotFound(to.to_string()))?;
+        Ok(diff_packages(&from_packages, &to_packages))
+    }
+}
+
+impl<R: CommandRunner> GenerationProvider for NixProfileService<R> {
+    fn list_generations(&self) -> Result<Vec<Generation>> {
+        Ok(self.history()?.into_iter().map(|(g, _)| g).collect())
+    }
+
+    fn get_current(&self) -> Result<Generation> {
+        let id = self
+            .nix
+            .get_current_generation()
+            .map_err(|e| current_unavailable(&self.profile(), e))?;
+
+        self.list_generations()?
+            .into_iter()
+            .find(|g| g.id == id)
+            .ok_or(Error::GenerationNotFound(id))
+    }
+
+    /// `nix profile` keeps a `manifest.json`, which `nix-env --query` can't
+    /// read, so the generation link is resolved directly.
+    fn get_store_path(&self, id: &str) -> Result<String> {
+        self.nix.resolve_generation_link(id)
+    }
+}
+
+fn apply(packages: &mut Packages, changes: DetailedDiff) {
+    for change in changes.removed {
+        packages.remove(&change.name);
+    }
+    for change in changes.added.into_iter().chain(changes.modified) {
+        packages.insert(change.name, change.new_version);
+    }
+}
+
+fn diff_packages(from: &Packages, to: &Packages) -> DetailedDiff {
+    let change = |name: &str, old: Option<&String>, new: Option<&String>| PackageChange {
+        name: name.to_string(),
+        old_version: old.cloned(),
+        new_version: new.cloned(),
+        direction: ChangeDirection::between(old.map(String::as_str), new.map(String::as_str)),
+        store_path: String::new(),
+        size_delta: None,
+        old_paths: Vec::new(),
+        new_paths: Vec::new(),
+        // Profile elements are what the user installed.
+        direct: Some(true),
+    };
+
+    let mut diff = DetailedDiff::default();
+    for (name, old) in from {
+        match to.get(name) {
+            None => diff.removed.push(change(name, old.as_ref(), None)),
+            Some(new) if new != old => diff.modified.push(change(name, old.as_ref(), new.as_ref())),
+            Some(_) => {}
+        }
+    }
+    for (name, new) in to {
+        if !from.contains_key(name) {
+            diff.added.push(change(name, None, new.as_ref()));
+        }
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::runner::MockCommandRunner;
+
+    const PROFILE: &str = "/home/alice/.local/state/nix/profiles/profile";
+
+    fn service() -> NixProfileService<MockCommandRunner> {
+        let runner = MockCommandRunner::new()
+            .with_stdout(
+                &format!("nix profile history --profile {}", PROFILE),
+                include_str!("../../tests/fixtures/nix-profile-history.txt"),
+            )
+            .with_stdout(&format!("readlink {}", PROFILE), "profile-4-link\n");
+        NixProfileService::new(NixService::with_runner(runner).with_profile(PROFILE))
+    }
+
+    #[test]
+    fn test_nix_profile_generations() {
+        let service = service();
+        let generations = service.list_generations().unwrap();
+        assert_eq!(generations.len(), 4);
+        assert_eq!(service.get_current().unwrap().id, "4");
+    }
+
+    #[test]
+    fn test_nix_profile_diff() {
+        let service = service();
+        let names = |changes: &[PackageChange]| -> Vec<String> {
+            changes
+                .iter()
+                .map(|c| c.name.rsplit('.').next().unwrap().to_string())
+                .collect()
+        };
+
+        let diff = service.get_diff("1", "4").unwrap();
+        assert_eq!(names(&diff.added), ["cowsay", "my-script"]);
+        assert_eq!(names(&diff.removed), ["ripgrep"]);
+        assert_eq!(names(&diff.modified), ["hello"]);
+        assert_eq!(diff.modified[0].direction, ChangeDirection::Upgrade);
+
+        let diff = service.get_diff("4", "2").unwrap();
+        assert_eq!(names(&diff.added), ["ripgrep"]);
+        assert_eq!(names(&diff.removed), ["my-script"]);
+        assert!(diff.modified.is_empty());
+
+        assert_eq!(service.get_diff("3", "3").unwrap().summary().added, 0);
+        assert!(matches!(
+            service.get_diff("1", "9"),
+            Err(Error::GenerationNotFound(_))
+        ));
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result};
+use crate::models::generation::Generation;
+use crate::output::write_output;
+
+/// Default location of the notes file, following the XDG base directory
+/// spec like the history database.
+pub fn default_notes_path() -> PathBuf {
+    env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")))
+        .unwrap_or_default()
+        .join("nix-timemach/notes.json")
+}
+
+/// User notes on generations, as JSON keyed by profile path and then by
+/// generation id. Changes are only written by `save`.
+#[derive(Debug, Default)]
+pub struct NoteStore {
+    path: PathBuf,
+    notes: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl NoteStore {
+    /// Read the notes at `path`; a missing file holds no notes.
+    pub fn open(path: &Path) -> Result<Self> {
+        let notes = match std::fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents).map_err(|e| {
+                Error::ParseError(format!("invalid notes file {}: {}", path.display(), e))
+            })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            notes,
+        })
+    }
+
+    pub fn get(&self, profile: &Path, id: &str) -> Option<&str> {
+        self.notes
+            .get(&key(profile))
+            .and_then(|notes| notes.get(id))
+            .map(String::as_str)
+    }
+
+    pub fn set(&mut self, profile: &Path, id: &str, note: &str) {
+        self.notes
+            .entry(key(profile))
+            .or_default()
+            .insert(id.to_string(), note.to_string());
+    }
+
+    /// Remove the note on `id`, returning it.
+    pub fn clear(&mut self, profile: &Path, id: &str) -> Option<String> {
+        let notes = self.notes.get_mut(&key(profile))?;
+        let note = notes.remove(id);
+        if notes.is_empty() {
+            self.notes.remove(&key(profile));
+        }
+        note
+    }
+
+    /// Fill in `note` on each of `generations` read from `profile`.
+    pub fn annotate(&self, profile: &Path, generations: &mut [Generation]) {
+        for generation in generations {
+            generation.note = self.get(profile, &generation.id).map(str::to_string);
+        }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let contents = serde_json::to_string_pretty(&self.notes)
+            .map_err(|e| Error::ParseError(e.to_string()))?;
+        write_output(&self.path, &(contents + "\n"))
+    }
+}
+
+fn key(profile: &Path) -> String {
+    profile.to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notes_round_trip() {
+        let dir = std::env::temp_dir().join(format!("nix-timemach-notes-{}", std::process::id()));
+        let path = dir.join("notes.json");
+        let system = Path::new("/nix/var/nix/profiles/system");
+        let home = Path::new("/home/alice/.local/state/nix/profiles/home-manager");
+
+        let mut store = NoteStore::open(&path).unwrap();
+        assert_eq!(store.get(system, "41"), None);
+        store.set(system, "41", "known-good, pre-kernel-6.8");
+        store.set(system, "42", "testing");
+        store.set(home, "41", "other profile");
+        store.save().unwrap();
+
+        let mut store = NoteStore::open(&path).unwrap();
+        assert_eq!(store.get(system, "41"), Some("known-good, pre-kernel-6.8"));
+        assert_eq!(store.clear(system, "42").as_deref(), Some("testing"));
+        assert_eq!(store.clear(system, "42"), None);
+
+        let mut generations = vec![
+            Generation {
+                id: "41".to_string(),
+                ..Default::default()
+            },
+            Generation {
+                id: "42".to_string(),
+                ..Default::default()
+            },
+        ];
+        store.annotate(system, &mut generations);
+        assert_eq!(
+            generations[0].note.as_deref(),
+            Some("known-good, pre-kernel-6.8")
+        );
+        assert_eq!(generations[1].note, None);
+
+        std::fs::write(&path, "not json").unwrap();
+        assert!(matches!(NoteStore::open(&path), Err(Error::ParseError(_))));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,78 @@
+use std::panic;
+use std::thread;
+
+/// Apply `f` to every item on at most `jobs` threads, keeping input order.
+///
+/// Items are split into one contiguous chunk per thread, so at most `jobs`
+/// commands run at once however many items there are.
+pub fn map_bounded<T, U, F>(items: &[T], jobs: usize, f: F) -> Vec<U>
+where
+    T: Sync,
+    U: Send,
+    F: Fn(&T) -> U + Sync,
+{
+    if jobs <= 1 || items.len() <= 1 {
+        return items.iter().map(f).collect();
+    }
+
+    let f = &f;
+    thread::scope(|scope| {
+        let workers: Vec<_> = items
+            .chunks(items.len().div_ceil(jobs))
+            .map(|chunk| scope.spawn(move || chunk.iter().map(f).collect::<Vec<_>>()))
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+            .collect()
+    })
+}
+
+/// Like `map_bounded`, updating the items in place.
+pub fn for_each_bounded<T, F>(items: &mut [T], jobs: usize, f: F)
+where
+    T: Send,
+    F: Fn(&mut T) + Sync,
+{
+    if jobs <= 1 || items.len() <= 1 {
+        items.iter_mut().for_each(f);
+        return;
+    }
+
+    let f = &f;
+    let chunk_len = items.len().div_ceil(jobs);
+    thread::scope(|scope| {
+        for chunk in items.chunks_mut(chunk_len) {
+            scope.spawn(move || chunk.iter_mut().for_each(f));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[test]
+    fn test_map_bounded_keeps_order_and_bound() {
+        let running = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+        let items: Vec<usize> = (0..20).collect();
+
+        let doubled = map_bounded(&items, 3, |&i| {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            peak.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(5));
+            running.fetch_sub(1, Ordering::SeqCst);
+            i * 2
+        });
+
+        assert_eq!(doubled, (0..20).map(|i| i * 2).collect::<Vec<_>>());
+        assert!(peak.load(Ordering::SeqCst) <= 3);
+
+        let mut items = items;
+        for_each_bounded(&mut items, 4, |i| *i += 1);
+        assert_eq!(items, (1..21).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,1085 @@
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use regex::Regex;
+use std::path::Path;
+use std::sync::LazyLock;
+use tracing::warn;
+
+use crate::error::{Error, Result};
+use crate::models::derivation::{DerivationChange, EnvironmentChange};
+use crate::models::diff::{ChangeDirection, DetailedDiff, GenerationDiff, PackageChange};
+use crate::models::gc::GcResult;
+use crate::models::generation::{FlakeInfo, Generation};
+use crate::models::store_path::StorePath;
+use crate::models::vulnerability::Vulnerability;
+
+// Fixed patterns are compiled once, on first use, rather than per call.
+static NIX_ENV_LINE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^\s*(→|->)?\s*(\d+)\s+(\d{4}-\d{2}-\d{2}\s+\d{2}:\d{2}:\d{2})(?:\s+(.*))?$")
+        .unwrap()
+});
+static ANSI_ESCAPE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\x1b\[[0-9;]*m").unwrap());
+static SIZE_DELTA: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^([+-]\d+(?:\.\d+)?) (B|KiB|MiB|GiB|TiB)$").unwrap());
+static GC_SUMMARY: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^(\d+) store paths deleted, (\d+(?:\.\d+)?) (B|KiB|MiB|GiB|TiB) freed$").unwrap()
+});
+static HISTORY_HEADER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^Version (\d+) \((\d{4}-\d{2}-\d{2})\)(?: <- \d+)?:$").unwrap());
+
+pub(crate) fn generation_link(profile: &str, id: &str) -> String {
+    format!("{}-{}-link", profile, id)
+}
+
+/// Parse either the classic `nix-env --list-generations` output or the
+/// table printed by `nixos-rebuild list-generations` on NixOS 23.11+,
+/// detected from its `Generation  Build-date ...` header.
+pub fn parse_generations_output(
+    output: &str,
+    profile: &str,
+    current_generation: &str,
+) -> Result<Vec<Generation>> {
+    let header = output.lines().find(|line| !line.trim().is_empty());
+    match header {
+        Some(header) if header.trim_start().starts_with("Generation") => {
+            parse_rebuild_generations(output, profile, current_generation)
+        }
+        _ => parse_nix_env_generations(output, profile, current_generation),
+    }
+}
+
+fn parse_nix_env_generations(
+    output: &str,
+    profile: &str,
+    current_generation: &str,
+) -> Result<Vec<Generation>> {
+    let mut generations = Vec::new();
+    for line in output.lines() {
+        let Some(caps) = NIX_ENV_LINE.captures(line) else {
+            if !line.trim().is_empty() {
+                warn!(line, "skipping unrecognised generation line");
+            }
+            continue;
+        };
+
+        let id = &caps[2];
+        let timestamp = parse_build_date(&caps[3])?;
+        // Depending on the release, nix-env marks the current generation
+        // with a trailing `(current)` or an arrow before the id. Either is
+        // dropped; the profile symlink decides which generation is current,
+        // the marker only when that is unknown.
+        let description = caps.get(4).map_or("", |m| m.as_str()).trim();
+        let (description, marked_current) = match description.strip_suffix("(current)") {
+            Some(rest) => (rest.trim_end(), true),
+            None => (description, caps.get(1).is_some()),
+        };
+        let nixos_version = description
+            .strip_prefix("nixos-")
+            .filter(|v| v.starts_with(|c: char| c.is_ascii_digit()))
+            .map(str::to_string);
+
+        generations.push(Generation {
+            id: id.to_string(),
+            timestamp,
+            description: (!description.is_empty()).then(|| description.to_string()),
+            profiles: vec![generation_link(profile, id)],
+            current: if current_generation.is_empty() {
+                marked_current
+            } else {
+                id == current_generation
+            },
+            booted: false,
+            nixos_version,
+            kernel_version: None,
+            size_bytes: None,
+            profile_name: None,
+            flake: None,
+            note: None,
+            age: None,
+        });
+    }
+
+    Ok(generations)
+}
+
+/// The `nixos-rebuild` table is column-aligned, so each cell is sliced
+/// out using the offsets of the header labels.
+fn parse_rebuild_generations(
+    output: &str,
+    profile: &str,
+    current_generation: &str,
+) -> Result<Vec<Generation>> {
+    let mut lines = output.lines().filter(|line| !line.trim().is_empty());
+    let header = lines.next().unwrap_or_default();
+
+    let mut columns: Vec<(&str, usize)> = [
+        "Generation",
+        "Build-date",
+        "NixOS version",
+        "Kernel",
+        "Configuration Revision",
+        "Specialisation",
+    ]
+    .into_iter()
+    .filter_map(|name| header.find(name).map(|start| (name, start)))
+    .collect();
+    columns.sort_by_key(|&(_, start)| start);
+
+    let cell = |line, name| cell(&columns, line, name);
+
+    let mut generations = Vec::new();
+    for line in lines {
+        let Some(id) = cell(line, "Generation").and_then(|value| {
+            value
+                .split_whitespace()
+                .find(|token| token.chars().all(|c| c.is_ascii_digit()))
+        }) else {
+            warn!(line, "skipping generation line without an id");
+            continue;
+        };
+        let Some(build_date) = cell(line, "Build-date") else {
+            warn!(line, "skipping generation line without a build date");
+            continue;
+        };
+
+        generations.push(Generation {
+            id: id.to_string(),
+            timestamp: parse_build_date(build_date)?,
+            description: None,
+            profiles: vec![generation_link(profile, id)],
+            current: id == current_generation,
+            booted: false,
+            nixos_version: cell(line, "NixOS version").map(str::to_string),
+            kernel_version: cell(line, "Kernel").map(str::to_string),
+            size_bytes: None,
+            profile_name: None,
+            flake: None,
+            note: None,
+            age: None,
+        });
+    }
+
+    Ok(generations)
+}
+
+/// The trimmed text under column `name`, which runs up to the next column's
+/// label.
+fn cell<'a>(columns: &[(&str, usize)], line: &'a str, name: &str) -> Option<&'a str> {
+    let index = columns.iter().position(|&(column, _)| column == name)?;
+    let start = columns[index].1.min(line.len());
+    let end = columns
+        .get(index + 1)
+        .map_or(line.len(), |&(_, end)| end.min(line.len()));
+    let value = line.get(start..end)?.trim();
+    (!value.is_empty()).then_some(value)
+}
+
+/// Extract the generation id from the target of the profile symlink, e.g.
+/// `system-42-link`. The target may be relative or absolute; only its last
+/// component has to name a generation of this profile.
+pub fn parse_current_generation(profile_path: &Path, target: &str) -> Result<String> {
+    let name = profile_path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let re = Regex::new(&format!(r"^{}-(\d+)-link$", regex::escape(&name)))
+        .map_err(|e| Error::ParseError(e.to_string()))?;
+    let link = Path::new(target.trim())
+        .file_name()
+        .map(|link| link.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    if let Some(caps) = re.captures(&link) {
+        Ok(caps[1].to_string())
+    } else {
+        warn!(target = %target.trim(), "profile does not point at a generation link");
+        Err(Error::ParseError(
+            "Failed to extract current generation ID".into(),
+        ))
+    }
+}
+
+/// The version of the kernel package a `<generation>/kernel` symlink points
+/// into, e.g. `/nix/store/<hash>-linux-6.6.15/bzImage`.
+pub fn kernel_version_from_target(target: &str) -> Option<String> {
+    let package = Path::new(target.trim())
+        .parent()
+        .map(|dir| dir.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    StorePath::parse(&package).version().map(str::to_string)
+}
+
+/// Read the flake revision from a `nixos-version.json`, as printed by
+/// `nixos-version --json`. Flakes commonly record a dirty tree as
+/// `<rev>-dirty`, or just `dirty` when no revision is known.
+pub(crate) fn parse_flake_info(json: &str) -> Option<FlakeInfo> {
+    let info: serde_json::Value = serde_json::from_str(json)
+        .inspect_err(|e| warn!(error = %e, "failed to parse nixos-version.json"))
+        .ok()?;
+    let revision = info["configurationRevision"].as_str()?.trim();
+
+    Some(match revision {
+        "" => return None,
+        "dirty" => FlakeInfo {
+            revision: None,
+            dirty: true,
+        },
+        revision => FlakeInfo {
+            revision: Some(revision.trim_end_matches("-dirty").to_string()),
+            dirty: revision.ends_with("-dirty"),
+        },
+    })
+}
+
+/// Parse `nix path-info --json` output into entries paired with the store
+/// path each describes.
+pub fn parse_path_info(stdout: &[u8]) -> Result<Vec<(String, serde_json::Value)>> {
+    let info: serde_json::Value = serde_json::from_slice(stdout).map_err(|e| {
+        warn!(error = %e, "failed to parse path-info output");
+        Error::ParseError(e.to_string())
+    })?;
+
+    // Older Nix prints an array of objects, newer releases an object keyed
+    // by store path.
+    Ok(match info {
+        serde_json::Value::Array(entries) => entries
+            .into_iter()
+            .map(|entry| {
+                let path = entry["path"].as_str().unwrap_or_default().to_string();
+                (path, entry)
+            })
+            .collect(),
+        serde_json::Value::Object(entries) => entries.into_iter().collect(),
+        _ => Vec::new(),
+    })
+}
+
+/// Parse `nix-diff` output. Unindented `+ `, `- ` and `~ ` lines become
+/// `added`, `removed` and `modified`, each sorted and without duplicates.
+/// Everything else is skipped: `•` explanations, the indented context under
+/// them (which has `+`/`-` lines of its own), and markers not followed by a
+/// space such as the `+{man}` of an output-set mismatch.
+pub fn parse_diff_output(output: &str) -> Result<GenerationDiff> {
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
+    let mut modified = Vec::new();
+
+    for line in output.lines() {
+        let mut chars = line.chars();
+        let (Some(marker), Some(' ')) = (chars.next(), chars.next()) else {
+            continue;
+        };
+        let path = chars.as_str().trim();
+        if path.is_empty() {
+            continue;
+        }
+        match marker {
+            '+' => added.push(path.to_string()),
+            '-' => removed.push(path.to_string()),
+            '~' => modified.push(path.to_string()),
+            _ => {}
+        }
+    }
+    for paths in [&mut added, &mut removed, &mut modified] {
+        paths.sort();
+        paths.dedup();
+    }
+
+    Ok(GenerationDiff {
+        added,
+        removed,
+        modified,
+        ..Default::default()
+    })
+}
+
+/// What the lines after one of nix-diff's `•` statements describe.
+enum DrvSection {
+    /// The `-`/`+` derivation paths, after `The input derivation named ...`.
+    Paths,
+    InputNames,
+    Environment,
+    /// Inside a multi-line `name=''` value of the environment.
+    Variable(String),
+    Other,
+}
+
+/// Parse the indented tree `nix-diff` prints into one change per derivation
+/// it descends into, each before its inputs. The first is the generations'
+/// own derivation, whose paths head the output.
+pub fn parse_derivation_diff(output: &str) -> Vec<DerivationChange> {
+    let mut changes = vec![DerivationChange::default()];
+    // The indent of each `The input derivation named` statement enclosing
+    // the current line, with the change it opened.
+    let mut stack: Vec<(usize, usize)> = Vec::new();
+    let mut section = DrvSection::Paths;
+
+    for line in output.lines() {
+        let line = ANSI_ESCAPE.replace_all(line, "");
+        let content = line.trim();
+        if content.is_empty() {
+            continue;
+        }
+        let indent = line.len() - line.trim_start().len();
+        while stack.last().is_some_and(|&(bullet, _)| bullet >= indent) {
+            stack.pop();
+        }
+        let node = stack.last().map_or(0, |&(_, change)| change);
+
+        if let Some(statement) = content.strip_prefix('•') {
+            let statement = statement.trim();
+            section = if let Some(name) = input_derivation_name(statement) {
+                changes[node].changed_inputs.push(name.to_string());
+                changes.push(DerivationChange {
+                    name: name.to_string(),
+                    depth: stack.len() + 1,
+                    ..Default::default()
+                });
+                stack.push((indent, changes.len() - 1));
+                DrvSection::Paths
+            } else if statement.starts_with("The set of input derivation names do not match") {
+                DrvSection::InputNames
+            } else if statement.starts_with("The environments do not match") {
+                DrvSection::Environment
+            } else {
+                changes[node]
+                    .other
+                    .push(statement.trim_end_matches(':').to_string());
+                DrvSection::Other
+            };
+            continue;
+        }
+
+        let change = &mut changes[node];
+        let (sign, rest) = match content.split_at_checked(2) {
+            Some((sign @ ("- " | "+ "), rest)) => (sign.chars().next(), rest.trim()),
+            _ => (None, content),
+        };
+        let next = match (&section, sign) {
+            (DrvSection::Paths, Some('-')) => {
+                change.old_path = Some(derivation_path(rest));
+                None
+            }
+            (DrvSection::Paths, Some(_)) => {
+                change.new_path = Some(derivation_path(rest));
+                None
+            }
+            (DrvSection::InputNames, Some('-')) => {
+                change.removed_inputs.push(rest.to_string());
+                None
+            }
+            (DrvSection::InputNames, Some(_)) => {
+                change.added_inputs.push(rest.to_string());
+                None
+            }
+            (DrvSection::Environment, None) => content
+                .strip_suffix("=''")
+                .map(|name| DrvSection::Variable(name.to_string())),
+            (DrvSection::Environment, Some(sign)) => {
+                if let Some((name, value)) = rest.split_once('=') {
+                    set_variable(change, name, sign, value);
+                }
+                None
+            }
+            (DrvSection::Variable(_), None) if content == "''" => Some(DrvSection::Environment),
+            (DrvSection::Variable(name), Some(sign)) => {
+                set_variable(change, name, sign, rest);
+                None
+            }
+            _ => None,
+        };
+        if let Some(next) = next {
+            section = next;
+        }
+    }
+
+    let root = &mut changes[0];
+    if let Some(path) = root.new_path.as_ref().or(root.old_path.as_ref()) {
+        root.name = derivation_name(path);
+    }
+    if root.name.is_empty() {
+        changes.remove(0);
+    }
+    changes
+}
+
+fn input_derivation_name(statement: &str) -> Option<&str> {
+    statement
+        .strip_prefix("The input derivation named `")?
+        .strip_suffix("` differs")
+}
+
+/// `/nix/store/<hash>-openssl-3.0.12.drv:{out}` without the outputs.
+fn derivation_path(path: &str) -> String {
+    path.split_once(":{")
+        .map_or(path, |(path, _)| path)
+        .to_string()
+}
+
+/// `openssl-3.0.12` for `/nix/store/<hash>-openssl-3.0.12.drv`.
+fn derivation_name(path: &str) -> String {
+    let basename = path.rsplit('/').next().unwrap_or(path);
+    let name = basename.split_once('-').map_or(basename, |(_, name)| name);
+    name.strip_suffix(".drv").unwrap_or(name).to_string()
+}
+
+/// Record one side of an environment variable, appending to what is already
+/// there so the changed lines of a multi-line value are kept together.
+fn set_variable(change: &mut DerivationChange, name: &str, sign: char, value: &str) {
+    let index = match change.environment.iter().position(|v| v.name == name) {
+        Some(index) => index,
+        None => {
+            change.environment.push(EnvironmentChange {
+                name: name.to_string(),
+                ..Default::default()
+            });
+            change.environment.len() - 1
+        }
+    };
+    let variable = &mut change.environment[index];
+    let side = if sign == '-' {
+        &mut variable.old_value
+    } else {
+        &mut variable.new_value
+    };
+    match side {
+        Some(existing) => {
+            existing.push('\n');
+            existing.push_str(value);
+        }
+        None => *side = Some(value.to_string()),
+    }
+}
+
+/// Parse `nix-store -q --references` output into sorted, distinct paths.
+pub(crate) fn parse_references(output: &str) -> Vec<String> {
+    let mut references: Vec<String> = output
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect();
+    references.sort();
+    references.dedup();
+    references
+}
+
+/// Parse `vulnix --json` output: one entry per vulnerable derivation, with
+/// its CVEs in `affected_by` and their scores in `cvssv3_basescore`.
+pub(crate) fn parse_vulnix_output(output: &str) -> Result<Vec<Vulnerability>> {
+    // vulnix prints nothing rather than `[]` for a clean closure.
+    if output.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    let entries: Vec<serde_json::Value> = serde_json::from_str(output)
+        .map_err(|e| Error::ParseError(format!("invalid vulnix output: {}", e)))?;
+
+    let mut vulnerabilities: Vec<Vulnerability> = entries
+        .iter()
+        .flat_map(|entry| {
+            let package = entry["pname"]
+                .as_str()
+                .or_else(|| entry["name"].as_str())
+                .unwrap_or_default()
+                .to_string();
+            let cves = entry["affected_by"].as_array().cloned().unwrap_or_default();
+            cves.into_iter().filter_map(move |cve| {
+                let cve = cve.as_str()?.to_string();
+                Some(Vulnerability {
+                    severity: entry["cvssv3_basescore"][&cve].as_f64().map(|s| s as f32),
+                    cve,
+                    package: package.clone(),
+                })
+            })
+        })
+        .collect();
+    vulnerabilities.sort_by(|a, b| (&a.cve, &a.package).cmp(&(&b.cve, &b.package)));
+    Ok(vulnerabilities)
+}
+
+/// Parse lines like `openssl: 3.0.10 → 3.0.12, +0.1 KiB`. A `∅` version
+/// marks a package that is absent on that side.
+pub fn parse_closure_diff_output(output: &str) -> Result<DetailedDiff> {
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
+    let mut modified = Vec::new();
+
+    for line in output.lines() {
+        let line = ANSI_ESCAPE.replace_all(line, "");
+        let Some((name, rest)) = line.trim().split_once(": ") else {
+            if !line.trim().is_empty() {
+                warn!(line = %line, "skipping unrecognised diff-closures line");
+            }
+            continue;
+        };
+
+        let (versions, size) = match rest.rsplit_once(", ") {
+            Some((versions, size)) if SIZE_DELTA.is_match(size) => (versions, Some(size)),
+            _ if SIZE_DELTA.is_match(rest) => ("", Some(rest)),
+            _ => (rest, None),
+        };
+
+        let size_delta = size.and_then(|size| {
+            let caps = SIZE_DELTA.captures(size)?;
+            let value: f64 = caps[1].parse().ok()?;
+            Some((value * unit_bytes(&caps[2])).round() as i64)
+        });
+
+        let (old_version, new_version) = match versions.split_once(" → ") {
+            Some((old, new)) => (old.trim(), new.trim()),
+            None => ("", ""),
+        };
+        let version = |v: &str| match v {
+            "" | "∅" | "ε" => None,
+            v => Some(v.to_string()),
+        };
+
+        let (old, new) = (version(old_version), version(new_version));
+        let change = PackageChange {
+            name: name.to_string(),
+            direction: ChangeDirection::between(old.as_deref(), new.as_deref()),
+            old_version: old,
+            new_version: new,
+            store_path: String::new(),
+            size_delta,
+            old_paths: Vec::new(),
+            new_paths: Vec::new(),
+            direct: None,
+        };
+
+        if old_version == "∅" {
+            added.push(change);
+        } else if new_version == "∅" {
+            removed.push(change);
+        } else {
+            modified.push(change);
+        }
+    }
+
+    Ok(DetailedDiff {
+        added,
+        removed,
+        modified,
+        ..Default::default()
+    })
+}
+
+/// Bytes in one B, KiB, MiB, GiB or TiB.
+fn unit_bytes(unit: &str) -> f64 {
+    match unit {
+        "B" => 1.0,
+        "KiB" => 1024.0,
+        "MiB" => 1024.0 * 1024.0,
+        "GiB" => 1024.0 * 1024.0 * 1024.0,
+        _ => 1024.0 * 1024.0 * 1024.0 * 1024.0,
+    }
+}
+
+/// Parse the `N store paths deleted, 12.34 MiB freed` summary that ends
+/// `nix-collect-garbage` output. Sizes are rounded to two decimals, so
+/// `freed_bytes` is approximate.
+pub fn parse_gc_output(output: &str) -> Result<GcResult> {
+    let lines: Vec<_> = output
+        .lines()
+        .map(|line| ANSI_ESCAPE.replace_all(line, ""))
+        .collect();
+    let caps = lines
+        .iter()
+        .rev()
+        .find_map(|line| GC_SUMMARY.captures(line.trim()))
+        .ok_or_else(|| Error::ParseError("no summary in nix-collect-garbage output".to_string()))?;
+
+    let invalid = |e: &dyn std::fmt::Display| Error::ParseError(format!("{}: {}", &caps[0], e));
+    let size: f64 = caps[2].parse().map_err(|e| invalid(&e))?;
+    Ok(GcResult {
+        freed_bytes: (size * unit_bytes(&caps[3])).round() as u64,
+        deleted_paths: caps[1].parse().map_err(|e| invalid(&e))?,
+        ..Default::default()
+    })
+}
+
+/// Parse `nix profile history`: a `Version N (date) <- M:` header per
+/// generation, followed by its package changes against the version before
+/// it as `name: old -> new`, with `∅` for an absent package and `ε` for a
+/// missing version. Dates are UTC days.
+pub fn parse_profile_history(
+    output: &str,
+    profile: &str,
+    current_generation: &str,
+) -> Result<Vec<(Generation, DetailedDiff)>> {
+    let mut versions: Vec<(Generation, DetailedDiff)> = Vec::new();
+    for line in output.lines() {
+        let line = ANSI_ESCAPE.replace_all(line, "");
+        let line = line.trim();
+
+        if let Some(caps) = HISTORY_HEADER.captures(line) {
+            let id = caps[1].to_string();
+            let date = NaiveDate::parse_from_str(&caps[2], "%Y-%m-%d")
+                .map_err(|e| Error::ParseError(format!("invalid date {}: {}", &caps[2], e)))?;
+            let generation = Generation {
+                id: id.clone(),
+                timestamp: date.and_time(NaiveTime::MIN).and_utc(),
+                profiles: vec![generation_link(profile, &id)],
+                current: id == current_generation,
+                ..Default::default()
+            };
+            versions.push((generation, DetailedDiff::default()));
+            continue;
+        }
+        if line.is_empty() || line == "No changes." {
+            continue;
+        }
+
+        let change = line
+            .split_once(": ")
+            .and_then(|(name, versions)| Some((name, versions.split_once(" -> ")?)));
+        let (Some((_, changes)), Some((name, (old, new)))) = (versions.last_mut(), change) else {
+            warn!(line, "skipping unrecognised profile history line");
+            continue;
+        };
+
+        let version = |v: &str| match v {
+            "∅" | "ε" => None,
+            v => Some(v.to_string()),
+        };
+        let (old_version, new_version) = (version(old), version(new));
+        let change = PackageChange {
+            name: name.to_string(),
+            direction: ChangeDirection::between(old_version.as_deref(), new_version.as_deref()),
+            old_version,
+            new_version,
+            store_path: String::new(),
+            size_delta: None,
+            old_paths: Vec::new(),
+            new_paths: Vec::new(),
+            direct: None,
+        };
+
+        if old == "∅" {
+            changes.added.push(change);
+        } else if new == "∅" {
+            changes.removed.push(change);
+        } else {
+            changes.modified.push(change);
+        }
+    }
+
+    Ok(versions)
+}
+
+/// Parse a build date as printed by nix, which is in the machine's local
+/// time.
+pub(crate) fn parse_build_date(date: &str) -> Result<DateTime<Utc>> {
+    parse_build_date_in(date, &Local)
+}
+
+fn parse_build_date_in<Tz: TimeZone>(date: &str, tz: &Tz) -> Result<DateTime<Utc>> {
+    let naive = NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S").map_err(|e| {
+        warn!(date, error = %e, "failed to parse build date");
+        Error::ParseError(e.to_string())
+    })?;
+
+    // An ambiguous time at the end of DST is taken as the earlier instant.
+    tz.from_local_datetime(&naive)
+        .earliest()
+        .map(|timestamp| timestamp.with_timezone(&Utc))
+        .ok_or_else(|| Error::ParseError(format!("build date {} does not exist locally", date)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::diff::DiffSummary;
+
+    const PROFILE: &str = "/nix/var/nix/profiles/system";
+
+    #[test]
+    fn test_parse_build_date_is_local() {
+        let offset = chrono::FixedOffset::east_opt(2 * 3600).unwrap();
+
+        assert_eq!(
+            parse_build_date_in("2024-02-10 11:30:00", &offset)
+                .unwrap()
+                .to_rfc3339(),
+            "2024-02-10T09:30:00+00:00"
+        );
+        assert!(parse_build_date_in("2024-02-10", &offset).is_err());
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn prop_build_date_round_trips(
+            (y, mo, d) in (1970..2100i32, 1..=12u32, 1..=28u32),
+            (h, mi, s) in (0..24u32, 0..60u32, 0..60u32),
+            offset_minutes in -12 * 60..=14 * 60i32,
+        ) {
+            let date = format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", y, mo, d, h, mi, s);
+            let offset = chrono::FixedOffset::east_opt(offset_minutes * 60).unwrap();
+
+            let parsed = parse_build_date_in(&date, &offset).unwrap();
+            proptest::prop_assert_eq!(
+                parsed.with_timezone(&offset).format("%Y-%m-%d %H:%M:%S").to_string(),
+                date.clone()
+            );
+            let utc = parse_build_date_in(&date, &Utc).unwrap();
+            proptest::prop_assert_eq!(
+                (utc - parsed).num_minutes(),
+                i64::from(offset_minutes)
+            );
+        }
+
+        #[test]
+        fn prop_out_of_range_fields_are_parse_errors(
+            month in 13..=99u32,
+            hour in 24..=99u32,
+        ) {
+            for date in [
+                format!("2024-{:02}-10 11:30:00", month),
+                format!("2024-02-10 {:02}:30:00", hour),
+                "2024-02-10 11:30".to_string(),
+            ] {
+                proptest::prop_assert!(matches!(
+                    parse_build_date_in(&date, &Utc),
+                    Err(Error::ParseError(_))
+                ));
+            }
+        }
+
+        #[test]
+        fn prop_arbitrary_input_never_panics(date in "\\PC{0,24}") {
+            let _ = parse_build_date_in(&date, &Utc);
+        }
+    }
+
+    #[test]
+    fn test_parse_flake_info() {
+        let info = |revision: &str| {
+            parse_flake_info(&format!(
+                r#"{{"nixosVersion": "24.05.20240210.f9d39fb", "configurationRevision": "{}"}}"#,
+                revision
+            ))
+        };
+
+        assert_eq!(
+            info("3c0ba4c7e1b9"),
+            Some(FlakeInfo {
+                revision: Some("3c0ba4c7e1b9".to_string()),
+                dirty: false,
+            })
+        );
+        assert_eq!(
+            info("3c0ba4c7e1b9-dirty"),
+            Some(FlakeInfo {
+                revision: Some("3c0ba4c7e1b9".to_string()),
+                dirty: true,
+            })
+        );
+        assert_eq!(info("dirty").unwrap().revision, None);
+        assert_eq!(parse_flake_info(r#"{"nixosVersion": "24.05"}"#), None);
+        assert_eq!(parse_flake_info("not json"), None);
+    }
+
+    #[test]
+    fn test_parse_vulnix_output() {
+        let output = r#"[{
+            "name": "openssl-3.0.10",
+            "pname": "openssl",
+            "version": "3.0.10",
+            "affected_by": ["CVE-2023-5678", "CVE-2023-3817"],
+            "whitelisted": [],
+            "cvssv3_basescore": {"CVE-2023-5678": 5.3}
+        }]"#;
+
+        let vulnerabilities = parse_vulnix_output(output).unwrap();
+        assert_eq!(
+            vulnerabilities,
+            vec![
+                Vulnerability {
+                    cve: "CVE-2023-3817".to_string(),
+                    package: "openssl".to_string(),
+                    severity: None,
+                },
+                Vulnerability {
+                    cve: "CVE-2023-5678".to_string(),
+                    package: "openssl".to_string(),
+                    severity: Some(5.3),
+                },
+            ]
+        );
+        assert!(parse_vulnix_output("").unwrap().is_empty());
+        assert!(parse_vulnix_output("not json").is_err());
+    }
+
+    #[test]
+    fn test_parse_generations_output() {
+        let sample_output = r#"   1   2024-02-09 10:00:00   nixos-22.11.20240209.123
+   2   2024-02-09 11:00:00   nixos-22.11.20240209.456"#;
+
+        let generations = parse_generations_output(sample_output, PROFILE, "2").unwrap();
+        assert_eq!(generations.len(), 2);
+        assert_eq!(generations[0].id, "1");
+        assert_eq!(generations[1].id, "2");
+    }
+
+    const NIX_ENV_FIXTURE: &str = include_str!("../../tests/fixtures/nix-env-list-generations.txt");
+    const NIXOS_REBUILD_FIXTURE: &str =
+        include_str!("../../tests/fixtures/nixos-rebuild-list-generations.txt");
+
+    #[test]
+    fn test_parse_diff_output_skips_context() {
+        let diff = parse_diff_output(include_str!("../../tests/fixtures/nix-diff.txt")).unwrap();
+
+        assert_eq!(
+            diff.added,
+            vec!["/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-firefox-122.0"]
+        );
+        assert_eq!(
+            diff.removed,
+            vec!["/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-vim-9.0"]
+        );
+        assert_eq!(
+            diff.modified,
+            vec!["/nix/store/cccccccccccccccccccccccccccccccc-openssl-3.0.10"]
+        );
+        assert_eq!(parse_diff_output("").unwrap().summary().added, 0);
+    }
+
+    #[test]
+    fn test_parse_closure_diff_output() {
+        let diff = parse_closure_diff_output(include_str!(
+            "../../tests/fixtures/nix-store-diff-closures.txt"
+        ))
+        .unwrap();
+
+        let names = |changes: &[PackageChange]| -> Vec<String> {
+            changes.iter().map(|c| c.name.clone()).collect()
+        };
+        assert_eq!(names(&diff.added), ["firefox"]);
+        assert_eq!(diff.added[0].size_delta, Some(245_891_072));
+        assert_eq!(names(&diff.removed), ["vim"]);
+        assert_eq!(diff.removed[0].old_version.as_deref(), Some("9.0"));
+        assert_eq!(names(&diff.modified), ["openssl", "linux", "nixos-system"]);
+        assert_eq!(diff.modified[0].direction, ChangeDirection::Upgrade);
+        assert_eq!(diff.modified[1].size_delta, None);
+        assert_eq!(diff.modified[2].old_version, None);
+        assert_eq!(diff.modified[2].size_delta, Some(2048));
+    }
+
+    #[test]
+    fn test_parse_gc_output() {
+        let output = "finding garbage collector roots...\n\
+                      removing stale link from '/nix/var/nix/gcroots/auto/abc'\n\
+                      deleting '/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-vim-9.0'\n\
+                      deleting unused links...\n\
+                      note: currently hard linking saves 1.50 MiB\n\
+                      1234 store paths deleted, 567.89 MiB freed\n";
+        let result = parse_gc_output(output).unwrap();
+        assert_eq!(result.deleted_paths, 1234);
+        assert_eq!(result.freed_bytes, 595_475_825);
+
+        let result = parse_gc_output("0 store paths deleted, 0.00 MiB freed\n").unwrap();
+        assert_eq!((result.deleted_paths, result.freed_bytes), (0, 0));
+        assert!(matches!(
+            parse_gc_output("deleting unused links...\n"),
+            Err(Error::ParseError(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_profile_history() {
+        let history = parse_profile_history(
+            include_str!("../../tests/fixtures/nix-profile-history.txt"),
+            PROFILE,
+            "4",
+        )
+        .unwrap();
+
+        let ids: Vec<&str> = history.iter().map(|(g, _)| g.id.as_str()).collect();
+        assert_eq!(ids, ["1", "2", "3", "4"]);
+        let (first, changes) = &history[0];
+        assert_eq!(
+            first.timestamp,
+            Utc.with_ymd_and_hms(2024, 2, 9, 0, 0, 0).unwrap()
+        );
+        assert_eq!(first.profiles, [generation_link(PROFILE, "1")]);
+        assert!(!first.current && history[3].0.current);
+        assert_eq!(changes.added.len(), 2);
+        assert_eq!(
+            changes.added[0].name,
+            "flake:nixpkgs#legacyPackages.x86_64-linux.hello"
+        );
+
+        let (_, changes) = &history[1];
+        assert_eq!(changes.modified[0].old_version.as_deref(), Some("2.12.1"));
+        assert_eq!(changes.modified[0].direction, ChangeDirection::Upgrade);
+        assert_eq!(changes.added.len(), 1);
+        assert_eq!(history[2].1.summary(), DiffSummary::default());
+        let (_, changes) = &history[3];
+        assert_eq!(changes.removed[0].old_version.as_deref(), Some("14.0.3"));
+        assert_eq!(changes.added[0].new_version, None);
+    }
+
+    #[test]
+    fn test_parse_references_dedups_and_sorts() {
+        let references = parse_references(include_str!(
+            "../../tests/fixtures/nix-store-references-duplicates.txt"
+        ));
+        assert_eq!(
+            references,
+            vec![
+                "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-bash-5.2",
+                "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-vim-9.0",
+                "/nix/store/cccccccccccccccccccccccccccccccc-openssl-3.0.10",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_derivation_diff() {
+        let changes =
+            parse_derivation_diff(include_str!("../../tests/fixtures/nix-diff-verbose.txt"));
+        let tree: Vec<(&str, usize)> = changes.iter().map(|c| (c.name.as_str(), c.depth)).collect();
+        assert_eq!(
+            tree,
+            [
+                ("nixos-system-host-24.05.20240212.0c2c8a0", 0),
+                ("etc", 1),
+                ("system-path", 2),
+                ("openssl", 3)
+            ]
+        );
+
+        let root = &changes[0];
+        assert_eq!(root.changed_inputs, ["etc"]);
+        assert!(root
+            .new_path
+            .as_deref()
+            .is_some_and(|path| path.ends_with("-nixos-system-host-24.05.20240212.0c2c8a0.drv")));
+        assert_eq!(root.other, ["The builders do not match"]);
+        assert_eq!(
+            root.environment,
+            [
+                EnvironmentChange {
+                    name: "NIXOS_LABEL".into(),
+                    old_value: Some("24.05.20240210.f9d39fb".into()),
+                    new_value: Some("24.05.20240212.0c2c8a0".into()),
+                },
+                EnvironmentChange {
+                    name: "NIXOS_DEBUG".into(),
+                    old_value: None,
+                    new_value: Some("1".into()),
+                },
+            ]
+        );
+
+        let system_path = &changes[2];
+        assert_eq!(system_path.changed_inputs, ["openssl"]);
+        assert_eq!(system_path.added_inputs, ["firefox-122.0"]);
+        assert_eq!(system_path.removed_inputs, ["vim-9.0"]);
+
+        let openssl = &changes[3];
+        assert_eq!(openssl.other, ["The input sources do not match"]);
+        let version = &openssl.environment[1];
+        assert_eq!(version.name, "version");
+        assert_eq!(
+            (version.old_value.as_deref(), version.new_value.as_deref()),
+            (Some("3.0.10"), Some("3.0.12"))
+        );
+
+        assert!(parse_derivation_diff("").is_empty());
+    }
+
+    #[test]
+    fn test_parse_current_markers() {
+        for fixture in [
+            include_str!("../../tests/fixtures/nix-env-current-arrow.txt"),
+            include_str!("../../tests/fixtures/nix-env-current-suffix.txt"),
+        ] {
+            let generations = parse_generations_output(fixture, PROFILE, "").unwrap();
+            let ids: Vec<&str> = generations.iter().map(|g| g.id.as_str()).collect();
+            assert_eq!(ids, ["1", "2", "3"]);
+            let current: Vec<bool> = generations.iter().map(|g| g.current).collect();
+            assert_eq!(current, [false, true, false]);
+            assert_eq!(
+                generations[1].description.as_deref(),
+                Some("nixos-24.05.20240210.f9d39fb")
+            );
+        }
+
+        let generations =
+            parse_generations_output("-> 7   2024-02-09 10:00:00", PROFILE, "").unwrap();
+        assert_eq!(generations[0].id, "7");
+        assert!(generations[0].current);
+        assert_eq!(generations[0].description, None);
+    }
+
+    #[test]
+    fn test_parse_nix_env_descriptions() {
+        let generations = parse_generations_output(
+            include_str!("../../tests/fixtures/nix-env-descriptions.txt"),
+            PROFILE,
+            "",
+        )
+        .unwrap();
+
+        let descriptions: Vec<Option<&str>> = generations
+            .iter()
+            .map(|g| g.description.as_deref())
+            .collect();
+        assert_eq!(
+            descriptions,
+            [
+                None,
+                Some("nixos-24.05.20240210.f9d39fb"),
+                Some("nixos-24.05.20240212.0c2c8a0"),
+                None,
+            ]
+        );
+        let current: Vec<bool> = generations.iter().map(|g| g.current).collect();
+        assert_eq!(current, [false, false, true, false]);
+        assert_eq!(
+            generations[2].nixos_version.as_deref(),
+            Some("24.05.20240212.0c2c8a0")
+        );
+
+        let old = parse_generations_output(NIX_ENV_FIXTURE, PROFILE, "2").unwrap();
+        assert_eq!(old[1].description, None);
+        assert!(old[1].current);
+
+        // The symlink wins over a stale marker.
+        let old = parse_generations_output(NIX_ENV_FIXTURE, PROFILE, "1").unwrap();
+        assert!(old[0].current);
+        assert!(!old[1].current);
+    }
+
+    #[test]
+    fn test_parse_both_generation_formats() {
+        let old = parse_generations_output(NIX_ENV_FIXTURE, PROFILE, "2").unwrap();
+        assert_eq!(old.len(), 2);
+        assert_eq!(old[0].nixos_version, None);
+
+        let new = parse_generations_output(NIXOS_REBUILD_FIXTURE, PROFILE, "2").unwrap();
+        assert_eq!(new.len(), 2);
+        assert_eq!(new[0].id, "2");
+        assert!(new[0].current);
+        assert_eq!(
+            new[0]
+                .timestamp
+                .with_timezone(&Local)
+                .naive_local()
+                .to_string(),
+            "2024-02-10 11:30:00"
+        );
+        assert_eq!(
+            new[0].nixos_version.as_deref(),
+            Some("24.05.20240210.f9d39fb (Uakari)")
+        );
+        assert_eq!(new[0].kernel_version.as_deref(), Some("6.6.15"));
+        assert_eq!(new[1].id, "1");
+        assert!(!new[1].current);
+        assert_eq!(new[1].kernel_version.as_deref(), Some("6.6.14"));
+        for (old, new) in old.iter().zip(new.iter().rev()) {
+            assert_eq!(old.id, new.id);
+            assert_eq!(old.timestamp, new.timestamp);
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use std::env;
+use std::path::{Path, PathBuf};
+
+pub const SYSTEM_PROFILE: &str = "/nix/var/nix/profiles/system";
+
+/// Resolve a well-known profile name to its path.
+///
+/// `system` and `home-manager` are recognised; anything else is treated as
+/// a literal profile path.
+pub fn resolve_profile(name: &str) -> PathBuf {
+    match name {
+        "system" => PathBuf::from(SYSTEM_PROFILE),
+        "home-manager" => home_manager_profile(),
+        path => PathBuf::from(path),
+    }
+}
+
+/// Whether `profile` holds NixOS system generations, the only ones with a
+/// NixOS version and kernel to read or that can have been booted: `system`,
+/// or a `system-profiles/<name>` made with `nixos-rebuild -p`.
+pub fn is_system_profile(profile: &Path) -> bool {
+    profile.file_name() == Some("system".as_ref())
+        || profile.parent().and_then(Path::file_name) == Some("system-profiles".as_ref())
+}
+
+/// The profile to inspect when none is named: the system profile, or on
+/// machines without one the highest-priority (last) existing entry of
+/// `NIX_PROFILES`.
+pub fn default_profile() -> PathBuf {
+    pick_default_profile(env::var("NIX_PROFILES").ok().as_deref(), |p| p.exists())
+}
+
+fn pick_default_profile(nix_profiles: Option<&str>, exists: impl Fn(&Path) -> bool) -> PathBuf {
+    let system = PathBuf::from(SYSTEM_PROFILE);
+    if exists(&system) {
+        return system;
+    }
+    nix_profiles
+        .unwrap_or_default()
+        .split_whitespace()
+        .rev()
+        .map(PathBuf::from)
+        .find(|p| exists(p))
+        .unwrap_or(system)
+}
+
+fn home_manager_profile() -> PathBuf {
+    let state_home = env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")));
+
+    if let Some(state_home) = state_home {
+        let profile = state_home.join("nix/profiles/home-manager");
+        if profile.exists() {
+            return profile;
+        }
+    }
+
+    // Legacy location used before Nix moved user profiles into XDG_STATE_HOME
+    let user = env::var("USER").unwrap_or_default();
+    PathBuf::from(format!(
+        "/nix/var/nix/profiles/per-user/{}/home-manager",
+        user
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_profile() {
+        assert_eq!(resolve_profile("system"), PathBuf::from(SYSTEM_PROFILE));
+        assert_eq!(
+            resolve_profile("/nix/var/nix/profiles/custom"),
+            PathBuf::from("/nix/var/nix/profiles/custom")
+        );
+        assert!(resolve_profile("home-manager").ends_with("home-manager"));
+    }
+
+    #[test]
+    fn test_is_system_profile() {
+        assert!(is_system_profile(Path::new(SYSTEM_PROFILE)));
+        assert!(is_system_profile(Path::new(
+            "/mnt/nix/var/nix/profiles/system"
+        )));
+        assert!(is_system_profile(Path::new(
+            "/nix/var/nix/profiles/system-profiles/work"
+        )));
+        assert!(!is_system_profile(Path::new("/home/alice/.nix-profile")));
+        assert!(!is_system_profile(&resolve_profile("home-manager")));
+    }
+
+    #[test]
+    fn test_pick_default_profile() {
+        let profiles = Some("/nix/var/nix/profiles/default /mnt/home/alice/.nix-profile /missing");
+        let exists = |p: &Path| p != Path::new("/missing");
+
+        assert_eq!(
+            pick_default_profile(profiles, exists),
+            PathBuf::from(SYSTEM_PROFILE)
+        );
+        assert_eq!(
+            pick_default_profile(profiles, |p| p != Path::new(SYSTEM_PROFILE) && exists(p)),
+            PathBuf::from("/mnt/home/alice/.nix-profile")
+        );
+        assert_eq!(
+            pick_default_profile(None, |_| false),
+            PathBuf::from(SYSTEM_PROFILE)
+        );
+    }
+}
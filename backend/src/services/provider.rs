@@ -0,0 +1,71 @@
+use std::env;
+use std::path::Path;
+
+use crate::error::Result;
+use crate::models::generation::Generation;
+use crate::services::nix::{step, Direction};
+
+/// Platform-specific access to the generations of a system profile.
+///
+/// NixOS and nix-darwin keep their system profiles the same way but ship
+/// different tools for inspecting them; each implementation wraps one.
+pub trait GenerationProvider {
+    fn list_generations(&self) -> Result<Vec<Generation>>;
+
+    /// The generations without the versions and boot state read from the
+    /// store, for listings that don't show them.
+    fn read_generations(&self) -> Result<Vec<Generation>> {
+        self.list_generations()
+    }
+
+    fn get_current(&self) -> Result<Generation>;
+
+    /// The store path generation `id` points at.
+    fn get_store_path(&self, id: &str) -> Result<String>;
+
+    /// The generation right before or after `id` in this provider's list,
+    /// or `None` at either end of it.
+    fn get_neighbor(&self, id: &str, direction: Direction) -> Result<Option<Generation>> {
+        let generations = self.list_generations()?;
+        let ids = generations.iter().map(|g| g.id.clone()).collect();
+        let Some(neighbor) = step(ids, id, 1, direction)? else {
+            return Ok(None);
+        };
+        Ok(generations.into_iter().find(|g| g.id == neighbor))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Platform {
+    Nixos,
+    /// nix-darwin on macOS
+    Darwin,
+}
+
+/// How a profile's generations are managed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ProfileKind {
+    /// nix-env, nixos-rebuild and home-manager
+    NixEnv,
+    /// The new-style `nix profile`
+    NixProfile,
+}
+
+impl Platform {
+    /// NixOS when `/etc/NIXOS` exists, nix-darwin when `darwin-rebuild` is
+    /// on `PATH`, and NixOS otherwise.
+    pub fn detect() -> Self {
+        if Path::new("/etc/NIXOS").exists() {
+            return Platform::Nixos;
+        }
+
+        let darwin_rebuild = env::var_os("PATH").is_some_and(|path| {
+            env::split_paths(&path).any(|dir| dir.join("darwin-rebuild").is_file())
+        });
+        if darwin_rebuild {
+            Platform::Darwin
+        } else {
+            Platform::Nixos
+        }
+    }
+}
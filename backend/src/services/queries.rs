@@ -0,0 +1,683 @@
+use futures_util::future::join_all;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::pin;
+use std::process::Output;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, trace, warn};
+
+use crate::error::{Error, Result};
+use crate::models::diff::{DetailedDiff, DiffMode, GenerationDiff};
+use crate::models::generation::Generation;
+use crate::services::cache::{
+    profile_modified, DiffCache, DiffKey, GenerationCache, ReferenceCache,
+};
+use crate::services::diff::diff_references;
+use crate::services::nix::{
+    closure_diff_result, current_unavailable, nth_before, parse_ref, same_generation, with_store,
+    GenerationRef, DEFAULT_JOBS,
+};
+use crate::services::parse::{
+    generation_link, kernel_version_from_target, parse_current_generation, parse_diff_output,
+    parse_generations_output, parse_references,
+};
+use crate::services::profile::{is_system_profile, SYSTEM_PROFILE};
+use crate::services::runner::RetryPolicy;
+
+/// How a service runs the commands behind `Queries`: `NixService` blocks
+/// on each one, `AsyncNixService` awaits it.
+pub(crate) trait Exec: Sync {
+    /// Run `program` once, exactly as given.
+    fn run_command(
+        &self,
+        program: &str,
+        args: &[&str],
+    ) -> impl Future<Output = Result<Output>> + Send;
+
+    fn sleep(&self, delay: Duration) -> impl Future<Output = ()> + Send;
+
+    /// The output of every future, in order, with at most `jobs` running at
+    /// once.
+    fn join<U, Fut>(&self, futures: Vec<Fut>, jobs: usize) -> impl Future<Output = Vec<U>> + Send
+    where
+        U: Send,
+        Fut: Future<Output = U> + Send;
+
+    /// See `CommandRunner::is_remote`.
+    fn is_remote(&self) -> bool;
+}
+
+/// Drive `future` to completion on this thread. Only for the futures of the
+/// blocking service, which never wait: its commands block instead.
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+        Poll::Ready(output) => output,
+        Poll::Pending => unreachable!("blocking commands never leave a future pending"),
+    }
+}
+
+/// `Exec::join` for async services: `jobs` futures at a time.
+pub(crate) async fn join_chunked<U, Fut: Future<Output = U>>(
+    futures: Vec<Fut>,
+    jobs: usize,
+) -> Vec<U> {
+    let mut results = Vec::with_capacity(futures.len());
+    let mut futures = futures.into_iter();
+    loop {
+        let chunk: Vec<Fut> = futures.by_ref().take(jobs.max(1)).collect();
+        if chunk.is_empty() {
+            return results;
+        }
+        results.extend(join_all(chunk).await);
+    }
+}
+
+/// The profile a service queries and what it remembers about it, with the
+/// queries `NixService` and `AsyncNixService` have in common. Each is
+/// written once, against whichever `Exec` the service provides.
+pub(crate) struct Queries {
+    pub(crate) profile_path: PathBuf,
+    pub(crate) store: Option<String>,
+    pub(crate) retry: RetryPolicy,
+    pub(crate) jobs: usize,
+    pub(crate) cache: GenerationCache,
+    pub(crate) references: ReferenceCache,
+    pub(crate) path_diffs: DiffCache<GenerationDiff>,
+    pub(crate) package_diffs: DiffCache<DetailedDiff>,
+}
+
+impl Default for Queries {
+    fn default() -> Self {
+        Self {
+            profile_path: PathBuf::from(SYSTEM_PROFILE),
+            store: None,
+            retry: RetryPolicy::default(),
+            jobs: DEFAULT_JOBS,
+            cache: GenerationCache::default(),
+            references: ReferenceCache::default(),
+            path_diffs: DiffCache::default(),
+            package_diffs: DiffCache::default(),
+        }
+    }
+}
+
+impl Queries {
+    pub(crate) fn profile_path(&self) -> &Path {
+        &self.profile_path
+    }
+
+    pub(crate) fn profile(&self) -> String {
+        self.profile_path.to_string_lossy().into_owned()
+    }
+
+    pub(crate) fn generation_link(&self, id: &str) -> String {
+        generation_link(&self.profile(), id)
+    }
+
+    /// The mtime of the profile symlink, with `stat` where the commands run
+    /// when that's another machine.
+    pub(crate) async fn profile_modified(&self, exec: &impl Exec) -> Option<SystemTime> {
+        if !exec.is_remote() {
+            return profile_modified(&self.profile_path);
+        }
+        let output = self
+            .run(exec, "stat", &["-c", "%Y", &self.profile()])
+            .await
+            .ok()?;
+        let secs = String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .parse()
+            .ok()?;
+        output
+            .status
+            .success()
+            .then(|| SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    pub(crate) fn diff_key(&self, from: &str, to: &str, mode: DiffMode) -> DiffKey {
+        DiffKey::new(&self.profile_path, self.store.as_deref(), from, to, mode)
+    }
+
+    /// Run a command, retrying with exponential backoff while it fails with
+    /// a stderr the retry policy considers transient.
+    pub(crate) async fn run(
+        &self,
+        exec: &impl Exec,
+        program: &str,
+        args: &[&str],
+    ) -> Result<Output> {
+        let args = &with_store(program, args, self.store.as_deref());
+        let mut attempt = 0;
+        loop {
+            let started = Instant::now();
+            let output = exec.run_command(program, args).await?;
+            debug!(
+                program,
+                ?args,
+                elapsed = ?started.elapsed(),
+                status = %output.status,
+                "ran command"
+            );
+
+            if output.status.success() {
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                trace!(program, stdout = %stdout, "command succeeded");
+                return Ok(output);
+            }
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            debug!(program, stderr = %stderr.trim(), "command failed");
+            if attempt >= self.retry.max_retries || !self.retry.is_retryable(&stderr) {
+                return Ok(output);
+            }
+
+            let delay = self.retry.delay(attempt);
+            debug!(
+                program,
+                attempt = attempt + 1,
+                ?delay,
+                "retrying transient failure"
+            );
+            exec.sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    pub(crate) async fn list_generations(&self, exec: &impl Exec) -> Result<Vec<Generation>> {
+        let mut generations = self.read_generations(exec).await?;
+        self.enrich(exec, &mut generations).await;
+
+        Ok(generations)
+    }
+
+    /// Read what the generation list leaves out from the store: boot state
+    /// and NixOS and kernel versions. Only system profiles have any, so
+    /// other profiles are left as they are without running anything.
+    pub(crate) async fn enrich(&self, exec: &impl Exec, generations: &mut [Generation]) {
+        self.enrich_versions(exec, generations).await;
+        self.mark_booted(exec, generations).await;
+    }
+
+    /// The generation list as printed by nix, without reading any versions
+    /// or boot state from the store.
+    pub(crate) async fn read_generations(&self, exec: &impl Exec) -> Result<Vec<Generation>> {
+        // Taken before the list is read, so a switch while reading is noticed.
+        // Without a cache there's no need to `stat` a remote profile.
+        let modified = if self.cache.ttl().is_zero() {
+            None
+        } else {
+            self.profile_modified(exec).await
+        };
+        if let Some(generations) = self.cache.get(&self.profile_path, modified) {
+            debug!(profile = %self.profile(), "using cached generation list");
+            return Ok(generations);
+        }
+
+        let profile = self.profile();
+        let output = self
+            .run(exec, "nix-env", &["--list-generations", "-p", &profile])
+            .await?;
+
+        if !output.status.success() {
+            return Err(Error::NixCommandError(
+                String::from_utf8_lossy(&output.stderr).to_string(),
+            ));
+        }
+
+        // A fresh profile has no generations and no symlink to read.
+        let output_str = String::from_utf8_lossy(&output.stdout);
+        let generations = if output_str.trim().is_empty() {
+            Vec::new()
+        } else {
+            let current_generation = self.get_current_generation(exec).await?;
+            parse_generations_output(&output_str, &profile, &current_generation)?
+        };
+        self.cache
+            .insert(&self.profile_path, modified, &generations);
+        Ok(generations)
+    }
+
+    pub(crate) async fn generation_exists(&self, exec: &impl Exec, id: &str) -> Result<bool> {
+        Ok(self
+            .read_generations(exec)
+            .await?
+            .iter()
+            .any(|g| g.id == id))
+    }
+
+    /// See `NixService::resolve_ref`.
+    pub(crate) async fn resolve_ref(&self, exec: &impl Exec, reference: &str) -> Result<String> {
+        let offset = match parse_ref(reference)? {
+            GenerationRef::Id(id) => return Ok(id),
+            GenerationRef::Booted => {
+                return self
+                    .get_booted_generation(exec)
+                    .await?
+                    .ok_or_else(|| Error::GenerationNotFound("booted".to_string()))
+            }
+            GenerationRef::Offset(offset) => offset,
+        };
+
+        let current = match self.get_current_generation(exec).await {
+            Ok(current) => current,
+            Err(e) => return Err(self.unless_empty(exec, e).await),
+        };
+        if offset == 0 {
+            return Ok(current);
+        }
+
+        let ids = self
+            .read_generations(exec)
+            .await?
+            .into_iter()
+            .map(|g| g.id)
+            .collect();
+        nth_before(ids, &current, offset, reference)
+    }
+
+    /// Set `booted` on the generation whose store path `/run/booted-system`
+    /// points at. Without `/run/booted-system` every flag stays `false`.
+    pub(crate) async fn mark_booted(&self, exec: &impl Exec, generations: &mut [Generation]) {
+        if !is_system_profile(&self.profile_path) {
+            return;
+        }
+        let Some(booted) = self
+            .resolve_symlink(exec, "/run/booted-system".to_string())
+            .await
+        else {
+            return;
+        };
+
+        let mut lookups = Vec::new();
+        for generation in generations.iter() {
+            lookups.push(self.resolve_symlink(exec, self.generation_link(&generation.id)));
+        }
+        let targets = exec.join(lookups, self.jobs).await;
+        for (generation, target) in generations.iter_mut().zip(targets) {
+            generation.booted = target.as_deref() == Some(booted.as_str());
+        }
+    }
+
+    async fn resolve_symlink(&self, exec: &impl Exec, path: String) -> Option<String> {
+        let output = self.run(exec, "readlink", &[&path]).await.ok()?;
+        let target = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        (output.status.success() && !target.is_empty()).then_some(target)
+    }
+
+    /// Id of the generation the machine was booted into, if it is still in
+    /// the profile.
+    pub(crate) async fn get_booted_generation(&self, exec: &impl Exec) -> Result<Option<String>> {
+        let mut generations = self.read_generations(exec).await?;
+        self.mark_booted(exec, &mut generations).await;
+        Ok(generations.into_iter().find(|g| g.booted).map(|g| g.id))
+    }
+
+    /// The generation the machine was booted into, with its versions.
+    pub(crate) async fn get_booted(&self, exec: &impl Exec) -> Result<Generation> {
+        let mut generations = self.read_generations(exec).await?;
+        self.mark_booted(exec, &mut generations).await;
+        let mut booted = generations
+            .into_iter()
+            .find(|g| g.booted)
+            .ok_or_else(|| Error::GenerationNotFound("booted".to_string()))?;
+        self.enrich_versions(exec, std::slice::from_mut(&mut booted))
+            .await;
+        Ok(booted)
+    }
+
+    /// Fill in missing NixOS and kernel versions from each generation's
+    /// store path. Profiles that aren't NixOS systems simply keep `None`.
+    async fn enrich_versions(&self, exec: &impl Exec, generations: &mut [Generation]) {
+        if !is_system_profile(&self.profile_path) {
+            return;
+        }
+        let mut reads = Vec::new();
+        for generation in generations.iter() {
+            reads.push(self.missing_versions(exec, generation));
+        }
+        let versions = exec.join(reads, self.jobs).await;
+
+        for (generation, (nixos, kernel)) in generations.iter_mut().zip(versions) {
+            generation.nixos_version = generation.nixos_version.take().or(nixos);
+            generation.kernel_version = generation.kernel_version.take().or(kernel);
+        }
+    }
+
+    /// The versions `generation` doesn't have yet, read from its link.
+    async fn missing_versions(
+        &self,
+        exec: &impl Exec,
+        generation: &Generation,
+    ) -> (Option<String>, Option<String>) {
+        let link = self.generation_link(&generation.id);
+        let nixos = match generation.nixos_version {
+            None => self.read_nixos_version(exec, &link).await.ok().flatten(),
+            Some(_) => None,
+        };
+        let kernel = match generation.kernel_version {
+            None => self.read_kernel_version(exec, &link).await.ok().flatten(),
+            Some(_) => None,
+        };
+        (nixos, kernel)
+    }
+
+    /// Read `<path>/nixos-version` from a system generation.
+    pub(crate) async fn read_nixos_version(
+        &self,
+        exec: &impl Exec,
+        store_path: &str,
+    ) -> Result<Option<String>> {
+        let output = self
+            .run(exec, "cat", &[&format!("{}/nixos-version", store_path)])
+            .await?;
+
+        if !output.status.success() {
+            return Ok(None);
+        }
+
+        let version = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        Ok((!version.is_empty()).then_some(version))
+    }
+
+    /// Resolve `<path>/kernel` and take the version of the kernel package it
+    /// points into, e.g. `/nix/store/<hash>-linux-6.6.15/bzImage`.
+    pub(crate) async fn read_kernel_version(
+        &self,
+        exec: &impl Exec,
+        store_path: &str,
+    ) -> Result<Option<String>> {
+        let output = self
+            .run(exec, "readlink", &[&format!("{}/kernel", store_path)])
+            .await?;
+
+        if !output.status.success() {
+            return Ok(None);
+        }
+
+        Ok(kernel_version_from_target(&String::from_utf8_lossy(
+            &output.stdout,
+        )))
+    }
+
+    /// The generation the profile symlink currently points at, with its
+    /// timestamp and description from the generation list.
+    pub(crate) async fn get_current(&self, exec: &impl Exec) -> Result<Generation> {
+        let id = match self.get_current_generation(exec).await {
+            Ok(id) => id,
+            Err(e) => match self.unless_empty(exec, e).await {
+                e @ Error::NoGenerations(_) => return Err(e),
+                e => return Err(current_unavailable(&self.profile(), e)),
+            },
+        };
+
+        let mut current = self
+            .read_generations(exec)
+            .await?
+            .into_iter()
+            .find(|g| g.id == id)
+            .ok_or(Error::GenerationNotFound(id))?;
+        self.enrich(exec, std::slice::from_mut(&mut current)).await;
+        Ok(current)
+    }
+
+    pub(crate) async fn get_current_generation(&self, exec: &impl Exec) -> Result<String> {
+        let profile = self.profile();
+        let output = self.run(exec, "readlink", &[&profile]).await?;
+
+        if !output.status.success() {
+            return Err(Error::NixCommandError(
+                String::from_utf8_lossy(&output.stderr).to_string(),
+            ));
+        }
+
+        parse_current_generation(&self.profile_path, &String::from_utf8_lossy(&output.stdout))
+    }
+
+    /// Diff two generations with `nix-diff`, or by their references when
+    /// nix-diff isn't installed.
+    pub(crate) async fn get_diff(
+        &self,
+        exec: &impl Exec,
+        from: &str,
+        to: &str,
+    ) -> Result<GenerationDiff> {
+        let key = self.diff_key(from, to, DiffMode::Auto);
+        self.path_diffs
+            .get_or_try_insert(self.profile_modified(exec).await, key, async {
+                match self.get_nix_diff(exec, from, to).await {
+                    Err(Error::ToolNotInstalled(_)) => {
+                        warn!("nix-diff is not installed, falling back to diffing references");
+                        self.get_reference_diff(exec, from, to).await
+                    }
+                    diff => diff,
+                }
+            })
+            .await
+    }
+
+    /// Diff the derivations of two generations with `nix-diff`.
+    pub(crate) async fn get_nix_diff(
+        &self,
+        exec: &impl Exec,
+        from: &str,
+        to: &str,
+    ) -> Result<GenerationDiff> {
+        let key = self.diff_key(from, to, DiffMode::NixDiff);
+        self.path_diffs
+            .get_or_try_insert(self.profile_modified(exec).await, key, async {
+                if same_generation(from, to)? {
+                    return Ok(GenerationDiff::default());
+                }
+
+                let (from_path, to_path) = self.store_paths(exec, from, to).await?;
+                parse_diff_output(&self.run_nix_diff(exec, &from_path, &to_path).await?)
+            })
+            .await
+    }
+
+    /// The raw output of `nix-diff` between two store paths.
+    pub(crate) async fn run_nix_diff(
+        &self,
+        exec: &impl Exec,
+        from_path: &str,
+        to_path: &str,
+    ) -> Result<String> {
+        let output = match self.run(exec, "nix-diff", &[from_path, to_path]).await {
+            Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(Error::ToolNotInstalled("nix-diff".to_string()));
+            }
+            output => output?,
+        };
+
+        if !output.status.success() {
+            return Err(Error::NixCommandError(
+                String::from_utf8_lossy(&output.stderr).to_string(),
+            ));
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+
+    /// Diff two generations by comparing the direct store references of
+    /// their profile links (`nix-store -q --references`).
+    pub(crate) async fn get_reference_diff(
+        &self,
+        exec: &impl Exec,
+        from: &str,
+        to: &str,
+    ) -> Result<GenerationDiff> {
+        let key = self.diff_key(from, to, DiffMode::References);
+        self.path_diffs
+            .get_or_try_insert(self.profile_modified(exec).await, key, async {
+                if same_generation(from, to)? {
+                    return Ok(GenerationDiff::default());
+                }
+
+                let references = self.both(
+                    exec,
+                    self.get_generation_references(exec, from),
+                    self.get_generation_references(exec, to),
+                );
+                let (from_refs, to_refs) = match references.await {
+                    Ok(refs) => refs,
+                    Err(e) => return Err(self.unless_empty(exec, e).await),
+                };
+
+                Ok(diff_references(&from_refs, &to_refs))
+            })
+            .await
+    }
+
+    /// Diff two generations with `nix store diff-closures`, which reports
+    /// version transitions and closure size deltas per package.
+    ///
+    /// Falls back to `get_diff` (nix-diff) when `nix store` is unavailable.
+    pub(crate) async fn get_closure_diff(
+        &self,
+        exec: &impl Exec,
+        from: &str,
+        to: &str,
+    ) -> Result<DetailedDiff> {
+        let key = self.diff_key(from, to, DiffMode::Closures);
+        self.package_diffs
+            .get_or_try_insert(self.profile_modified(exec).await, key, async {
+                if same_generation(from, to)? {
+                    return Ok(DetailedDiff::default());
+                }
+
+                let (from_path, to_path) = self.store_paths(exec, from, to).await?;
+                let output = self
+                    .run(
+                        exec,
+                        "nix",
+                        &["store", "diff-closures", &from_path, &to_path],
+                    )
+                    .await;
+                match closure_diff_result(output) {
+                    Some(diff) => diff,
+                    None => Ok(self.get_diff(exec, from, to).await?.to_detailed()),
+                }
+            })
+            .await
+    }
+
+    /// Run the queries for both sides of a diff concurrently.
+    async fn both<T, Fut>(&self, exec: &impl Exec, from: Fut, to: Fut) -> Result<(T, T)>
+    where
+        T: Send,
+        Fut: Future<Output = Result<T>> + Send,
+    {
+        let mut results = exec.join(vec![from, to], self.jobs).await.into_iter();
+        match (results.next(), results.next()) {
+            (Some(from), Some(to)) => Ok((from?, to?)),
+            _ => unreachable!("one result per side"),
+        }
+    }
+
+    async fn store_paths(
+        &self,
+        exec: &impl Exec,
+        from: &str,
+        to: &str,
+    ) -> Result<(String, String)> {
+        self.both(
+            exec,
+            self.get_generation_store_path(exec, from),
+            self.get_generation_store_path(exec, to),
+        )
+        .await
+    }
+
+    pub(crate) async fn get_generation_store_path(
+        &self,
+        exec: &impl Exec,
+        id: &str,
+    ) -> Result<String> {
+        if self.store.is_some() {
+            return match self.resolve_generation_link(exec, id).await {
+                Ok(path) => Ok(path),
+                Err(e) => Err(self.unless_empty(exec, e).await),
+            };
+        }
+
+        let link = self.generation_link(id);
+        let output = self
+            .run(exec, "nix-env", &["-p", &link, "--query", "--out-path"])
+            .await?;
+
+        if !output.status.success() {
+            return Err(self
+                .unless_empty(exec, Error::GenerationNotFound(id.to_string()))
+                .await);
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    /// The direct references of generation `id`.
+    pub(crate) async fn get_generation_references(
+        &self,
+        exec: &impl Exec,
+        id: &str,
+    ) -> Result<Vec<String>> {
+        let path = self.store_target(exec, id).await?;
+        self.get_references(exec, &path).await
+    }
+
+    /// What store queries about generation `id` are run on. That is the
+    /// generation link itself, except with `--store`: the store can't follow
+    /// links outside it and may be on another machine, so the link is
+    /// resolved on this side (or `--host`) first.
+    pub(crate) async fn store_target(&self, exec: &impl Exec, id: &str) -> Result<String> {
+        match self.store {
+            Some(_) => self.resolve_generation_link(exec, id).await,
+            None => Ok(self.generation_link(id)),
+        }
+    }
+
+    /// The store path generation `id` points at, from its link alone, for
+    /// profiles with no manifest `nix-env --query` understands.
+    pub(crate) async fn resolve_generation_link(
+        &self,
+        exec: &impl Exec,
+        id: &str,
+    ) -> Result<String> {
+        let link = self.generation_link(id);
+        let output = self.run(exec, "readlink", &["-f", &link]).await?;
+        let path = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+        if !output.status.success() || path.is_empty() {
+            return Err(Error::GenerationNotFound(id.to_string()));
+        }
+        Ok(path)
+    }
+
+    pub(crate) async fn get_references(&self, exec: &impl Exec, path: &str) -> Result<Vec<String>> {
+        if let Some(references) = self.references.get(path) {
+            return Ok(references);
+        }
+
+        let output = self
+            .run(exec, "nix-store", &["-q", "--references", path])
+            .await?;
+
+        if !output.status.success() {
+            return Err(Error::NixCommandError(
+                String::from_utf8_lossy(&output.stderr).to_string(),
+            ));
+        }
+
+        let references = parse_references(&String::from_utf8_lossy(&output.stdout));
+        self.references.insert(path, &references);
+        Ok(references)
+    }
+
+    /// `error`, or `NoGenerations` when it was caused by the profile having
+    /// no generations at all.
+    pub(crate) async fn unless_empty(&self, exec: &impl Exec, error: Error) -> Error {
+        match self.read_generations(exec).await {
+            Ok(generations) if generations.is_empty() => Error::NoGenerations(self.profile()),
+            _ => error,
+        }
+    }
+}
@@ -0,0 +1,479 @@
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io::Read;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::error::{Error, Result};
+use crate::services::metrics::Metrics;
+
+/// Executes external programs on behalf of `NixService`.
+///
+/// Abstracting this lets the service be exercised against canned output
+/// instead of a real Nix store.
+pub trait CommandRunner: Sync {
+    fn run(&self, program: &str, args: &[&str]) -> Result<Output>;
+
+    /// Whether commands run on another machine, whose files can't be read
+    /// directly.
+    fn is_remote(&self) -> bool {
+        false
+    }
+}
+
+/// Runs commands via `std::process::Command`, killing any that run longer
+/// than `timeout`. Commands run locally, or on `host` through `ssh`.
+#[derive(Debug, Clone)]
+pub struct RealCommandRunner {
+    timeout: Duration,
+    host: Option<String>,
+}
+
+impl Default for RealCommandRunner {
+    fn default() -> Self {
+        Self::with_timeout(Duration::from_secs(60))
+    }
+}
+
+impl RealCommandRunner {
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            host: None,
+        }
+    }
+
+    /// Run every command on `host` (`user@host`) through `ssh`.
+    pub fn on_host(mut self, host: impl Into<String>) -> Self {
+        self.host = Some(host.into());
+        self
+    }
+
+    fn spawn(&self, program: &str, args: &[&str]) -> Result<Output> {
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // Drain both pipes while waiting so a chatty child can't block on a
+        // full pipe buffer.
+        let stdout = spawn_reader(child.stdout.take());
+        let stderr = spawn_reader(child.stderr.take());
+
+        // Poll quickly at first so short commands return promptly, backing
+        // off for long-running ones.
+        let deadline = Instant::now() + self.timeout;
+        let mut poll = Duration::from_millis(1);
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                child.kill()?;
+                child.wait()?;
+                // The readers are left behind: a grandchild may still hold
+                // the pipes open.
+                return Err(Error::CommandTimedOut {
+                    command: command_line(program, args),
+                    seconds: self.timeout.as_secs_f64(),
+                });
+            }
+            thread::sleep(poll.min(deadline.saturating_duration_since(Instant::now())));
+            poll = (poll * 2).min(Duration::from_millis(50));
+        };
+
+        Ok(Output {
+            status,
+            stdout: stdout.join().unwrap_or_default(),
+            stderr: stderr.join().unwrap_or_default(),
+        })
+    }
+}
+
+impl CommandRunner for RealCommandRunner {
+    fn run(&self, program: &str, args: &[&str]) -> Result<Output> {
+        match &self.host {
+            None => self.spawn(program, args),
+            Some(host) => {
+                let ssh_args = ssh_args(host, program, args);
+                let ssh_args: Vec<&str> = ssh_args.iter().map(String::as_str).collect();
+                remote_output(host, self.spawn("ssh", &ssh_args)?)
+            }
+        }
+    }
+
+    fn is_remote(&self) -> bool {
+        self.host.is_some()
+    }
+}
+
+/// The async counterpart of `CommandRunner`, used by `AsyncNixService`.
+pub trait AsyncCommandRunner: Send + Sync {
+    fn run(&self, program: &str, args: &[&str]) -> impl Future<Output = Result<Output>> + Send;
+
+    /// See `CommandRunner::is_remote`.
+    fn is_remote(&self) -> bool {
+        false
+    }
+}
+
+/// Runs commands via `tokio::process::Command`, killing any that run longer
+/// than `timeout`. Like `RealCommandRunner`, optionally on a remote host.
+#[derive(Debug, Clone)]
+pub struct TokioCommandRunner {
+    timeout: Duration,
+    host: Option<String>,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl Default for TokioCommandRunner {
+    fn default() -> Self {
+        Self::with_timeout(Duration::from_secs(60))
+    }
+}
+
+impl TokioCommandRunner {
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            host: None,
+            metrics: None,
+        }
+    }
+
+    /// Run every command on `host` (`user@host`) through `ssh`.
+    pub fn on_host(mut self, host: impl Into<String>) -> Self {
+        self.host = Some(host.into());
+        self
+    }
+
+    /// Count every command run, its failures and duration in `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    async fn run_once(&self, program: &str, args: &[&str]) -> Result<Output> {
+        match &self.host {
+            None => self.spawn(program, args).await,
+            Some(host) => {
+                let ssh_args = ssh_args(host, program, args);
+                let ssh_args: Vec<&str> = ssh_args.iter().map(String::as_str).collect();
+                remote_output(host, self.spawn("ssh", &ssh_args).await?)
+            }
+        }
+    }
+
+    async fn spawn(&self, program: &str, args: &[&str]) -> Result<Output> {
+        // Dropping the future on timeout kills the child.
+        let output = tokio::process::Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .output();
+
+        match tokio::time::timeout(self.timeout, output).await {
+            Ok(output) => Ok(output?),
+            Err(_) => Err(Error::CommandTimedOut {
+                command: command_line(program, args),
+                seconds: self.timeout.as_secs_f64(),
+            }),
+        }
+    }
+}
+
+impl AsyncCommandRunner for TokioCommandRunner {
+    async fn run(&self, program: &str, args: &[&str]) -> Result<Output> {
+        let started = Instant::now();
+        let output = self.run_once(program, args).await;
+        if let Some(metrics) = &self.metrics {
+            let succeeded = matches!(&output, Ok(output) if output.status.success());
+            metrics.record_command(program, started.elapsed(), succeeded);
+        }
+        output
+    }
+
+    fn is_remote(&self) -> bool {
+        self.host.is_some()
+    }
+}
+
+/// Arguments for `ssh` to run `program args...` on `host`. The remote shell
+/// splits the command line again, so every word is quoted. `BatchMode`
+/// makes a missing key fail instead of prompting for a password.
+fn ssh_args(host: &str, program: &str, args: &[&str]) -> Vec<String> {
+    let command = std::iter::once(program)
+        .chain(args.iter().copied())
+        .map(shell_quote)
+        .collect::<Vec<_>>()
+        .join(" ");
+    vec![
+        "-o".to_string(),
+        "BatchMode=yes".to_string(),
+        "--".to_string(),
+        host.to_string(),
+        command,
+    ]
+}
+
+fn shell_quote(word: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "@%+=:,./_-".contains(c);
+    if !word.is_empty() && word.chars().all(safe) {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', "'\\''"))
+    }
+}
+
+/// ssh exits with 255 when it couldn't reach `host`, as opposed to the
+/// remote command failing.
+fn remote_output(host: &str, output: Output) -> Result<Output> {
+    if output.status.code() == Some(255) {
+        return Err(Error::RemoteConnectionFailed {
+            host: host.to_string(),
+            reason: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
+    Ok(output)
+}
+
+/// `program arg1 arg2 ...`, as used in logs, errors and mock keys.
+fn command_line(program: &str, args: &[&str]) -> String {
+    std::iter::once(program)
+        .chain(args.iter().copied())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn spawn_reader(pipe: Option<impl Read + Send + 'static>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        buf
+    })
+}
+
+/// Which failed commands are worth retrying, and how long to wait between
+/// attempts.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt; `0` disables retrying.
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for every further one.
+    pub base_delay: Duration,
+    /// Substrings of stderr that mark a failure as transient.
+    pub retryable_patterns: Vec<String>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            base_delay: Duration::from_millis(100),
+            retryable_patterns: vec!["database is locked".to_string(), "is busy".to_string()],
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn is_retryable(&self, stderr: &str) -> bool {
+        self.retryable_patterns
+            .iter()
+            .any(|pattern| stderr.contains(pattern.as_str()))
+    }
+
+    /// Backoff before retry number `attempt`, counting from zero.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.base_delay.saturating_mul(2u32.saturating_pow(attempt))
+    }
+}
+
+/// Returns pre-recorded output for known command lines.
+///
+/// Responses are keyed by the full command line (`program arg1 arg2 ...`).
+/// When several responses are queued for the same command they are returned
+/// in order, with the last one repeated once the queue is drained.
+#[derive(Debug, Default)]
+pub struct MockCommandRunner {
+    responses: Mutex<HashMap<String, VecDeque<Output>>>,
+    calls: Mutex<Vec<String>>,
+    missing: Vec<String>,
+    remote: bool,
+}
+
+impl MockCommandRunner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue a response for `command`, e.g. `"readlink /nix/var/nix/profiles/system"`.
+    pub fn with_response(self, command: &str, status: i32, stdout: &str, stderr: &str) -> Self {
+        let output = Output {
+            status: ExitStatus::from_raw(status << 8),
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: stderr.as_bytes().to_vec(),
+        };
+
+        self.responses
+            .lock()
+            .unwrap()
+            .entry(command.to_string())
+            .or_default()
+            .push_back(output);
+        self
+    }
+
+    /// Shorthand for a successful response with the given stdout.
+    pub fn with_stdout(self, command: &str, stdout: &str) -> Self {
+        self.with_response(command, 0, stdout, "")
+    }
+
+    /// Fail every run of `program` as if it weren't installed.
+    pub fn with_missing_program(mut self, program: &str) -> Self {
+        self.missing.push(program.to_string());
+        self
+    }
+
+    /// Pretend to run every command on another machine.
+    pub fn remote(mut self) -> Self {
+        self.remote = true;
+        self
+    }
+
+    /// Command lines that have been run so far, in order.
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.lock().unwrap().clone()
+    }
+}
+
+impl CommandRunner for MockCommandRunner {
+    fn run(&self, program: &str, args: &[&str]) -> Result<Output> {
+        let command = command_line(program, args);
+        self.calls.lock().unwrap().push(command.clone());
+        if self.missing.iter().any(|missing| missing == program) {
+            return Err(std::io::Error::from(std::io::ErrorKind::NotFound).into());
+        }
+
+        let mut responses = self.responses.lock().unwrap();
+        let queue = responses
+            .get_mut(&command)
+            .ok_or_else(|| Error::NixCommandError(format!("no mock response for `{}`", command)))?;
+
+        if queue.len() > 1 {
+            Ok(queue.pop_front().unwrap())
+        } else {
+            Ok(queue.front().unwrap().clone())
+        }
+    }
+
+    fn is_remote(&self) -> bool {
+        self.remote
+    }
+}
+
+impl AsyncCommandRunner for MockCommandRunner {
+    fn run(&self, program: &str, args: &[&str]) -> impl Future<Output = Result<Output>> + Send {
+        std::future::ready(CommandRunner::run(self, program, args))
+    }
+
+    fn is_remote(&self) -> bool {
+        self.remote
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_real_runner_captures_output() {
+        let runner = RealCommandRunner::default();
+        let output = runner
+            .run("sh", &["-c", "echo out; echo err >&2; exit 3"])
+            .unwrap();
+
+        assert_eq!(output.stdout, b"out\n");
+        assert_eq!(output.stderr, b"err\n");
+        assert_eq!(output.status.code(), Some(3));
+    }
+
+    #[test]
+    fn test_real_runner_kills_on_timeout() {
+        let runner = RealCommandRunner::with_timeout(Duration::from_millis(100));
+        let started = Instant::now();
+
+        let err = runner.run("sleep", &["10"]).unwrap_err();
+        assert!(matches!(err, Error::CommandTimedOut { ref command, .. } if command == "sleep 10"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_ssh_transport() {
+        assert_eq!(
+            ssh_args(
+                "root@box",
+                "nix-env",
+                &["-p", "/nix/var/nix/profiles/system"]
+            ),
+            [
+                "-o",
+                "BatchMode=yes",
+                "--",
+                "root@box",
+                "nix-env -p /nix/var/nix/profiles/system"
+            ]
+        );
+        assert_eq!(shell_quote("it's here"), "'it'\\''s here'");
+        assert_eq!(shell_quote(""), "''");
+
+        let failed = Output {
+            status: ExitStatus::from_raw(255 << 8),
+            stdout: Vec::new(),
+            stderr: b"ssh: connect to host box port 22: Connection refused\n".to_vec(),
+        };
+        assert!(matches!(
+            remote_output("box", failed),
+            Err(Error::RemoteConnectionFailed { ref reason, .. }) if reason.ends_with("refused")
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_tokio_runner_captures_output_and_times_out() {
+        let runner = TokioCommandRunner::with_timeout(Duration::from_millis(100));
+        let output = AsyncCommandRunner::run(&runner, "sh", &["-c", "echo out; exit 3"])
+            .await
+            .unwrap();
+        assert_eq!(output.stdout, b"out\n");
+        assert_eq!(output.status.code(), Some(3));
+
+        let err = AsyncCommandRunner::run(&runner, "sleep", &["10"])
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::CommandTimedOut { ref command, .. } if command == "sleep 10"));
+    }
+
+    #[tokio::test]
+    async fn test_tokio_runner_records_metrics() {
+        let metrics = Arc::new(Metrics::new());
+        let runner = TokioCommandRunner::with_timeout(Duration::from_millis(100))
+            .with_metrics(metrics.clone());
+        AsyncCommandRunner::run(&runner, "true", &[]).await.unwrap();
+        AsyncCommandRunner::run(&runner, "false", &[])
+            .await
+            .unwrap();
+        let _ = AsyncCommandRunner::run(&runner, "sleep", &["10"]).await;
+
+        let text = metrics.render();
+        assert!(text.contains("nix_timemach_commands_total{program=\"false\"} 1\n"));
+        assert!(text.contains("nix_timemach_command_failures_total{program=\"false\"} 1\n"));
+        assert!(text.contains("nix_timemach_command_failures_total{program=\"sleep\"} 1\n"));
+        assert!(!text.contains("nix_timemach_command_failures_total{program=\"true\"}"));
+    }
+}
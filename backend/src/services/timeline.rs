@@ -0,0 +1,73 @@
+use crate::models::generation::{compare_generation_ids, Generation};
+
+/// Merge the generations of several profiles into one list ordered by build
+/// date, tagging each with the name of the profile it came from.
+///
+/// Generations are never collapsed, so profiles sharing an id each keep
+/// their own entry. Equal timestamps fall back to the order the profiles
+/// were given in, then to the generation id.
+pub fn merge_timeline(profiles: Vec<(String, Vec<Generation>)>) -> Vec<Generation> {
+    let mut timeline: Vec<(usize, Generation)> = profiles
+        .into_iter()
+        .enumerate()
+        .flat_map(|(index, (name, generations))| {
+            generations.into_iter().map(move |mut generation| {
+                generation.profile_name = Some(name.clone());
+                (index, generation)
+            })
+        })
+        .collect();
+
+    timeline.sort_by(|(a_index, a), (b_index, b)| {
+        a.timestamp
+            .cmp(&b.timestamp)
+            .then(a_index.cmp(b_index))
+            .then_with(|| compare_generation_ids(&a.id, &b.id))
+    });
+    timeline
+        .into_iter()
+        .map(|(_, generation)| generation)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+
+    fn generation(id: &str, day: u32) -> Generation {
+        Generation {
+            id: id.to_string(),
+            timestamp: Utc.with_ymd_and_hms(2024, 2, day, 10, 0, 0).unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_merge_timeline_keeps_shared_ids_apart() {
+        let timeline = merge_timeline(vec![
+            (
+                "system".to_string(),
+                vec![generation("1", 9), generation("2", 12)],
+            ),
+            (
+                "home-manager".to_string(),
+                vec![generation("1", 10), generation("2", 12)],
+            ),
+        ]);
+
+        let entries: Vec<(&str, &str)> = timeline
+            .iter()
+            .map(|g| (g.profile_name.as_deref().unwrap(), g.id.as_str()))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                ("system", "1"),
+                ("home-manager", "1"),
+                ("system", "2"),
+                ("home-manager", "2"),
+            ]
+        );
+    }
+}
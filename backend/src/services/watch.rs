@@ -0,0 +1,95 @@
+use std::collections::HashSet;
+
+use crate::models::event::WatchEvent;
+use crate::models::generation::{compare_generation_ids, Generation};
+
+/// Remembers what a previous poll of the generation list returned, so that
+/// repeated polls only report what changed.
+#[derive(Debug, Default)]
+pub struct GenerationWatcher {
+    seen: Option<HashSet<String>>,
+    current: Option<String>,
+}
+
+impl GenerationWatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// New generations (oldest first), then a rollback if the current
+    /// generation moved to an older one that already existed. The first
+    /// poll only records what already exists.
+    pub fn poll(&mut self, generations: Vec<Generation>) -> Vec<WatchEvent> {
+        let ids = generations.iter().map(|g| g.id.clone()).collect();
+        let current = generations.iter().find(|g| g.current).cloned();
+        let previous = std::mem::replace(&mut self.current, current.as_ref().map(|g| g.id.clone()));
+        let Some(seen) = self.seen.replace(ids) else {
+            return Vec::new();
+        };
+
+        let mut new: Vec<Generation> = generations
+            .into_iter()
+            .filter(|g| !seen.contains(&g.id))
+            .collect();
+        new.sort_by(|a, b| compare_generation_ids(&a.id, &b.id));
+        let mut events: Vec<WatchEvent> = new.into_iter().map(WatchEvent::NewGeneration).collect();
+
+        if let (Some(from), Some(to)) = (previous, current) {
+            if seen.contains(&to.id) && compare_generation_ids(&to.id, &from).is_lt() {
+                events.push(WatchEvent::Rollback { from, to });
+            }
+        }
+
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generations(ids: &[&str], current: &str) -> Vec<Generation> {
+        ids.iter()
+            .map(|id| Generation {
+                id: id.to_string(),
+                current: *id == current,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    fn describe(events: &[WatchEvent]) -> Vec<String> {
+        events
+            .iter()
+            .map(|event| match event {
+                WatchEvent::NewGeneration(g) => format!("new {}", g.id),
+                WatchEvent::Rollback { from, to } => format!("rollback {} -> {}", from, to.id),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_watcher_reports_new_generations() {
+        let mut watcher = GenerationWatcher::new();
+        assert!(watcher.poll(generations(&["1", "2"], "2")).is_empty());
+        assert!(watcher.poll(generations(&["1", "2"], "2")).is_empty());
+
+        let events = watcher.poll(generations(&["2", "11", "3"], "11"));
+        assert_eq!(describe(&events), ["new 3", "new 11"]);
+        assert!(watcher
+            .poll(generations(&["2", "3", "11"], "11"))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_watcher_reports_rollbacks() {
+        let mut watcher = GenerationWatcher::new();
+        watcher.poll(generations(&["1", "2", "3"], "3"));
+
+        let events = watcher.poll(generations(&["1", "2", "3"], "1"));
+        assert_eq!(describe(&events), ["rollback 3 -> 1"]);
+
+        // Switching forward again is not a rollback.
+        assert!(watcher.poll(generations(&["1", "2", "3"], "3")).is_empty());
+    }
+}
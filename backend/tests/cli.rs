@@ -0,0 +1,175 @@
+use assert_cmd::Command;
+use serde_json::{json, Value};
+
+/// Run the binary with the fake nix tools in `tests/fixtures/bin` first on
+/// `PATH`, returning its output.
+fn run(args: &[&str]) -> String {
+    run_with_env(args, &[])
+}
+
+fn run_with_env(args: &[&str], envs: &[(&str, &str)]) -> String {
+    let bin = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/bin");
+    let path = format!("{}:{}", bin, std::env::var("PATH").unwrap());
+    let output = Command::cargo_bin("nix-timemach")
+        .unwrap()
+        .arg("--skip-env-check")
+        .args(args)
+        .env("PATH", path)
+        .envs(envs.iter().copied())
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    String::from_utf8(output).unwrap()
+}
+
+fn nix_timemach(args: &[&str]) -> Value {
+    serde_json::from_str(&run(args)).unwrap()
+}
+
+#[test]
+fn test_list_generations() {
+    let generations = nix_timemach(&["list-generations"]);
+    let generations = generations.as_array().unwrap();
+
+    assert_eq!(generations.len(), 2);
+    assert_eq!(generations[0]["id"], "1");
+    assert_eq!(generations[0]["current"], false);
+    assert_eq!(generations[1]["id"], "2");
+    assert_eq!(generations[1]["current"], true);
+    assert!(generations[1]["timestamp"].is_string());
+}
+
+#[test]
+fn test_no_description() {
+    let descriptions = [("NIX_ENV_GENERATIONS", "nix-env-descriptions.txt")];
+    let generations: Value =
+        serde_json::from_str(&run_with_env(&["list-generations"], &descriptions)).unwrap();
+    assert_eq!(
+        generations[1]["description"],
+        "nixos-24.05.20240210.f9d39fb"
+    );
+    // Generations without one leave the field out rather than null.
+    assert!(generations[0].get("description").is_none());
+
+    let lean: Value = serde_json::from_str(&run_with_env(
+        &["list-generations", "--no-description"],
+        &descriptions,
+    ))
+    .unwrap();
+    let lean = lean.as_array().unwrap();
+    assert_eq!(lean.len(), 4);
+    assert!(lean.iter().all(|g| g.get("description").is_none()));
+    assert_eq!(lean[2]["id"], "3");
+
+    let table = run_with_env(
+        &["list-generations", "--no-description", "--format", "table"],
+        &descriptions,
+    );
+    assert!(!table.contains("DESCRIPTION"));
+}
+
+#[test]
+fn test_field_projection() {
+    let generations = nix_timemach(&["list-generations", "--field", "id,timestamp,current"]);
+    let generations = generations.as_array().unwrap();
+    assert_eq!(generations.len(), 2);
+    assert_eq!(
+        generations[1]
+            .as_object()
+            .unwrap()
+            .keys()
+            .collect::<Vec<_>>(),
+        ["current", "id", "timestamp"]
+    );
+    assert_eq!(generations[1]["current"], true);
+
+    let table = run(&[
+        "list-generations",
+        "--field",
+        "id",
+        "--field",
+        "current",
+        "--format",
+        "table",
+    ]);
+    assert!(table.starts_with("ID"), "{}", table);
+    assert!(!table.contains("DATE"));
+
+    let bin = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/bin");
+    let path = format!("{}:{}", bin, std::env::var("PATH").unwrap());
+    let output = Command::cargo_bin("nix-timemach")
+        .unwrap()
+        .args([
+            "--skip-env-check",
+            "list-generations",
+            "--field",
+            "id,sizes",
+        ])
+        .env("PATH", path)
+        .assert()
+        .code(2)
+        .get_output()
+        .stderr
+        .clone();
+    let stderr = String::from_utf8(output).unwrap();
+    assert!(stderr.contains("unknown field 'sizes'"), "{}", stderr);
+    assert!(stderr.contains("id, timestamp, description"), "{}", stderr);
+}
+
+#[test]
+fn test_reference_diff() {
+    let diff = nix_timemach(&["diff", "1", "2"]);
+
+    assert_eq!(
+        diff["added"],
+        json!([
+            "/nix/store/dddddddddddddddddddddddddddddddd-openssl-3.0.12",
+            "/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-firefox-122.0",
+        ])
+    );
+    assert_eq!(
+        diff["removed"],
+        json!([
+            "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-vim-9.0",
+            "/nix/store/cccccccccccccccccccccccccccccccc-openssl-3.0.10",
+        ])
+    );
+    assert_eq!(
+        diff["modified"],
+        json!(["/nix/store/cccccccccccccccccccccccccccccccc-openssl-3.0.10"])
+    );
+    assert_eq!(diff["summary"]["upgraded"], 1);
+}
+
+#[test]
+fn test_nix_diff() {
+    let diff = nix_timemach(&["diff", "1", "2", "--mode", "nix-diff"]);
+
+    assert_eq!(
+        diff["added"],
+        json!(["/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-firefox-122.0"])
+    );
+    assert_eq!(
+        diff["removed"],
+        json!(["/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-vim-9.0"])
+    );
+    assert_eq!(diff["summary"]["modified"], 1);
+}
+
+#[test]
+fn test_pretty() {
+    let compact = run(&["diff", "1", "2"]);
+    assert_eq!(compact.lines().count(), 1);
+
+    let pretty = run(&["diff", "1", "2", "--pretty"]);
+    assert!(pretty.starts_with("{\n  \"added\": ["));
+    assert_eq!(
+        serde_json::from_str::<Value>(&pretty).unwrap(),
+        serde_json::from_str::<Value>(&compact).unwrap()
+    );
+
+    let table = run(&["diff", "1", "2", "--pretty", "--format", "table"]);
+    assert!(table.starts_with("Added (2):"));
+}
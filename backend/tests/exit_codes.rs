@@ -0,0 +1,58 @@
+use std::process::{Command, Output};
+
+/// Run the binary with no nix tools on `PATH`.
+fn nix_timemach(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_nix-timemach"))
+        .args(args)
+        .env("PATH", "/nonexistent")
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn test_bad_generation_id_exits_with_2() {
+    let output = nix_timemach(&["--skip-env-check", "diff", "bogus", "2"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stdout.is_empty());
+
+    let output = nix_timemach(&[
+        "--skip-env-check",
+        "--error-format",
+        "json",
+        "diff",
+        "bogus",
+        "2",
+    ]);
+    assert_eq!(output.status.code(), Some(2));
+    let error: serde_json::Value = serde_json::from_slice(&output.stderr).unwrap();
+    assert_eq!(error["error"]["kind"], "invalid_argument");
+}
+
+#[test]
+fn test_missing_nix_is_reported_up_front() {
+    let output = nix_timemach(&["--error-format", "json", "list-generations"]);
+    assert_eq!(output.status.code(), Some(17));
+    let error: serde_json::Value = serde_json::from_slice(&output.stderr).unwrap();
+    assert_eq!(error["error"]["kind"], "nix_not_available");
+
+    let output = nix_timemach(&["--skip-env-check", "list-generations"]);
+    assert_eq!(output.status.code(), Some(15));
+
+    // Bad arguments are reported before nix is looked for.
+    let output = nix_timemach(&[
+        "--error-format",
+        "json",
+        "list-generations",
+        "--since",
+        "2024-02-11",
+        "--until",
+        "2024-02-10",
+    ]);
+    assert_eq!(output.status.code(), Some(2));
+    let error: serde_json::Value = serde_json::from_slice(&output.stderr).unwrap();
+    assert_eq!(error["error"]["kind"], "invalid_argument");
+
+    // Commands that never run nix don't need it.
+    let output = nix_timemach(&["schema", "generation"]);
+    assert_eq!(output.status.code(), Some(0));
+}
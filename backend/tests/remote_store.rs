@@ -0,0 +1,84 @@
+use std::fs;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::Path;
+use std::process::Command;
+
+/// Install a fake nix tool that fails unless it was given `--store uri`.
+fn fake_tool(bin: &Path, name: &str, uri: &str, body: &str) {
+    let path = bin.join(name);
+    let script = format!(
+        "#!/bin/sh\n\
+         if [ \"$1\" != --store ] || [ \"$2\" != '{uri}' ]; then\n\
+         echo \"missing --store: $*\" >&2; exit 1\n\
+         fi\n\
+         shift 2\n\
+         {body}\n"
+    );
+    fs::write(&path, script).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+/// A `file://` store stands in for `ssh-ng://`: neither can follow the
+/// profile's generation links, so they must be resolved before querying it.
+#[test]
+fn test_reference_diff_against_file_store() {
+    let dir = std::env::temp_dir().join(format!("nix-timemach-store-{}", std::process::id()));
+    let (bin, profiles, store) = (dir.join("bin"), dir.join("profiles"), dir.join("store"));
+    for path in [&bin, &profiles, &store] {
+        fs::create_dir_all(path).unwrap();
+    }
+    let uri = format!("file://{}", store.display());
+
+    let system = |id: &str| store.join(format!("{}-nixos-system-{id}", id.repeat(32)));
+    for id in ["1", "2"] {
+        fs::create_dir_all(system(id)).unwrap();
+        symlink(system(id), profiles.join(format!("system-{id}-link"))).unwrap();
+    }
+    symlink("system-2-link", profiles.join("system")).unwrap();
+
+    fake_tool(
+        &bin,
+        "nix-env",
+        &uri,
+        "printf '   1   2024-02-09 10:00:00   \\n   2   2024-02-10 11:30:00   (current)\\n'",
+    );
+    fake_tool(
+        &bin,
+        "nix-store",
+        &uri,
+        &format!(
+            "case \"$3\" in\n\
+             {} ) echo /nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-htop-3.2 ;;\n\
+             {} ) echo /nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-btop-1.3 ;;\n\
+             * ) echo \"not a store path: $3\" >&2; exit 1 ;;\n\
+             esac",
+            system("1").display(),
+            system("2").display()
+        ),
+    );
+
+    let path = format!("{}:{}", bin.display(), std::env::var("PATH").unwrap());
+    let output = Command::new(env!("CARGO_BIN_EXE_nix-timemach"))
+        .args(["--skip-env-check", "--store", &uri, "--profile"])
+        .arg(profiles.join("system"))
+        .args(["diff", "1", "2", "--mode", "references"])
+        .env("PATH", path)
+        .output()
+        .unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let diff: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(
+        diff["added"],
+        serde_json::json!(["/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-btop-1.3"])
+    );
+    assert_eq!(
+        diff["removed"],
+        serde_json::json!(["/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-htop-3.2"])
+    );
+}